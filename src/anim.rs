@@ -0,0 +1,165 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+//===========================================================================//
+
+/// An animation that cycles through some of the tiles in one tile file.
+/// Animations are listed in an optional `.anim` sidecar next to the tile
+/// file's `.ahi` file, one per line: the frame duration in milliseconds, a
+/// colon, and the tile indices of the frames (e.g. `150: 4 5 6 7`).  Blank
+/// lines are ignored, and `#` starts a comment.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TileAnimation {
+    frames: Vec<usize>,
+    frame_millis: u32,
+}
+
+impl TileAnimation {
+    pub fn parse_sidecar(text: &str) -> io::Result<Vec<TileAnimation>> {
+        let mut animations = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            animations.push(TileAnimation::from_line(line)?);
+        }
+        Ok(animations)
+    }
+
+    /// Parses one animation as written in a sidecar line (without any
+    /// comment), e.g. `150: 4 5 6 7`.
+    pub fn from_line(line: &str) -> io::Result<TileAnimation> {
+        TileAnimation::parse(line).ok_or_else(|| {
+            let msg = format!("invalid animation: {:?}", line);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })
+    }
+
+    /// Returns this animation as a sidecar line, as accepted by `from_line`.
+    pub fn to_line(&self) -> String {
+        let frames: Vec<String> =
+            self.frames.iter().map(usize::to_string).collect();
+        format!("{}: {}", self.frame_millis, frames.join(" "))
+    }
+
+    /// Loads the animations for the named tile file, returning an empty
+    /// list if the tile file has no sidecar.
+    pub fn load_sidecar(
+        dirpath: &Path,
+        filename: &str,
+    ) -> io::Result<Vec<TileAnimation>> {
+        let path = dirpath.join(filename).with_extension("anim");
+        match fs::read_to_string(path) {
+            Ok(text) => TileAnimation::parse_sidecar(&text),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(Vec::new())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Writes the given animations as the named tile file's sidecar, or
+    /// removes the sidecar if there are none.
+    pub fn save_sidecar(
+        dirpath: &Path,
+        filename: &str,
+        animations: &[TileAnimation],
+    ) -> io::Result<()> {
+        let path = dirpath.join(filename).with_extension("anim");
+        if animations.is_empty() {
+            return match fs::remove_file(path) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                    Ok(())
+                }
+                result => result,
+            };
+        }
+        let mut text = String::new();
+        for animation in animations.iter() {
+            text.push_str(&animation.to_line());
+            text.push('\n');
+        }
+        fs::write(path, text)
+    }
+
+    fn parse(line: &str) -> Option<TileAnimation> {
+        let (millis, frames) = line.split_once(':')?;
+        let frame_millis = millis.trim().parse::<u32>().ok()?;
+        let mut indices = Vec::new();
+        for token in frames.split_whitespace() {
+            indices.push(token.parse::<usize>().ok()?);
+        }
+        if frame_millis == 0 || indices.is_empty() {
+            return None;
+        }
+        Some(TileAnimation { frames: indices, frame_millis })
+    }
+
+    pub fn frames(&self) -> &[usize] {
+        &self.frames
+    }
+
+    pub fn frame_millis(&self) -> u32 {
+        self.frame_millis
+    }
+
+    /// Returns the length of one full cycle of the animation.
+    pub fn cycle_millis(&self) -> u32 {
+        self.frame_millis * self.frames.len() as u32
+    }
+
+    /// If the given tile index is one of this animation's frames, returns
+    /// the tile index to show in its place after `millis` milliseconds.
+    pub fn frame_at(&self, tile_index: usize, millis: u64) -> Option<usize> {
+        let start = self.frames.iter().position(|&i| i == tile_index)?;
+        let elapsed = (millis / self.frame_millis as u64) as usize;
+        Some(self.frames[(start + elapsed) % self.frames.len()])
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::TileAnimation;
+
+    #[test]
+    fn parse_sidecar() {
+        let text = "# Water\n150: 4 5 6 7\n\n500: 0 2  # blinking light\n";
+        let animations = TileAnimation::parse_sidecar(text).unwrap();
+        assert_eq!(animations.len(), 2);
+        assert_eq!(animations[0].cycle_millis(), 600);
+        assert_eq!(animations[0].frame_at(6, 0), Some(6));
+        assert_eq!(animations[0].frame_at(6, 300), Some(4));
+        assert_eq!(animations[1].frame_at(1, 500), None);
+        assert!(TileAnimation::parse_sidecar("0: 1 2").is_err());
+        assert!(TileAnimation::parse_sidecar("100 1 2").is_err());
+        assert_eq!(animations[0].to_line(), "150: 4 5 6 7");
+        let line = TileAnimation::from_line(" 80 :3  9 ").unwrap().to_line();
+        assert_eq!(line, "80: 3 9");
+        assert!(TileAnimation::from_line("80:").is_err());
+    }
+}
+
+//===========================================================================//
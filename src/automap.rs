@@ -0,0 +1,287 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::tilegrid::{parse_tile_name, Tile, TileGrid, Tileset};
+use std::fs;
+use std::io;
+
+//===========================================================================//
+
+/// One cell of an automap rule's pattern or replacement.  In a pattern,
+/// `Any` matches every cell; in a replacement, it leaves the cell unchanged.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum RuleCell {
+    Any,
+    Empty,
+    Tile(String, usize),
+}
+
+impl RuleCell {
+    fn parse(token: &str) -> io::Result<RuleCell> {
+        match token {
+            "*" => Ok(RuleCell::Any),
+            "-" => Ok(RuleCell::Empty),
+            _ => match parse_tile_name(token) {
+                Some((filename, index)) => {
+                    Ok(RuleCell::Tile(filename.to_string(), index))
+                }
+                None => {
+                    let msg = format!("invalid rule cell: {:?}", token);
+                    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+                }
+            },
+        }
+    }
+
+    fn matches(&self, tile: &Option<Tile>) -> bool {
+        match (self, tile) {
+            (&RuleCell::Any, _) => true,
+            (&RuleCell::Empty, &None) => true,
+            (RuleCell::Tile(filename, index), Some(tile)) => {
+                tile.filename() == filename && tile.index() == *index
+            }
+            _ => false,
+        }
+    }
+}
+
+//===========================================================================//
+
+#[derive(Debug, Eq, PartialEq)]
+struct AutomapRule {
+    width: u32,
+    height: u32,
+    pattern: Vec<RuleCell>,
+    replacement: Vec<RuleCell>,
+}
+
+impl AutomapRule {
+    fn parse(lines: &[&str]) -> io::Result<AutomapRule> {
+        let arrow = lines.iter().position(|line| line.trim() == "=>");
+        let arrow = arrow.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "rule is missing =>")
+        })?;
+        let (width, height, pattern) = parse_block(&lines[..arrow])?;
+        let (rwidth, rheight, replacement) =
+            parse_block(&lines[(arrow + 1)..])?;
+        if (width, height) != (rwidth, rheight) {
+            let msg = format!(
+                "rule pattern is {}x{}, but replacement is {}x{}",
+                width, height, rwidth, rheight
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        Ok(AutomapRule { width, height, pattern, replacement })
+    }
+
+    fn matches_at(&self, tilegrid: &TileGrid, left: u32, top: u32) -> bool {
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let cell = &self.pattern[(row * self.width + col) as usize];
+                if !cell.matches(&tilegrid[(left + col, top + row)]) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn replace_at(
+        &self,
+        tilegrid: &mut TileGrid,
+        tileset: &Tileset,
+        left: u32,
+        top: u32,
+    ) -> bool {
+        let mut changed = false;
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let new_tile = match self.replacement
+                    [(row * self.width + col) as usize]
+                {
+                    RuleCell::Any => continue,
+                    RuleCell::Empty => None,
+                    RuleCell::Tile(ref filename, index) => {
+                        match tileset.get_named(filename, index) {
+                            Some(tile) => Some(tile),
+                            None => continue,
+                        }
+                    }
+                };
+                let position = (left + col, top + row);
+                if tilegrid[position] != new_tile {
+                    tilegrid.set(position, new_tile);
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+fn parse_block(lines: &[&str]) -> io::Result<(u32, u32, Vec<RuleCell>)> {
+    let mut width = 0;
+    let mut cells = Vec::new();
+    for line in lines.iter() {
+        let row = line
+            .split_whitespace()
+            .map(RuleCell::parse)
+            .collect::<io::Result<Vec<RuleCell>>>()?;
+        if width == 0 {
+            width = row.len();
+        } else if row.len() != width {
+            let msg = "rule rows have differing lengths";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        cells.extend(row);
+    }
+    if width == 0 {
+        let msg = "rule block is empty";
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    Ok((width as u32, lines.len() as u32, cells))
+}
+
+//===========================================================================//
+
+/// A list of automapping rules.  Each rule replaces every occurrence of a
+/// pattern of tiles in the grid with another arrangement of the same size.
+///
+/// In the rules file, each rule is a block of pattern rows, a line containing
+/// just `=>`, and then the replacement rows; rules are separated by blank
+/// lines, and lines starting with `#` are ignored.  Each cell is a tile name
+/// (`filename:index`), `-` for an empty cell, or `*` to match any cell (in a
+/// pattern) or leave the cell alone (in a replacement).
+#[derive(Debug, Eq, PartialEq)]
+pub struct Automap {
+    rules: Vec<AutomapRule>,
+}
+
+impl Automap {
+    pub fn parse(text: &str) -> io::Result<Automap> {
+        let mut rules = Vec::new();
+        let mut block = Vec::new();
+        for line in text.lines().chain(std::iter::once("")) {
+            if line.starts_with('#') {
+                continue;
+            }
+            if line.trim().is_empty() {
+                if !block.is_empty() {
+                    rules.push(AutomapRule::parse(&block)?);
+                    block.clear();
+                }
+            } else {
+                block.push(line);
+            }
+        }
+        Ok(Automap { rules })
+    }
+
+    pub fn load_from_path(path: &str) -> io::Result<Automap> {
+        Automap::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Returns an error if any rule refers to a tile that doesn't exist in
+    /// the given tileset.
+    pub fn validate(&self, tileset: &Tileset) -> io::Result<()> {
+        for rule in self.rules.iter() {
+            for cell in rule.pattern.iter().chain(rule.replacement.iter()) {
+                if let RuleCell::Tile(ref filename, index) = *cell {
+                    if tileset.get_named(filename, index).is_none() {
+                        let msg =
+                            format!("no such tile: {}:{}", filename, index);
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            msg,
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies each rule in turn to the whole grid, returning true if any
+    /// cells changed.  Each rule matches against the grid as it was before
+    /// that rule ran, so a rule's own replacements never cascade.
+    pub fn apply(&self, tilegrid: &mut TileGrid) -> bool {
+        let tileset = tilegrid.tileset();
+        let mut changed = false;
+        for rule in self.rules.iter() {
+            if rule.width > tilegrid.width() || rule.height > tilegrid.height()
+            {
+                continue;
+            }
+            let original = tilegrid.clone();
+            for top in 0..=(tilegrid.height() - rule.height) {
+                for left in 0..=(tilegrid.width() - rule.width) {
+                    if rule.matches_at(&original, left, top) {
+                        changed |=
+                            rule.replace_at(tilegrid, &tileset, left, top);
+                    }
+                }
+            }
+        }
+        changed
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{Automap, RuleCell};
+
+    #[test]
+    fn parse_rules() {
+        let text = "# Comment\n\
+                    a:0 *\n\
+                    - a:1\n\
+                    =>\n\
+                    b:0 *\n\
+                    * -\n\
+                    \n\
+                    a:2\n\
+                    =>\n\
+                    a:3\n";
+        let automap = Automap::parse(text).unwrap();
+        assert_eq!(automap.rules.len(), 2);
+        let rule = &automap.rules[0];
+        assert_eq!((rule.width, rule.height), (2, 2));
+        assert_eq!(
+            rule.pattern,
+            vec![
+                RuleCell::Tile("a".to_string(), 0),
+                RuleCell::Any,
+                RuleCell::Empty,
+                RuleCell::Tile("a".to_string(), 1),
+            ]
+        );
+        assert_eq!((automap.rules[1].width, automap.rules[1].height), (1, 1));
+    }
+
+    #[test]
+    fn parse_invalid_rules() {
+        assert!(Automap::parse("a:0\n").is_err());
+        assert!(Automap::parse("a:0 a:1\n=>\na:2\n").is_err());
+        assert!(Automap::parse("a:0\n=>\nbogus\n").is_err());
+    }
+}
+
+//===========================================================================//
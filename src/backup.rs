@@ -0,0 +1,204 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//===========================================================================//
+
+/// The name of the directory (next to each map) that its backups go in.
+const BACKUP_DIR_NAME: &str = ".linoleum-backups";
+
+/// Backups made within the same second get a two-digit counter after their
+/// timestamps (e.g. "20200101-000000-01"), which sorts after the first one.
+const MAX_COUNTER: u32 = 99;
+
+//===========================================================================//
+
+/// Returns the directory that backups of the map at the given path go in.
+fn backup_dir(map_path: &Path) -> PathBuf {
+    let parent = map_path.parent().unwrap_or_else(|| Path::new(""));
+    parent.join(BACKUP_DIR_NAME)
+}
+
+/// Returns the path of the backup of the map at the given path with the
+/// given timestamp.  Each backup is named after its map's file name plus the
+/// time of the save, so that backups of different maps in the same
+/// directory don't collide.
+pub fn backup_path(map_path: &Path, stamp: &str) -> PathBuf {
+    let file_name = map_path.file_name().unwrap_or_default();
+    let mut name = file_name.to_os_string();
+    name.push(".");
+    name.push(stamp);
+    backup_dir(map_path).join(name)
+}
+
+/// Returns the timestamps of the existing backups of the map at the given
+/// path, oldest first.
+pub fn list_backups(map_path: &Path) -> Vec<String> {
+    let prefix = match map_path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!("{}.", name),
+        None => return Vec::new(),
+    };
+    let entries = match fs::read_dir(backup_dir(map_path)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut stamps: Vec<String> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|name| {
+            let stamp = name.strip_prefix(&prefix)?;
+            if is_timestamp(stamp) {
+                Some(stamp.to_string())
+            } else {
+                None
+            }
+        })
+        .collect();
+    stamps.sort();
+    stamps
+}
+
+/// Creates the backup directory for the map at the given path if necessary,
+/// and returns the path to write a new backup of the map made at the given
+/// time to.  If there's already a backup from the same second (on disk, or
+/// among the `pending` paths still waiting to be written), a counter is
+/// added to the new backup's timestamp.
+pub fn new_backup_path(
+    map_path: &Path,
+    time: SystemTime,
+    pending: &[PathBuf],
+) -> io::Result<PathBuf> {
+    fs::create_dir_all(backup_dir(map_path))?;
+    let stamp = timestamp(time);
+    let taken = |path: &PathBuf| path.exists() || pending.contains(path);
+    let mut path = backup_path(map_path, &stamp);
+    for count in 1..=MAX_COUNTER {
+        if !taken(&path) {
+            return Ok(path);
+        }
+        let stamp = format!("{}-{:02}", stamp, count);
+        path = backup_path(map_path, &stamp);
+    }
+    let msg = format!("too many backups at {}", stamp);
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, msg))
+}
+
+/// Deletes the oldest backups of the map at the given path, so that at most
+/// `max_backups` remain.  This is done only once a new backup has been
+/// written, so that a failed save never leaves fewer good backups.
+pub fn prune_backups(map_path: &Path, max_backups: usize) -> io::Result<()> {
+    let stamps = list_backups(map_path);
+    let num_to_delete = stamps.len().saturating_sub(max_backups);
+    for old in stamps.iter().take(num_to_delete) {
+        fs::remove_file(backup_path(map_path, old))?;
+    }
+    Ok(())
+}
+
+/// Formats the given time as a "YYYYMMDD-HHMMSS" UTC timestamp, which sorts
+/// chronologically.
+pub fn timestamp(time: SystemTime) -> String {
+    let secs =
+        time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Convert days since the epoch to a civil date; see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn is_timestamp(string: &str) -> bool {
+    (string.len() == 15 || string.len() == 18)
+        && string.char_indices().all(|(index, chr)| {
+            if index == 8 || index == 15 {
+                chr == '-'
+            } else {
+                chr.is_ascii_digit()
+            }
+        })
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        backup_path, list_backups, new_backup_path, prune_backups, timestamp,
+    };
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn format_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101-000000");
+        let time = UNIX_EPOCH + Duration::from_secs(951_827_696);
+        assert_eq!(timestamp(time), "20000229-123456");
+    }
+
+    #[test]
+    fn rotate_backups() {
+        let dir = std::env::temp_dir()
+            .join(format!("linoleum-backup-test-{}", std::process::id()));
+        let map_path = dir.join("map.bg");
+        for stamp in &["20200101-000000", "20200102-000000", "20200103-000000"]
+        {
+            let path = backup_path(&map_path, stamp);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        fs::write(backup_path(&dir.join("other.bg"), "20200104-000000"), b"")
+            .unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(1_577_923_200);
+        let path = new_backup_path(&map_path, time, &[]).unwrap();
+        assert_eq!(path, backup_path(&map_path, "20200102-000000-01"));
+        let pending = vec![path.clone()];
+        let next = new_backup_path(&map_path, time, &pending).unwrap();
+        assert_eq!(next, backup_path(&map_path, "20200102-000000-02"));
+        fs::write(path, b"").unwrap();
+        assert_eq!(list_backups(&map_path).len(), 4);
+        prune_backups(&map_path, 3).unwrap();
+        assert_eq!(
+            list_backups(&map_path),
+            vec!["20200102-000000", "20200102-000000-01", "20200103-000000"]
+        );
+        assert_eq!(list_backups(&dir.join("other.bg")).len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+//===========================================================================//
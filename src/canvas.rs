@@ -20,31 +20,81 @@
 use ahi;
 use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::{Point, Rect};
+use sdl2::render::BlendMode;
 use sdl2::render::Canvas as SdlCanvas;
 use sdl2::render::Texture;
 use sdl2::surface::Surface;
 use sdl2::video::Window as SdlWindow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 //===========================================================================//
 
 pub struct Window<'a> {
     renderer: &'a mut SdlCanvas<SdlWindow>,
+    textures: Option<&'a mut TextureCache>,
 }
 
 impl<'a> Window<'a> {
     pub fn from_renderer(
         renderer: &'a mut SdlCanvas<SdlWindow>,
     ) -> Window<'a> {
-        Window { renderer }
+        Window { renderer, textures: None }
+    }
+
+    /// Like `from_renderer`, but for a window that draws sprites created by
+    /// another window; their textures are uploaded again for this window's
+    /// renderer and kept in the given cache.
+    pub fn with_texture_cache(
+        renderer: &'a mut SdlCanvas<SdlWindow>,
+        textures: &'a mut TextureCache,
+    ) -> Window<'a> {
+        Window { renderer, textures: Some(textures) }
     }
 
     pub fn present(&mut self) {
         self.renderer.present();
+        if let Some(ref mut textures) = self.textures {
+            textures.prune();
+        }
+    }
+
+    pub fn clipboard_text(&self) -> Option<String> {
+        let clipboard = self.renderer.window().subsystem().clipboard();
+        clipboard.clipboard_text().ok()
+    }
+
+    pub fn set_clipboard_text(&self, text: &str) -> bool {
+        let clipboard = self.renderer.window().subsystem().clipboard();
+        clipboard.set_clipboard_text(text).is_ok()
+    }
+
+    /// Reads back the RGBA pixels most recently rendered within the given
+    /// (logical) rect, at the window's actual output resolution.  Returns
+    /// the width and height of the pixel data along with the data itself.
+    pub fn read_pixels(&self, rect: Rect) -> Option<(u32, u32, Vec<u8>)> {
+        let (logical_width, _) = self.renderer.logical_size();
+        let (output_width, _) = self.renderer.output_size().ok()?;
+        let scale =
+            output_width.checked_div(logical_width).unwrap_or(1).max(1);
+        let real = Rect::new(
+            rect.x() * scale as i32,
+            rect.y() * scale as i32,
+            rect.width() * scale,
+            rect.height() * scale,
+        );
+        let data = self.renderer.read_pixels(real, rgba_format()).ok()?;
+        Some((real.width(), real.height(), data))
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        if self.renderer.window().title() != title {
+            self.renderer.window_mut().set_title(title).unwrap();
+        }
     }
 
     pub fn canvas(&mut self) -> Canvas {
-        Canvas::from_renderer(self.renderer)
+        Canvas::from_renderer(self.renderer, self.textures.as_deref_mut())
     }
 
     pub fn new_sprite(
@@ -55,17 +105,21 @@ impl<'a> Window<'a> {
         let width = image.width();
         let height = image.height();
         let mut data = image.rgba_data(palette);
-        let format = if cfg!(target_endian = "big") {
-            PixelFormatEnum::RGBA8888
-        } else {
-            PixelFormatEnum::ABGR8888
-        };
-        let surface =
-            Surface::from_data(&mut data, width, height, width * 4, format)
-                .unwrap();
+        let rgba = data.clone();
+        let surface = Surface::from_data(
+            &mut data,
+            width,
+            height,
+            width * 4,
+            rgba_format(),
+        )
+        .unwrap();
         Sprite {
             width,
             height,
+            rgba,
+            id: NEXT_SPRITE_ID.fetch_add(1, Ordering::Relaxed),
+            window_id: self.renderer.window().id(),
             texture: self
                 .renderer
                 .create_texture_from_surface(&surface)
@@ -99,44 +153,118 @@ impl<'a> Window<'a> {
 pub struct Canvas<'a> {
     clip_rect: Option<Rect>,
     prev_clip_rect: Option<Rect>,
+    // The logical position that drawing coordinates (0, 0) map to:
+    origin: Point,
+    // Each unit drawn to this canvas covers a square this many logical
+    // pixels wide:
+    zoom: u32,
     renderer: &'a mut SdlCanvas<SdlWindow>,
+    textures: Option<&'a mut TextureCache>,
 }
 
 impl<'a> Canvas<'a> {
-    fn from_renderer(renderer: &'a mut SdlCanvas<SdlWindow>) -> Canvas<'a> {
-        Canvas { clip_rect: None, prev_clip_rect: None, renderer }
+    fn from_renderer(
+        renderer: &'a mut SdlCanvas<SdlWindow>,
+        textures: Option<&'a mut TextureCache>,
+    ) -> Canvas<'a> {
+        Canvas {
+            clip_rect: None,
+            prev_clip_rect: None,
+            origin: Point::new(0, 0),
+            zoom: 1,
+            renderer,
+            textures,
+        }
     }
 
+    /// Returns the size of the canvas, in (zoomed) drawing units.
     pub fn size(&self) -> (u32, u32) {
-        if let Some(rect) = self.clip_rect {
+        let (width, height) = if let Some(rect) = self.clip_rect {
             (rect.width(), rect.height())
         } else {
             self.renderer.logical_size()
-        }
+        };
+        (width.div_ceil(self.zoom), height.div_ceil(self.zoom))
     }
 
+    /// Returns the visible area of the canvas, in drawing coordinates.
+    /// This starts at (0, 0) unless the canvas has been scrolled.
     pub fn rect(&self) -> Rect {
         let (width, height) = self.size();
-        Rect::new(0, 0, width, height)
+        let (left, top) = match self.clip_rect {
+            Some(rect) => (rect.x(), rect.y()),
+            None => (0, 0),
+        };
+        let zoom = self.zoom as i32;
+        Rect::new(
+            (left - self.origin.x()).div_euclid(zoom),
+            (top - self.origin.y()).div_euclid(zoom),
+            width,
+            height,
+        )
     }
 
     pub fn draw_sprite(&mut self, sprite: &Sprite, topleft: Point) {
-        let (x, y) = match self.clip_rect {
-            Some(rect) => (rect.x(), rect.y()),
-            None => (0, 0),
+        self.draw_sprite_flipped(sprite, topleft, false, false);
+    }
+
+    /// Draws the sprite mirrored horizontally and/or vertically.
+    pub fn draw_sprite_flipped(
+        &mut self,
+        sprite: &Sprite,
+        topleft: Point,
+        horz: bool,
+        vert: bool,
+    ) {
+        self.draw_sprite_flipped_alpha(sprite, topleft, horz, vert, 255);
+    }
+
+    /// Draws the sprite with its opacity scaled by `alpha` (0-255).
+    pub fn draw_sprite_alpha(
+        &mut self,
+        sprite: &Sprite,
+        topleft: Point,
+        alpha: u8,
+    ) {
+        self.draw_sprite_flipped_alpha(sprite, topleft, false, false, alpha);
+    }
+
+    /// Combines `draw_sprite_flipped` and `draw_sprite_alpha`.
+    pub fn draw_sprite_flipped_alpha(
+        &mut self,
+        sprite: &Sprite,
+        topleft: Point,
+        horz: bool,
+        vert: bool,
+        alpha: u8,
+    ) {
+        let (x, y) = (self.origin.x(), self.origin.y());
+        let zoom = self.zoom;
+        let dest = Rect::new(
+            x + topleft.x() * zoom as i32,
+            y + topleft.y() * zoom as i32,
+            sprite.width() * zoom,
+            sprite.height() * zoom,
+        );
+        // Textures belong to the renderer that created them, so to draw a
+        // sprite in a different window, we have to upload its pixels again.
+        let mut temporary = None;
+        let texture = if sprite.window_id == self.renderer.window().id() {
+            &sprite.texture
+        } else if let Some(ref mut textures) = self.textures {
+            textures.get(self.renderer, sprite)
+        } else {
+            temporary.insert(sprite.upload(self.renderer))
         };
+        let raw = texture.raw();
+        unsafe { sdl2::sys::SDL_SetTextureAlphaMod(raw, alpha) };
         self.renderer
-            .copy(
-                &sprite.texture,
-                None,
-                Some(Rect::new(
-                    x + topleft.x(),
-                    y + topleft.y(),
-                    sprite.width(),
-                    sprite.height(),
-                )),
-            )
+            .copy_ex(texture, None, Some(dest), 0.0, None, horz, vert)
             .unwrap();
+        unsafe { sdl2::sys::SDL_SetTextureAlphaMod(raw, 255) };
+        if let Some(texture) = temporary {
+            unsafe { texture.destroy() };
+        }
     }
 
     pub fn clear(&mut self, color: (u8, u8, u8, u8)) {
@@ -167,30 +295,71 @@ impl<'a> Canvas<'a> {
         self.renderer.fill_rect(subrect).unwrap();
     }
 
+    /// Like `fill_rect`, but blends the color over what's already been
+    /// drawn according to its alpha.
+    pub fn tint_rect(&mut self, color: (u8, u8, u8, u8), rect: Rect) {
+        self.renderer.set_blend_mode(BlendMode::Blend);
+        self.fill_rect(color, rect);
+        self.renderer.set_blend_mode(BlendMode::None);
+    }
+
     pub fn draw_text(&mut self, font: &Font, start: Point, text: &str) {
-        let top = start.y() - font.baseline;
-        let mut left = start.x();
-        for chr in text.chars() {
-            let glyph = font.glyph(chr);
-            left -= glyph.left_edge;
-            self.draw_sprite(&glyph.sprite, Point::new(left, top));
-            left += glyph.right_edge;
+        for (sprite, topleft) in font.layout_text(start, text) {
+            self.draw_sprite(sprite, topleft);
         }
     }
 
     pub fn subcanvas(&mut self, rect: Rect) -> Canvas {
-        let new_clip_rect = Some(self.subrect(rect));
-        self.renderer.set_clip_rect(new_clip_rect);
+        let new_clip_rect = self.subrect(rect);
+        self.renderer.set_clip_rect(Some(new_clip_rect));
+        Canvas {
+            clip_rect: Some(new_clip_rect),
+            prev_clip_rect: self.clip_rect,
+            origin: new_clip_rect.top_left(),
+            zoom: self.zoom,
+            renderer: self.renderer,
+            textures: self.textures.as_deref_mut(),
+        }
+    }
+
+    /// Returns a canvas covering the same area as this one, but on which
+    /// everything drawn is magnified by the given factor.
+    pub fn zoomed(&mut self, zoom: u32) -> Canvas<'_> {
+        Canvas {
+            clip_rect: self.clip_rect,
+            prev_clip_rect: self.clip_rect,
+            origin: self.origin,
+            zoom: self.zoom * zoom.max(1),
+            renderer: self.renderer,
+            textures: self.textures.as_deref_mut(),
+        }
+    }
+
+    /// Returns a canvas covering the same area as this one, but scrolled so
+    /// that the given point (in drawing coordinates) appears at its
+    /// top-left corner.
+    pub fn scrolled(&mut self, scroll: Point) -> Canvas<'_> {
+        let zoom = self.zoom as i32;
         Canvas {
-            clip_rect: new_clip_rect,
+            clip_rect: self.clip_rect,
             prev_clip_rect: self.clip_rect,
+            origin: self.origin - scroll * zoom,
+            zoom: self.zoom,
             renderer: self.renderer,
+            textures: self.textures.as_deref_mut(),
         }
     }
 
-    fn subrect(&self, mut child: Rect) -> Rect {
+    fn subrect(&self, child: Rect) -> Rect {
+        let zoom = self.zoom;
+        let mut child = Rect::new(
+            child.x() * zoom as i32,
+            child.y() * zoom as i32,
+            child.width() * zoom,
+            child.height() * zoom,
+        );
+        child.offset(self.origin.x(), self.origin.y());
         if let Some(parent) = self.clip_rect {
-            child.offset(parent.x(), parent.y());
             if let Some(intersection) = parent.intersection(child) {
                 intersection
             } else {
@@ -211,9 +380,57 @@ impl<'a> Drop for Canvas<'a> {
 
 //===========================================================================//
 
+/// Textures for drawing sprites in a window other than the one that created
+/// them (e.g. the detached palette), keyed by sprite.  The cache must not
+/// outlive the renderer it's used with.
+#[derive(Default)]
+pub struct TextureCache {
+    textures: BTreeMap<u64, Texture>,
+    // The sprites drawn since the last present:
+    used: BTreeSet<u64>,
+}
+
+impl TextureCache {
+    fn get(
+        &mut self,
+        renderer: &SdlCanvas<SdlWindow>,
+        sprite: &Sprite,
+    ) -> &Texture {
+        self.used.insert(sprite.id);
+        self.textures
+            .entry(sprite.id)
+            .or_insert_with(|| sprite.upload(renderer))
+    }
+
+    /// Frees the textures of sprites that weren't drawn in the last frame,
+    /// so that sprites that have since been dropped don't leak.
+    fn prune(&mut self) {
+        let used = &self.used;
+        let unused: Vec<u64> = self
+            .textures
+            .keys()
+            .copied()
+            .filter(|id| !used.contains(id))
+            .collect();
+        for id in unused {
+            let texture = self.textures.remove(&id).unwrap();
+            unsafe { texture.destroy() };
+        }
+        self.used.clear();
+    }
+}
+
+//===========================================================================//
+
+static NEXT_SPRITE_ID: AtomicU64 = AtomicU64::new(0);
+
 pub struct Sprite {
     width: u32,
     height: u32,
+    rgba: Vec<u8>,
+    // Identifies the sprite in a `TextureCache`:
+    id: u64,
+    window_id: u32,
     texture: Texture,
 }
 
@@ -225,6 +442,49 @@ impl Sprite {
     pub fn height(&self) -> u32 {
         self.height
     }
+
+    /// Returns the sprite's pixels as row-major RGBA bytes, for rendering
+    /// outside of the window (e.g. when exporting images).
+    pub fn rgba_data(&self) -> &[u8] {
+        &self.rgba
+    }
+
+    /// Returns a sprite with no pixels or texture, for tests that need tiles
+    /// but never draw them.
+    #[cfg(test)]
+    pub fn blank() -> Sprite {
+        Sprite {
+            width: 0,
+            height: 0,
+            rgba: Vec::new(),
+            id: NEXT_SPRITE_ID.fetch_add(1, Ordering::Relaxed),
+            window_id: 0,
+            // Textures aren't freed when dropped, so a null one is fine as
+            // long as it's never drawn:
+            texture: unsafe { std::mem::zeroed() },
+        }
+    }
+
+    fn upload(&self, renderer: &SdlCanvas<SdlWindow>) -> Texture {
+        let mut data = self.rgba.clone();
+        let surface = Surface::from_data(
+            &mut data,
+            self.width,
+            self.height,
+            self.width * 4,
+            rgba_format(),
+        )
+        .unwrap();
+        renderer.create_texture_from_surface(&surface).unwrap()
+    }
+}
+
+fn rgba_format() -> PixelFormatEnum {
+    if cfg!(target_endian = "big") {
+        PixelFormatEnum::RGBA8888
+    } else {
+        PixelFormatEnum::ABGR8888
+    }
 }
 
 //===========================================================================//
@@ -255,6 +515,25 @@ impl Font {
         width
     }
 
+    /// Returns the sprite and top-left position of each glyph in the text,
+    /// with the start of the text's baseline at `start`.
+    pub fn layout_text(
+        &self,
+        start: Point,
+        text: &str,
+    ) -> Vec<(&Sprite, Point)> {
+        let top = start.y() - self.baseline;
+        let mut left = start.x();
+        let mut glyphs = Vec::new();
+        for chr in text.chars() {
+            let glyph = self.glyph(chr);
+            left -= glyph.left_edge;
+            glyphs.push((&glyph.sprite, Point::new(left, top)));
+            left += glyph.right_edge;
+        }
+        glyphs
+    }
+
     fn glyph(&self, chr: char) -> &Glyph {
         self.glyphs.get(&chr).unwrap_or(&self.default_glyph)
     }
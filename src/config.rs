@@ -0,0 +1,152 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+//===========================================================================//
+
+/// User preferences that persist between sessions, stored as `key = value`
+/// lines.  Blank lines are ignored, and lines starting with `#` are
+/// comments (which are not preserved when the file is rewritten).
+pub struct Config {
+    path: Option<PathBuf>,
+    entries: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// Returns `$XDG_CONFIG_HOME/linoleum/config`, falling back to
+    /// `$HOME/.config/linoleum/config`.
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("linoleum").join("config"))
+    }
+
+    /// Returns an empty config that will not be saved anywhere.
+    pub fn empty() -> Config {
+        Config { path: None, entries: BTreeMap::new() }
+    }
+
+    /// Loads the config file at the given path, or returns an empty config
+    /// (to be saved at that path) if the file doesn't exist yet.
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let entries = match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                BTreeMap::new()
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(Config { path: Some(path.to_path_buf()), entries })
+    }
+
+    fn parse(text: &str) -> io::Result<BTreeMap<String, String>> {
+        let mut entries = BTreeMap::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                let msg = format!("invalid config line: {:?}", line);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?;
+            entries.insert(key.trim().to_string(), value.trim().to_string());
+        }
+        Ok(entries)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key)?.parse().ok()
+    }
+
+    /// Gets a color written as "r,g,b" or "r,g,b,a".
+    pub fn get_color(&self, key: &str) -> Option<(u8, u8, u8, u8)> {
+        let parts: Vec<&str> = self.get(key)?.split(',').collect();
+        if parts.len() != 3 && parts.len() != 4 {
+            return None;
+        }
+        let mut color = [255u8; 4];
+        for (index, part) in parts.iter().enumerate() {
+            color[index] = part.trim().parse().ok()?;
+        }
+        Some((color[0], color[1], color[2], color[3]))
+    }
+
+    pub fn set(&mut self, key: &str, value: String) {
+        self.entries.insert(key.to_string(), value);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut text = String::new();
+        for (key, value) in self.entries.iter() {
+            text.push_str(&format!("{} = {}\n", key, value));
+        }
+        fs::write(path, text)
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn parse_config() {
+        let text = "# Panels\nlayout.toolbox = 10,34\n\n  foo=bar baz \n";
+        let entries = Config::parse(text).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["layout.toolbox"], "10,34");
+        assert_eq!(entries["foo"], "bar baz");
+        assert!(Config::parse("no equals sign").is_err());
+        let mut config = Config { path: None, entries };
+        assert_eq!(config.get_parsed::<i32>("foo"), None);
+        config.set("color", "1, 2,3".to_string());
+        assert_eq!(config.get_color("color"), Some((1, 2, 3, 255)));
+    }
+}
+
+//===========================================================================//
@@ -18,10 +18,11 @@
 // +--------------------------------------------------------------------------+
 
 use super::canvas::{Canvas, Font};
-use super::element::{Action, GuiElement};
+use super::element::{Action, GuiElement, Movable};
 use super::event::Event;
 use super::state::EditorState;
-use sdl2::rect::Point;
+use super::tilegrid::{GRID_DEFAULT_NUM_COLS, GRID_DEFAULT_NUM_ROWS};
+use sdl2::rect::{Point, Rect};
 use std::rc::Rc;
 
 //===========================================================================//
@@ -31,14 +32,71 @@ pub enum CoordsKind {
     PixelDec,
     PixelHex,
     TileDec,
+    TileHex,
+    /// Tile coordinates as "screen:offset", where each screen is the size
+    /// of a default-sized grid.
+    Screen,
 }
 
 impl CoordsKind {
-    fn format(self, value: i32, tile_size: i32) -> String {
+    /// All the kinds, in the order that clicking an indicator cycles
+    /// through them.
+    const ALL: &'static [CoordsKind] = &[
+        CoordsKind::TileDec,
+        CoordsKind::TileHex,
+        CoordsKind::PixelDec,
+        CoordsKind::PixelHex,
+        CoordsKind::Screen,
+    ];
+
+    /// Returns a name for this kind, as accepted by `CoordsKind::from_name`.
+    pub fn name(self) -> &'static str {
+        match self {
+            CoordsKind::PixelDec => "pixel",
+            CoordsKind::PixelHex => "pixel_hex",
+            CoordsKind::TileDec => "tile",
+            CoordsKind::TileHex => "tile_hex",
+            CoordsKind::Screen => "screen",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CoordsKind> {
+        CoordsKind::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+
+    fn next(self) -> CoordsKind {
+        let index = CoordsKind::ALL.iter().position(|&kind| kind == self);
+        let next = index.map_or(0, |index| index + 1) % CoordsKind::ALL.len();
+        CoordsKind::ALL[next]
+    }
+
+    /// Returns a short caption identifying this kind on the indicator.
+    fn caption(self) -> &'static str {
+        match self {
+            CoordsKind::PixelDec => "p",
+            CoordsKind::PixelHex => "px",
+            CoordsKind::TileDec => "t",
+            CoordsKind::TileHex => "tx",
+            CoordsKind::Screen => "s",
+        }
+    }
+
+    /// Formats a tile coordinate, where `screen_tiles` is the size of a
+    /// screen (in tiles) along the coordinate's axis.
+    fn format(self, value: i32, tile_size: i32, screen_tiles: u32) -> String {
         match self {
             CoordsKind::PixelDec => format!("{}", value * tile_size),
             CoordsKind::PixelHex => format!("{:03x}", value * tile_size),
             CoordsKind::TileDec => format!("{}", value),
+            CoordsKind::TileHex => format!("{:02x}", value),
+            CoordsKind::Screen => {
+                let screen_tiles = screen_tiles as i32;
+                format!(
+                    "{}:{}",
+                    value.div_euclid(screen_tiles),
+                    value.rem_euclid(screen_tiles)
+                )
+            }
         }
     }
 }
@@ -60,11 +118,77 @@ impl CoordsIndicator {
     ) -> CoordsIndicator {
         CoordsIndicator { topleft: Point::new(left, top), font, kind }
     }
+
+    pub fn kind(&self) -> CoordsKind {
+        self.kind
+    }
+
+    pub fn set_kind(&mut self, kind: CoordsKind) {
+        self.kind = kind;
+    }
+
+    /// Returns the area of the indicator holding the kind caption; clicking
+    /// there cycles the kind, while clicking elsewhere copies the coords.
+    fn caption_rect(&self) -> Rect {
+        Rect::new(self.topleft.x(), self.topleft.y(), 12, 12)
+    }
+
+    /// Formats the current selection's coordinates for copying, as
+    /// "left,top" for a single cell or "left,top,right,bottom" otherwise.
+    /// With no selection, formats the hovered cell's coordinates instead.
+    fn coords_text(&self, state: &EditorState) -> Option<String> {
+        let tile_size = state.tilegrid().tile_size() as i32;
+        let (cols, rows) = (GRID_DEFAULT_NUM_COLS, GRID_DEFAULT_NUM_ROWS);
+        let (subgrid, position) = match state.selection() {
+            Some(selection) => selection,
+            None => {
+                let (col, row) = state.hovered_cell()?;
+                return Some(format!(
+                    "{},{}",
+                    self.kind.format(col as i32, tile_size, cols),
+                    self.kind.format(row as i32, tile_size, rows)
+                ));
+            }
+        };
+        let left = position.x();
+        let top = position.y();
+        let mut text = format!(
+            "{},{}",
+            self.kind.format(left, tile_size, cols),
+            self.kind.format(top, tile_size, rows)
+        );
+        if subgrid.width() > 1 || subgrid.height() > 1 {
+            let right = left + subgrid.width() as i32;
+            let bottom = top + subgrid.height() as i32;
+            text.push_str(&format!(
+                ",{},{}",
+                self.kind.format(right, tile_size, cols),
+                self.kind.format(bottom, tile_size, rows)
+            ));
+        }
+        Some(text)
+    }
+}
+
+impl Movable for CoordsIndicator {
+    fn rect(&self) -> Rect {
+        Rect::new(self.topleft.x(), self.topleft.y(), 60, 45)
+    }
+
+    fn move_to(&mut self, topleft: Point) {
+        self.topleft = topleft;
+    }
 }
 
-impl GuiElement<EditorState, ()> for CoordsIndicator {
+impl GuiElement<EditorState, String> for CoordsIndicator {
     fn draw(&self, state: &EditorState, canvas: &mut Canvas) {
         let tile_size = state.tilegrid().tile_size() as i32;
+        canvas.draw_text(
+            &self.font,
+            self.topleft + Point::new(0, 10),
+            self.kind.caption(),
+        );
+        let (cols, rows) = (GRID_DEFAULT_NUM_COLS, GRID_DEFAULT_NUM_ROWS);
         if let Some((subgrid, position)) = state.selection() {
             let left = position.x();
             let top = position.y();
@@ -73,28 +197,57 @@ impl GuiElement<EditorState, ()> for CoordsIndicator {
             canvas.draw_text(
                 &self.font,
                 self.topleft + Point::new(15, 10),
-                &self.kind.format(top, tile_size),
+                &self.kind.format(top, tile_size, rows),
             );
             canvas.draw_text(
                 &self.font,
                 self.topleft + Point::new(0, 25),
-                &self.kind.format(left, tile_size),
+                &self.kind.format(left, tile_size, cols),
             );
             canvas.draw_text(
                 &self.font,
                 self.topleft + Point::new(30, 25),
-                &self.kind.format(right, tile_size),
+                &self.kind.format(right, tile_size, cols),
             );
             canvas.draw_text(
                 &self.font,
                 self.topleft + Point::new(15, 40),
-                &self.kind.format(bottom, tile_size),
+                &self.kind.format(bottom, tile_size, rows),
+            );
+        } else if let Some((col, row)) = state.hovered_cell() {
+            canvas.draw_text(
+                &self.font,
+                self.topleft + Point::new(15, 10),
+                &self.kind.format(row as i32, tile_size, rows),
+            );
+            canvas.draw_text(
+                &self.font,
+                self.topleft + Point::new(0, 25),
+                &self.kind.format(col as i32, tile_size, cols),
             );
         }
     }
 
-    fn on_event(&mut self, _: &Event, _: &mut EditorState) -> Action<()> {
-        Action::ignore()
+    fn on_event(
+        &mut self,
+        event: &Event,
+        state: &mut EditorState,
+    ) -> Action<String> {
+        match event {
+            &Event::MouseDown(pt, _)
+                if self.caption_rect().contains_point(pt) =>
+            {
+                self.kind = self.kind.next();
+                Action::redraw().and_stop()
+            }
+            &Event::MouseDown(pt, _) if self.rect().contains_point(pt) => {
+                match self.coords_text(state) {
+                    Some(text) => Action::ignore().and_stop().and_return(text),
+                    None => Action::ignore().and_stop(),
+                }
+            }
+            _ => Action::ignore(),
+        }
     }
 }
 
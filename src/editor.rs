@@ -17,31 +17,138 @@
 // | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
 // +--------------------------------------------------------------------------+
 
+use crate::anim::TileAnimation;
+use crate::automap::Automap;
+use crate::backup;
 use crate::canvas::Canvas;
 use crate::canvas::{Font, Sprite, Window};
+use crate::config::Config;
 use crate::coords::{CoordsIndicator, CoordsKind};
-use crate::element::{Action, AggregateElement, GuiElement};
-use crate::event::{Event, Keycode, COMMAND, SHIFT};
-use crate::paint::GridCanvas;
+use crate::element::{Action, AggregateElement, GuiElement, Movable};
+use crate::event::{Event, Keycode, ALT, COMMAND, SHIFT};
+use crate::export::{export_contact_sheet, ExportFormat};
+use crate::generate::{LevelGenerator, NoiseFill, WeightedTiles};
+use crate::layers::LayerSelector;
+use crate::paint::{
+    attribute_block_size_from_config, CameraSettings, GridCanvas, MarqueeStyle,
+};
 use crate::palette::TilePalette;
-use crate::state::EditorState;
+use crate::pixels::PixelEditor;
+use crate::png::RgbaImage;
+use crate::random::Rng;
+use crate::regions::Regions;
+use crate::state::{EditorState, ResizeFill, Tool};
+use crate::status::StatusBar;
+use crate::terrain::Terrain;
 use crate::textbox::{ModalTextBox, Mode};
-use crate::tilegrid::TileGrid;
+use crate::theme::Theme;
+use crate::tilegrid::{
+    metadata_text, parse_metadata_text, tile_token, Backdrop, SubGrid, Tile,
+    TileGrid,
+};
+use crate::tilesource::{TileSource, DEFAULT_TILE_EDITOR_COMMAND};
 use crate::toolbox::Toolbox;
 use crate::unsaved::UnsavedIndicator;
+use crate::util;
+use ahi::Palette;
+use sdl2::rect::{Point, Rect};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::mem;
+use std::path::Path;
 use std::rc::Rc;
 
 //===========================================================================//
 
-// These limits are currently arbitrary:
-const MAX_GRID_WIDTH: u32 = 100;
-const MAX_GRID_HEIGHT: u32 = 100;
+// The default limits on grid size, which can be overridden with the
+// `grid.max_width` and `grid.max_height` config keys:
+const DEFAULT_MAX_GRID_WIDTH: u32 = 1024;
+const DEFAULT_MAX_GRID_HEIGHT: u32 = 1024;
+// The most that those config keys may raise the limits to:
+const GRID_SIZE_LIMIT: u32 = 8192;
+
+// How many files the session store remembers, and the fields it records for
+// each one:
+const MAX_SESSION_FILES: usize = 100;
+const SESSION_FIELDS: &[&str] =
+    &["brush", "eraser", "tool", "page", "scroll", "last"];
+
+// The frame duration suggested for a new tile animation:
+const DEFAULT_FRAME_MILLIS: u32 = 150;
+
+//===========================================================================//
+
+// The panels that can be rearranged in layout mode, in the order used by
+// `EditorView::panel`, along with their config keys and default positions:
+const PANELS: &[(&str, i32, i32)] = &[
+    ("layout.toolbox", 10, 34),
+    ("layout.palette", 10, 182),
+    ("layout.coords_tile", 658, 34),
+    ("layout.coords_pixel", 658, 334),
+    ("layout.coords_hex", 658, 392),
+];
+
+// Where the pixel editor panel appears, over the right side of the canvas:
+const PIXEL_EDITOR_LEFT: i32 = 510;
+const PIXEL_EDITOR_TOP: i32 = 38;
+
+// How much Cmd+[ and Cmd+] change the overlay's opacity (out of 255):
+const OVERLAY_OPACITY_STEP: i32 = 32;
+
+const LAYOUT_OUTLINE_COLOR: (u8, u8, u8, u8) = (255, 255, 0, 255);
+
+// When the side panels are collapsed, the canvas fills the whole window,
+// except for a thin handle along the left edge that re-expands them.
+const COLLAPSE_HANDLE_WIDTH: u32 = 4;
+const COLLAPSE_HANDLE_COLOR: (u8, u8, u8, u8) = (191, 191, 191, 255);
+const EXPANDED_CANVAS_RECT: (i32, i32, u32, u32) = (72, 34, 576, 400);
+const COLLAPSED_CANVAS_RECT: (i32, i32, u32, u32) = (8, 2, 710, 436);
+
+//===========================================================================//
+
+/// Things that the view can't do while handling an event, since they need
+/// the window (e.g. for the system clipboard or loading sprites), and so are
+/// queued for `EditorView::perform_commands`.
+enum Command {
+    CopyMapText,
+    CopyCoords(String),
+    PasteMapText,
+    // Puts the clipboard's tiles on (or takes them from) the system
+    // clipboard, as text:
+    CopyTiles,
+    PasteTiles,
+    ReloadTileFile(String),
+    SwapTilesDir,
+}
 
 //===========================================================================//
 
 pub struct EditorView {
     aggregate: AggregateElement<EditorState, ()>,
+    grid_canvas: GridCanvas,
+    panels_collapsed: bool,
+    toolbox: Toolbox,
+    palette: TilePalette,
+    palette_detached: bool,
+    coords: Vec<CoordsIndicator>,
     textbox: ModalTextBox,
+    font: Rc<Font>,
+    theme: Theme,
+    config: Config,
+    // Per-file brush, tool, and palette page, remembered between sessions:
+    session: Config,
+    layout_mode: bool,
+    layout_drag: Option<(usize, Point)>,
+    tile_source: Option<TileSource>,
+    pixel_editor: Option<PixelEditor>,
+    // Whether the pending screenshot should include the editing overlays:
+    screenshot_overlays: bool,
+    // The cells that the pending tileset change will clear, as shown while
+    // waiting for it to be confirmed:
+    tile_change_preview: Vec<String>,
+    // Commands waiting for `perform_commands`, oldest first:
+    commands: Vec<Command>,
 }
 
 impl EditorView {
@@ -50,35 +157,316 @@ impl EditorView {
         arrow_icons: Vec<Sprite>,
         unsaved_icon: Sprite,
         font: Rc<Font>,
+        config: Config,
+        session: Config,
     ) -> EditorView {
+        let theme = Theme::from_config(&config);
         let elements: Vec<Box<dyn GuiElement<EditorState, ()>>> = vec![
-            Box::new(Toolbox::new(10, 34, tool_icons)),
-            Box::new(TilePalette::new(10, 116, arrow_icons)),
-            Box::new(GridCanvas::new(72, 34, font.clone())),
             Box::new(UnsavedIndicator::new(10, 10, unsaved_icon)),
-            Box::new(CoordsIndicator::new(
-                658,
-                34,
-                font.clone(),
-                CoordsKind::TileDec,
-            )),
-            Box::new(CoordsIndicator::new(
-                658,
-                334,
-                font.clone(),
-                CoordsKind::PixelDec,
-            )),
-            Box::new(CoordsIndicator::new(
-                658,
-                392,
-                font.clone(),
-                CoordsKind::PixelHex,
-            )),
+            Box::new(StatusBar::new(704, 12, font.clone())),
+            Box::new(LayerSelector::new(658, 90, font.clone())),
         ];
-        EditorView {
+        let coords = vec![
+            CoordsIndicator::new(0, 0, font.clone(), CoordsKind::TileDec),
+            CoordsIndicator::new(0, 0, font.clone(), CoordsKind::PixelDec),
+            CoordsIndicator::new(0, 0, font.clone(), CoordsKind::PixelHex),
+        ];
+        let mut view = EditorView {
             aggregate: AggregateElement::new(elements),
-            textbox: ModalTextBox::new(32, 8, font.clone()),
+            grid_canvas: GridCanvas::new(
+                EXPANDED_CANVAS_RECT.0,
+                EXPANDED_CANVAS_RECT.1,
+                font.clone(),
+                MarqueeStyle::from_config(&config, &theme),
+                CameraSettings::from_config(&config),
+                attribute_block_size_from_config(&config),
+                max_grid_size_from_config(&config),
+            ),
+            panels_collapsed: false,
+            toolbox: Toolbox::new(0, 0, tool_icons, theme),
+            palette: TilePalette::new(
+                0,
+                0,
+                46,
+                234,
+                arrow_icons,
+                font.clone(),
+                theme,
+            ),
+            palette_detached: false,
+            coords,
+            textbox: ModalTextBox::new(32, 8, font.clone(), theme),
+            font,
+            theme,
+            config,
+            session,
+            layout_mode: false,
+            layout_drag: None,
+            tile_source: None,
+            pixel_editor: None,
+            screenshot_overlays: true,
+            tile_change_preview: Vec::new(),
+            commands: Vec::new(),
+        };
+        for (index, &(key, left, top)) in PANELS.iter().enumerate() {
+            let topleft = view
+                .config
+                .get(key)
+                .and_then(parse_point)
+                .unwrap_or(Point::new(left, top));
+            view.panel_mut(index).move_to(topleft);
+        }
+        for (index, coords) in view.coords.iter_mut().enumerate() {
+            let key = format!("{}.kind", PANELS[index + 2].0);
+            if let Some(kind) =
+                view.config.get(&key).and_then(CoordsKind::from_name)
+            {
+                coords.set_kind(kind);
+            }
+        }
+        view
+    }
+
+    /// After a copy or cut, asks for the clipboard's tiles to also be put
+    /// on the system clipboard, so that other editors can paste them.
+    fn export_clipboard(&mut self, redraw: bool) -> Action<(Mode, String)> {
+        self.commands.push(Command::CopyTiles);
+        Action::redraw_if(redraw).and_stop()
+    }
+
+    pub fn theme(&self) -> Theme {
+        self.theme
+    }
+
+    fn panel(&self, index: usize) -> &dyn Movable {
+        match index {
+            0 => &self.toolbox,
+            1 => &self.palette,
+            _ => &self.coords[index - 2],
+        }
+    }
+
+    fn panel_mut(&mut self, index: usize) -> &mut dyn Movable {
+        match index {
+            0 => &mut self.toolbox,
+            1 => &mut self.palette,
+            _ => &mut self.coords[index - 2],
+        }
+    }
+
+    fn panel_visible(&self, index: usize) -> bool {
+        !self.panels_collapsed && (index != 1 || !self.palette_detached)
+    }
+
+    /// Hides or shows all the panels and indicators around the canvas,
+    /// growing the canvas to fill the window while they're hidden.
+    fn toggle_panels_collapsed(&mut self) {
+        self.panels_collapsed = !self.panels_collapsed;
+        let (left, top, width, height) = if self.panels_collapsed {
+            COLLAPSED_CANVAS_RECT
+        } else {
+            EXPANDED_CANVAS_RECT
+        };
+        self.grid_canvas.set_rect(Rect::new(left, top, width, height));
+    }
+
+    /// Toggles layout mode, in which the panels can be dragged around
+    /// instead of used.  Leaving layout mode saves the new layout.
+    fn toggle_layout_mode(&mut self, state: &mut EditorState) {
+        self.layout_mode = !self.layout_mode;
+        self.layout_drag = None;
+        if !self.layout_mode {
+            for (index, &(key, _, _)) in PANELS.iter().enumerate() {
+                let rect = self.panel(index).rect();
+                self.config.set(key, format!("{},{}", rect.x(), rect.y()));
+            }
+            self.save_config(state);
+        }
+    }
+
+    /// Writes the config file, reporting any error in the status bar.
+    fn save_config(&self, state: &mut EditorState) {
+        if let Err(error) = self.config.save() {
+            state.set_notice(format!("Couldn't save config: {}", error));
+        }
+    }
+
+    /// Passes the event to the coords indicators, saving the new kind to the
+    /// config file if one was clicked.
+    fn on_coords_event(
+        &mut self,
+        event: &Event,
+        state: &mut EditorState,
+    ) -> Action<(Mode, String)> {
+        for index in 0..self.coords.len() {
+            let old_kind = self.coords[index].kind();
+            let mut action = self.coords[index].on_event(event, state);
+            let new_kind = self.coords[index].kind();
+            if new_kind != old_kind {
+                let key = format!("{}.kind", PANELS[index + 2].0);
+                self.config.set(&key, new_kind.name().to_string());
+                self.save_config(state);
+            }
+            if let Some(text) = action.take_value() {
+                self.commands.push(Command::CopyCoords(text));
+            }
+            if action.should_stop() {
+                return action.but_no_value();
+            }
+        }
+        Action::ignore()
+    }
+
+    /// Handles dragging a tile from the palette onto the canvas, which
+    /// paints with it as though with the Pencil.
+    fn on_palette_drag_event(
+        &mut self,
+        event: &Event,
+        state: &mut EditorState,
+    ) -> Option<Action<(Mode, String)>> {
+        let tile = self.palette.dragged_tile()?.clone();
+        match event {
+            &Event::MouseDrag(pt, _) => {
+                let changed =
+                    self.grid_canvas.paint_dragged_tile(pt, tile, state);
+                Some(Action::redraw_if(changed).and_stop())
+            }
+            &Event::MouseUp(_) => {
+                // Let the palette see the MouseUp too, to end the drag.
+                self.grid_canvas.finish_dragged_tile(state);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn on_layout_event(&mut self, event: &Event) -> Action<(Mode, String)> {
+        match event {
+            &Event::MouseDown(pt, _) => {
+                for index in 0..PANELS.len() {
+                    let rect = self.panel(index).rect();
+                    if self.panel_visible(index) && rect.contains_point(pt) {
+                        self.layout_drag = Some((index, pt - rect.top_left()));
+                        break;
+                    }
+                }
+                Action::ignore().and_stop()
+            }
+            &Event::MouseDrag(pt, _) => {
+                if let Some((index, offset)) = self.layout_drag {
+                    let topleft = pt - offset;
+                    let topleft =
+                        Point::new(topleft.x().max(0), topleft.y().max(0));
+                    self.panel_mut(index).move_to(topleft);
+                    Action::redraw().and_stop()
+                } else {
+                    Action::ignore().and_stop()
+                }
+            }
+            &Event::MouseUp(_) => {
+                self.layout_drag = None;
+                Action::ignore().and_stop()
+            }
+            _ => Action::ignore(),
+        }
+    }
+
+    /// Returns true if the tile palette has been popped out into its own
+    /// window (in which case this view doesn't show it).
+    pub fn palette_detached(&self) -> bool {
+        self.palette_detached
+    }
+
+    /// Flips the palette to the page for the current brush's tile file (see
+    /// `TilePalette::show_brush_file`).
+    pub fn show_brush_file(&mut self, state: &EditorState) -> bool {
+        self.palette.show_brush_file(state)
+    }
+
+    /// Flashes the current brush's entry in the palette (see
+    /// `TilePalette::flash_brush`).
+    pub fn flash_brush(&mut self, state: &EditorState) -> bool {
+        self.palette.flash_brush(state)
+    }
+
+    /// Records the current brush, eraser tile, tool, palette page, and
+    /// scroll position for the current file in the session store, so that
+    /// `restore_session` can put them back the next time the file is
+    /// opened.  Only the most recently used files are kept.
+    pub fn remember_session(&mut self, state: &EditorState) {
+        let key = match session_key(state.filepath()) {
+            Some(key) => key,
+            None => return,
+        };
+        let last_used = session_files(&self.session)
+            .iter()
+            .map(|&(last_used, _)| last_used + 1)
+            .max()
+            .unwrap_or(0);
+        self.session.set(&format!("{}.last", key), last_used.to_string());
+        self.session.set(&format!("{}.brush", key), tile_token(state.brush()));
+        self.session
+            .set(&format!("{}.eraser", key), tile_token(state.eraser_tile()));
+        self.session
+            .set(&format!("{}.tool", key), state.tool().name().to_string());
+        self.session
+            .set(&format!("{}.page", key), self.palette.page().to_string());
+        let scroll = self.grid_canvas.scroll();
+        self.session.set(
+            &format!("{}.scroll", key),
+            format!("{},{}", scroll.x(), scroll.y()),
+        );
+        prune_session(&mut self.session);
+        let _ = self.session.save();
+    }
+
+    /// Restores the brush, eraser tile, tool, palette page, and scroll
+    /// position last used for the current file (if any were recorded by
+    /// `remember_session`).
+    pub fn restore_session(&mut self, state: &mut EditorState) {
+        let key = match session_key(state.filepath()) {
+            Some(key) => key,
+            None => return,
+        };
+        let tileset = state.tilegrid().tileset();
+        if let Some(brush) = self
+            .session
+            .get(&format!("{}.brush", key))
+            .and_then(|token| tileset.parse_tile(token))
+        {
+            state.set_brush(brush);
+        }
+        if let Some(eraser) = self
+            .session
+            .get(&format!("{}.eraser", key))
+            .and_then(|token| tileset.parse_tile(token))
+        {
+            state.set_eraser_tile(eraser);
+        }
+        if let Some(tool) = self
+            .session
+            .get(&format!("{}.tool", key))
+            .and_then(Tool::from_name)
+        {
+            state.set_tool(tool);
+        }
+        match self.session.get_parsed::<usize>(&format!("{}.page", key)) {
+            Some(page) => self.palette.set_page(state, page),
+            None => {
+                self.palette.show_brush_file(state);
+            }
         }
+        let scroll = self
+            .session
+            .get(&format!("{}.scroll", key))
+            .and_then(|value| value.split_once(','))
+            .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)));
+        let (x, y) = scroll.unwrap_or((0, 0));
+        self.grid_canvas.set_scroll(state, Point::new(x, y));
+    }
+
+    pub fn set_palette_detached(&mut self, detached: bool) {
+        self.palette_detached = detached;
     }
 
     fn begin_load_file(&mut self, state: &mut EditorState) -> bool {
@@ -102,6 +490,18 @@ impl EditorView {
         }
     }
 
+    /// Shows a description of the .bg file format, noting which of its
+    /// optional parts the current map uses.
+    fn begin_format_help(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            self.textbox.set_mode(Mode::FormatHelp, String::new());
+            self.textbox.show_matches(state.tilegrid().format_help());
+            true
+        } else {
+            false
+        }
+    }
+
     fn begin_resize_grid(&mut self, state: &mut EditorState) -> bool {
         if self.textbox.mode() == Mode::Edit {
             state.unselect_if_necessary();
@@ -122,9 +522,13 @@ impl EditorView {
     fn begin_change_color(&mut self, state: &mut EditorState) -> bool {
         if self.textbox.mode() == Mode::Edit {
             state.unselect_if_necessary();
-            let (r, g, b, _) = state.tilegrid().background_color();
-            self.textbox
-                .set_mode(Mode::ChangeColor, format!("{},{},{}", r, g, b));
+            let tilegrid = state.tilegrid();
+            let (r, g, b, _) = tilegrid.background_color();
+            let mut text = format!("{},{},{}", r, g, b);
+            if let Some((r, g, b, _)) = tilegrid.background_gradient() {
+                text.push_str(&format!(" {},{},{}", r, g, b));
+            }
+            self.textbox.set_mode(Mode::ChangeColor, text);
             true
         } else {
             false
@@ -148,162 +552,1457 @@ impl EditorView {
         }
     }
 
-    pub fn mode_perform(
-        &mut self,
-        window: &Window,
-        state: &mut EditorState,
-        mode: Mode,
-        text: String,
-    ) -> bool {
-        let success = self.mode_perform_internal(window, state, mode, text);
-        if success {
-            self.textbox.clear_mode();
+    fn begin_automap(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let path = match state.automap_path() {
+                Some(path) => path.to_string(),
+                None => Path::new(state.filepath())
+                    .with_extension("rules")
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            self.textbox.set_mode(Mode::Automap, path);
+            true
+        } else {
+            false
         }
-        success
     }
 
-    fn mode_perform_internal(
-        &mut self,
-        window: &Window,
-        state: &mut EditorState,
-        mode: Mode,
-        text: String,
-    ) -> bool {
-        match mode {
-            Mode::Edit => false,
-            Mode::LoadFile => {
-                match TileGrid::load_from_path(
-                    window,
-                    state.tilegrid().tileset().dirpath(),
-                    &text,
-                ) {
-                    Ok(tilegrid) => {
-                        state.load_tilegrid(text, tilegrid);
-                        true
-                    }
-                    Err(_) => false,
-                }
-            }
-            Mode::SaveAs => {
-                let old = state.swap_filepath(text);
-                match state.save_to_file() {
-                    Ok(()) => true,
-                    Err(_) => {
-                        state.swap_filepath(old);
-                        false
+    /// Prompts for a `.regions` file of placement rules, starting in the
+    /// map's directory.
+    fn begin_regions(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let path = match state.regions_path() {
+                Some(path) => path.to_string(),
+                None => match Path::new(state.filepath()).parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => {
+                        format!("{}/", dir.display())
                     }
-                }
+                    _ => String::new(),
+                },
+            };
+            self.textbox.set_mode(Mode::Regions, path);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Prompts for the animation (frame duration and tile indices) that the
+    /// brush's tile is part of, listing its tile file's other animations.
+    fn begin_edit_animation(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() != Mode::Edit {
+            return false;
+        }
+        let brush = match state.brush() {
+            Some(tile) => tile.clone(),
+            None => {
+                state.set_notice("Pick a brush tile to animate".to_string());
+                return true;
             }
-            Mode::Resize => {
-                let pieces: Vec<&str> = text.split('x').collect();
-                if pieces.len() != 2 {
-                    return false;
-                }
-                let new_width = match pieces[0].parse::<u32>() {
-                    Ok(width) => width,
-                    Err(_) => return false,
-                };
-                let new_height = match pieces[1].parse::<u32>() {
-                    Ok(height) => height,
-                    Err(_) => return false,
-                };
-                if new_width == 0
-                    || new_height == 0
-                    || new_width > MAX_GRID_WIDTH
-                    || new_height > MAX_GRID_HEIGHT
-                {
-                    return false;
-                }
-                state.mutation().resize_grid(new_width, new_height);
+        };
+        let tileset = state.tilegrid().tileset();
+        let animations = tileset.file_animations(brush.filename());
+        let text = match animations
+            .iter()
+            .find(|animation| animation.frames().contains(&brush.index()))
+        {
+            Some(animation) => animation.to_line(),
+            None => format!("{}: {}", DEFAULT_FRAME_MILLIS, brush.index()),
+        };
+        let mut lines = vec![format!(
+            "Animations in {} (ms: tile indices; empty text removes):",
+            brush.filename()
+        )];
+        lines.extend(animations.iter().map(TileAnimation::to_line));
+        self.textbox.set_mode(Mode::Animation, text);
+        self.textbox.show_matches(lines);
+        true
+    }
+
+    /// Shows the details of the cell under the mouse (its tile on every
+    /// layer, with flips and tileset metadata) until dismissed.
+    fn begin_inspect(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() != Mode::Edit {
+            return false;
+        }
+        match self.grid_canvas.describe_hovered_cell(state) {
+            Some(lines) => {
+                self.textbox.set_mode(Mode::Inspect, String::new());
+                self.textbox.show_matches(lines);
                 true
             }
-            Mode::ChangeColor => {
-                let pieces: Vec<&str> = text.split(',').collect();
-                if pieces.len() != 3 {
-                    return false;
+            None => false,
+        }
+    }
+
+    /// Prompts for a `.terrain` rules file for the terrain brush, starting
+    /// in the tiles directory.
+    fn begin_terrain(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let path = match state.terrain_path() {
+                Some(path) => path.to_string(),
+                None => {
+                    let tileset = state.tilegrid().tileset();
+                    let mut path =
+                        tileset.dirpath().to_string_lossy().into_owned();
+                    if !path.ends_with('/') {
+                        path.push('/');
+                    }
+                    path
                 }
-                let red = match pieces[0].parse::<u8>() {
-                    Ok(red) => red,
-                    Err(_) => return false,
-                };
-                let green = match pieces[1].parse::<u8>() {
-                    Ok(green) => green,
-                    Err(_) => return false,
-                };
-                let blue = match pieces[2].parse::<u8>() {
-                    Ok(blue) => blue,
-                    Err(_) => return false,
-                };
-                state.mutation().set_background_color(red, green, blue);
-                true
-            }
-            Mode::ChangeTiles => {
-                let pieces: Vec<&str> = text.split(',').collect();
-                if pieces.len() < 1 {
-                    return false;
+            };
+            self.textbox.set_mode(Mode::Terrain, path);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_replace_adjacent(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let text = format!(
+                "{} -> {} near -",
+                tile_token(state.brush()),
+                tile_token(state.alt_brush())
+            );
+            self.textbox.set_mode(Mode::ReplaceAdjacent, text);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_rerandomize(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            // Unlike most modes, this one leaves the selection in place,
+            // since it operates on the selection if there is one.
+            state.reset_persistent_mutation();
+            let mut string = String::new();
+            for tile in state.brush().iter().chain(state.alt_brush().iter()) {
+                if !string.is_empty() {
+                    string.push(',');
                 }
-                state.mutation().set_tile_filenames(window, pieces).is_ok()
+                string.push_str(&tile.name());
             }
+            self.textbox.set_mode(Mode::Rerandomize, string);
+            true
+        } else {
+            false
         }
     }
-}
 
-impl GuiElement<EditorState, (Mode, String)> for EditorView {
-    fn draw(&self, state: &EditorState, canvas: &mut Canvas) {
-        let rect = canvas.rect();
-        canvas.draw_rect((127, 127, 127, 127), rect);
-        self.aggregate.draw(state, canvas);
-        self.textbox.draw(state, canvas);
+    fn begin_border_tiles(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let tokens: Vec<String> = match state.border_tiles() {
+                Some(tiles) => tiles.iter().map(tile_token).collect(),
+                None => vec![tile_token(state.brush())],
+            };
+            self.textbox.set_mode(Mode::BorderTiles, tokens.join(" "));
+            true
+        } else {
+            false
+        }
     }
 
-    fn on_event(
-        &mut self,
-        event: &Event,
-        state: &mut EditorState,
-    ) -> Action<(Mode, String)> {
-        match event {
-            &Event::KeyDown(Keycode::A, kmod) if kmod == COMMAND => {
-                state.mutation().select_all();
-                Action::redraw().and_stop()
-            }
-            &Event::KeyDown(Keycode::B, kmod) if kmod == COMMAND => {
-                Action::redraw_if(self.begin_change_color(state)).and_stop()
-            }
-            &Event::KeyDown(Keycode::C, kmod) if kmod == COMMAND => {
-                state.mutation().copy_selection();
-                Action::ignore().and_stop()
-            }
-            &Event::KeyDown(Keycode::H, kmod) if kmod == COMMAND | SHIFT => {
-                state.mutation().flip_selection_horz();
-                Action::redraw().and_stop()
-            }
-            &Event::KeyDown(Keycode::O, kmod) if kmod == COMMAND => {
-                Action::redraw_if(self.begin_load_file(state)).and_stop()
-            }
-            &Event::KeyDown(Keycode::R, kmod) if kmod == COMMAND => {
-                Action::redraw_if(self.begin_resize_grid(state)).and_stop()
-            }
-            &Event::KeyDown(Keycode::S, kmod) if kmod == COMMAND => {
-                state.save_to_file().unwrap();
-                Action::redraw().and_stop()
-            }
-            &Event::KeyDown(Keycode::S, kmod) if kmod == COMMAND | SHIFT => {
-                Action::redraw_if(self.begin_save_as(state)).and_stop()
-            }
-            &Event::KeyDown(Keycode::T, kmod) if kmod == COMMAND => {
-                Action::redraw_if(self.begin_change_tiles(state)).and_stop()
+    /// Shows the tiles that the dither fill blends across.  Clearing the
+    /// text goes back to blending from the brush to the alternate brush.
+    fn begin_dither_tiles(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let tokens: Vec<String> =
+                state.dither_tiles().iter().map(tile_token).collect();
+            self.textbox.set_mode(Mode::DitherTiles, tokens.join(" "));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_noise_fill(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            // Leave the selection in place, since this fills the selection.
+            state.reset_persistent_mutation();
+            let text = format!(
+                "6 {} 50 {}",
+                tile_token(state.brush()),
+                tile_token(state.alt_brush())
+            );
+            self.textbox.set_mode(Mode::NoiseFill, text);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Shows the paint bucket's random fill tiles, starting with an even mix
+    /// of the two brushes if none are set.  Clearing the text turns random
+    /// filling off.
+    fn begin_random_fill(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let text = match state.random_fill() {
+                Some(random_fill) => random_fill.to_text(),
+                None => format!(
+                    "{}*1, {}*1",
+                    tile_token(state.brush()),
+                    tile_token(state.alt_brush())
+                ),
+            };
+            self.textbox.set_mode(Mode::RandomFill, text);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_generate(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            // Leave the selection in place, since this fills the selection.
+            state.reset_persistent_mutation();
+            let text = format!(
+                "maze {} {}",
+                tile_token(state.brush()),
+                tile_token(state.alt_brush())
+            );
+            self.textbox.set_mode(Mode::Generate, text);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_add_layer(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            self.textbox.set_mode(Mode::AddLayer, String::new());
+            true
+        } else {
+            false
+        }
+    }
+
+    fn remove_layer(&mut self, state: &mut EditorState) -> bool {
+        if state.tilegrid().num_layers() < 2 {
+            return false;
+        }
+        state.unselect_if_necessary();
+        state.mutation("delete layer").tilegrid().remove_active_layer()
+    }
+
+    fn begin_save_checkpoint(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            self.textbox.set_mode(Mode::SaveCheckpoint, String::new());
+            self.textbox.show_matches(state.checkpoint_names());
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_restore_checkpoint(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            let names = state.checkpoint_names();
+            if names.is_empty() {
+                return false;
+            }
+            state.unselect_if_necessary();
+            let last = names.last().unwrap().clone();
+            self.textbox.set_mode(Mode::RestoreCheckpoint, last);
+            self.textbox.show_matches(names);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_restore_backup(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            let names = state.backup_names();
+            if names.is_empty() {
+                return false;
+            }
+            state.unselect_if_necessary();
+            let last = names.last().unwrap().clone();
+            self.textbox.set_mode(Mode::RestoreBackup, last);
+            self.textbox.show_matches(names);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_overlay(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let path = state.overlay_path().unwrap_or("").to_string();
+            self.textbox.set_mode(Mode::Overlay, path);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_backdrop(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let text = match state.tilegrid().backdrop() {
+                Some(backdrop) => {
+                    let offset = backdrop.offset();
+                    format!(
+                        "{} {},{}",
+                        backdrop.filename(),
+                        offset.x(),
+                        offset.y()
+                    )
+                }
+                None => String::new(),
+            };
+            self.textbox.set_mode(Mode::Backdrop, text);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Opens the tile file of the current brush in an external editor, and
+    /// starts watching it so that it can be reloaded when it changes.
+    fn edit_tile_source(&mut self, state: &EditorState) -> bool {
+        let filename = match *state.brush() {
+            Some(ref tile) => tile.filename().to_string(),
+            None => return false,
+        };
+        let path = state.tilegrid().tileset().file_path(&filename);
+        let command = self
+            .config
+            .get("tile_editor.command")
+            .unwrap_or(DEFAULT_TILE_EDITOR_COMMAND);
+        match TileSource::launch(command, &filename, path) {
+            Ok(source) => {
+                self.tile_source = Some(source);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Lists each group of identical tiles as "a:1=b:2=...", separated by
+    /// commas (since tile names may contain spaces), so that confirming will
+    /// change all references to each group's first tile.
+    fn begin_dedupe(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let groups: Vec<String> = state
+                .tilegrid()
+                .tileset()
+                .duplicate_tiles()
+                .iter()
+                .map(|group| {
+                    let names: Vec<String> =
+                        group.iter().map(Tile::name).collect();
+                    names.join("=")
+                })
+                .collect();
+            self.textbox.set_mode(Mode::Dedupe, groups.join(", "));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Shows the map's metadata as "key=value; key=value; ..." (see
+    /// `metadata_text`).
+    fn begin_metadata(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let text = metadata_text(state.tilegrid().metadata());
+            self.textbox.set_mode(Mode::Metadata, text);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Prompts for the tile that the palette's eraser paints with ("-" to
+    /// erase to empty cells).
+    fn begin_eraser(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let text = tile_token(state.eraser_tile());
+            self.textbox.set_mode(Mode::Eraser, text);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_merge_tiles(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            // The text is "into,from"; start with the brush's file as the
+            // one to merge into.
+            let text = match *state.brush() {
+                Some(ref tile) => format!("{},", tile.filename()),
+                None => String::new(),
+            };
+            self.textbox.set_mode(Mode::MergeTiles, text);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_rename_tiles(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            // The text is "from,to"; start with the palette page's file as
+            // the one to rename.
+            let text = match state
+                .tilegrid()
+                .tileset()
+                .filenames()
+                .nth(self.palette.page())
+            {
+                Some(filename) => format!("{},", filename),
+                None => String::new(),
+            };
+            self.textbox.set_mode(Mode::RenameTiles, text);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Opens the pixel editor on the current brush tile, or closes it if it
+    /// is already open.
+    fn toggle_pixel_editor(&mut self, state: &EditorState) -> bool {
+        if self.pixel_editor.take().is_some() {
+            return true;
+        }
+        if let Some(ref tile) = *state.brush() {
+            let tileset = state.tilegrid().tileset();
+            self.pixel_editor = PixelEditor::open(
+                PIXEL_EDITOR_LEFT,
+                PIXEL_EDITOR_TOP,
+                &tileset,
+                tile,
+            )
+            .ok();
+        }
+        self.pixel_editor.is_some()
+    }
+
+    fn begin_contact_sheet(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let stem = Path::new(state.filepath()).with_extension("");
+            let path = format!("{}_tiles.png", stem.to_string_lossy());
+            self.textbox.set_mode(Mode::ContactSheet, path);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_screenshot(
+        &mut self,
+        state: &mut EditorState,
+        overlays: bool,
+    ) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            let stem = Path::new(state.filepath()).with_extension("");
+            let path = format!("{}_shot.png", stem.to_string_lossy());
+            self.screenshot_overlays = overlays;
+            self.textbox.set_mode(Mode::Screenshot, path);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn begin_export(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit {
+            state.unselect_if_necessary();
+            let path = Path::new(state.filepath()).with_extension("png");
+            let path = path.to_string_lossy().into_owned();
+            self.textbox.set_mode(Mode::Export, path);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Prompts for a path to export just the selected region of the map
+    /// to, keeping the selection in place.
+    fn begin_export_selection(&mut self, state: &mut EditorState) -> bool {
+        if self.textbox.mode() == Mode::Edit && state.selection().is_some() {
+            let path = Path::new(state.filepath());
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let path = path.with_file_name(format!("{}-selection.png", stem));
+            let path = path.to_string_lossy().into_owned();
+            self.textbox.set_mode(Mode::ExportSelection, path);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn mode_perform(
+        &mut self,
+        window: &mut Window,
+        state: &mut EditorState,
+        mode: Mode,
+        text: String,
+    ) -> bool {
+        let success = self.mode_perform_internal(window, state, mode, text);
+        if success {
+            self.textbox.clear_mode();
+        }
+        success
+    }
+
+    /// Performs any commands queued while handling events.  Returns true if
+    /// any of them changed what should be drawn.
+    pub fn perform_commands(
+        &mut self,
+        window: &mut Window,
+        state: &mut EditorState,
+    ) -> bool {
+        let mut redraw = false;
+        for command in mem::take(&mut self.commands) {
+            redraw |= perform_command(window, state, command);
+        }
+        redraw
+    }
+
+    fn mode_perform_internal(
+        &mut self,
+        window: &mut Window,
+        state: &mut EditorState,
+        mode: Mode,
+        text: String,
+    ) -> bool {
+        match mode {
+            Mode::Edit => false,
+            Mode::FormatHelp | Mode::Inspect => true,
+            Mode::LoadFile => {
+                let tileset = state.tilegrid().tileset();
+                match TileGrid::load_from_path(
+                    window,
+                    tileset.dirpath(),
+                    tileset.size_lock(),
+                    state.tile_renames(),
+                    &text,
+                ) {
+                    Ok(tilegrid) => {
+                        self.remember_session(state);
+                        state.load_tilegrid(text, tilegrid);
+                        self.restore_session(state);
+                        true
+                    }
+                    Err(_) => false,
+                }
+            }
+            Mode::SaveAs => {
+                let old = state.swap_filepath(text);
+                match state.save_to_file() {
+                    Ok(()) => {
+                        state.lock_map_file();
+                        true
+                    }
+                    Err(_) => {
+                        state.swap_filepath(old);
+                        false
+                    }
+                }
+            }
+            Mode::Resize => {
+                // A size like "20x15", optionally followed by how to fill
+                // any new cells, e.g. "20x15 extend".
+                let (text, fill) = match text.split_once(' ') {
+                    Some((size, fill)) => (size, fill.trim()),
+                    None => (text.as_str(), ""),
+                };
+                let fill = match ResizeFill::parse(fill) {
+                    Some(fill) => fill,
+                    None => return false,
+                };
+                let pieces: Vec<&str> = text.split('x').collect();
+                if pieces.len() != 2 {
+                    return false;
+                }
+                let new_width = match pieces[0].parse::<u32>() {
+                    Ok(width) => width,
+                    Err(_) => return false,
+                };
+                let new_height = match pieces[1].parse::<u32>() {
+                    Ok(height) => height,
+                    Err(_) => return false,
+                };
+                let (max_width, max_height) =
+                    max_grid_size_from_config(&self.config);
+                if new_width == 0
+                    || new_height == 0
+                    || new_width > max_width
+                    || new_height > max_height
+                {
+                    return false;
+                }
+                state
+                    .mutation("resize grid")
+                    .resize_grid(new_width, new_height, fill);
+                true
+            }
+            Mode::ChangeColor => {
+                // Either a single "r,g,b" color, or "r,g,b r,g,b" for a
+                // vertical gradient from the first color to the second.
+                let colors: Vec<&str> = text.split_whitespace().collect();
+                if colors.is_empty() || colors.len() > 2 {
+                    return false;
+                }
+                let (red, green, blue) = match parse_color(colors[0]) {
+                    Some(color) => color,
+                    None => return false,
+                };
+                let gradient = match colors.get(1) {
+                    Some(text) => match parse_color(text) {
+                        Some(color) => Some(color),
+                        None => return false,
+                    },
+                    None => None,
+                };
+                let mut mutation = state.mutation("change background");
+                mutation.set_background_color(red, green, blue);
+                mutation.set_background_gradient(gradient);
+                true
+            }
+            Mode::ChangeTiles | Mode::ConfirmChangeTiles => {
+                let pieces: Vec<&str> = text
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|piece| !piece.is_empty())
+                    .collect();
+                if pieces.is_empty() {
+                    return false;
+                }
+                // If cells would be cleared, list them and wait for the
+                // same change to be confirmed before applying it.
+                let preview = tile_change_preview(state, &pieces);
+                if !preview.is_empty()
+                    && (mode == Mode::ChangeTiles
+                        || preview != self.tile_change_preview)
+                {
+                    self.textbox.set_mode(Mode::ConfirmChangeTiles, text);
+                    self.textbox.show_matches(preview.clone());
+                    self.tile_change_preview = preview;
+                    return false;
+                }
+                self.tile_change_preview.clear();
+                state.change_tile_filenames(window, pieces).is_ok()
+            }
+            Mode::Metadata => {
+                let metadata = match parse_metadata_text(&text) {
+                    Some(metadata) => metadata,
+                    None => return false,
+                };
+                if metadata.as_slice() == state.tilegrid().metadata() {
+                    return true;
+                }
+                state.mutation("edit properties").set_metadata(metadata);
+                true
+            }
+            Mode::Eraser => {
+                match state.tilegrid().tileset().parse_tile(text.trim()) {
+                    Some(tile) => {
+                        // Keep the brush on the eraser if it was on it.
+                        if *state.brush() == *state.eraser_tile() {
+                            state.set_brush(tile.clone());
+                        }
+                        state.set_eraser_tile(tile);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Mode::Dedupe => {
+                let tileset = state.tilegrid().tileset();
+                let mut substitutions = BTreeMap::new();
+                for group in text.split(',') {
+                    if group.trim().is_empty() {
+                        continue;
+                    }
+                    let mut tiles = Vec::new();
+                    for piece in group.split('=') {
+                        match tileset.parse_tile(piece.trim()) {
+                            Some(Some(tile)) => tiles.push(tile),
+                            _ => return false,
+                        }
+                    }
+                    for tile in tiles.iter().skip(1) {
+                        substitutions.insert(tile.clone(), tiles[0].clone());
+                    }
+                }
+                if substitutions.is_empty() {
+                    return true;
+                }
+                state.unselect_if_necessary();
+                state
+                    .mutation("dedupe tiles")
+                    .substitute_tiles(&substitutions);
+                true
+            }
+            Mode::MergeTiles => {
+                let (into, from) = match text.split_once(',') {
+                    Some((into, from)) => (into.trim(), from.trim()),
+                    None => return false,
+                };
+                state.unselect_if_necessary();
+                state.merge_tile_files(window, into, from).is_ok()
+            }
+            Mode::RenameTiles => {
+                let (from, to) = match text.split_once(',') {
+                    Some((from, to)) => (from.trim(), to.trim()),
+                    None => return false,
+                };
+                if to.is_empty() {
+                    return false;
+                }
+                state.unselect_if_necessary();
+                state.rename_tile_file(window, from, to).is_ok()
+            }
+            Mode::Automap => {
+                let automap = match Automap::load_from_path(&text) {
+                    Ok(automap) => automap,
+                    Err(_) => return false,
+                };
+                if automap.validate(&state.tilegrid().tileset()).is_err() {
+                    return false;
+                }
+                state.set_automap(text, automap);
+                state.apply_automap();
+                true
+            }
+            Mode::Terrain => {
+                let terrain = match Terrain::load_from_path(Path::new(&text)) {
+                    Ok(terrain) => terrain,
+                    Err(_) => return false,
+                };
+                if terrain.validate(&state.tilegrid().tileset()).is_err() {
+                    return false;
+                }
+                state.set_terrain(text, terrain);
+                state.set_tool(Tool::Terrain);
+                true
+            }
+            Mode::Regions => {
+                // An empty path turns the region rules off.
+                if text.is_empty() {
+                    state.set_regions(None);
+                    state.set_notice("Region rules off".to_string());
+                    return true;
+                }
+                let regions = match Regions::load_from_path(Path::new(&text)) {
+                    Ok(regions) => regions,
+                    Err(_) => return false,
+                };
+                state.set_regions(Some((text, regions)));
+                let count = state.region_violations().len();
+                state.set_notice(format!(
+                    "Region rules loaded ({} violation{})",
+                    count,
+                    if count == 1 { "" } else { "s" }
+                ));
+                true
+            }
+            Mode::ReplaceAdjacent => {
+                // The syntax is "FROM -> TO near NEIGHBOR".
+                let pieces: Vec<&str> = text.split_whitespace().collect();
+                if pieces.len() != 5
+                    || pieces[1] != "->"
+                    || pieces[3] != "near"
+                {
+                    return false;
+                }
+                let tileset = state.tilegrid().tileset();
+                let from = match tileset.parse_tile(pieces[0]) {
+                    Some(tile) => tile,
+                    None => return false,
+                };
+                let to = match tileset.parse_tile(pieces[2]) {
+                    Some(tile) => tile,
+                    None => return false,
+                };
+                let near = match tileset.parse_tile(pieces[4]) {
+                    Some(tile) => tile,
+                    None => return false,
+                };
+                state
+                    .mutation("replace edges")
+                    .replace_adjacent(&from, &to, &near);
+                true
+            }
+            Mode::Rerandomize => {
+                let tileset = state.tilegrid().tileset();
+                let mut variants = Vec::new();
+                for piece in text.split(',') {
+                    match tileset.parse_tile(piece.trim()) {
+                        Some(Some(tile)) => variants.push(tile),
+                        _ => return false,
+                    }
+                }
+                state.mutation("vary tiles").rerandomize_variants(&variants);
+                true
+            }
+            Mode::BorderTiles => {
+                // Either a single tile for the whole border, or eight tiles
+                // in reading order (corners and edges).
+                let tileset = state.tilegrid().tileset();
+                let mut tiles = Vec::new();
+                for piece in text.split_whitespace() {
+                    match tileset.parse_tile(piece) {
+                        Some(tile) => tiles.push(tile),
+                        None => return false,
+                    }
+                }
+                if tiles.len() == 1 {
+                    tiles = vec![tiles[0].clone(); 8];
+                }
+                match <[Option<Tile>; 8]>::try_from(tiles) {
+                    Ok(tiles) => {
+                        state.set_border_tiles(tiles);
+                        true
+                    }
+                    Err(_) => false,
+                }
+            }
+            Mode::DitherTiles => {
+                // Two or three shades, from the start of the drag to its
+                // end.
+                let tileset = state.tilegrid().tileset();
+                let mut tiles = Vec::new();
+                for piece in text.split_whitespace() {
+                    match tileset.parse_tile(piece) {
+                        Some(tile) => tiles.push(tile),
+                        None => return false,
+                    }
+                }
+                if tiles.is_empty() {
+                    state.set_dither_tiles(None)
+                } else {
+                    state.set_dither_tiles(Some(tiles))
+                }
+            }
+            Mode::NoiseFill => {
+                if state.selection().is_none() {
+                    return false;
+                }
+                let tileset = state.tilegrid().tileset();
+                let noise = match NoiseFill::parse(&text, &tileset) {
+                    Some(noise) => noise,
+                    None => return false,
+                };
+                let mut rng = Rng::from_time();
+                state.mutation("noise fill").generate(
+                    |width, height, topleft| {
+                        noise.generate(width, height, topleft, &mut rng)
+                    },
+                );
+                true
             }
-            &Event::KeyDown(Keycode::V, kmod) if kmod == COMMAND => {
-                state.mutation().paste_selection();
+            Mode::Animation => {
+                let brush = match state.brush() {
+                    Some(tile) => tile.clone(),
+                    None => return false,
+                };
+                let tileset = state.tilegrid().tileset();
+                let mut animations =
+                    tileset.file_animations(brush.filename()).to_vec();
+                let existing = animations.iter().position(|animation| {
+                    animation.frames().contains(&brush.index())
+                });
+                if text.trim().is_empty() {
+                    match existing {
+                        Some(index) => {
+                            animations.remove(index);
+                        }
+                        None => return true,
+                    }
+                } else {
+                    let animation = match TileAnimation::from_line(&text) {
+                        Ok(animation) => animation,
+                        Err(_) => return false,
+                    };
+                    if let Some(&frame) =
+                        animation.frames().iter().find(|&&frame| {
+                            tileset
+                                .get_named(brush.filename(), frame)
+                                .is_none()
+                        })
+                    {
+                        state.set_notice(format!(
+                            "No such tile: {}:{}",
+                            brush.filename(),
+                            frame
+                        ));
+                        return false;
+                    }
+                    match existing {
+                        Some(index) => animations[index] = animation,
+                        None => animations.push(animation),
+                    }
+                }
+                let count = animations.len();
+                match state.set_tile_animations(brush.filename(), animations) {
+                    Ok(()) => {
+                        state.set_notice(format!(
+                            "Saved {} animation(s) for {}",
+                            count,
+                            brush.filename()
+                        ));
+                        true
+                    }
+                    Err(err) => {
+                        state.set_notice(format!(
+                            "Can't save animations: {}",
+                            err
+                        ));
+                        false
+                    }
+                }
+            }
+            Mode::RandomFill => {
+                if text.trim().is_empty() {
+                    state.set_random_fill(None);
+                    state.set_notice("Random fill off".to_string());
+                    return true;
+                }
+                let tileset = state.tilegrid().tileset();
+                match WeightedTiles::parse(&text, &tileset) {
+                    Some(random_fill) => {
+                        state.set_notice(format!(
+                            "Paint bucket fills randomly from {} tiles",
+                            random_fill.num_tiles()
+                        ));
+                        state.set_random_fill(Some(random_fill));
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Mode::Generate => {
+                let tileset = state.tilegrid().tileset();
+                let generator = match LevelGenerator::parse(&text, &tileset) {
+                    Some(generator) => generator,
+                    None => return false,
+                };
+                let mut rng = Rng::from_time();
+                state.mutation("generate level").generate(
+                    |width, height, _| {
+                        generator.generate(width, height, &mut rng)
+                    },
+                );
+                true
+            }
+            Mode::SaveCheckpoint => {
+                let name = text.trim();
+                if name.is_empty() {
+                    return false;
+                }
+                state.save_checkpoint(name.to_string());
+                true
+            }
+            Mode::RestoreCheckpoint => state.restore_checkpoint(text.trim()),
+            Mode::AddLayer => {
+                let name = text.trim();
+                let tilegrid = state.tilegrid();
+                if name.is_empty()
+                    || (0..tilegrid.num_layers())
+                        .any(|index| tilegrid.layer_name(index) == name)
+                {
+                    return false;
+                }
+                state
+                    .mutation("add layer")
+                    .tilegrid()
+                    .add_layer(name.to_string());
+                true
+            }
+            Mode::RestoreBackup => {
+                let stamp = text.trim();
+                if !state.backup_names().iter().any(|name| name == stamp) {
+                    return false;
+                }
+                let path =
+                    backup::backup_path(Path::new(state.filepath()), stamp);
+                let reader = match util::open_maybe_gzipped(&path) {
+                    Ok(reader) => reader,
+                    Err(_) => return false,
+                };
+                let tileset = state.tilegrid().tileset();
+                let dirpath = tileset.dirpath().to_path_buf();
+                let size_lock = tileset.size_lock();
+                let renames = state.tile_renames();
+                match TileGrid::load(
+                    window, &dirpath, size_lock, renames, reader,
+                ) {
+                    Ok(tilegrid) => {
+                        state.restore_backup(tilegrid);
+                        true
+                    }
+                    Err(_) => false,
+                }
+            }
+            Mode::Export | Mode::ExportSelection => {
+                // The export format is chosen by the file extension.
+                let format =
+                    match Path::new(&text).extension().and_then(|ext| {
+                        ExportFormat::parse(&ext.to_string_lossy())
+                    }) {
+                        Some(format) => format,
+                        None => return false,
+                    };
+                let region;
+                let tilegrid = if mode == Mode::ExportSelection {
+                    region = match state.selected_region() {
+                        Some(region) => region,
+                        None => return false,
+                    };
+                    &region
+                } else {
+                    state.tilegrid()
+                };
+                let images = format.image_files(tilegrid, Path::new(&text));
+                match File::create(&text) {
+                    Ok(file) => {
+                        format.export(tilegrid, file).is_ok()
+                            && images.into_iter().all(|(path, contents)| {
+                                match contents() {
+                                    Ok(data) => fs::write(path, data).is_ok(),
+                                    Err(_) => false,
+                                }
+                            })
+                    }
+                    Err(_) => false,
+                }
+            }
+            Mode::Overlay => {
+                // An empty path removes the overlay.
+                if text.is_empty() {
+                    state.set_overlay(None);
+                    return true;
+                }
+                let collection = match util::load_ahi_from_file(&text) {
+                    Ok(collection) => collection,
+                    Err(_) => return false,
+                };
+                let image = match collection.images.first() {
+                    Some(image) => image,
+                    None => return false,
+                };
+                let palette =
+                    collection.palettes.first().unwrap_or(Palette::default());
+                state.set_overlay(Some((
+                    text,
+                    window.new_sprite(image, palette),
+                )));
+                true
+            }
+            Mode::Backdrop => {
+                // The text is a filename in the tiles directory, optionally
+                // followed by a pixel offset (e.g. "sky 0,-16").  Empty text
+                // removes the backdrop.
+                let text = text.trim();
+                if text.is_empty() {
+                    state.mutation("remove backdrop").set_backdrop(None);
+                    return true;
+                }
+                let (filename, offset) = match text.rsplit_once(' ') {
+                    Some((filename, offset)) => match parse_point(offset) {
+                        Some(offset) => (filename.trim(), offset),
+                        None => (text, Point::new(0, 0)),
+                    },
+                    None => (text, Point::new(0, 0)),
+                };
+                let dirpath =
+                    state.tilegrid().tileset().dirpath().to_path_buf();
+                match Backdrop::load(window, &dirpath, filename, offset) {
+                    Ok(backdrop) => {
+                        state
+                            .mutation("set backdrop")
+                            .set_backdrop(Some(backdrop));
+                        true
+                    }
+                    Err(_) => false,
+                }
+            }
+            Mode::Screenshot => {
+                let rect = self.grid_canvas.rect();
+                {
+                    let mut canvas = window.canvas();
+                    canvas.clear((64, 64, 64, 255));
+                    self.grid_canvas.draw_snapshot(
+                        state,
+                        &mut canvas,
+                        self.screenshot_overlays,
+                    );
+                }
+                let image = match window.read_pixels(rect).and_then(
+                    |(width, height, data)| {
+                        RgbaImage::from_rgba_data(width, height, data)
+                    },
+                ) {
+                    Some(image) => image,
+                    None => return false,
+                };
+                match File::create(&text) {
+                    Ok(file) => image.write_png(file).is_ok(),
+                    Err(_) => false,
+                }
+            }
+            Mode::ContactSheet => {
+                let file = match File::create(&text) {
+                    Ok(file) => file,
+                    Err(_) => return false,
+                };
+                let tilegrid = state.tilegrid();
+                export_contact_sheet(
+                    &tilegrid.tileset(),
+                    tilegrid.background_color(),
+                    &self.font,
+                    file,
+                )
+                .is_ok()
+            }
+        }
+    }
+}
+
+impl GuiElement<EditorState, (Mode, String)> for EditorView {
+    fn draw(&self, state: &EditorState, canvas: &mut Canvas) {
+        let rect = canvas.rect();
+        canvas.draw_rect((127, 127, 127, 127), rect);
+        self.grid_canvas.draw(state, canvas);
+        if let Some(ref pixel_editor) = self.pixel_editor {
+            pixel_editor.draw(state, canvas);
+        }
+        if self.panels_collapsed {
+            let handle = Rect::new(0, 0, COLLAPSE_HANDLE_WIDTH, rect.height());
+            canvas.fill_rect(COLLAPSE_HANDLE_COLOR, handle);
+            self.textbox.draw(state, canvas);
+            return;
+        }
+        self.aggregate.draw(state, canvas);
+        self.toolbox.draw(state, canvas);
+        if !self.palette_detached {
+            self.palette.draw(state, canvas);
+        }
+        for coords in self.coords.iter() {
+            coords.draw(state, canvas);
+        }
+        if self.layout_mode {
+            for index in 0..PANELS.len() {
+                if self.panel_visible(index) {
+                    let rect = self.panel(index).rect();
+                    canvas.draw_rect(LAYOUT_OUTLINE_COLOR, rect);
+                }
+            }
+        }
+        self.textbox.draw(state, canvas);
+    }
+
+    fn on_event(
+        &mut self,
+        event: &Event,
+        state: &mut EditorState,
+    ) -> Action<(Mode, String)> {
+        match event {
+            &Event::KeyDown(Keycode::A, kmod) if kmod == COMMAND => {
+                state.mutation("select all").select_all();
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::A, kmod)
+                if kmod == COMMAND | SHIFT | ALT =>
+            {
+                Action::redraw_if(self.begin_edit_animation(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::A, kmod) if kmod == COMMAND | SHIFT => {
+                state.unselect_if_necessary();
+                let tile = state.brush().clone();
+                if state.tilegrid().rects_containing(&tile).is_empty() {
+                    state.set_notice("No cells match the brush".to_string());
+                } else {
+                    let count = state
+                        .mutation("select matching")
+                        .select_matching(&tile);
+                    state.set_notice(format!("Selected {} cells", count));
+                }
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::B, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.begin_change_color(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::B, kmod) if kmod == COMMAND | SHIFT => {
+                if !state.selection_to_brush() {
+                    return Action::ignore().and_stop();
+                }
+                let (width, height) = state.brush_grid().size();
+                state.unselect_if_necessary();
+                state.set_tool(Tool::Pencil);
+                state.set_notice(format!(
+                    "Stamp brush: {} x {}",
+                    width, height
+                ));
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::B, kmod) if kmod == COMMAND | ALT => {
+                Action::redraw_if(self.begin_random_fill(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::C, kmod) if kmod == COMMAND => {
+                state.mutation("copy").copy_selection();
+                self.export_clipboard(false)
+            }
+            &Event::KeyDown(Keycode::C, kmod) if kmod == COMMAND | SHIFT => {
+                Action::redraw_if(self.begin_screenshot(state, true))
+                    .and_stop()
+            }
+            &Event::KeyDown(Keycode::C, kmod)
+                if kmod == COMMAND | SHIFT | ALT =>
+            {
+                Action::redraw_if(self.begin_screenshot(state, false))
+                    .and_stop()
+            }
+            &Event::KeyDown(Keycode::C, kmod) if kmod == COMMAND | ALT => {
+                self.commands.push(Command::CopyMapText);
+                Action::ignore().and_stop()
+            }
+            &Event::KeyDown(Keycode::D, kmod) if kmod == COMMAND => {
+                if state.selection().is_some() {
+                    state.mutation("duplicate").duplicate_selection();
+                } else {
+                    state.set_notice("Nothing selected".to_string());
+                }
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::D, kmod) if kmod == COMMAND | SHIFT => {
+                Action::redraw_if(self.begin_dedupe(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::D, kmod)
+                if kmod == COMMAND | SHIFT | ALT =>
+            {
+                Action::redraw_if(self.begin_dither_tiles(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::E, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.begin_replace_adjacent(state))
+                    .and_stop()
+            }
+            &Event::KeyDown(Keycode::E, kmod) if kmod == COMMAND | ALT => {
+                Action::redraw_if(self.begin_eraser(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::E, kmod) if kmod == COMMAND | SHIFT => {
+                Action::redraw_if(self.begin_contact_sheet(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::F, kmod) if kmod == COMMAND | SHIFT => {
+                self.toggle_panels_collapsed();
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::G, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.begin_rerandomize(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::G, kmod) if kmod == COMMAND | SHIFT => {
+                if state.selection().is_some() {
+                    Action::redraw_if(self.begin_noise_fill(state)).and_stop()
+                } else {
+                    state.set_notice("Nothing selected".to_string());
+                    Action::redraw().and_stop()
+                }
+            }
+            &Event::KeyDown(Keycode::G, kmod) if kmod == COMMAND | ALT => {
+                Action::redraw_if(self.begin_generate(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::H, kmod) if kmod == COMMAND | SHIFT => {
+                state.mutation("flip horizontally").flip_selection_horz();
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::I, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.toggle_pixel_editor(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::I, kmod) if kmod == COMMAND | SHIFT => {
+                Action::redraw_if(self.begin_inspect(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::I, kmod) if kmod == COMMAND | ALT => {
+                state.set_notice(grid_stats(state.tilegrid()));
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::K, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.begin_save_checkpoint(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::K, kmod) if kmod == COMMAND | SHIFT => {
+                Action::redraw_if(self.begin_restore_checkpoint(state))
+                    .and_stop()
+            }
+            &Event::KeyDown(Keycode::K, kmod) if kmod == COMMAND | ALT => {
+                Action::redraw_if(self.begin_restore_backup(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::L, kmod) if kmod == COMMAND | ALT => {
+                self.commands.push(Command::SwapTilesDir);
+                Action::ignore().and_stop()
+            }
+            &Event::KeyDown(Keycode::L, kmod) if kmod == COMMAND | SHIFT => {
+                self.toggle_layout_mode(state);
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::M, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.begin_automap(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::M, kmod) if kmod == COMMAND | ALT => {
+                Action::redraw_if(self.begin_rename_tiles(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::M, kmod) if kmod == COMMAND | SHIFT => {
+                state.toggle_automap_after_stroke();
+                Action::ignore().and_stop()
+            }
+            &Event::KeyDown(Keycode::M, kmod)
+                if kmod == COMMAND | SHIFT | ALT =>
+            {
+                let was_clipped = state.paint_clip().is_some();
+                let notice = if !state.toggle_paint_clip() {
+                    "Select the cells to clip painting to"
+                } else if was_clipped {
+                    "Painting no longer clipped"
+                } else {
+                    "Pencil and paint bucket clipped to the selection"
+                };
+                state.set_notice(notice.to_string());
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::N, kmod) if kmod == COMMAND | ALT => {
+                Action::redraw_if(self.begin_add_layer(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::N, kmod)
+                if kmod == COMMAND | SHIFT | ALT =>
+            {
+                Action::redraw_if(self.remove_layer(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::O, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.begin_load_file(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::O, kmod) if kmod == COMMAND | SHIFT => {
+                Action::redraw_if(self.begin_border_tiles(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::O, kmod) if kmod == COMMAND | ALT => {
+                if state.border_tiles().is_none() {
+                    state.set_notice(
+                        "No border tiles set (Cmd+Shift+O)".to_string(),
+                    );
+                    Action::redraw().and_stop()
+                } else if state.selection().is_some() {
+                    state.mutation("draw border").border_selection();
+                    Action::redraw().and_stop()
+                } else {
+                    Action::ignore().and_stop()
+                }
+            }
+            &Event::KeyDown(Keycode::P, kmod) if kmod == COMMAND | SHIFT => {
+                self.palette_detached = !self.palette_detached;
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::P, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.begin_metadata(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::R, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.begin_resize_grid(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::R, kmod)
+                if kmod == COMMAND | SHIFT | ALT =>
+            {
+                Action::redraw_if(self.begin_regions(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::R, kmod) if kmod == COMMAND | ALT => {
+                Action::redraw_if(state.trim_to_content()).and_stop()
+            }
+            &Event::KeyDown(Keycode::S, kmod) if kmod == COMMAND => {
+                if let Err(error) = state.save_to_file() {
+                    state.set_notice(format!("Couldn't save: {}", error));
+                }
                 Action::redraw().and_stop()
             }
+            &Event::KeyDown(Keycode::S, kmod) if kmod == COMMAND | SHIFT => {
+                Action::redraw_if(self.begin_save_as(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::T, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.begin_change_tiles(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::T, kmod)
+                if kmod == COMMAND | SHIFT | ALT =>
+            {
+                Action::redraw_if(self.begin_terrain(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::T, kmod) if kmod == COMMAND | ALT => {
+                Action::redraw_if(self.begin_merge_tiles(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::T, kmod) if kmod == COMMAND | SHIFT => {
+                self.edit_tile_source(state);
+                Action::ignore().and_stop()
+            }
+            &Event::KeyDown(Keycode::U, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.begin_overlay(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::U, kmod) if kmod == COMMAND | SHIFT => {
+                Action::redraw_if(self.begin_backdrop(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::LeftBracket, kmod) if kmod == COMMAND => {
+                let delta = -OVERLAY_OPACITY_STEP;
+                Action::redraw_if(state.adjust_overlay_opacity(delta))
+                    .and_stop()
+            }
+            &Event::KeyDown(Keycode::RightBracket, kmod)
+                if kmod == COMMAND =>
+            {
+                let delta = OVERLAY_OPACITY_STEP;
+                Action::redraw_if(state.adjust_overlay_opacity(delta))
+                    .and_stop()
+            }
+            &Event::KeyDown(Keycode::Slash, kmod) if kmod == COMMAND => {
+                Action::redraw_if(self.begin_format_help(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::Equals, kmod)
+                if kmod == COMMAND | SHIFT =>
+            {
+                Action::redraw_if(state.grow_selection(1)).and_stop()
+            }
+            &Event::KeyDown(Keycode::Minus, kmod)
+                if kmod == COMMAND | SHIFT =>
+            {
+                Action::redraw_if(state.grow_selection(-1)).and_stop()
+            }
+            &Event::KeyDown(Keycode::V, kmod) if kmod == COMMAND => {
+                self.commands.push(Command::PasteTiles);
+                Action::ignore().and_stop()
+            }
             &Event::KeyDown(Keycode::V, kmod) if kmod == COMMAND | SHIFT => {
-                state.mutation().flip_selection_vert();
+                state.mutation("flip vertically").flip_selection_vert();
                 Action::redraw().and_stop()
             }
+            &Event::KeyDown(Keycode::V, kmod) if kmod == COMMAND | ALT => {
+                self.commands.push(Command::PasteMapText);
+                Action::ignore().and_stop()
+            }
             &Event::KeyDown(Keycode::X, kmod) if kmod == COMMAND => {
-                state.mutation().cut_selection();
-                Action::redraw().and_stop()
+                state.mutation("cut").cut_selection();
+                self.export_clipboard(true)
+            }
+            &Event::KeyDown(Keycode::X, kmod) if kmod == COMMAND | SHIFT => {
+                Action::redraw_if(self.begin_export(state)).and_stop()
+            }
+            &Event::KeyDown(Keycode::X, kmod) if kmod == COMMAND | ALT => {
+                Action::redraw_if(self.begin_export_selection(state))
+                    .and_stop()
             }
             &Event::KeyDown(Keycode::Z, kmod) if kmod == COMMAND => {
                 Action::redraw_if(state.undo()).and_stop()
@@ -312,15 +2011,299 @@ impl GuiElement<EditorState, (Mode, String)> for EditorView {
                 Action::redraw_if(state.redo()).and_stop()
             }
             _ => {
+                if let Some(action) = self.on_palette_drag_event(event, state)
+                {
+                    return action;
+                }
                 let mut action = self.textbox.on_event(event, state);
                 if !action.should_stop() {
+                    if let Some(ref mut pixel_editor) = self.pixel_editor {
+                        let mut subaction =
+                            pixel_editor.on_event(event, state);
+                        if let Some(filename) = subaction.take_value() {
+                            self.commands
+                                .push(Command::ReloadTileFile(filename));
+                        }
+                        action.merge(subaction.but_no_value());
+                    }
+                }
+                if !action.should_stop() && self.layout_mode {
+                    action.merge(self.on_layout_event(event));
+                }
+                if !action.should_stop() && self.panels_collapsed {
+                    if let &Event::MouseDown(pt, _) = event {
+                        if pt.x() < COLLAPSE_HANDLE_WIDTH as i32 {
+                            self.toggle_panels_collapsed();
+                            return Action::redraw().and_stop();
+                        }
+                    }
+                }
+                // While the panels are collapsed, they still get keyboard
+                // shortcuts, but not mouse events.
+                let is_mouse = matches!(
+                    event,
+                    Event::MouseDown(_, _)
+                        | Event::MouseDrag(_, _)
+                        | Event::MouseMove(_)
+                        | Event::MouseUp(_)
+                );
+                let panels_active = !self.panels_collapsed || !is_mouse;
+                if !action.should_stop() && panels_active {
+                    let subaction = self.toolbox.on_event(event, state);
+                    action.merge(subaction.but_no_value());
+                }
+                if !action.should_stop()
+                    && panels_active
+                    && !self.palette_detached
+                {
+                    let subaction = self.palette.on_event(event, state);
+                    action.merge(subaction.but_no_value());
+                }
+                if !action.should_stop() && panels_active {
+                    action.merge(self.on_coords_event(event, state));
+                }
+                if !action.should_stop() {
+                    let subaction = self.grid_canvas.on_event(event, state);
+                    action.merge(subaction.but_no_value());
+                }
+                if !action.should_stop() && !self.panels_collapsed {
                     let subaaction = self.aggregate.on_event(event, state);
                     action.merge(subaaction.but_no_value());
                 }
+                if let &Event::ClockTick = event {
+                    if let Some(ref mut source) = self.tile_source {
+                        if source.poll() {
+                            let filename = source.filename().to_string();
+                            self.commands
+                                .push(Command::ReloadTileFile(filename));
+                        }
+                    }
+                }
                 action
             }
         }
     }
 }
 
+/// Performs a command queued by the editor view.  Returns true if it
+/// succeeded.
+fn perform_command(
+    window: &mut Window,
+    state: &mut EditorState,
+    command: Command,
+) -> bool {
+    match command {
+        Command::CopyMapText => {
+            state.unselect_if_necessary();
+            let mut data = Vec::new();
+            if state.tilegrid().save(&mut data).is_err() {
+                return false;
+            }
+            window.set_clipboard_text(&String::from_utf8_lossy(&data))
+        }
+        Command::CopyCoords(text) => window.set_clipboard_text(&text),
+        Command::CopyTiles => match state.clipboard_text() {
+            Some(text) => window.set_clipboard_text(&text),
+            None => false,
+        },
+        Command::PasteTiles => {
+            // Tiles copied in another editor (or this one) take the
+            // place of the clipboard's contents before pasting.
+            let text = window.clipboard_text().unwrap_or_default();
+            if SubGrid::is_subgrid_text(&text)
+                && state.clipboard_text().as_ref() != Some(&text)
+            {
+                let tileset = state.tilegrid().tileset();
+                match SubGrid::from_text(&text, &tileset) {
+                    Ok((subgrid, position)) => {
+                        state.set_clipboard(subgrid, position);
+                    }
+                    Err(err) => {
+                        state
+                            .set_notice(format!("Can't paste tiles: {}", err));
+                        return false;
+                    }
+                }
+            }
+            state.mutation("paste").paste_selection();
+            true
+        }
+        Command::PasteMapText => {
+            let text = match window.clipboard_text() {
+                Some(text) => text,
+                None => return false,
+            };
+            let tileset = state.tilegrid().tileset();
+            let dirpath = tileset.dirpath().to_path_buf();
+            let size_lock = tileset.size_lock();
+            match TileGrid::load(
+                window,
+                &dirpath,
+                size_lock,
+                state.tile_renames(),
+                text.as_bytes(),
+            ) {
+                Ok(tilegrid) => {
+                    state.unselect_if_necessary();
+                    *state.mutation("paste map").tilegrid() = tilegrid;
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+        Command::ReloadTileFile(filename) => {
+            state.reload_tile_file(window, &filename).is_ok()
+        }
+        Command::SwapTilesDir => match state.swap_tiles_dir(window) {
+            Ok(true) => {
+                let tileset = state.tilegrid().tileset();
+                let dirpath = tileset.dirpath().display();
+                state.set_notice(format!("Tiles from {}", dirpath));
+                true
+            }
+            Ok(false) => {
+                state.set_notice(
+                    "No alternate tiles directory (see --alt-tiles)"
+                        .to_string(),
+                );
+                false
+            }
+            Err(err) => {
+                state.set_notice(format!("Can't swap tiles: {}", err));
+                false
+            }
+        },
+    }
+}
+
+//===========================================================================//
+
+/// Lists, for each tile file that changing the tileset to the given files
+/// would remove, how many cells using its tiles would be cleared.  Files
+/// with no placed tiles are left out.
+fn tile_change_preview(
+    state: &EditorState,
+    filenames: &[&str],
+) -> Vec<String> {
+    let tilegrid = state.tilegrid();
+    tilegrid
+        .tileset()
+        .filenames()
+        .filter(|filename| !filenames.contains(&filename.as_str()))
+        .filter_map(|filename| {
+            let count = tilegrid.count_tiles_from(&filename);
+            if count == 0 {
+                return None;
+            }
+            let plural = if count == 1 { "" } else { "s" };
+            Some(format!("{}: {} cell{} cleared", filename, count, plural))
+        })
+        .collect()
+}
+
+/// Returns the largest width and height, in tiles, that the grid may be
+/// resized to, as set by `grid.max_width` and `grid.max_height` in the config
+/// file.  Zero is ignored, and larger values are clamped to
+/// `GRID_SIZE_LIMIT`.
+fn max_grid_size_from_config(config: &Config) -> (u32, u32) {
+    let get_max = |key: &str, default: u32| {
+        config
+            .get_parsed::<u32>(key)
+            .filter(|&max| max > 0)
+            .map_or(default, |max| max.min(GRID_SIZE_LIMIT))
+    };
+    let max_width = get_max("grid.max_width", DEFAULT_MAX_GRID_WIDTH);
+    let max_height = get_max("grid.max_height", DEFAULT_MAX_GRID_HEIGHT);
+    (max_width, max_height)
+}
+
+/// Returns the key prefix under which the session store records state for
+/// the file at the given path, or `None` if the path can't be stored as a
+/// config key.
+fn session_key(path: &str) -> Option<String> {
+    let key = match fs::canonicalize(path) {
+        Ok(path) => path.to_str()?.to_string(),
+        Err(_) => path.to_string(),
+    };
+    if key.contains('=') || key.contains('\n') {
+        None
+    } else {
+        Some(key)
+    }
+}
+
+/// Returns the key prefix of each file recorded in the session store, along
+/// with when it was last used (as a count that increases with each use, or
+/// zero for files recorded before that was tracked).
+fn session_files(session: &Config) -> Vec<(u64, String)> {
+    let mut files = BTreeMap::<String, u64>::new();
+    for key in session.keys() {
+        if let Some((file, field)) = key.rsplit_once('.') {
+            if SESSION_FIELDS.contains(&field) {
+                let last_used = files.entry(file.to_string()).or_insert(0);
+                if field == "last" {
+                    *last_used = session.get_parsed(key).unwrap_or(0);
+                }
+            }
+        }
+    }
+    files.into_iter().map(|(file, last_used)| (last_used, file)).collect()
+}
+
+/// Forgets the least recently used files in the session store, so that it
+/// holds at most `MAX_SESSION_FILES` of them.
+fn prune_session(session: &mut Config) {
+    let mut files = session_files(session);
+    if files.len() <= MAX_SESSION_FILES {
+        return;
+    }
+    files.sort();
+    let excess = files.len() - MAX_SESSION_FILES;
+    for (_, file) in files.into_iter().take(excess) {
+        for field in SESSION_FIELDS {
+            session.remove(&format!("{}.{}", file, field));
+        }
+    }
+}
+
+/// Summarizes how much of the grid is empty, and where its content lies.
+fn grid_stats(tilegrid: &TileGrid) -> String {
+    let (width, height) = tilegrid.size();
+    let total = width as usize * height as usize;
+    let empty = tilegrid.count_empty_cells();
+    let percent = (empty * 100).checked_div(total).unwrap_or(0);
+    let mut text = format!("Empty: {}/{} ({}%)", empty, total, percent);
+    match tilegrid.content_bounds() {
+        Some(bounds) => text.push_str(&format!(
+            ", content: {},{} to {},{} ({} x {})",
+            bounds.left(),
+            bounds.top(),
+            bounds.right() - 1,
+            bounds.bottom() - 1,
+            bounds.width(),
+            bounds.height()
+        )),
+        None => text.push_str(", no content"),
+    }
+    text
+}
+
+/// Parses a point written as "x,y", as stored in the config file.
+fn parse_point(text: &str) -> Option<Point> {
+    let (x, y) = text.split_once(',')?;
+    Some(Point::new(x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+fn parse_color(text: &str) -> Option<(u8, u8, u8)> {
+    let pieces: Vec<&str> = text.split(',').collect();
+    if pieces.len() != 3 {
+        return None;
+    }
+    Some((
+        pieces[0].parse().ok()?,
+        pieces[1].parse().ok()?,
+        pieces[2].parse().ok()?,
+    ))
+}
+
 //===========================================================================//
@@ -19,7 +19,7 @@
 
 use super::canvas::Canvas;
 use super::event::Event;
-use sdl2::rect::Rect;
+use sdl2::rect::{Point, Rect};
 use std::mem;
 
 //===========================================================================//
@@ -127,6 +127,15 @@ pub trait GuiElement<S, A> {
 
 //===========================================================================//
 
+/// A GUI element that the user can reposition within the window.
+pub trait Movable {
+    fn rect(&self) -> Rect;
+
+    fn move_to(&mut self, topleft: Point);
+}
+
+//===========================================================================//
+
 pub struct SubrectElement<E> {
     subrect: Rect,
     element: E,
@@ -141,6 +150,10 @@ impl<E> SubrectElement<E> {
         self.subrect
     }
 
+    pub fn set_rect(&mut self, subrect: Rect) {
+        self.subrect = subrect;
+    }
+
     pub fn inner(&self) -> &E {
         &self.element
     }
@@ -161,7 +174,7 @@ where
 
     fn on_event(&mut self, event: &Event, state: &mut S) -> Action<A> {
         match event {
-            &Event::MouseDown(pt) => {
+            &Event::MouseDown(pt, _) => {
                 if !self.subrect.contains_point(pt) {
                     return Action::ignore();
                 }
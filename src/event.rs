@@ -31,22 +31,37 @@ use std::ops::{BitOr, BitOrAssign};
 pub enum Event {
     Quit,
     ClockTick,
-    MouseDrag(Point),
-    MouseDown(Point),
-    MouseUp,
+    /// The mouse moved with the button held down, with the modifiers held
+    /// at the time.
+    MouseDrag(Point, KeyMod),
+    /// The mouse moved with no button held down.
+    MouseMove(Point),
+    MouseDown(Point, KeyMod),
+    MouseUp(KeyMod),
+    /// A key was pressed (or auto-repeated while held down).
     KeyDown(Keycode, KeyMod),
+    /// A key was released.  The modifiers are those still held down after
+    /// the release (so releasing Shift gives a KeyMod without `SHIFT`).
+    KeyUp(Keycode, KeyMod),
     TextInput(String),
 }
 
 impl Event {
-    pub fn from_sdl2(event: &sdl2::event::Event) -> Option<Event> {
+    /// Converts an SDL event, given the modifier keys currently held (which
+    /// SDL doesn't include in mouse events).
+    pub fn from_sdl2(
+        event: &sdl2::event::Event,
+        mod_state: Mod,
+        command_key: CommandKey,
+    ) -> Option<Event> {
+        let mouse_mod = KeyMod::from_sdl2(mod_state, command_key);
         match event {
             &sdl2::event::Event::Quit { .. } => Some(Event::Quit),
             &sdl2::event::Event::MouseMotion { x, y, mousestate, .. } => {
                 if mousestate.left() {
-                    Some(Event::MouseDrag(Point::new(x, y)))
+                    Some(Event::MouseDrag(Point::new(x, y), mouse_mod))
                 } else {
-                    None
+                    Some(Event::MouseMove(Point::new(x, y)))
                 }
             }
             &sdl2::event::Event::MouseButtonDown {
@@ -54,16 +69,27 @@ impl Event {
                 x,
                 y,
                 ..
-            } => Some(Event::MouseDown(Point::new(x, y))),
+            } => Some(Event::MouseDown(Point::new(x, y), mouse_mod)),
             &sdl2::event::Event::MouseButtonUp {
                 mouse_btn: MouseButton::Left,
                 ..
-            } => Some(Event::MouseUp),
+            } => Some(Event::MouseUp(mouse_mod)),
             &sdl2::event::Event::KeyDown {
                 keycode: Some(keycode),
                 keymod,
                 ..
-            } => Some(Event::KeyDown(keycode, KeyMod::from_sdl2(keymod))),
+            } => Some(Event::KeyDown(
+                keycode,
+                KeyMod::from_sdl2(keymod, command_key),
+            )),
+            &sdl2::event::Event::KeyUp {
+                keycode: Some(keycode),
+                keymod,
+                ..
+            } => Some(Event::KeyUp(
+                keycode,
+                KeyMod::from_sdl2(keymod, command_key),
+            )),
             &sdl2::event::Event::TextInput { ref text, .. } => {
                 Some(Event::TextInput(text.clone()))
             }
@@ -73,8 +99,13 @@ impl Event {
 
     pub fn translate(&self, dx: i32, dy: i32) -> Event {
         match self {
-            &Event::MouseDrag(pt) => Event::MouseDrag(pt.offset(dx, dy)),
-            &Event::MouseDown(pt) => Event::MouseDown(pt.offset(dx, dy)),
+            &Event::MouseDrag(pt, kmod) => {
+                Event::MouseDrag(pt.offset(dx, dy), kmod)
+            }
+            &Event::MouseMove(pt) => Event::MouseMove(pt.offset(dx, dy)),
+            &Event::MouseDown(pt, kmod) => {
+                Event::MouseDown(pt.offset(dx, dy), kmod)
+            }
             _ => self.clone(),
         }
     }
@@ -82,13 +113,62 @@ impl Event {
 
 // ========================================================================= //
 
+/// Which physical key acts as the `COMMAND` modifier, as set by the
+/// `keys.command` config key (`ctrl`, `cmd`, or `alt`).  By default, it's
+/// Cmd on macOS and Ctrl elsewhere.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CommandKey {
+    Ctrl,
+    Cmd,
+    Alt,
+}
+
+impl CommandKey {
+    pub fn from_name(name: &str) -> Option<CommandKey> {
+        match name {
+            "ctrl" => Some(CommandKey::Ctrl),
+            "cmd" => Some(CommandKey::Cmd),
+            "alt" => Some(CommandKey::Alt),
+            _ => None,
+        }
+    }
+
+    pub fn platform_default() -> CommandKey {
+        if cfg!(target_os = "macos") {
+            CommandKey::Cmd
+        } else {
+            CommandKey::Ctrl
+        }
+    }
+
+    fn sdl2_mod(self) -> Mod {
+        match self {
+            CommandKey::Ctrl => Mod::LCTRLMOD | Mod::RCTRLMOD,
+            CommandKey::Cmd => Mod::LGUIMOD | Mod::RGUIMOD,
+            CommandKey::Alt => Mod::LALTMOD | Mod::RALTMOD,
+        }
+    }
+
+    /// Returns the physical key that acts as the `ALT` modifier.  That's
+    /// normally Alt, but if Alt is the command key, then Ctrl takes its
+    /// place, so that Cmd+Alt shortcuts stay reachable.
+    fn alt_key(self) -> CommandKey {
+        match self {
+            CommandKey::Alt => CommandKey::Ctrl,
+            _ => CommandKey::Alt,
+        }
+    }
+}
+
+// ========================================================================= //
+
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub struct KeyMod {
     bits: u8,
 }
 
 impl KeyMod {
-    pub fn from_sdl2(kmod: Mod) -> KeyMod {
+    pub fn from_sdl2(kmod: Mod, command_key: CommandKey) -> KeyMod {
         let mut result = NONE;
 
         let sdl2_shift = Mod::LSHIFTMOD | Mod::RSHIFTMOD;
@@ -96,17 +176,11 @@ impl KeyMod {
             result |= SHIFT;
         }
 
-        let sdl2_alt = Mod::LALTMOD | Mod::RALTMOD;
-        if kmod.intersects(sdl2_alt) {
+        if kmod.intersects(command_key.alt_key().sdl2_mod()) {
             result |= ALT;
         }
 
-        let sdl2_command = if cfg!(target_os = "macos") {
-            Mod::LGUIMOD | Mod::RGUIMOD
-        } else {
-            Mod::LCTRLMOD | Mod::RCTRLMOD
-        };
-        if kmod.intersects(sdl2_command) {
+        if kmod.intersects(command_key.sdl2_mod()) {
             result |= COMMAND;
         }
 
@@ -133,3 +207,88 @@ pub const ALT: KeyMod = KeyMod { bits: 0x2 };
 pub const COMMAND: KeyMod = KeyMod { bits: 0x4 };
 
 // ========================================================================= //
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CommandKey, Event, KeyMod, Keycode, ALT, COMMAND, NONE, SHIFT,
+    };
+    use sdl2::keyboard::Mod;
+    use sdl2::rect::Point;
+
+    #[test]
+    fn key_events_from_sdl2() {
+        let down = sdl2::event::Event::KeyDown {
+            timestamp: 0,
+            window_id: 0,
+            keycode: Some(Keycode::Y),
+            scancode: None,
+            keymod: Mod::LSHIFTMOD,
+            repeat: false,
+        };
+        assert!(
+            Event::from_sdl2(&down, Mod::LSHIFTMOD, CommandKey::Ctrl)
+                == Some(Event::KeyDown(Keycode::Y, SHIFT))
+        );
+        let up = sdl2::event::Event::KeyUp {
+            timestamp: 0,
+            window_id: 0,
+            keycode: Some(Keycode::Y),
+            scancode: None,
+            keymod: Mod::NOMOD,
+            repeat: false,
+        };
+        assert!(
+            Event::from_sdl2(&up, Mod::NOMOD, CommandKey::Ctrl)
+                == Some(Event::KeyUp(Keycode::Y, NONE))
+        );
+        let unknown = sdl2::event::Event::KeyUp {
+            timestamp: 0,
+            window_id: 0,
+            keycode: None,
+            scancode: None,
+            keymod: Mod::NOMOD,
+            repeat: false,
+        };
+        assert!(
+            Event::from_sdl2(&unknown, Mod::NOMOD, CommandKey::Ctrl).is_none()
+        );
+    }
+
+    #[test]
+    fn mouse_events_from_sdl2_carry_modifiers() {
+        let down = sdl2::event::Event::MouseButtonDown {
+            timestamp: 0,
+            window_id: 0,
+            which: 0,
+            mouse_btn: sdl2::mouse::MouseButton::Left,
+            clicks: 1,
+            x: 3,
+            y: 4,
+        };
+        assert!(
+            Event::from_sdl2(&down, Mod::LALTMOD, CommandKey::Ctrl)
+                == Some(Event::MouseDown(Point::new(3, 4), ALT))
+        );
+        assert!(
+            Event::from_sdl2(&down, Mod::NOMOD, CommandKey::Ctrl)
+                == Some(Event::MouseDown(Point::new(3, 4), NONE))
+        );
+    }
+
+    #[test]
+    fn command_key_choice() {
+        let ctrl_alt = Mod::LCTRLMOD | Mod::RALTMOD;
+        assert!(
+            KeyMod::from_sdl2(ctrl_alt, CommandKey::Ctrl) == COMMAND | ALT
+        );
+        assert!(KeyMod::from_sdl2(ctrl_alt, CommandKey::Cmd) == ALT);
+        assert!(KeyMod::from_sdl2(Mod::LGUIMOD, CommandKey::Cmd) == COMMAND);
+        assert!(KeyMod::from_sdl2(Mod::LALTMOD, CommandKey::Alt) == COMMAND);
+        assert!(KeyMod::from_sdl2(Mod::LCTRLMOD, CommandKey::Alt) == ALT);
+        assert_eq!(CommandKey::from_name("alt"), Some(CommandKey::Alt));
+        assert_eq!(CommandKey::from_name("meta"), None);
+    }
+}
+
+// ========================================================================= //
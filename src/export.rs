@@ -0,0 +1,425 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::canvas::Font;
+use super::png::RgbaImage;
+use super::tilegrid::{Flip, SubGrid, TileGrid, Tileset};
+use super::writer::Contents;
+use sdl2::rect::{Point, Rect};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+//===========================================================================//
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExportFormat {
+    /// A PNG image of the whole map, drawn over the background color.
+    Png,
+    /// A little-endian binary dump: a u16 width and height, followed by one
+    /// u16 tile ID per cell in row-major order (see `tile_ids`), taken from
+    /// the map's layers flattened into one (see `TileGrid::flattened`).  The
+    /// top two bits of a cell's ID are set if its tile is mirrored
+    /// horizontally or vertically, respectively.
+    Raw,
+    /// An animated PNG of the whole map, cycling through the tile
+    /// animations defined in the tileset's `.anim` sidecars.
+    Apng,
+    /// A map for the Tiled editor, with one tileset per tile file and one
+    /// layer per map layer.  Each tileset refers to a `<filename>.png` strip
+    /// of its tiles, written alongside the map (see `image_files`).
+    /// Mirrored cells use Tiled's flip flags.
+    Tmx,
+}
+
+impl ExportFormat {
+    pub fn parse(name: &str) -> Option<ExportFormat> {
+        match name.to_lowercase().as_str() {
+            "png" => Some(ExportFormat::Png),
+            "apng" => Some(ExportFormat::Apng),
+            "raw" => Some(ExportFormat::Raw),
+            "tmx" => Some(ExportFormat::Tmx),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Apng => "apng",
+            ExportFormat::Raw => "raw",
+            ExportFormat::Tmx => "tmx",
+        }
+    }
+
+    pub fn export<W: io::Write>(
+        self,
+        tilegrid: &TileGrid,
+        mut writer: W,
+    ) -> io::Result<()> {
+        writer.write_all(&self.prepare(tilegrid)?()?)
+    }
+
+    /// Does the part of exporting the map that needs the map itself (e.g.
+    /// rendering it), and returns the rest (e.g. compressing the rendered
+    /// image), which can then be run on another thread.
+    pub fn prepare(self, tilegrid: &TileGrid) -> io::Result<Contents> {
+        match self {
+            ExportFormat::Png => Ok(png_contents(render_map(tilegrid, 0))),
+            ExportFormat::Apng => {
+                let (frames, step_millis) = render_animation(tilegrid);
+                Ok(Box::new(move || {
+                    let mut data = Vec::new();
+                    RgbaImage::write_apng(&frames, step_millis, &mut data)?;
+                    Ok(data)
+                }))
+            }
+            ExportFormat::Raw | ExportFormat::Tmx => {
+                let mut data = Vec::new();
+                if self == ExportFormat::Raw {
+                    export_raw(tilegrid, &mut data)?;
+                } else {
+                    export_tmx(tilegrid, &mut data)?;
+                }
+                Ok(Box::new(move || Ok(data)))
+            }
+        }
+    }
+
+    /// Returns the other files (as paths and contents) that an export to
+    /// the given path refers to, which must be written along with it: for
+    /// TMX, a PNG strip of each tile file's tiles, in the same directory.
+    pub fn image_files(
+        self,
+        tilegrid: &TileGrid,
+        path: &Path,
+    ) -> Vec<(PathBuf, Contents)> {
+        let mut files = Vec::new();
+        if self == ExportFormat::Tmx {
+            let dir = path.parent().unwrap_or_else(|| Path::new(""));
+            let tileset = tilegrid.tileset();
+            for (file_index, filename) in tileset.filenames().enumerate() {
+                let strip = tile_strip(&tileset, file_index);
+                let path = dir.join(format!("{}.png", filename));
+                files.push((path, png_contents(strip)));
+            }
+        }
+        files
+    }
+}
+
+// The bits set in an exported tile ID for a mirrored cell:
+const RAW_FLIP_HORZ: u16 = 0x8000;
+const RAW_FLIP_VERT: u16 = 0x4000;
+const TMX_FLIP_HORZ: u32 = 0x8000_0000;
+const TMX_FLIP_VERT: u32 = 0x4000_0000;
+
+// Animated exports are cut off after this long, in case the animations'
+// cycle lengths have no small common multiple.
+const MAX_APNG_MILLIS: u32 = 10_000;
+
+const SHEET_COLUMNS: usize = 8;
+const SHEET_MARGIN: u32 = 4;
+const SHEET_LABEL_HEIGHT: u32 = 12;
+const SHEET_BACKGROUND_COLOR: (u8, u8, u8, u8) = (255, 255, 255, 255);
+
+/// Writes a PNG contact sheet of every tile in the tileset, with each tile
+/// drawn over the given background color and labeled with its name.  Each
+/// tile file starts a new row.
+pub fn export_contact_sheet<W: io::Write>(
+    tileset: &Tileset,
+    background_color: (u8, u8, u8, u8),
+    font: &Font,
+    writer: W,
+) -> io::Result<()> {
+    let tile_size = tileset.tile_size();
+    let mut cell_width = tile_size;
+    let mut num_rows = 0;
+    for (file_index, _) in tileset.filenames().enumerate() {
+        let tiles: Vec<_> = tileset.tiles(file_index).collect();
+        for tile in tiles.iter() {
+            let label_width = font.text_width(&tile.name()).max(0) as u32;
+            cell_width = cell_width.max(label_width);
+        }
+        num_rows += tiles.len().div_ceil(SHEET_COLUMNS).max(1) as u32;
+    }
+    let cell_width = cell_width + SHEET_MARGIN;
+    let cell_height = tile_size + SHEET_LABEL_HEIGHT + SHEET_MARGIN;
+    let mut image = RgbaImage::new(
+        SHEET_COLUMNS as u32 * cell_width + SHEET_MARGIN,
+        num_rows * cell_height + SHEET_MARGIN,
+    );
+    let rect = Rect::new(0, 0, image.width(), image.height());
+    image.fill_rect(SHEET_BACKGROUND_COLOR, rect);
+    let mut row = 0;
+    for (file_index, _) in tileset.filenames().enumerate() {
+        let tiles: Vec<_> = tileset.tiles(file_index).collect();
+        for (index, tile) in tiles.iter().enumerate() {
+            let col = (index % SHEET_COLUMNS) as u32;
+            let left = (SHEET_MARGIN + col * cell_width) as i32;
+            let top = (SHEET_MARGIN
+                + (row + (index / SHEET_COLUMNS) as u32) * cell_height)
+                as i32;
+            let rect = Rect::new(left, top, tile_size, tile_size);
+            image.fill_rect(background_color, rect);
+            image.draw_sprite(tile.sprite(), left, top);
+            let baseline = top + (tile_size + SHEET_LABEL_HEIGHT) as i32 - 2;
+            image.draw_text(font, Point::new(left, baseline), &tile.name());
+        }
+        row += tiles.len().div_ceil(SHEET_COLUMNS).max(1) as u32;
+    }
+    image.write_png(writer)
+}
+
+//===========================================================================//
+
+/// Returns the first tile ID for each tile file.  Tile IDs are numbered
+/// consecutively across all of the map's tile files, starting from 1, so
+/// that 0 can represent an empty cell.
+fn first_tile_ids(tilegrid: &TileGrid) -> BTreeMap<String, usize> {
+    let tileset = tilegrid.tileset();
+    let mut first_ids = BTreeMap::new();
+    let mut next_id = 1;
+    for (file_index, filename) in tileset.filenames().enumerate() {
+        first_ids.insert(filename, next_id);
+        next_id += tileset.tiles(file_index).count();
+    }
+    first_ids
+}
+
+/// Returns the tile ID of each cell of the given layer of the map in
+/// row-major order, along with how the cell's tile is mirrored.
+fn tile_ids(tilegrid: &TileGrid, layer: &SubGrid) -> Vec<(usize, Flip)> {
+    let first_ids = first_tile_ids(tilegrid);
+    let mut ids = Vec::new();
+    for row in 0..layer.height() {
+        for col in 0..layer.width() {
+            ids.push(match layer[(col, row)] {
+                Some(ref tile) => {
+                    (first_ids[tile.filename()] + tile.index(), tile.flip())
+                }
+                None => (0, Flip::default()),
+            });
+        }
+    }
+    ids
+}
+
+/// Draws the tiles of the given tile file side by side, as a strip for a
+/// TMX tileset image.
+fn tile_strip(tileset: &Tileset, file_index: usize) -> RgbaImage {
+    let tile_size = tileset.tile_size();
+    let count = tileset.tiles(file_index).count() as u32;
+    let mut image = RgbaImage::new(count.max(1) * tile_size, tile_size);
+    for (index, tile) in tileset.tiles(file_index).enumerate() {
+        image.draw_sprite(tile.sprite(), index as i32 * tile_size as i32, 0);
+    }
+    image
+}
+
+/// Escapes the given text for use in an XML attribute value.
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+fn png_contents(image: RgbaImage) -> Contents {
+    Box::new(move || {
+        let mut data = Vec::new();
+        image.write_png(&mut data)?;
+        Ok(data)
+    })
+}
+
+/// Renders the frames of the map's tile animations, returning them along
+/// with how long to show each frame for, in milliseconds.
+fn render_animation(tilegrid: &TileGrid) -> (Vec<RgbaImage>, u16) {
+    let tileset = tilegrid.tileset();
+    let animations = tileset.animations();
+    let step_millis = animations
+        .iter()
+        .map(|animation| animation.frame_millis())
+        .fold(0, gcd)
+        .clamp(1, u16::MAX as u32);
+    let total_millis = animations
+        .iter()
+        .map(|animation| animation.cycle_millis())
+        .fold(step_millis, |a, b| (a / gcd(a, b)).saturating_mul(b))
+        .min(MAX_APNG_MILLIS);
+    let frames: Vec<RgbaImage> = (0..(total_millis / step_millis).max(1))
+        .map(|index| render_map(tilegrid, (index * step_millis) as u64))
+        .collect();
+    (frames, step_millis as u16)
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Renders the map with its tile animations advanced by `millis`
+/// milliseconds.
+fn render_map(tilegrid: &TileGrid, millis: u64) -> RgbaImage {
+    let tileset = tilegrid.tileset();
+    let tile_size = tilegrid.tile_size();
+    let mut image = RgbaImage::new(
+        tilegrid.width() * tile_size,
+        tilegrid.height() * tile_size,
+    );
+    for y in 0..image.height() {
+        let rect = Rect::new(0, y as i32, image.width(), 1);
+        image.fill_rect(tilegrid.background_color_at(y), rect);
+    }
+    if let Some(backdrop) = tilegrid.backdrop() {
+        if let Some(sprite) = backdrop.sprite() {
+            let offset = backdrop.offset();
+            image.draw_sprite(sprite, offset.x(), offset.y());
+        }
+    }
+    for index in 0..tilegrid.num_layers() {
+        let layer = tilegrid.layer(index);
+        for row in 0..tilegrid.height() {
+            for col in 0..tilegrid.width() {
+                if let Some(ref tile) = layer[(col, row)] {
+                    let left = (col * tile_size) as i32;
+                    let top = (row * tile_size) as i32;
+                    let frame = tileset.animation_frame(tile, millis);
+                    let flip = frame.flip();
+                    image.draw_sprite_flipped(
+                        frame.sprite(),
+                        left,
+                        top,
+                        flip.horz,
+                        flip.vert,
+                    );
+                }
+            }
+        }
+    }
+    image
+}
+
+fn export_raw<W: io::Write>(
+    tilegrid: &TileGrid,
+    mut writer: W,
+) -> io::Result<()> {
+    writer.write_all(&(tilegrid.width() as u16).to_le_bytes())?;
+    writer.write_all(&(tilegrid.height() as u16).to_le_bytes())?;
+    for (id, flip) in tile_ids(tilegrid, &tilegrid.flattened()) {
+        let mut id = id as u16;
+        if flip.horz {
+            id |= RAW_FLIP_HORZ;
+        }
+        if flip.vert {
+            id |= RAW_FLIP_VERT;
+        }
+        writer.write_all(&id.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn export_tmx<W: io::Write>(
+    tilegrid: &TileGrid,
+    mut writer: W,
+) -> io::Result<()> {
+    let (width, height) = tilegrid.size();
+    let tile_size = tilegrid.tile_size();
+    let (red, green, blue, _) = tilegrid.background_color();
+    writeln!(writer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        writer,
+        "<map version=\"1.0\" orientation=\"orthogonal\" \
+         renderorder=\"right-down\" width=\"{}\" height=\"{}\" \
+         tilewidth=\"{}\" tileheight=\"{}\" \
+         backgroundcolor=\"#{:02x}{:02x}{:02x}\">",
+        width, height, tile_size, tile_size, red, green, blue
+    )?;
+    let tileset = tilegrid.tileset();
+    let first_ids = first_tile_ids(tilegrid);
+    for (file_index, filename) in tileset.filenames().enumerate() {
+        let count = tileset.tiles(file_index).count();
+        writeln!(
+            writer,
+            " <tileset firstgid=\"{}\" name=\"{}\" tilewidth=\"{}\" \
+             tileheight=\"{}\" tilecount=\"{}\" columns=\"{}\">",
+            first_ids[&filename],
+            xml_escape(&filename),
+            tile_size,
+            tile_size,
+            count,
+            count
+        )?;
+        writeln!(
+            writer,
+            "  <image source=\"{}.png\" width=\"{}\" height=\"{}\"/>",
+            xml_escape(&filename),
+            count.max(1) as u32 * tile_size,
+            tile_size
+        )?;
+        writeln!(writer, " </tileset>")?;
+    }
+    for index in 0..tilegrid.num_layers() {
+        writeln!(
+            writer,
+            " <layer id=\"{}\" name=\"{}\" width=\"{}\" height=\"{}\">",
+            index + 1,
+            xml_escape(tilegrid.layer_name(index)),
+            width,
+            height
+        )?;
+        writeln!(writer, "  <data encoding=\"csv\">")?;
+        let ids: Vec<u32> = tile_ids(tilegrid, tilegrid.layer(index))
+            .into_iter()
+            .map(|(id, flip)| {
+                let mut id = id as u32;
+                if flip.horz {
+                    id |= TMX_FLIP_HORZ;
+                }
+                if flip.vert {
+                    id |= TMX_FLIP_VERT;
+                }
+                id
+            })
+            .collect();
+        for (row, line) in ids.chunks(width.max(1) as usize).enumerate() {
+            let line: Vec<String> = line.iter().map(u32::to_string).collect();
+            let last = row + 1 == height as usize;
+            let comma = if last { "" } else { "," };
+            writeln!(writer, "{}{}", line.join(","), comma)?;
+        }
+        writeln!(writer, "  </data>")?;
+        writeln!(writer, " </layer>")?;
+    }
+    writeln!(writer, "</map>")
+}
+
+//===========================================================================//
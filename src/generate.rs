@@ -0,0 +1,383 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::canvas::Sprite;
+use super::random::Rng;
+use super::tilegrid::{tile_token, SubGrid, Tile, Tileset};
+use sdl2::rect::Point;
+use std::rc::Rc;
+
+//===========================================================================//
+
+/// A set of tiles (or empty cells) to choose among at random, each with a
+/// relative weight, for the paint bucket's random fill.
+pub struct WeightedTiles {
+    tiles: Vec<(Option<Tile>, u32)>,
+    total: u32,
+}
+
+impl WeightedTiles {
+    /// Parses a comma-separated list of `TILE*WEIGHT` entries, where the
+    /// weight defaults to 1 (e.g. `grass:0*8, grass:5`).  At least one
+    /// weight must be nonzero.
+    pub fn parse(text: &str, tileset: &Tileset) -> Option<WeightedTiles> {
+        let mut tiles = Vec::new();
+        for entry in text.split(',') {
+            let (token, weight) = match entry.split_once('*') {
+                Some((token, weight)) => {
+                    (token.trim(), weight.trim().parse::<u32>().ok()?)
+                }
+                None => (entry.trim(), 1),
+            };
+            tiles.push((tileset.parse_tile(token)?, weight));
+        }
+        let total = tiles.iter().map(|&(_, weight)| weight).sum();
+        if total == 0 {
+            return None;
+        }
+        Some(WeightedTiles { tiles, total })
+    }
+
+    /// Returns the entries in the form accepted by `parse`.
+    pub fn to_text(&self) -> String {
+        let entries: Vec<String> = self
+            .tiles
+            .iter()
+            .map(|&(ref tile, weight)| {
+                format!("{}*{}", tile_token(tile), weight)
+            })
+            .collect();
+        entries.join(", ")
+    }
+
+    pub fn num_tiles(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Picks one of the tiles at random, according to the weights.
+    pub fn choose(&self, rng: &mut Rng) -> Option<Tile> {
+        let mut roll = rng.below(self.total);
+        for &(ref tile, weight) in self.tiles.iter() {
+            if roll < weight {
+                return tile.clone();
+            }
+            roll -= weight;
+        }
+        unreachable!()
+    }
+
+    /// Updates the tiles from the named tile file to use the given images
+    /// (see `Tile::with_sprites`).
+    pub fn replace_sprites(&mut self, filename: &str, sprites: &[Rc<Sprite>]) {
+        for &mut (ref mut cell, _) in self.tiles.iter_mut() {
+            let replacement = match *cell {
+                Some(ref tile) => tile.with_sprites(filename, sprites),
+                None => None,
+            };
+            if let Some(replacement) = replacement {
+                *cell = replacement;
+            }
+        }
+    }
+}
+
+//===========================================================================//
+
+/// Fills an area with tiles chosen by thresholding two octaves of value
+/// noise, which gives organic-looking blobs of each tile.
+pub struct NoiseFill {
+    scale: u32,
+    tiles: Vec<Option<Tile>>,
+    thresholds: Vec<u32>,
+}
+
+impl NoiseFill {
+    /// Parses a noise fill spec of the form `SCALE TILE [PERCENT TILE]...`,
+    /// where `SCALE` is the approximate feature size in cells, and each
+    /// `PERCENT` (0-100) is the noise level above which the following tile is
+    /// used instead of the preceding one.
+    pub fn parse(text: &str, tileset: &Tileset) -> Option<NoiseFill> {
+        let pieces: Vec<&str> = text.split_whitespace().collect();
+        if pieces.len() < 2 {
+            return None;
+        }
+        let scale = pieces[0].parse::<u32>().ok().filter(|&s| s > 0)?;
+        let mut tiles = vec![tileset.parse_tile(pieces[1])?];
+        let mut thresholds = Vec::new();
+        for pair in pieces[2..].chunks(2) {
+            if pair.len() != 2 {
+                return None;
+            }
+            let threshold =
+                pair[0].parse::<u32>().ok().filter(|&t| t <= 100)?;
+            if thresholds.last().cloned().unwrap_or(0) > threshold {
+                return None;
+            }
+            thresholds.push(threshold);
+            tiles.push(tileset.parse_tile(pair[1])?);
+        }
+        Some(NoiseFill { scale, tiles, thresholds })
+    }
+
+    /// Generates a subgrid of the given size, sampling the noise field as
+    /// though the subgrid were positioned at `topleft` in the grid.
+    pub fn generate(
+        &self,
+        width: u32,
+        height: u32,
+        topleft: Point,
+        rng: &mut Rng,
+    ) -> SubGrid {
+        let seed = (rng.next_u32() as u64) << 32 | rng.next_u32() as u64;
+        let scale = self.scale as f64;
+        let mut subgrid = SubGrid::new(width, height);
+        for row in 0..height {
+            for col in 0..width {
+                let x = (topleft.x() as f64 + col as f64 + 0.5) / scale;
+                let y = (topleft.y() as f64 + row as f64 + 0.5) / scale;
+                let value = (value_noise(seed, x, y)
+                    + 0.5 * value_noise(!seed, 2.0 * x, 2.0 * y))
+                    / 1.5;
+                let percent = (value * 100.0) as u32;
+                let band = self
+                    .thresholds
+                    .iter()
+                    .take_while(|&&threshold| percent >= threshold)
+                    .count();
+                subgrid.set((col, row), self.tiles[band].clone());
+            }
+        }
+        subgrid
+    }
+}
+
+/// Returns a smoothly-interpolated noise value in the range [0, 1).
+fn value_noise(seed: u64, x: f64, y: f64) -> f64 {
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let fx = smoothstep(x - x0);
+    let fy = smoothstep(y - y0);
+    let (x0, y0) = (x0 as i32, y0 as i32);
+    let top = lerp(lattice(seed, x0, y0), lattice(seed, x0 + 1, y0), fx);
+    let bottom =
+        lerp(lattice(seed, x0, y0 + 1), lattice(seed, x0 + 1, y0 + 1), fx);
+    lerp(top, bottom, fy)
+}
+
+fn lattice(seed: u64, x: i32, y: i32) -> f64 {
+    let coords = ((x as u32 as u64) << 32) | (y as u32 as u64);
+    Rng::new(seed ^ coords).next_u32() as f64 / 4294967296.0
+}
+
+fn smoothstep(t: f64) -> f64 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+//===========================================================================//
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Layout {
+    Maze,
+    Rooms,
+}
+
+/// Generates a test level out of wall and floor tiles, either as a maze or
+/// as a set of rooms connected by corridors.
+pub struct LevelGenerator {
+    layout: Layout,
+    wall: Option<Tile>,
+    floor: Option<Tile>,
+}
+
+// These limits are currently arbitrary:
+const MIN_ROOM_SIZE: u32 = 3;
+const MAX_ROOM_SIZE: u32 = 8;
+const ROOM_PLACEMENT_ATTEMPTS: u32 = 40;
+
+impl LevelGenerator {
+    /// Parses a generator spec of the form `maze WALL FLOOR` or
+    /// `rooms WALL FLOOR`.
+    pub fn parse(text: &str, tileset: &Tileset) -> Option<LevelGenerator> {
+        let pieces: Vec<&str> = text.split_whitespace().collect();
+        if pieces.len() != 3 {
+            return None;
+        }
+        let layout = match pieces[0] {
+            "maze" => Layout::Maze,
+            "rooms" => Layout::Rooms,
+            _ => return None,
+        };
+        let wall = tileset.parse_tile(pieces[1])?;
+        let floor = tileset.parse_tile(pieces[2])?;
+        Some(LevelGenerator { layout, wall, floor })
+    }
+
+    pub fn generate(&self, width: u32, height: u32, rng: &mut Rng) -> SubGrid {
+        let mut subgrid = SubGrid::new(width, height);
+        for row in 0..height {
+            for col in 0..width {
+                subgrid.set((col, row), self.wall.clone());
+            }
+        }
+        match self.layout {
+            Layout::Maze => self.carve_maze(&mut subgrid, rng),
+            Layout::Rooms => self.carve_rooms(&mut subgrid, rng),
+        }
+        subgrid
+    }
+
+    /// Carves a perfect maze using a randomized depth-first search.  Maze
+    /// cells sit at odd coordinates, with walls between them.
+    fn carve_maze(&self, subgrid: &mut SubGrid, rng: &mut Rng) {
+        let (width, height) = subgrid.size();
+        if width < 3 || height < 3 {
+            return;
+        }
+        let cols = (width - 1) / 2;
+        let rows = (height - 1) / 2;
+        let mut visited = vec![false; (cols * rows) as usize];
+        let mut stack = vec![(0u32, 0u32)];
+        visited[0] = true;
+        subgrid.set((1, 1), self.floor.clone());
+        while let Some(&(col, row)) = stack.last() {
+            let mut unvisited = Vec::with_capacity(4);
+            if col > 0 {
+                unvisited.push((col - 1, row));
+            }
+            if col + 1 < cols {
+                unvisited.push((col + 1, row));
+            }
+            if row > 0 {
+                unvisited.push((col, row - 1));
+            }
+            if row + 1 < rows {
+                unvisited.push((col, row + 1));
+            }
+            unvisited.retain(|&(c, r)| !visited[(r * cols + c) as usize]);
+            if unvisited.is_empty() {
+                stack.pop();
+                continue;
+            }
+            let index = rng.below(unvisited.len() as u32) as usize;
+            let (next_col, next_row) = unvisited[index];
+            visited[(next_row * cols + next_col) as usize] = true;
+            subgrid.set(
+                (col + next_col + 1, row + next_row + 1),
+                self.floor.clone(),
+            );
+            subgrid
+                .set((2 * next_col + 1, 2 * next_row + 1), self.floor.clone());
+            stack.push((next_col, next_row));
+        }
+    }
+
+    /// Carves randomly-placed, non-overlapping rooms, each connected to the
+    /// previous one by an L-shaped corridor.
+    fn carve_rooms(&self, subgrid: &mut SubGrid, rng: &mut Rng) {
+        let (width, height) = subgrid.size();
+        if width < MIN_ROOM_SIZE + 2 || height < MIN_ROOM_SIZE + 2 {
+            return;
+        }
+        let mut rooms: Vec<(u32, u32, u32, u32)> = Vec::new();
+        for _ in 0..ROOM_PLACEMENT_ATTEMPTS {
+            let max_width = MAX_ROOM_SIZE.min(width - 2);
+            let max_height = MAX_ROOM_SIZE.min(height - 2);
+            let room_width =
+                MIN_ROOM_SIZE + rng.below(max_width - MIN_ROOM_SIZE + 1);
+            let room_height =
+                MIN_ROOM_SIZE + rng.below(max_height - MIN_ROOM_SIZE + 1);
+            let left = 1 + rng.below(width - room_width - 1);
+            let top = 1 + rng.below(height - room_height - 1);
+            // Keep at least one wall cell between any two rooms.
+            let overlaps = rooms.iter().any(|&(l, t, w, h)| {
+                left <= l + w
+                    && l <= left + room_width
+                    && top <= t + h
+                    && t <= top + room_height
+            });
+            if overlaps {
+                continue;
+            }
+            for row in top..(top + room_height) {
+                for col in left..(left + room_width) {
+                    subgrid.set((col, row), self.floor.clone());
+                }
+            }
+            if let Some(&(l, t, w, h)) = rooms.last() {
+                let (from_col, from_row) = (l + w / 2, t + h / 2);
+                let (to_col, to_row) =
+                    (left + room_width / 2, top + room_height / 2);
+                for col in from_col.min(to_col)..=from_col.max(to_col) {
+                    subgrid.set((col, from_row), self.floor.clone());
+                }
+                for row in from_row.min(to_row)..=from_row.max(to_row) {
+                    subgrid.set((to_col, row), self.floor.clone());
+                }
+            }
+            rooms.push((left, top, room_width, room_height));
+        }
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::NoiseFill;
+    use crate::random::Rng;
+    use crate::tilegrid::{Tile, Tileset};
+    use sdl2::rect::Point;
+
+    #[test]
+    fn parse_invalid_noise_fill() {
+        let tileset = Tileset::empty();
+        assert!(NoiseFill::parse("6 - 50 -", &tileset).is_some());
+        assert!(NoiseFill::parse("0 - 50 -", &tileset).is_none());
+        assert!(NoiseFill::parse("big - 50 -", &tileset).is_none());
+        assert!(NoiseFill::parse("6 - 60 - 40 -", &tileset).is_none());
+        assert!(NoiseFill::parse("6 - 101 -", &tileset).is_none());
+        assert!(NoiseFill::parse("6 - 50", &tileset).is_none());
+        assert!(NoiseFill::parse("6", &tileset).is_none());
+    }
+
+    #[test]
+    fn noise_fill_is_deterministic() {
+        let noise = NoiseFill {
+            scale: 4,
+            tiles: vec![None, Some(Tile::blank("grass", 0))],
+            thresholds: vec![50],
+        };
+        let topleft = Point::new(3, 5);
+        let first = noise.generate(16, 16, topleft, &mut Rng::new(1234));
+        let second = noise.generate(16, 16, topleft, &mut Rng::new(1234));
+        let empty = first.count_empty_cells();
+        assert!(empty > 0 && empty < 16 * 16);
+        for row in 0..16 {
+            for col in 0..16 {
+                assert!(first[(col, row)] == second[(col, row)]);
+            }
+        }
+    }
+}
+
+//===========================================================================//
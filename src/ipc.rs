@@ -0,0 +1,152 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::state::EditorState;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+//===========================================================================//
+
+/// A simple line-based protocol for letting a running game hot-reload the
+/// map as it's edited.  Whenever the map is saved, each connected client is
+/// sent a line `SAVED <checksum> <path>` (with the checksum, in hex, of the
+/// data written to the file).  A client
+/// may also send the line `GET`, to which the server replies with a line
+/// `GRID <length>` followed by `length` bytes of the map in .bg format.
+pub struct IpcServer {
+    listener: TcpListener,
+    clients: Vec<IpcClient>,
+    num_saves: u64,
+}
+
+struct IpcClient {
+    stream: TcpStream,
+    input: Vec<u8>,
+    // Data waiting to be sent, once the client is ready to receive it:
+    output: Vec<u8>,
+}
+
+impl IpcServer {
+    pub fn bind(address: &str) -> io::Result<IpcServer> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(IpcServer { listener, clients: Vec::new(), num_saves: 0 })
+    }
+
+    /// Accepts any new connections, answers any pending requests, and
+    /// notifies clients if the map has been saved since the last poll.  This
+    /// should be called regularly from the main loop.
+    pub fn poll(&mut self, state: &EditorState) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.clients.push(IpcClient {
+                    stream,
+                    input: Vec::new(),
+                    output: Vec::new(),
+                });
+            }
+        }
+        let mut notification = None;
+        if state.num_saves() != self.num_saves {
+            self.num_saves = state.num_saves();
+            if let Some((path, checksum)) = state.last_save() {
+                notification =
+                    Some(format!("SAVED {:016x} {}\n", checksum, path));
+            }
+        }
+        self.clients.retain_mut(|client| {
+            let result = client.poll(state, notification.as_deref());
+            result.is_ok()
+        });
+    }
+}
+
+impl IpcClient {
+    /// Handles any pending requests from this client, and sends it the
+    /// given notification, if any.  This never blocks; any output the client
+    /// isn't ready for yet is sent on later polls.  Returns an error if the
+    /// client should be disconnected.
+    fn poll(
+        &mut self,
+        state: &EditorState,
+        notification: Option<&str>,
+    ) -> io::Result<()> {
+        let mut buffer = [0u8; 256];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    let msg = "client disconnected";
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        msg,
+                    ));
+                }
+                Ok(size) => self.input.extend_from_slice(&buffer[..size]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        if let Some(notification) = notification {
+            self.output.extend_from_slice(notification.as_bytes());
+        }
+        while let Some(newline) = self.input.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.input.drain(..=newline).collect();
+            match String::from_utf8_lossy(&line).trim() {
+                "GET" => {
+                    let data = serialize(state);
+                    writeln!(self.output, "GRID {}", data.len())?;
+                    self.output.extend_from_slice(&data);
+                }
+                "" => {}
+                other => writeln!(self.output, "ERROR unknown: {}", other)?,
+            }
+        }
+        self.flush()
+    }
+
+    /// Sends as much of the pending output as the client is ready for.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => {
+                    let msg = "client stopped receiving";
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, msg));
+                }
+                Ok(size) => {
+                    self.output.drain(..size);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn serialize(state: &EditorState) -> Vec<u8> {
+    let mut data = Vec::new();
+    state.tilegrid().save(&mut data).unwrap();
+    data
+}
+
+//===========================================================================//
@@ -0,0 +1,119 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::canvas::{Canvas, Font};
+use super::element::{Action, GuiElement};
+use super::event::Event;
+use super::state::EditorState;
+use sdl2::rect::{Point, Rect};
+use std::rc::Rc;
+
+// ========================================================================= //
+
+const ROW_HEIGHT: i32 = 12;
+const ROW_WIDTH: u32 = 60;
+const ACTIVE_ROW_COLOR: (u8, u8, u8, u8) = (96, 96, 96, 255);
+const VISIBILITY_BOX_SIZE: u32 = 8;
+const VISIBILITY_BOX_COLOR: (u8, u8, u8, u8) = (255, 255, 255, 255);
+
+// ========================================================================= //
+
+/// Lists the map's layers, top layer first.  Clicking a layer's name makes
+/// it the active layer, and clicking the box beside it shows or hides it.
+pub struct LayerSelector {
+    topleft: Point,
+    font: Rc<Font>,
+}
+
+impl LayerSelector {
+    pub fn new(left: i32, top: i32, font: Rc<Font>) -> LayerSelector {
+        LayerSelector { topleft: Point::new(left, top), font }
+    }
+
+    /// Returns the rect of the row for the given layer.
+    fn row_rect(&self, state: &EditorState, index: usize) -> Rect {
+        let num_layers = state.tilegrid().num_layers();
+        let row = (num_layers - 1 - index) as i32;
+        Rect::new(
+            self.topleft.x(),
+            self.topleft.y() + row * ROW_HEIGHT,
+            ROW_WIDTH,
+            ROW_HEIGHT as u32,
+        )
+    }
+
+    fn visibility_box(&self, row_rect: Rect) -> Rect {
+        Rect::new(
+            row_rect.x() + 1,
+            row_rect.y() + 2,
+            VISIBILITY_BOX_SIZE,
+            VISIBILITY_BOX_SIZE,
+        )
+    }
+}
+
+impl GuiElement<EditorState, ()> for LayerSelector {
+    fn draw(&self, state: &EditorState, canvas: &mut Canvas) {
+        let tilegrid = state.tilegrid();
+        for index in 0..tilegrid.num_layers() {
+            let name = tilegrid.layer_name(index);
+            let rect = self.row_rect(state, index);
+            if index == tilegrid.active_layer() {
+                canvas.fill_rect(ACTIVE_ROW_COLOR, rect);
+            }
+            let visibility_box = self.visibility_box(rect);
+            if state.is_layer_visible(name) {
+                canvas.fill_rect(VISIBILITY_BOX_COLOR, visibility_box);
+            } else {
+                canvas.draw_rect(VISIBILITY_BOX_COLOR, visibility_box);
+            }
+            let start = rect.top_left() + Point::new(12, 9);
+            canvas.draw_text(&self.font, start, name);
+        }
+    }
+
+    fn on_event(
+        &mut self,
+        event: &Event,
+        state: &mut EditorState,
+    ) -> Action<()> {
+        match event {
+            &Event::MouseDown(pt, _) => {
+                for index in 0..state.tilegrid().num_layers() {
+                    let rect = self.row_rect(state, index);
+                    if !rect.contains_point(pt) {
+                        continue;
+                    }
+                    if self.visibility_box(rect).contains_point(pt) {
+                        let name = state.tilegrid().layer_name(index);
+                        let name = name.to_string();
+                        state.toggle_layer_visible(&name);
+                        return Action::redraw().and_stop();
+                    }
+                    let changed = state.set_active_layer(index);
+                    return Action::redraw_if(changed).and_stop();
+                }
+                Action::ignore()
+            }
+            _ => Action::ignore(),
+        }
+    }
+}
+
+// ========================================================================= //
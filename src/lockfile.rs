@@ -0,0 +1,175 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::backup;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//===========================================================================//
+
+/// An advisory lock on a map file, so that two people editing the same map
+/// (e.g. on a shared drive) find out about each other.  The lock is a
+/// sidecar file next to the map (e.g. `level1.bg.lock`) saying who has the
+/// map open and since when.  It is removed when the lock is dropped, if it
+/// is still ours.
+pub struct FileLock {
+    lock_path: PathBuf,
+    // The contents of the lock file that mark it as ours:
+    owner: String,
+    held: bool,
+}
+
+impl FileLock {
+    /// Locks the map at the given path, unless another instance already has
+    /// it locked (in which case `other_holder` says who).
+    pub fn acquire(map_path: &Path) -> FileLock {
+        // The nanoseconds distinguish locks taken by the same process within
+        // the same second.
+        let now = SystemTime::now();
+        let nanos = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let owner = format!(
+            "{} {} {} {}",
+            user_and_host(),
+            process::id(),
+            backup::timestamp(now),
+            nanos.subsec_nanos()
+        );
+        let mut lock =
+            FileLock { lock_path: lock_path(map_path), owner, held: false };
+        lock.retry();
+        lock
+    }
+
+    /// Takes the lock if we don't hold it and nobody else does now.
+    pub fn retry(&mut self) {
+        if self.held {
+            return;
+        }
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.lock_path);
+        if let Ok(mut file) = file {
+            self.held = writeln!(file, "{}", self.owner).is_ok();
+        }
+    }
+
+    /// If someone else holds the lock, returns a description of who they
+    /// are and since when.
+    pub fn other_holder(&self) -> Option<String> {
+        let contents = fs::read_to_string(&self.lock_path).ok()?;
+        let contents = contents.trim();
+        if contents == self.owner {
+            return None;
+        }
+        Some(describe_owner(contents))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if self.held && self.other_holder().is_none() {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
+
+//===========================================================================//
+
+/// Returns the path of the lock file for the map at the given path.
+fn lock_path(map_path: &Path) -> PathBuf {
+    let mut name = map_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    map_path.with_file_name(name)
+}
+
+/// Returns "user@host" for whoever is running this instance, as best we can
+/// tell.
+fn user_and_host() -> String {
+    let user = env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_else(|_| "someone".to_string());
+    let host = env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty());
+    match host {
+        Some(host) => format!("{}@{}", user, host),
+        None => user,
+    }
+}
+
+/// Turns the contents of a lock file ("user@host pid timestamp nanos") into
+/// a description for the status bar.
+fn describe_owner(contents: &str) -> String {
+    let pieces: Vec<&str> = contents.split_whitespace().collect();
+    if pieces.len() == 4 {
+        format!("{} (pid {}) since {}", pieces[0], pieces[1], pieces[2])
+    } else {
+        format!("another editor ({:?})", contents)
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{describe_owner, lock_path, FileLock};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn lock_file_path() {
+        assert_eq!(
+            lock_path(Path::new("maps/level1.bg")),
+            Path::new("maps/level1.bg.lock")
+        );
+        assert_eq!(
+            describe_owner("pat@box 123 20200101-000000 42"),
+            "pat@box (pid 123) since 20200101-000000"
+        );
+    }
+
+    #[test]
+    fn second_lock_sees_first() {
+        let dir = std::env::temp_dir()
+            .join(format!("linoleum-lock-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let map_path = dir.join("map.bg");
+        let first = FileLock::acquire(&map_path);
+        assert!(first.held);
+        assert_eq!(first.other_holder(), None);
+        let second = FileLock::acquire(&map_path);
+        assert!(!second.held);
+        assert!(second.other_holder().is_some());
+        drop(second);
+        assert!(lock_path(&map_path).exists());
+        drop(first);
+        assert!(!lock_path(&map_path).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+//===========================================================================//
@@ -21,28 +21,55 @@ extern crate ahi;
 extern crate getopts;
 extern crate sdl2;
 
+mod anim;
+mod automap;
+mod backup;
 mod canvas;
+mod config;
 mod coords;
 mod editor;
 mod element;
 mod event;
+mod export;
+mod generate;
+mod ipc;
+mod layers;
+mod lockfile;
 mod paint;
 mod palette;
+mod pixels;
+mod png;
+mod random;
+mod regions;
+mod report;
 mod state;
+mod status;
+mod terrain;
 mod textbox;
+mod theme;
 mod tilegrid;
+mod tilesource;
 mod toolbox;
 mod unsaved;
 mod util;
+mod writer;
 
-use self::canvas::{Font, Sprite, Window};
+use self::canvas::{Font, Sprite, TextureCache, Window};
+use self::config::Config;
 use self::editor::EditorView;
 use self::element::GuiElement;
-use self::event::Event;
-use self::state::EditorState;
-use self::tilegrid::{TileGrid, Tileset};
+use self::event::{CommandKey, Event};
+use self::export::ExportFormat;
+use self::ipc::IpcServer;
+use self::palette::TilePalette;
+use self::state::{EditorState, PencilUndo, Tool};
+use self::theme::Theme;
+use self::tilegrid::{TileFit, TileGrid, TileSizeLock, Tileset};
 use ahi::Palette;
-use std::path::PathBuf;
+use sdl2::event::{Event as SdlEvent, WindowEvent};
+use sdl2::render::Canvas as SdlCanvas;
+use sdl2::video::Window as SdlWindow;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::time::Instant;
 
@@ -50,28 +77,51 @@ use std::time::Instant;
 
 const FRAME_DELAY_MILLIS: u32 = 100;
 
+const PALETTE_WINDOW_WIDTH: u32 = 160;
+
+// The name of the project settings file, in the tiles directory:
+const PROJECT_FILE_NAME: &str = "linoleum.conf";
+
 fn render_screen(window: &mut Window, state: &EditorState, gui: &EditorView) {
+    let unsaved = if state.is_unsaved() { "*" } else { "" };
+    window.set_title(&format!("{}{} - Linoleum", state.filepath(), unsaved));
     {
         let mut canvas = window.canvas();
-        canvas.clear((64, 64, 64, 255));
+        canvas.clear(gui.theme().background);
         gui.draw(state, &mut canvas);
     }
     window.present();
 }
 
+fn render_palette_window(
+    renderer: &mut SdlCanvas<SdlWindow>,
+    state: &EditorState,
+    textures: &mut TextureCache,
+    palette: &TilePalette,
+    theme: &Theme,
+) {
+    let mut window = Window::with_texture_cache(renderer, textures);
+    {
+        let mut canvas = window.canvas();
+        canvas.clear(theme.background);
+        palette.draw(state, &mut canvas);
+    }
+    window.present();
+}
+
 fn load_font(window: &Window, path: &str) -> Font {
     let ahf = util::load_ahf_from_file(&path.to_string()).unwrap();
     window.new_font(&ahf)
 }
 
 fn load_sprite(window: &Window, path: &str) -> Sprite {
-    let collection = util::load_ahi_from_file(&path.to_string()).unwrap();
+    let collection = util::load_ahi_from_file(path).unwrap();
     let palette = collection.palettes.first().unwrap_or(Palette::default());
     window.new_sprite(&collection.images[0], palette)
 }
 
 fn load_sprites(window: &Window, path: &str) -> Vec<Sprite> {
-    let collection = util::load_ahi_from_file(&path.to_string()).unwrap();
+    let collection = util::load_ahi_from_file(path).unwrap();
     let palette = collection.palettes.first().unwrap_or(Palette::default());
     collection
         .images
@@ -80,6 +130,33 @@ fn load_sprites(window: &Window, path: &str) -> Vec<Sprite> {
         .collect()
 }
 
+/// Reads the project settings file in the tiles directory, if any, for a
+/// `tile_size` to lock all tilesets to (and a `tile_fit` of overflow, crop,
+/// or letterbox, for how to fit tiles larger than that).
+fn tile_size_lock(tiles_dir: &Path) -> Option<TileSizeLock> {
+    let path = tiles_dir.join(PROJECT_FILE_NAME);
+    let project = Config::load(&path).unwrap_or_else(|err| {
+        println!("Error: failed to load {:?}: {}", path, err);
+        std::process::exit(1);
+    });
+    let size = project.get("tile_size")?;
+    let size = match size.parse::<u32>() {
+        Ok(size) if size > 0 => size,
+        _ => {
+            println!("Error: invalid tile_size in {:?}: {:?}", path, size);
+            std::process::exit(1);
+        }
+    };
+    let fit = match project.get("tile_fit") {
+        Some(name) => TileFit::from_name(name).unwrap_or_else(|| {
+            println!("Error: invalid tile_fit in {:?}: {:?}", path, name);
+            std::process::exit(1);
+        }),
+        None => TileFit::Overflow,
+    };
+    Some(TileSizeLock { size, fit })
+}
+
 //===========================================================================//
 
 fn main() {
@@ -87,19 +164,131 @@ fn main() {
     let mut opts = getopts::Options::new();
     opts.optflag("h", "help", "print this help menu");
     opts.optopt("", "tiles", "set tiles directory", "DIR");
+    opts.optopt(
+        "",
+        "alt-tiles",
+        "alternate tiles directory, to swap to with Cmd+Alt+L",
+        "DIR",
+    );
     opts.optopt("", "bg", "background file to open", "FILE");
+    opts.optopt("", "config", "set config file path", "FILE");
+    opts.optopt(
+        "",
+        "pencil-undo",
+        "pencil undo granularity: stroke (default), cell, or a number of \
+         seconds within which consecutive strokes are merged",
+        "MODE",
+    );
+    opts.optopt(
+        "",
+        "watch-export",
+        "on every save, also export the map as png, apng, raw, or tmx",
+        "FORMAT",
+    );
+    opts.optflag(
+        "",
+        "trim-on-save",
+        "save maps without their trailing empty rows and columns",
+    );
+    opts.optopt(
+        "",
+        "backups",
+        "on every save, also keep a timestamped backup of the map, keeping \
+         the last N backups of each map in a .linoleum-backups directory",
+        "N",
+    );
+    opts.optmulti(
+        "",
+        "rename-tiles",
+        "load maps as though their references to tile file OLD were to NEW, \
+         for tile files that have been renamed or moved",
+        "OLD=NEW",
+    );
+    opts.optopt(
+        "",
+        "ipc",
+        "listen for live-reload clients on a local TCP address",
+        "ADDR",
+    );
     let matches = opts.parse(&args[1..]).unwrap_or_else(|failure| {
         println!("Error: {:?}", failure);
         println!("Run with --help to see available flags.");
         std::process::exit(1);
     });
     if matches.opt_present("help") {
-        let brief = format!("Usage: {} [options]", &args[0]);
+        let brief = format!(
+            "Usage: {0} [options]\n       {0} [options] report FILE...",
+            &args[0]
+        );
         print!("{}", opts.usage(&brief));
         std::process::exit(0);
     }
     let tiles_dir =
         PathBuf::from(matches.opt_str("tiles").unwrap_or("tiles".to_string()));
+    let pencil_undo = match matches.opt_str("pencil-undo") {
+        Some(mode) => PencilUndo::parse(&mode).unwrap_or_else(|| {
+            println!("Error: invalid --pencil-undo mode: {:?}", mode);
+            std::process::exit(1);
+        }),
+        None => PencilUndo::Stroke,
+    };
+    if matches.free.first().map(String::as_str) == Some("report") {
+        if let Err(err) =
+            report::print_usage_report(&tiles_dir, &matches.free[1..])
+        {
+            println!("Error: {}", err);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+    let config_path = matches
+        .opt_str("config")
+        .map(PathBuf::from)
+        .or_else(Config::default_path);
+    let config = match config_path {
+        Some(ref path) => Config::load(path).unwrap_or_else(|err| {
+            println!("Error: failed to load {:?}: {}", path, err);
+            std::process::exit(1);
+        }),
+        None => Config::empty(),
+    };
+    let command_key = config
+        .get("keys.command")
+        .and_then(CommandKey::from_name)
+        .unwrap_or(CommandKey::platform_default());
+    // The session store lives next to the config file.  It's not worth
+    // refusing to start over a bad session file, so just start afresh.
+    let session = config_path
+        .map(|path| path.with_file_name("session"))
+        .and_then(|path| Config::load(&path).ok())
+        .unwrap_or_else(Config::empty);
+    let max_backups = match matches.opt_str("backups") {
+        Some(count) => count.parse::<usize>().unwrap_or_else(|_| {
+            println!("Error: invalid --backups count: {:?}", count);
+            std::process::exit(1);
+        }),
+        None => 0,
+    };
+    let tile_renames: Vec<(String, String)> = matches
+        .opt_strs("rename-tiles")
+        .into_iter()
+        .map(|rename| match rename.split_once('=') {
+            Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+                (old.to_string(), new.to_string())
+            }
+            _ => {
+                println!("Error: invalid --rename-tiles pair: {:?}", rename);
+                std::process::exit(1);
+            }
+        })
+        .collect();
+    let size_lock = tile_size_lock(&tiles_dir);
+    let watch_export = matches.opt_str("watch-export").map(|format| {
+        ExportFormat::parse(&format).unwrap_or_else(|| {
+            println!("Error: invalid --watch-export format: {:?}", format);
+            std::process::exit(1);
+        })
+    });
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -122,31 +311,112 @@ fn main() {
     let font: Rc<Font> = Rc::new(load_font(&window, "data/font.ahf"));
 
     let mut state = if let Some(path) = matches.opt_str("bg") {
-        match TileGrid::load_from_path(&window, &tiles_dir, &path) {
-            Ok(tilegrid) => EditorState::new(path, tilegrid),
+        match TileGrid::load_from_path(
+            &window,
+            &tiles_dir,
+            size_lock,
+            &tile_renames,
+            &path,
+        ) {
+            Ok(tilegrid) => {
+                let mut state = EditorState::new(path, tilegrid);
+                state.lock_map_file();
+                state
+            }
             Err(err) => {
                 println!("Failed to load bg: {:?}", err);
                 std::process::exit(0);
             }
         }
     } else {
-        let tileset =
-            Tileset::load(&window, &tiles_dir, &["green_pipes".to_string()])
-                .unwrap();
+        let tileset = Tileset::load(
+            &window,
+            &tiles_dir,
+            &["green_pipes".to_string()],
+            size_lock,
+        )
+        .unwrap();
         EditorState::new("out.bg".to_string(), TileGrid::new(tileset))
     };
 
-    let mut gui = EditorView::new(tool_icons, arrow_icons, unsaved_icon, font);
+    state.set_pencil_undo(pencil_undo);
+    state.set_watch_export(watch_export);
+    state.set_trim_on_save(matches.opt_present("trim-on-save"));
+    state.set_max_backups(max_backups);
+    state.set_alt_tiles_dir(matches.opt_str("alt-tiles").map(PathBuf::from));
+    state.set_tile_renames(tile_renames);
+
+    let mut ipc_server = matches.opt_str("ipc").map(|address| {
+        IpcServer::bind(&address).unwrap_or_else(|err| {
+            println!("Failed to listen on {}: {}", address, err);
+            std::process::exit(1);
+        })
+    });
+
+    let mut gui = EditorView::new(
+        tool_icons,
+        arrow_icons,
+        unsaved_icon,
+        font.clone(),
+        config,
+        session,
+    );
+    gui.restore_session(&mut state);
     render_screen(&mut window, &state, &gui);
 
     let mut event_pump = sdl_context.event_pump().unwrap();
+    let keyboard = sdl_context.keyboard();
     let mut last_clock_tick = Instant::now();
+    let mut palette_window: Option<(
+        SdlCanvas<SdlWindow>,
+        TextureCache,
+        TilePalette,
+    )> = None;
     loop {
+        if gui.palette_detached() != palette_window.is_some() {
+            if gui.palette_detached() {
+                let sdl_window = video_subsystem
+                    .window(
+                        "Palette",
+                        PALETTE_WINDOW_WIDTH * 2,
+                        window_height * 2,
+                    )
+                    .build()
+                    .unwrap();
+                let mut palette_renderer =
+                    sdl_window.into_canvas().build().unwrap();
+                palette_renderer
+                    .set_logical_size(PALETTE_WINDOW_WIDTH, window_height)
+                    .unwrap();
+                let palette = TilePalette::new(
+                    0,
+                    0,
+                    PALETTE_WINDOW_WIDTH,
+                    window_height,
+                    load_sprites(&window, "data/arrows.ahi"),
+                    font.clone(),
+                    gui.theme(),
+                );
+                let mut textures = TextureCache::default();
+                render_palette_window(
+                    &mut palette_renderer,
+                    &state,
+                    &mut textures,
+                    &palette,
+                    &gui.theme(),
+                );
+                palette_window = Some((palette_renderer, textures, palette));
+            } else {
+                palette_window = None;
+            }
+            render_screen(&mut window, &state, &gui);
+        }
         let now = Instant::now();
         let elapsed_millis = now
             .duration_since(last_clock_tick)
             .as_millis()
             .min(u32::MAX as u128) as u32;
+        let mut in_palette_window = false;
         let opt_sdl_event = if elapsed_millis >= FRAME_DELAY_MILLIS {
             None
         } else {
@@ -157,22 +427,111 @@ fn main() {
                 last_clock_tick = now;
                 Event::ClockTick
             }
-            Some(sdl_event) => match Event::from_sdl2(&sdl_event) {
-                Some(event) => event,
-                None => continue,
-            },
+            Some(sdl_event) => {
+                if let Some((ref renderer, _, _)) = palette_window {
+                    let id = renderer.window().id();
+                    if sdl_event.get_window_id() == Some(id) {
+                        in_palette_window = true;
+                        if let SdlEvent::Window {
+                            win_event: WindowEvent::Close,
+                            ..
+                        } = sdl_event
+                        {
+                            gui.set_palette_detached(false);
+                        }
+                    }
+                }
+                match Event::from_sdl2(
+                    &sdl_event,
+                    keyboard.mod_state(),
+                    command_key,
+                ) {
+                    Some(event) => event,
+                    None => continue,
+                }
+            }
         };
-        let mut action = match event {
-            Event::Quit => return,
-            event => gui.on_event(&event, &mut state),
+        let old_brush = state.brush().clone();
+        let old_tool = state.tool();
+        let is_tick = matches!(event, Event::ClockTick);
+        let mut action = match (event, palette_window.as_mut()) {
+            (Event::Quit, _) => {
+                gui.remember_session(&state);
+                return;
+            }
+            (event, Some((_, _, ref mut palette))) if in_palette_window => {
+                // Mouse events in the palette window only go to the
+                // palette, but key events fall through to the main view.
+                let mut action =
+                    palette.on_event(&event, &mut state).but_no_value();
+                let is_key = matches!(
+                    event,
+                    Event::KeyDown(..)
+                        | Event::KeyUp(..)
+                        | Event::TextInput(_)
+                );
+                if is_key && !action.should_stop() {
+                    action.merge(gui.on_event(&event, &mut state));
+                }
+                action
+            }
+            (event, _) => gui.on_event(&event, &mut state),
         };
+        if is_tick {
+            if let Some((_, _, ref mut palette)) = palette_window {
+                let subaction =
+                    palette.on_event(&Event::ClockTick, &mut state);
+                action.merge(subaction.but_no_value());
+            }
+        }
+        // When the brush changes (e.g. via the eyedropper), flip the palette
+        // to the page containing the new brush.  If the eyedropper picked it,
+        // also flash the picked entry.
+        if *state.brush() != old_brush {
+            let eyedropped = old_tool == Tool::Eyedropper
+                && state.tool() != Tool::Eyedropper;
+            if gui.show_brush_file(&state) {
+                action.also_redraw();
+            }
+            if eyedropped && gui.flash_brush(&state) {
+                action.also_redraw();
+            }
+            if let Some((_, _, ref mut palette)) = palette_window {
+                if palette.show_brush_file(&state) {
+                    action.also_redraw();
+                }
+                if eyedropped && palette.flash_brush(&state) {
+                    action.also_redraw();
+                }
+            }
+        }
         if let Some((mode, text)) = action.take_value() {
-            if gui.mode_perform(&window, &mut state, mode, text) {
+            if gui.mode_perform(&mut window, &mut state, mode, text) {
                 action.also_redraw();
             }
         }
+        if gui.perform_commands(&mut window, &mut state) {
+            action.also_redraw();
+        }
+        if state.poll_saves() {
+            action.also_redraw();
+        }
         if action.should_redraw() {
             render_screen(&mut window, &state, &gui);
+            if let Some((ref mut renderer, ref mut textures, ref palette)) =
+                palette_window
+            {
+                render_palette_window(
+                    renderer,
+                    &state,
+                    textures,
+                    palette,
+                    &gui.theme(),
+                );
+            }
+        }
+        if let Some(ref mut server) = ipc_server {
+            server.poll(&state);
         }
     }
 }
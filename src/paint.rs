@@ -18,12 +18,17 @@
 // +--------------------------------------------------------------------------+
 
 use super::canvas::{Canvas, Font};
+use super::config::Config;
 use super::element::{Action, GuiElement, SubrectElement};
-use super::event::{Event, Keycode, COMMAND, SHIFT};
-use super::state::{EditorState, Tool};
-use super::tilegrid::TileGrid;
+use super::event::{Event, KeyMod, Keycode, ALT, COMMAND, NONE, SHIFT};
+use super::random::Rng;
+use super::regions::Regions;
+use super::state::{EditorState, ResizeFill, Tool};
+use super::theme::Theme;
+use super::tilegrid::{tile_token, SubGrid, Tile, TileGrid};
 use sdl2::rect::{Point, Rect};
 use std::cmp::{max, min};
+use std::collections::BTreeSet;
 use std::rc::Rc;
 
 //===========================================================================//
@@ -37,17 +42,180 @@ enum ViewSize {
     Margin,
 }
 
+/// What to label the rows and columns with, along the top and left edges of
+/// the canvas.
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum GridLabels {
+    Off,
+    Tiles,
+    Pixels,
+}
+
+/// Whether to show the game camera preview, and whether to darken the map
+/// outside of it.
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum CameraView {
+    Off,
+    Frame,
+    Letterbox,
+}
+
+/// How close (in screen pixels) the mouse must be to an edge of the canvas
+/// while dragging for the canvas to scroll in that direction.
+const EDGE_SCROLL_MARGIN: i32 = 8;
+
+const SCROLLBAR_THICKNESS: u32 = 3;
+const SCROLLBAR_TRACK_COLOR: (u8, u8, u8, u8) = (0, 0, 0, 96);
+const SCROLLBAR_THUMB_COLOR: (u8, u8, u8, u8) = (255, 255, 255, 192);
+
+/// The magnifications that the canvas can be zoomed to, in order.
+const ZOOM_LEVELS: &[u32] = &[1, 2, 4, 8];
+
+const CAMERA_FRAME_COLOR: (u8, u8, u8, u8) = (255, 255, 0, 255);
+const CAMERA_LETTERBOX_COLOR: (u8, u8, u8, u8) = (0, 0, 0, 192);
+const DEFAULT_CAMERA_SIZE: (u32, u32) = (256, 224);
+const DEFAULT_CAMERA_PAN_SPEED: u32 = 2;
+
+const GRID_LABEL_STRIP_COLOR: (u8, u8, u8, u8) = (255, 255, 255, 192);
+const GRID_LABEL_HEIGHT: i32 = 11;
+
+/// The opacity with which the previous undo state is ghosted beneath the
+/// grid, when onion skinning is on.
+const ONION_OPACITY: u8 = 72;
+
+/// The opacity of the brush tiles previewed along a line being dragged out
+/// with the Line tool.
+const LINE_PREVIEW_OPACITY: u8 = 160;
+
+/// The tint for cells that differ from the saved file, when the unsaved
+/// changes view is on.
+const UNSAVED_TINT_COLOR: (u8, u8, u8, u8) = (255, 64, 0, 96);
+
+/// The tint for cells whose tiles are drawn in front of sprites, when the
+/// front tiles view is on.
+const FRONT_TINT_COLOR: (u8, u8, u8, u8) = (0, 160, 255, 96);
+
+/// The width and height, in tiles, of the blocks that share a palette,
+/// unless the config file sets `attributes.block_size`.
+const DEFAULT_ATTRIBUTE_BLOCK_SIZE: u32 = 2;
+const ATTRIBUTE_GRID_COLOR: (u8, u8, u8, u8) = (255, 0, 255, 128);
+
+/// The tint for attribute blocks whose tiles need different palettes, when
+/// the attribute grid view is on.
+const MIXED_ATTRIBUTE_TINT_COLOR: (u8, u8, u8, u8) = (255, 0, 0, 112);
+
+/// The outline color for the regions of the placement rules, and the tint
+/// for cells that break those rules.
+const REGION_COLOR: (u8, u8, u8, u8) = (0, 255, 128, 192);
+const REGION_VIOLATION_TINT_COLOR: (u8, u8, u8, u8) = (255, 0, 0, 144);
+
+/// The outline color for the cells that painting is clipped to.
+const PAINT_CLIP_COLOR: (u8, u8, u8, u8) = (255, 128, 255, 224);
+
+/// The outline color for the keyboard cursor, when painting by keyboard.
+const KEY_CURSOR_COLOR: (u8, u8, u8, u8) = (0, 255, 255, 255);
+
+/// How far (in map pixels) outside of the right and bottom edges of the map
+/// a click grabs that edge to resize the grid.  Clicks inside the map never
+/// grab an edge, so that they always paint.
+const GRID_EDGE_OUTSIDE: i32 = 5;
+/// The outline color for the new size of the grid while dragging its edges.
+const GRID_EDGE_COLOR: (u8, u8, u8, u8) = (255, 255, 0, 255);
+
 pub struct GridCanvas {
     element: SubrectElement<InnerCanvas>,
 }
 
 impl GridCanvas {
-    pub fn new(left: i32, top: i32, font: Rc<Font>) -> GridCanvas {
-        GridCanvas {
-            element: SubrectElement::new(
-                InnerCanvas::new(font),
-                Rect::new(left, top, 36 * 16, 25 * 16),
-            ),
+    pub fn new(
+        left: i32,
+        top: i32,
+        font: Rc<Font>,
+        marquee_style: MarqueeStyle,
+        camera: CameraSettings,
+        attribute_block_size: u32,
+        max_grid_size: (u32, u32),
+    ) -> GridCanvas {
+        let rect = Rect::new(left, top, 36 * 16, 25 * 16);
+        let mut inner = InnerCanvas::new(
+            font,
+            marquee_style,
+            camera,
+            attribute_block_size,
+            max_grid_size,
+        );
+        inner.viewport_size = rect.size();
+        GridCanvas { element: SubrectElement::new(inner, rect) }
+    }
+
+    pub fn set_rect(&mut self, rect: Rect) {
+        self.element.set_rect(rect);
+        self.element.inner_mut().viewport_size = rect.size();
+    }
+
+    pub fn rect(&self) -> Rect {
+        self.element.rect()
+    }
+
+    /// Returns the map pixel at the top-left corner of the canvas.
+    pub fn scroll(&self) -> Point {
+        self.element.inner().scroll
+    }
+
+    /// Scrolls so that the given map pixel is at the top-left corner of the
+    /// canvas, as nearly as possible.
+    pub fn set_scroll(&mut self, state: &EditorState, scroll: Point) {
+        self.element.inner_mut().scroll_to(state.tilegrid(), scroll);
+    }
+
+    /// Paints the given tile, dragged from the palette, at the given mouse
+    /// position (in window coordinates).  Returns true if a redraw is needed.
+    pub fn paint_dragged_tile(
+        &mut self,
+        mouse: Point,
+        tile: Option<Tile>,
+        state: &mut EditorState,
+    ) -> bool {
+        let rect = self.element.rect();
+        if !rect.contains_point(mouse) {
+            return false;
+        }
+        let inner = self.element.inner_mut();
+        let mouse = inner.screen_to_map(mouse.offset(-rect.x(), -rect.y()));
+        inner.paint_dragged_tile(mouse, tile, state)
+    }
+
+    /// Returns a description of the cell under the mouse, one line per
+    /// layer, or `None` if the mouse isn't over the map.
+    pub fn describe_hovered_cell(
+        &self,
+        state: &EditorState,
+    ) -> Option<Vec<String>> {
+        let inner = self.element.inner();
+        let (col, row) = inner.hovered_cell(state.tilegrid())?;
+        Some(describe_cell(state, col, row))
+    }
+
+    /// Ends a stroke painted by dragging a tile from the palette.
+    pub fn finish_dragged_tile(&mut self, state: &mut EditorState) -> bool {
+        self.element.inner_mut().finish_stroke(state)
+    }
+
+    /// Draws just the canvas region, as for a screenshot, optionally
+    /// leaving out the editing overlays.
+    pub fn draw_snapshot(
+        &self,
+        state: &EditorState,
+        canvas: &mut Canvas,
+        overlays: bool,
+    ) {
+        let mut subcanvas = canvas.subcanvas(self.element.rect());
+        let inner = self.element.inner();
+        let mut zoomed = subcanvas.zoomed(inner.zoom);
+        let mut scrolled = zoomed.scrolled(inner.scroll);
+        inner.draw_content(state, &mut scrolled, overlays);
+        if overlays {
+            inner.draw_overlays(state, &mut scrolled);
         }
     }
 }
@@ -70,35 +238,733 @@ impl GuiElement<EditorState, ()> for GridCanvas {
         event: &Event,
         state: &mut EditorState,
     ) -> Action<()> {
-        self.element.on_event(event, state)
+        let mut action = self.element.on_event(event, state);
+        if let Event::MouseMove(_) = *event {
+            let inner = self.element.inner();
+            if let Some(cell) = inner.hovered_cell(state.tilegrid()) {
+                if state.hovered_cell() != Some(cell) {
+                    state.set_hovered_cell(cell);
+                    action.also_redraw();
+                }
+            }
+        }
+        action
     }
 }
 
 //===========================================================================//
 
+/// An in-progress drag of the right and/or bottom edge of the map, to resize
+/// the grid.
+struct EdgeDrag {
+    // True if the right edge is being dragged:
+    horz: bool,
+    // True if the bottom edge is being dragged:
+    vert: bool,
+    // The current mouse position, in map pixels:
+    to_pixel: Point,
+}
+
 struct CanvasDrag {
     from_selection: Point,
     from_pixel: Point,
     to_pixel: Point,
+    // If resizing the selection, the handle being dragged (as a direction
+    // from the selection's center, e.g. (1, -1) for the top-right corner)
+    // and the selection's rect (in tiles) when the drag began:
+    handle: Option<((i32, i32), Rect)>,
+    // If resizing the selection with Alt held, the selection's contents
+    // when the drag began, which are repeated to fill the new size:
+    tiling: Option<SubGrid>,
+    // True if dragging out another rectangle to add to the selection (with
+    // Shift held when the drag began):
+    adding: bool,
+}
+
+impl CanvasDrag {
+    /// Resizes the selection according to the handle being dragged, either
+    /// re-cutting it from the grid or (if `tiling` is set) repeating its
+    /// original contents to fill the new size.
+    fn resize_selection(&self, state: &mut EditorState) {
+        let (handle, from_rect) = match self.handle {
+            Some(handle) => handle,
+            None => return,
+        };
+        let tile_size = state.tilegrid().tile_size() as i32;
+        let delta = (self.to_pixel - self.from_pixel) / tile_size;
+        let grid_rect = Rect::new(
+            0,
+            0,
+            state.tilegrid().width(),
+            state.tilegrid().height(),
+        );
+        let new_rect = match resize_rect(from_rect, handle, delta)
+            .intersection(grid_rect)
+        {
+            Some(rect) if selection_rect(state) != Some(rect) => rect,
+            _ => return,
+        };
+        if let Some(ref original) = self.tiling {
+            let origin = from_rect.top_left() - new_rect.top_left();
+            let subgrid =
+                original.tiled(new_rect.width(), new_rect.height(), origin);
+            state
+                .persistent_mutation("extend selection")
+                .replace_selection(subgrid, new_rect.top_left());
+        } else {
+            state.persistent_mutation("resize selection").select(new_rect);
+        }
+    }
 }
 
 struct InnerCanvas {
     font: Rc<Font>,
     drag_from_to: Option<CanvasDrag>,
+    edge_drag: Option<EdgeDrag>,
+    // The largest width and height (in tiles) that dragging the map's edges
+    // can resize the grid to:
+    max_grid_size: (u32, u32),
+    stroke_changed: bool,
     selection_animation_counter: i32,
+    marquee_style: MarqueeStyle,
     view_size: ViewSize,
+    // The modifier keys currently held down (as of the last key event):
+    keymod: KeyMod,
+    grid_labels: GridLabels,
+    // If true, the previous undo state is ghosted beneath the grid:
+    onion: bool,
+    // If true, cells that differ from the saved file are highlighted:
+    show_unsaved: bool,
+    // If true, cells whose tiles are drawn in front of sprites are
+    // highlighted:
+    show_front: bool,
+    // If true, the boundaries of the blocks of tiles that share a palette
+    // are drawn, and blocks needing more than one palette are highlighted:
+    show_attributes: bool,
+    attribute_block_size: u32,
+    camera_view: CameraView,
+    camera: CameraSettings,
+    // The top-left corner of the game camera, in map pixels:
+    camera_pos: Point,
+    // If the scroll preview is running, the direction that the camera is
+    // panning in:
+    camera_pan: Option<(i32, i32)>,
+    // The current magnification (one of `ZOOM_LEVELS`):
+    zoom: u32,
+    // The map pixel shown at the top-left corner of the canvas:
+    scroll: Point,
+    // The size of the canvas on screen, in unzoomed pixels:
+    viewport_size: (u32, u32),
+    // The last known mouse position (in screen pixels, relative to the
+    // canvas), for edge-scrolling and for commands that act on the hovered
+    // cell:
+    mouse: Option<Point>,
+    // True while the mouse button is held down after a click on the
+    // canvas:
+    mouse_held: bool,
+    // The cell under the keyboard cursor, if painting by keyboard is on:
+    key_cursor: Option<(u32, u32)>,
+    // The cell where the selection being extended with Shift+arrows began:
+    key_anchor: Option<(u32, u32)>,
 }
 
 impl InnerCanvas {
-    pub fn new(font: Rc<Font>) -> InnerCanvas {
+    pub fn new(
+        font: Rc<Font>,
+        marquee_style: MarqueeStyle,
+        camera: CameraSettings,
+        attribute_block_size: u32,
+        max_grid_size: (u32, u32),
+    ) -> InnerCanvas {
         InnerCanvas {
             font,
             drag_from_to: None,
+            edge_drag: None,
+            max_grid_size,
+            stroke_changed: false,
             selection_animation_counter: 0,
+            marquee_style,
             view_size: ViewSize::Full,
+            keymod: NONE,
+            grid_labels: GridLabels::Off,
+            onion: false,
+            show_unsaved: false,
+            show_front: false,
+            show_attributes: false,
+            attribute_block_size,
+            camera_view: CameraView::Off,
+            camera,
+            camera_pos: Point::new(0, 0),
+            camera_pan: None,
+            zoom: 1,
+            scroll: Point::new(0, 0),
+            viewport_size: (0, 0),
+            mouse: None,
+            mouse_held: false,
+            key_cursor: None,
+            key_anchor: None,
+        }
+    }
+
+    /// Returns the size of the visible area of the map, in map pixels.
+    fn visible_size(&self) -> (u32, u32) {
+        let (width, height) = self.viewport_size;
+        (width / self.zoom, height / self.zoom)
+    }
+
+    /// Scrolls so that the given map pixel is at the top-left corner of the
+    /// canvas, as nearly as possible while keeping the map in view.
+    fn scroll_to(&mut self, tilegrid: &TileGrid, scroll: Point) {
+        let (width, height) = self.visible_size();
+        let max_x =
+            (tilegrid.width() * tilegrid.tile_size()) as i32 - width as i32;
+        let max_y =
+            (tilegrid.height() * tilegrid.tile_size()) as i32 - height as i32;
+        self.scroll = Point::new(
+            scroll.x().min(max_x).max(0),
+            scroll.y().min(max_y).max(0),
+        );
+    }
+
+    /// Scrolls by the given number of steps, where each step is one tile,
+    /// or one whole canvas if `by_page` is true.  Returns true if the view
+    /// changed.
+    fn scroll_by(
+        &mut self,
+        tilegrid: &TileGrid,
+        dx: i32,
+        dy: i32,
+        by_page: bool,
+    ) -> bool {
+        let (step_x, step_y) = if by_page {
+            let (width, height) = self.visible_size();
+            (width as i32, height as i32)
+        } else {
+            (tilegrid.tile_size() as i32, tilegrid.tile_size() as i32)
+        };
+        let old_scroll = self.scroll;
+        self.scroll_to(tilegrid, old_scroll.offset(dx * step_x, dy * step_y));
+        self.scroll != old_scroll
+    }
+
+    /// Scrolls as little as possible so that the given rect (in map
+    /// pixels) is in view.
+    fn scroll_to_show(&mut self, tilegrid: &TileGrid, rect: Rect) {
+        let (width, height) = self.visible_size();
+        let (width, height) = (width as i32, height as i32);
+        let mut scroll = self.scroll;
+        if rect.right() > scroll.x() + width {
+            scroll = Point::new(rect.right() - width, scroll.y());
+        }
+        if rect.bottom() > scroll.y() + height {
+            scroll = Point::new(scroll.x(), rect.bottom() - height);
+        }
+        scroll = Point::new(
+            scroll.x().min(rect.left()),
+            scroll.y().min(rect.top()),
+        );
+        self.scroll_to(tilegrid, scroll);
+    }
+
+    /// If the mouse is near an edge of the canvas, returns the direction in
+    /// which to edge-scroll.
+    fn edge_scroll_direction(&self) -> Option<(i32, i32)> {
+        let mouse = self.mouse?;
+        let (width, height) = self.viewport_size;
+        let direction = |pos: i32, size: u32| {
+            if pos < EDGE_SCROLL_MARGIN {
+                -1
+            } else if pos >= size as i32 - EDGE_SCROLL_MARGIN {
+                1
+            } else {
+                0
+            }
+        };
+        let dx = direction(mouse.x(), width);
+        let dy = direction(mouse.y(), height);
+        if dx == 0 && dy == 0 {
+            None
+        } else {
+            Some((dx, dy))
+        }
+    }
+
+    /// Draws scrollbars along the bottom and right edges of the canvas (in
+    /// screen pixels) for any direction in which the map doesn't fit.
+    fn draw_scrollbars(&self, tilegrid: &TileGrid, canvas: &mut Canvas) {
+        let (view_width, view_height) = self.visible_size();
+        let map_width = tilegrid.width() * tilegrid.tile_size();
+        let map_height = tilegrid.height() * tilegrid.tile_size();
+        let (width, height) = self.viewport_size;
+        let thickness = SCROLLBAR_THICKNESS;
+        if map_width > view_width {
+            let track = Rect::new(
+                0,
+                (height - thickness) as i32,
+                width - thickness,
+                thickness,
+            );
+            canvas.tint_rect(SCROLLBAR_TRACK_COLOR, track);
+            let length = track.width() * view_width / map_width;
+            let start =
+                track.width() as i32 * self.scroll.x() / map_width as i32;
+            canvas.tint_rect(
+                SCROLLBAR_THUMB_COLOR,
+                Rect::new(start, track.y(), length.max(1), thickness),
+            );
+        }
+        if map_height > view_height {
+            let track = Rect::new(
+                (width - thickness) as i32,
+                0,
+                thickness,
+                height - thickness,
+            );
+            canvas.tint_rect(SCROLLBAR_TRACK_COLOR, track);
+            let length = track.height() * view_height / map_height;
+            let start =
+                track.height() as i32 * self.scroll.y() / map_height as i32;
+            canvas.tint_rect(
+                SCROLLBAR_THUMB_COLOR,
+                Rect::new(track.x(), start, thickness, length.max(1)),
+            );
+        }
+    }
+
+    /// Steps the zoom level up (if `delta` is positive) or down (if
+    /// negative).  Returns true if the zoom changed.
+    fn change_zoom(&mut self, tilegrid: &TileGrid, delta: i32) -> bool {
+        let index = ZOOM_LEVELS.iter().position(|&z| z == self.zoom);
+        let index = index.unwrap_or(0) as i32 + delta;
+        if index < 0 || index >= ZOOM_LEVELS.len() as i32 {
+            return false;
+        }
+        // Keep the same map pixel at the center of the canvas.
+        let (width, height) = self.visible_size();
+        let center = self.scroll.offset(width as i32 / 2, height as i32 / 2);
+        self.zoom = ZOOM_LEVELS[index as usize];
+        let (width, height) = self.visible_size();
+        let scroll = center.offset(-(width as i32) / 2, -(height as i32) / 2);
+        self.scroll_to(tilegrid, scroll);
+        true
+    }
+
+    /// Converts a point in screen pixels (relative to the canvas) to map
+    /// pixels, according to the current zoom and scroll.
+    fn screen_to_map(&self, pt: Point) -> Point {
+        let zoom = self.zoom as i32;
+        Point::new(
+            pt.x().div_euclid(zoom) + self.scroll.x(),
+            pt.y().div_euclid(zoom) + self.scroll.y(),
+        )
+    }
+
+    /// If the given point (in map pixels) is just outside the right and/or
+    /// bottom edge of the map, returns whether it is on each of those edges.
+    fn grid_edge_at(
+        &self,
+        tilegrid: &TileGrid,
+        pt: Point,
+    ) -> Option<(bool, bool)> {
+        let width = (tilegrid.width() * tilegrid.tile_size()) as i32;
+        let height = (tilegrid.height() * tilegrid.tile_size()) as i32;
+        let near = |pos: i32, edge: i32| {
+            pos >= edge && pos < edge + GRID_EDGE_OUTSIDE
+        };
+        let horz = near(pt.x(), width) && pt.y() < height + GRID_EDGE_OUTSIDE;
+        let vert = near(pt.y(), height) && pt.x() < width + GRID_EDGE_OUTSIDE;
+        if horz || vert {
+            Some((horz, vert))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the width and height (in tiles) that the grid would be
+    /// resized to by the edge drag in progress, if any.
+    fn dragged_grid_size(&self, tilegrid: &TileGrid) -> Option<(u32, u32)> {
+        let drag = self.edge_drag.as_ref()?;
+        let tile_size = tilegrid.tile_size() as i32;
+        let cells = |pos: i32, max: u32| {
+            (pos + tile_size / 2).div_euclid(tile_size).clamp(1, max as i32)
+                as u32
+        };
+        let (max_width, max_height) = self.max_grid_size;
+        let width = if drag.horz {
+            cells(drag.to_pixel.x(), max_width)
+        } else {
+            tilegrid.width()
+        };
+        let height = if drag.vert {
+            cells(drag.to_pixel.y(), max_height)
+        } else {
+            tilegrid.height()
+        };
+        Some((width, height))
+    }
+
+    /// Returns how to fill the cells added by an edge drag: with the brush
+    /// if Shift is held, or by extending the edges if Alt is held.
+    fn edge_drag_fill(&self) -> ResizeFill {
+        if self.keymod == SHIFT {
+            ResizeFill::Brush
+        } else if self.keymod == ALT {
+            ResizeFill::Extend
+        } else {
+            ResizeFill::Empty
+        }
+    }
+
+    /// Returns the game camera rectangle (in map pixels), clamped to lie
+    /// within the map where possible, or `None` if the camera preview is
+    /// off.
+    fn camera_rect(&self, tilegrid: &TileGrid) -> Option<Rect> {
+        if self.camera_view == CameraView::Off {
+            return None;
+        }
+        let (width, height) = self.camera.size;
+        let max_x =
+            (tilegrid.width() * tilegrid.tile_size()) as i32 - width as i32;
+        let max_y =
+            (tilegrid.height() * tilegrid.tile_size()) as i32 - height as i32;
+        let x = self.camera_pos.x().min(max_x).max(0);
+        let y = self.camera_pos.y().min(max_y).max(0);
+        Some(Rect::new(x, y, width, height))
+    }
+
+    /// Moves the game camera by the given number of steps, where each step
+    /// is one tile, or one whole screen if `by_screen` is true.
+    fn move_camera(
+        &mut self,
+        tilegrid: &TileGrid,
+        dx: i32,
+        dy: i32,
+        by_screen: bool,
+    ) {
+        if let Some(rect) = self.camera_rect(tilegrid) {
+            let (step_x, step_y) = if by_screen {
+                (rect.width() as i32, rect.height() as i32)
+            } else {
+                (tilegrid.tile_size() as i32, tilegrid.tile_size() as i32)
+            };
+            let pos = rect.top_left().offset(dx * step_x, dy * step_y);
+            self.camera_pos = pos;
+            if let Some(rect) = self.camera_rect(tilegrid) {
+                self.camera_pos = rect.top_left();
+                self.scroll_to_show(tilegrid, rect);
+            }
+        }
+    }
+
+    /// Starts the scroll preview, which pans the camera along whichever
+    /// axis the map extends furthest beyond it, starting over from the
+    /// beginning if the camera is already at the far end.
+    fn start_camera_pan(&mut self, tilegrid: &TileGrid) {
+        if self.camera_view == CameraView::Off {
+            self.camera_view = CameraView::Frame;
+        }
+        let rect = match self.camera_rect(tilegrid) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let map_width = (tilegrid.width() * tilegrid.tile_size()) as i32;
+        let map_height = (tilegrid.height() * tilegrid.tile_size()) as i32;
+        let excess_x = map_width - rect.width() as i32;
+        let excess_y = map_height - rect.height() as i32;
+        if excess_x >= excess_y {
+            if rect.x() >= excess_x {
+                self.camera_pos = Point::new(0, rect.y());
+            }
+            self.camera_pan = Some((1, 0));
+        } else {
+            if rect.y() >= excess_y {
+                self.camera_pos = Point::new(rect.x(), 0);
+            }
+            self.camera_pan = Some((0, 1));
+        }
+    }
+
+    /// Advances the scroll preview by one clock tick.  Returns false (and
+    /// stops the preview) once the camera can't pan any further.
+    fn step_camera_pan(&mut self, tilegrid: &TileGrid) -> bool {
+        let (dx, dy) = match self.camera_pan {
+            Some(direction) => direction,
+            None => return false,
+        };
+        let old_rect = match self.camera_rect(tilegrid) {
+            Some(rect) => rect,
+            None => {
+                self.camera_pan = None;
+                return false;
+            }
+        };
+        let speed = self.camera.pan_speed as i32;
+        self.camera_pos = old_rect.top_left().offset(dx * speed, dy * speed);
+        match self.camera_rect(tilegrid) {
+            Some(rect) if rect != old_rect => {
+                self.camera_pos = rect.top_left();
+                self.scroll_to_show(tilegrid, rect);
+                true
+            }
+            _ => {
+                self.camera_pan = None;
+                false
+            }
+        }
+    }
+
+    /// Outlines the game camera, darkening everything outside of it if
+    /// letterboxing is on.
+    fn draw_camera(&self, tilegrid: &TileGrid, canvas: &mut Canvas) {
+        let rect = match self.camera_rect(tilegrid) {
+            Some(rect) => rect,
+            None => return,
+        };
+        if self.camera_view == CameraView::Letterbox {
+            let view = canvas.rect();
+            let width = view.width() as i32;
+            let strips = [
+                (view.left(), view.top(), width, rect.top() - view.top()),
+                (
+                    view.left(),
+                    rect.bottom(),
+                    width,
+                    view.bottom() - rect.bottom(),
+                ),
+                (
+                    view.left(),
+                    rect.top(),
+                    rect.left() - view.left(),
+                    rect.height() as i32,
+                ),
+                (
+                    rect.right(),
+                    rect.top(),
+                    view.right() - rect.right(),
+                    rect.height() as i32,
+                ),
+            ];
+            for &(x, y, w, h) in strips.iter() {
+                if w > 0 && h > 0 {
+                    canvas.tint_rect(
+                        CAMERA_LETTERBOX_COLOR,
+                        Rect::new(x, y, w as u32, h as u32),
+                    );
+                }
+            }
+        }
+        canvas.draw_rect(CAMERA_FRAME_COLOR, rect);
+    }
+
+    /// Tints each cell (in any visible layer) whose tile is flagged to be
+    /// drawn in front of sprites.
+    fn draw_front_cells(&self, state: &EditorState, canvas: &mut Canvas) {
+        let tilegrid = state.tilegrid();
+        let tileset = tilegrid.tileset();
+        let tile_size = tilegrid.tile_size();
+        for row in 0..tilegrid.height() {
+            for col in 0..tilegrid.width() {
+                let front = (0..tilegrid.num_layers()).any(|index| {
+                    state.is_layer_visible(tilegrid.layer_name(index))
+                        && match tilegrid.layer(index)[(col, row)] {
+                            Some(ref tile) => tileset.is_front(tile),
+                            None => false,
+                        }
+                });
+                if front {
+                    let rect = Rect::new(
+                        (col * tile_size) as i32,
+                        (row * tile_size) as i32,
+                        tile_size,
+                        tile_size,
+                    );
+                    canvas.tint_rect(FRONT_TINT_COLOR, rect);
+                }
+            }
+        }
+    }
+
+    /// Outlines the regions of the placement rules, labeled with their names,
+    /// and highlights the cells that break the rules.
+    fn draw_regions(
+        &self,
+        state: &EditorState,
+        regions: &Regions,
+        canvas: &mut Canvas,
+    ) {
+        let tile_size = state.tilegrid().tile_size();
+        for &(col, row) in state.region_violations().iter() {
+            let rect = Rect::new(
+                (col * tile_size) as i32,
+                (row * tile_size) as i32,
+                tile_size,
+                tile_size,
+            );
+            canvas.tint_rect(REGION_VIOLATION_TINT_COLOR, rect);
+        }
+        for (name, rect) in regions.rects() {
+            let rect = tile_rect_to_pixels(rect, tile_size);
+            canvas.draw_rect(REGION_COLOR, rect);
+            let baseline = Point::new(rect.left() + 2, rect.top() + 9);
+            canvas.draw_text(&self.font, baseline, name);
+        }
+    }
+
+    /// Returns the rects (in tiles) of the attribute blocks whose tiles, on
+    /// visible layers, need more than one palette.
+    fn mixed_attribute_blocks(&self, state: &EditorState) -> Vec<Rect> {
+        let tilegrid = state.tilegrid();
+        let tileset = tilegrid.tileset();
+        let block = self.attribute_block_size;
+        let mut mixed = Vec::new();
+        for block_row in 0..tilegrid.height().div_ceil(block) {
+            for block_col in 0..tilegrid.width().div_ceil(block) {
+                let rect = Rect::new(
+                    (block_col * block) as i32,
+                    (block_row * block) as i32,
+                    block.min(tilegrid.width() - block_col * block),
+                    block.min(tilegrid.height() - block_row * block),
+                );
+                let mut palettes = BTreeSet::new();
+                for index in 0..tilegrid.num_layers() {
+                    if !state.is_layer_visible(tilegrid.layer_name(index)) {
+                        continue;
+                    }
+                    let layer = tilegrid.layer(index);
+                    for row in rect.top()..rect.bottom() {
+                        for col in rect.left()..rect.right() {
+                            if let Some(ref tile) =
+                                layer[(col as u32, row as u32)]
+                            {
+                                palettes.extend(tileset.tile_palette(tile));
+                            }
+                        }
+                    }
+                }
+                if palettes.len() > 1 {
+                    mixed.push(rect);
+                }
+            }
+        }
+        mixed
+    }
+
+    /// Draws the boundaries of the attribute blocks, and tints those whose
+    /// tiles need more than one palette.  Does nothing if the tileset has no
+    /// palette attributes.
+    fn draw_attribute_grid(&self, state: &EditorState, canvas: &mut Canvas) {
+        let tilegrid = state.tilegrid();
+        if !tilegrid.tileset().has_palettes() {
+            return;
+        }
+        let tile_size = tilegrid.tile_size();
+        for rect in self.mixed_attribute_blocks(state) {
+            canvas.tint_rect(
+                MIXED_ATTRIBUTE_TINT_COLOR,
+                tile_rect_to_pixels(rect, tile_size),
+            );
+        }
+        let step = self.attribute_block_size * tile_size;
+        let width = tilegrid.width() * tile_size;
+        let height = tilegrid.height() * tile_size;
+        for x in (step..width).step_by(step as usize) {
+            canvas.tint_rect(
+                ATTRIBUTE_GRID_COLOR,
+                Rect::new(x as i32, 0, 1, height),
+            );
+        }
+        for y in (step..height).step_by(step as usize) {
+            canvas.tint_rect(
+                ATTRIBUTE_GRID_COLOR,
+                Rect::new(0, y as i32, width, 1),
+            );
+        }
+    }
+
+    /// Tints each cell whose contents differ from the grid as last saved to
+    /// disk (including cells outside the saved grid's bounds).
+    fn draw_unsaved_cells(&self, state: &EditorState, canvas: &mut Canvas) {
+        let tilegrid = state.tilegrid();
+        let saved = state.saved_tilegrid();
+        let tile_size = tilegrid.tile_size();
+        for row in 0..tilegrid.height() {
+            for col in 0..tilegrid.width() {
+                let unchanged = col < saved.width()
+                    && row < saved.height()
+                    && tilegrid[(col, row)] == saved[(col, row)];
+                if !unchanged {
+                    let rect = Rect::new(
+                        (col * tile_size) as i32,
+                        (row * tile_size) as i32,
+                        tile_size,
+                        tile_size,
+                    );
+                    canvas.tint_rect(UNSAVED_TINT_COLOR, rect);
+                }
+            }
         }
     }
 
+    /// Draws row and column numbers along the top and left edges of the
+    /// grid, skipping rows/columns as needed to keep the labels from
+    /// overlapping.
+    fn draw_grid_labels(&self, tilegrid: &TileGrid, canvas: &mut Canvas) {
+        let tile_size = tilegrid.tile_size();
+        let label = |index: u32| match self.grid_labels {
+            GridLabels::Pixels => format!("{}", index * tile_size),
+            _ => format!("{}", index),
+        };
+        let last = max(tilegrid.width(), tilegrid.height()).saturating_sub(1);
+        let label_width = self.font.text_width(&label(last)) + 3;
+        let col_step = (label_width as u32 / tile_size + 1) as usize;
+        let row_step = (GRID_LABEL_HEIGHT as u32 / tile_size + 1) as usize;
+        let baseline = self.font.baseline();
+        // Keep the strips along the edges of the visible area, even when
+        // the canvas is scrolled.
+        let view = canvas.rect();
+        let (left, top) = (view.x().max(0), view.y().max(0));
+        canvas.tint_rect(
+            GRID_LABEL_STRIP_COLOR,
+            Rect::new(
+                0,
+                top,
+                tilegrid.width() * tile_size,
+                GRID_LABEL_HEIGHT as u32,
+            ),
+        );
+        canvas.tint_rect(
+            GRID_LABEL_STRIP_COLOR,
+            Rect::new(
+                left,
+                top + GRID_LABEL_HEIGHT,
+                label_width as u32,
+                (tilegrid.height() * tile_size)
+                    .saturating_sub((top + GRID_LABEL_HEIGHT) as u32),
+            ),
+        );
+        for col in (0..tilegrid.width()).step_by(col_step) {
+            let start =
+                Point::new((col * tile_size) as i32 + 1, top + baseline);
+            canvas.draw_text(&self.font, start, &label(col));
+        }
+        // Skip the labels that would be hidden under the top strip.
+        let first_row = ((top + GRID_LABEL_HEIGHT) as u32).div_ceil(tile_size);
+        for row in (first_row..tilegrid.height()).step_by(row_step) {
+            let row_top = (row * tile_size) as i32;
+            canvas.draw_text(
+                &self.font,
+                Point::new(left + 1, row_top + baseline),
+                &label(row),
+            );
+        }
+    }
+
+    /// Returns the cell under the last known mouse position, if any.
+    fn hovered_cell(&self, tilegrid: &TileGrid) -> Option<(u32, u32)> {
+        let mouse = self.screen_to_map(self.mouse?);
+        self.mouse_to_row_col(mouse, tilegrid)
+    }
+
     fn mouse_to_row_col(
         &self,
         mouse: Point,
@@ -146,6 +1012,12 @@ impl InnerCanvas {
         }
     }
 
+    /// Returns the cells of the line being dragged out, if any.
+    fn dragged_line(&self, tilegrid: &TileGrid) -> Option<Vec<(u32, u32)>> {
+        let (from, to) = self.dragged_points(tilegrid)?;
+        Some(line_cells(from, to))
+    }
+
     fn dragged_rect(&self, tilegrid: &TileGrid) -> Option<Rect> {
         if let Some(((from_col, from_row), (to_col, to_row))) =
             self.dragged_points(tilegrid)
@@ -160,17 +1032,172 @@ impl InnerCanvas {
         }
     }
 
+    /// Like `try_paint`, but for a tile dragged in from the palette, so the
+    /// stroke begins with the first cell the drag reaches.
+    fn paint_dragged_tile(
+        &mut self,
+        mouse: Point,
+        tile: Option<Tile>,
+        state: &mut EditorState,
+    ) -> bool {
+        let position = match self.mouse_to_row_col(mouse, state.tilegrid()) {
+            Some(position) => position,
+            None => return false,
+        };
+        if let Some(notice) = forbidden_notice(state, position, &tile) {
+            state.set_notice(notice);
+            return true;
+        }
+        if !state.clip_allows(position) {
+            return false;
+        }
+        if !self.stroke_changed {
+            state.unselect_if_necessary();
+            state.begin_stroke();
+            self.stroke_changed = true;
+        }
+        if !state.tilegrid().has_placed(position, &tile) {
+            state
+                .stroke_mutation("paint")
+                .tilegrid()
+                .place_tile(position, tile);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn finish_stroke(&mut self, state: &mut EditorState) -> bool {
+        if self.stroke_changed {
+            self.stroke_changed = false;
+            state.finish_stroke();
+            true
+        } else {
+            false
+        }
+    }
+
     fn try_paint(&self, mouse: Point, state: &mut EditorState) -> bool {
         if let Some(position) = self.mouse_to_row_col(mouse, state.tilegrid())
         {
-            let brush = state.brush().clone();
-            state.persistent_mutation().tilegrid()[position] = brush;
+            self.paint_cell(position, state);
             true
         } else {
             false
         }
     }
 
+    /// Turns painting by keyboard on (starting at the hovered cell, if any)
+    /// or off.
+    fn toggle_key_cursor(&mut self, state: &mut EditorState) {
+        self.key_anchor = None;
+        if self.key_cursor.take().is_some() {
+            state.set_notice("Keyboard painting off".to_string());
+            return;
+        }
+        let hovered = self.hovered_cell(state.tilegrid());
+        let top_left = self
+            .mouse_to_row_col(self.scroll, state.tilegrid())
+            .unwrap_or((0, 0));
+        self.key_cursor = Some(hovered.unwrap_or(top_left));
+        state.set_notice(
+            "Keyboard painting on: Space or keypad 5 paints, Delete erases"
+                .to_string(),
+        );
+    }
+
+    /// Moves the keyboard cursor by the given number of cells, keeping it on
+    /// the map and in view.  If `extend` is true, selects the cells between
+    /// where the extension began and the cursor.
+    fn move_key_cursor(
+        &mut self,
+        state: &mut EditorState,
+        (dx, dy): (i32, i32),
+        extend: bool,
+    ) {
+        let (old_col, old_row) = match self.key_cursor {
+            Some(position) => position,
+            None => return,
+        };
+        let tilegrid = state.tilegrid();
+        let col = (old_col as i32 + dx).clamp(0, tilegrid.width() as i32 - 1);
+        let row = (old_row as i32 + dy).clamp(0, tilegrid.height() as i32 - 1);
+        self.key_cursor = Some((col as u32, row as u32));
+        let cell = Rect::new(col, row, 1, 1);
+        self.scroll_to_show(
+            tilegrid,
+            tile_rect_to_pixels(cell, tilegrid.tile_size()),
+        );
+        if !extend {
+            self.key_anchor = None;
+            return;
+        }
+        if self.key_anchor.is_none() || state.selection().is_none() {
+            // Start a new selection, as a new undo step.
+            self.key_anchor = Some((old_col, old_row));
+            state.reset_persistent_mutation();
+        }
+        let (anchor_col, anchor_row) = self.key_anchor.unwrap();
+        let rect = Rect::new(
+            col.min(anchor_col as i32),
+            row.min(anchor_row as i32),
+            (col as u32).abs_diff(anchor_col) + 1,
+            (row as u32).abs_diff(anchor_row) + 1,
+        );
+        state.persistent_mutation("select").select(rect);
+    }
+
+    /// Paints the brush (with its top-left corner, for a block brush) at
+    /// the given cell, as part of a Pencil stroke.  Returns true if the grid
+    /// changed.
+    fn paint_cell(
+        &self,
+        position: (u32, u32),
+        state: &mut EditorState,
+    ) -> bool {
+        let cells = brush_stamp(state, position);
+        paint_cells(state, cells, "paint", true)
+    }
+
+    /// Paints the state's terrain at the given mouse position, picking edge
+    /// and corner tiles for it and its neighbors (see `Terrain`).
+    fn try_paint_terrain(
+        &self,
+        mouse: Point,
+        state: &mut EditorState,
+    ) -> bool {
+        let position = match self.mouse_to_row_col(mouse, state.tilegrid()) {
+            Some(position) => position,
+            None => return false,
+        };
+        let mut changes = match state.terrain() {
+            Some(terrain) => terrain.paint_changes(state.tilegrid(), position),
+            None => return false,
+        };
+        let mut forbidden = None;
+        changes.retain(|&(cell, ref tile)| {
+            let tile = Some(tile.clone());
+            match forbidden_notice(state, cell, &tile) {
+                Some(notice) => {
+                    forbidden = Some(notice);
+                    false
+                }
+                None => true,
+            }
+        });
+        if let Some(notice) = forbidden {
+            state.set_notice(notice);
+        }
+        if !changes.is_empty() {
+            let mut mutation = state.stroke_mutation("paint terrain");
+            let tilegrid = mutation.tilegrid();
+            for (cell, tile) in changes {
+                tilegrid.place_tile(cell, Some(tile));
+            }
+        }
+        true
+    }
+
     fn try_eyedrop(&self, mouse: Point, state: &mut EditorState) -> bool {
         if let Some(position) = self.mouse_to_row_col(mouse, state.tilegrid())
         {
@@ -186,37 +1213,154 @@ impl InnerCanvas {
             Some(position) => position,
             None => return false,
         };
+        if state.random_fill().is_some() {
+            return self.random_flood_fill(start, state);
+        }
         let to_tile = state.brush().clone();
         let from_tile = state.tilegrid()[start].clone();
         if from_tile == to_tile {
             return false;
         }
-        let mut mutation = state.mutation();
+        let region = flood_region(start, state);
+        if region.is_empty() {
+            return false;
+        }
+        let mut mutation = state.mutation("flood fill");
         let tilegrid = mutation.tilegrid();
-        tilegrid[start] = to_tile.clone();
-        let mut stack: Vec<(u32, u32)> = vec![start];
-        while let Some((col, row)) = stack.pop() {
-            let mut next: Vec<(u32, u32)> = vec![];
-            if col > 0 {
-                next.push((col - 1, row));
-            }
-            if col < tilegrid.width() - 1 {
-                next.push((col + 1, row));
-            }
-            if row > 0 {
-                next.push((col, row - 1));
-            }
-            if row < tilegrid.height() - 1 {
-                next.push((col, row + 1));
+        for position in region {
+            tilegrid.set(position, to_tile.clone());
+        }
+        true
+    }
+
+    /// Flood-fills the region around the start cell with tiles chosen at
+    /// random from the state's random fill tiles.
+    fn random_flood_fill(
+        &self,
+        start: (u32, u32),
+        state: &mut EditorState,
+    ) -> bool {
+        let region = flood_region(start, state);
+        if region.is_empty() {
+            return false;
+        }
+        let mut rng = Rng::from_time();
+        let tiles: Vec<((u32, u32), Option<Tile>)> = match state.random_fill()
+        {
+            Some(random_fill) => region
+                .into_iter()
+                .map(|position| (position, random_fill.choose(&mut rng)))
+                .collect(),
+            None => return false,
+        };
+        let mut mutation = state.mutation("random fill");
+        let tilegrid = mutation.tilegrid();
+        for (position, tile) in tiles {
+            tilegrid.set(position, tile);
+        }
+        true
+    }
+
+    fn try_dither_fill(&self, state: &mut EditorState) -> bool {
+        let ((from_col, from_row), (to_col, to_row)) =
+            match self.dragged_points(state.tilegrid()) {
+                Some(points) => points,
+                None => return false,
+            };
+        let tiles = state.dither_tiles();
+        let num_steps = tiles.len() as u32 - 1;
+        let width = from_col.abs_diff(to_col) + 1;
+        let height = from_row.abs_diff(to_row) + 1;
+        let mut mutation = state.mutation("dither fill");
+        let tilegrid = mutation.tilegrid();
+        for row in min(from_row, to_row)..=max(from_row, to_row) {
+            for col in min(from_col, to_col)..=max(from_col, to_col) {
+                let (step, length) = if width >= height {
+                    (col.abs_diff(from_col), width)
+                } else {
+                    (row.abs_diff(from_row), height)
+                };
+                let threshold =
+                    DITHER_MATRIX[(row % 4) as usize][(col % 4) as usize];
+                // Scale (step + 0.5) / length across the gaps between
+                // shades, then compare how far it is into its gap against
+                // the matrix threshold (threshold + 0.5) / 16, in integer
+                // arithmetic.
+                let position = (2 * step + 1) * num_steps;
+                let gap = position / (2 * length);
+                let into_gap = position % (2 * length);
+                let index = if into_gap * 16 > (2 * threshold + 1) * length {
+                    gap + 1
+                } else {
+                    gap
+                };
+                tilegrid.set((col, row), tiles[index as usize].clone());
             }
-            for coords in next {
-                if tilegrid[coords] == from_tile {
-                    tilegrid[coords] = to_tile.clone();
-                    stack.push(coords);
+        }
+        true
+    }
+
+    fn try_draw_border(&self, state: &mut EditorState) -> bool {
+        let tiles = match state.border_tiles() {
+            Some(tiles) => tiles.clone(),
+            None => return false,
+        };
+        if let Some(rect) = self.dragged_rect(state.tilegrid()) {
+            state.mutation("draw border").tilegrid().draw_border(rect, &tiles);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Fills the dragged-out rectangle with the brush, or (if Shift is held)
+    /// just its outline.  A block brush is repeated across the rectangle
+    /// from its top-left corner.  As with the Pencil, cells outside the
+    /// paint clip or forbidden by the region rules are left alone.
+    fn try_draw_rectangle(&self, state: &mut EditorState) -> bool {
+        let rect = match self.dragged_rect(state.tilegrid()) {
+            Some(rect) => rect,
+            None => return false,
+        };
+        let outline = self.keymod == SHIFT;
+        let brush = state.brush_grid();
+        let single = brush.size() == (1, 1);
+        let mut cells = Vec::new();
+        for row in rect.top()..rect.bottom() {
+            for col in rect.left()..rect.right() {
+                let on_edge = row == rect.top()
+                    || row == rect.bottom() - 1
+                    || col == rect.left()
+                    || col == rect.right() - 1;
+                if !on_edge && outline {
+                    continue;
+                }
+                let tile = &brush[(
+                    (col - rect.left()) as u32 % brush.width(),
+                    (row - rect.top()) as u32 % brush.height(),
+                )];
+                if single || tile.is_some() {
+                    cells.push(((col as u32, row as u32), tile.clone()));
                 }
             }
         }
-        true
+        let description =
+            if outline { "outline rectangle" } else { "fill rectangle" };
+        paint_cells(state, cells, description, false)
+    }
+
+    /// Paints the brush along the dragged-out line, as though with the
+    /// Pencil (so a block brush is stamped at each cell of the line).
+    fn try_draw_line(&self, state: &mut EditorState) -> bool {
+        let line = match self.dragged_line(state.tilegrid()) {
+            Some(line) => line,
+            None => return false,
+        };
+        let cells = line
+            .into_iter()
+            .flat_map(|position| brush_stamp(state, position))
+            .collect();
+        paint_cells(state, cells, "draw line", false)
     }
 
     fn try_palette_replace(
@@ -235,24 +1379,31 @@ impl InnerCanvas {
             return false;
         }
         state.set_brush(from_tile.clone());
-        let mut mutation = state.mutation();
+        let mut mutation =
+            state.mutation(if swap { "swap tiles" } else { "replace tiles" });
         let tilegrid = mutation.tilegrid();
         for y in 0..tilegrid.height() {
             for x in 0..tilegrid.width() {
                 let tile = tilegrid[(x, y)].clone();
                 if tile == from_tile {
-                    tilegrid[(x, y)] = to_tile.clone();
+                    tilegrid.set((x, y), to_tile.clone());
                 } else if swap && tile == to_tile {
-                    tilegrid[(x, y)] = from_tile.clone();
+                    tilegrid.set((x, y), from_tile.clone());
                 }
             }
         }
         true
     }
-}
 
-impl GuiElement<EditorState, ()> for InnerCanvas {
-    fn draw(&self, state: &EditorState, canvas: &mut Canvas) {
+    /// Draws the map itself (including any floating selection), plus, if
+    /// `overlays` is true, the overlay image and (when onion skinning is on)
+    /// the ghost of the previous undo state beneath the map.
+    fn draw_content(
+        &self,
+        state: &EditorState,
+        canvas: &mut Canvas,
+        overlays: bool,
+    ) {
         let tilegrid = state.tilegrid();
         let horz_margin = 3;
         let vert_margin = 2;
@@ -272,28 +1423,180 @@ impl GuiElement<EditorState, ()> for InnerCanvas {
                 0..tilegrid.width()
             }
         };
-        canvas.fill_rect(
-            tilegrid.background_color(),
-            Rect::new(
-                (col_range.start * tilegrid.tile_size()) as i32,
-                (row_range.start * tilegrid.tile_size()) as i32,
-                (col_range.end - col_range.start) * tilegrid.tile_size(),
-                (row_range.end - row_range.start) * tilegrid.tile_size(),
-            ),
-        );
-        for row in row_range {
-            for col in col_range.clone() {
-                if let Some(ref tile) = tilegrid[(col, row)] {
-                    canvas.draw_sprite(
-                        tile.sprite(),
-                        Point::new(
-                            (col * tilegrid.tile_size()) as i32,
-                            (row * tilegrid.tile_size()) as i32,
-                        ),
+        let tile_size = tilegrid.tile_size();
+        if tilegrid.background_gradient().is_some() {
+            for y in (row_range.start * tile_size)..(row_range.end * tile_size)
+            {
+                canvas.fill_rect(
+                    tilegrid.background_color_at(y),
+                    Rect::new(
+                        (col_range.start * tile_size) as i32,
+                        y as i32,
+                        (col_range.end - col_range.start) * tile_size,
+                        1,
+                    ),
+                );
+            }
+        } else {
+            canvas.fill_rect(
+                tilegrid.background_color(),
+                Rect::new(
+                    (col_range.start * tile_size) as i32,
+                    (row_range.start * tile_size) as i32,
+                    (col_range.end - col_range.start) * tile_size,
+                    (row_range.end - row_range.start) * tile_size,
+                ),
+            );
+        }
+        if let Some(backdrop) = tilegrid.backdrop() {
+            if let Some(sprite) = backdrop.sprite() {
+                canvas.draw_sprite(sprite, backdrop.offset());
+            }
+        }
+        if let Some(previous) = state.previous_tilegrid() {
+            if overlays && self.onion {
+                let rows =
+                    row_range.start..row_range.end.min(previous.height());
+                let cols =
+                    col_range.start..col_range.end.min(previous.width());
+                for row in rows {
+                    for col in cols.clone() {
+                        if let Some(ref tile) = previous[(col, row)] {
+                            let topleft = Point::new(
+                                (col * tile_size) as i32,
+                                (row * tile_size) as i32,
+                            );
+                            draw_tile(canvas, tile, topleft, ONION_OPACITY);
+                        }
+                    }
+                }
+            }
+        }
+        // Tiles flagged to be in front of sprites are drawn last, above the
+        // overlay image (which is typically a preview of the sprites).
+        let tileset = tilegrid.tileset();
+        for front in [false, true] {
+            for index in 0..tilegrid.num_layers() {
+                if !state.is_layer_visible(tilegrid.layer_name(index)) {
+                    continue;
+                }
+                let layer = tilegrid.layer(index);
+                for row in row_range.clone() {
+                    for col in col_range.clone() {
+                        if let Some(ref tile) = layer[(col, row)] {
+                            if tileset.is_front(tile) != front {
+                                continue;
+                            }
+                            let topleft = Point::new(
+                                (col * tilegrid.tile_size()) as i32,
+                                (row * tilegrid.tile_size()) as i32,
+                            );
+                            draw_tile(canvas, tile, topleft, 255);
+                        }
+                    }
+                }
+                if index == tilegrid.active_layer() && !front {
+                    self.draw_selection(state, canvas);
+                }
+            }
+            if !front && overlays {
+                if let Some((sprite, opacity)) = state.overlay() {
+                    canvas.draw_sprite_alpha(
+                        sprite,
+                        Point::new(0, 0),
+                        opacity,
                     );
                 }
             }
         }
+    }
+
+    /// Draws the tiles of the floating selection, if any.
+    fn draw_selection(&self, state: &EditorState, canvas: &mut Canvas) {
+        let tilegrid = state.tilegrid();
+        if let Some((selected, topleft)) = state.selection() {
+            for row in 0..selected.height() {
+                for col in 0..selected.width() {
+                    if let Some(ref tile) = selected[(col, row)] {
+                        let coords = Point::new(col as i32, row as i32);
+                        let pos =
+                            (coords + topleft) * (tilegrid.tile_size() as i32);
+                        draw_tile(canvas, tile, pos, 255);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draws a preview of the line being dragged out (if any), returning the
+    /// label to show for it.
+    fn draw_dragged_line(
+        &self,
+        state: &EditorState,
+        canvas: &mut Canvas,
+    ) -> Option<(Point, String)> {
+        let tilegrid = state.tilegrid();
+        let cells = self.dragged_line(tilegrid)?;
+        let tile_size = tilegrid.tile_size();
+        for &(col, row) in cells.iter() {
+            let rect = Rect::new(
+                (col * tile_size) as i32,
+                (row * tile_size) as i32,
+                tile_size,
+                tile_size,
+            );
+            match *state.brush() {
+                Some(ref tile) => draw_tile(
+                    canvas,
+                    tile,
+                    rect.top_left(),
+                    LINE_PREVIEW_OPACITY,
+                ),
+                None => canvas.tint_rect(self.marquee_style.tint_color, rect),
+            }
+        }
+        let anchor = match self.drag_from_to {
+            Some(ref drag) => drag.to_pixel + Point::new(8, 0),
+            None => return None,
+        };
+        Some((anchor, format!("{}", cells.len())))
+    }
+
+    /// Draws the rect being dragged out (if any), returning the label to
+    /// show for it.
+    fn draw_dragged_rect(
+        &self,
+        state: &EditorState,
+        canvas: &mut Canvas,
+    ) -> Option<(Point, String)> {
+        let tilegrid = state.tilegrid();
+        let rect = self.dragged_rect(tilegrid)?;
+        let marquee_rect = Rect::new(
+            rect.x() * (tilegrid.tile_size() as i32),
+            rect.y() * (tilegrid.tile_size() as i32),
+            rect.width() * tilegrid.tile_size(),
+            rect.height() * tilegrid.tile_size(),
+        );
+        if state.tool() == Tool::Select {
+            canvas.tint_rect(self.marquee_style.tint_color, marquee_rect);
+        }
+        self.marquee_style.draw(canvas, marquee_rect, 0);
+        // Show the size next to the cursor while dragging out the
+        // selection, so it's visible before releasing.
+        let anchor = match self.drag_from_to {
+            Some(ref drag) => drag.to_pixel + Point::new(8, 0),
+            None => marquee_rect.top_left(),
+        };
+        let text = format!("{} x {}", rect.width(), rect.height());
+        Some((anchor, text))
+    }
+
+    /// Draws the editing aids on top of the map: margins, the overlay image,
+    /// grid labels, and the selection marquee.
+    fn draw_overlays(&self, state: &EditorState, canvas: &mut Canvas) {
+        let tilegrid = state.tilegrid();
+        let horz_margin = 3;
+        let vert_margin = 2;
         if self.view_size == ViewSize::Margin {
             let rect = Rect::new(
                 (horz_margin * tilegrid.tile_size()) as i32,
@@ -303,54 +1606,119 @@ impl GuiElement<EditorState, ()> for InnerCanvas {
             );
             canvas.draw_rect((63, 63, 63, 255), rect);
         }
-        let label = if let Some((ref selected, topleft)) = state.selection() {
-            for row in 0..selected.height() {
-                for col in 0..selected.width() {
-                    if let Some(ref tile) = selected[(col, row)] {
-                        let coords = Point::new(col as i32, row as i32);
-                        let pos =
-                            (coords + topleft) * (tilegrid.tile_size() as i32);
-                        canvas.draw_sprite(tile.sprite(), pos);
-                    }
-                }
+        self.draw_camera(tilegrid, canvas);
+        if self.show_front {
+            self.draw_front_cells(state, canvas);
+        }
+        if self.show_unsaved {
+            self.draw_unsaved_cells(state, canvas);
+        }
+        if self.show_attributes {
+            self.draw_attribute_grid(state, canvas);
+        }
+        if let Some(regions) = state.regions() {
+            self.draw_regions(state, regions, canvas);
+        }
+        for &rect in state.paint_clip().unwrap_or(&[]) {
+            let rect = tile_rect_to_pixels(rect, tilegrid.tile_size());
+            canvas.draw_rect(PAINT_CLIP_COLOR, rect);
+        }
+        if self.grid_labels != GridLabels::Off {
+            self.draw_grid_labels(tilegrid, canvas);
+        }
+        if let Some((col, row)) = self.key_cursor {
+            let cell = Rect::new(col as i32, row as i32, 1, 1);
+            let rect = tile_rect_to_pixels(cell, tilegrid.tile_size());
+            canvas.draw_rect(KEY_CURSOR_COLOR, rect);
+            canvas.draw_rect(
+                KEY_CURSOR_COLOR,
+                Rect::new(
+                    rect.x() + 1,
+                    rect.y() + 1,
+                    rect.width() - 2,
+                    rect.height() - 2,
+                ),
+            );
+        }
+        let label = if let Some((width, height)) =
+            self.dragged_grid_size(tilegrid)
+        {
+            let rect = Rect::new(
+                0,
+                0,
+                width * tilegrid.tile_size(),
+                height * tilegrid.tile_size(),
+            );
+            canvas.draw_rect(GRID_EDGE_COLOR, rect);
+            let mut text = format!("{} x {}", width, height);
+            let fill = self.edge_drag_fill();
+            if fill != ResizeFill::Empty {
+                text.push_str(&format!(" ({})", fill.name()));
             }
+            Some((Point::new(rect.right(), rect.bottom()), text))
+        } else if let Some((ref selected, topleft)) = state.selection() {
             let marquee_rect = Rect::new(
                 topleft.x() * (tilegrid.tile_size() as i32),
                 topleft.y() * (tilegrid.tile_size() as i32),
                 selected.width() * tilegrid.tile_size(),
                 selected.height() * tilegrid.tile_size(),
             );
-            draw_marquee(
-                canvas,
-                marquee_rect,
-                self.selection_animation_counter,
-            );
-            if let Some(drag) = self.drag_from_to.as_ref() {
+            match state.selection_mask() {
+                Some(mask) => {
+                    for &part in mask.iter() {
+                        let mut part = part;
+                        part.offset(topleft.x(), topleft.y());
+                        self.marquee_style.draw(
+                            canvas,
+                            tile_rect_to_pixels(part, tilegrid.tile_size()),
+                            self.selection_animation_counter,
+                        );
+                    }
+                }
+                None => self.marquee_style.draw(
+                    canvas,
+                    marquee_rect,
+                    self.selection_animation_counter,
+                ),
+            }
+            if state.tool() == Tool::Select {
+                for &handle in HANDLES.iter() {
+                    self.marquee_style.draw_handle(
+                        canvas,
+                        handle_rect(marquee_rect, handle),
+                    );
+                }
+            }
+            if let Some(&CanvasDrag { adding: true, .. }) =
+                self.drag_from_to.as_ref()
+            {
+                self.draw_dragged_rect(state, canvas)
+            } else if let Some(&CanvasDrag { handle: Some(_), .. }) =
+                self.drag_from_to.as_ref()
+            {
+                let text =
+                    format!("{} x {}", selected.width(), selected.height());
+                Some((marquee_rect.top_left(), text))
+            } else if let Some(drag) = self.drag_from_to.as_ref() {
                 let from = drag.from_selection;
                 let delta_x = topleft.x() - from.x();
                 let delta_y = topleft.y() - from.y();
                 let text = format!("{},{}", delta_x.abs(), delta_y.abs());
-                Some((marquee_rect, text))
+                Some((marquee_rect.top_left(), text))
             } else {
                 None
             }
-        } else if let Some(rect) = self.dragged_rect(tilegrid) {
-            let marquee_rect = Rect::new(
-                rect.x() * (tilegrid.tile_size() as i32),
-                rect.y() * (tilegrid.tile_size() as i32),
-                rect.width() * tilegrid.tile_size(),
-                rect.height() * tilegrid.tile_size(),
-            );
-            draw_marquee(canvas, marquee_rect, 0);
-            let text = format!("{}x{}", rect.width(), rect.height());
-            Some((marquee_rect, text))
+        } else if state.tool() == Tool::Line {
+            self.draw_dragged_line(state, canvas)
         } else {
-            None
+            self.draw_dragged_rect(state, canvas)
         };
-        if let Some((marquee_rect, text)) = label {
-            let left = marquee_rect.x().max(0);
-            let top = marquee_rect.y().max(12);
+        if let Some((anchor, text)) = label {
             let text_width = self.font.text_width(&text) as u32;
+            let view = canvas.rect();
+            let max_left = view.right() - text_width as i32 - 3;
+            let left = anchor.x().min(max_left).max(view.left());
+            let top = anchor.y().max(view.top() + 12);
             canvas.fill_rect(
                 (255, 255, 255, 255),
                 Rect::new(left + 1, top - 11, text_width + 1, 10),
@@ -358,39 +1726,307 @@ impl GuiElement<EditorState, ()> for InnerCanvas {
             canvas.draw_text(&self.font, Point::new(left + 2, top - 2), &text);
         }
     }
+}
+
+impl GuiElement<EditorState, ()> for InnerCanvas {
+    fn draw(&self, state: &EditorState, canvas: &mut Canvas) {
+        {
+            let mut zoomed = canvas.zoomed(self.zoom);
+            let mut scrolled = zoomed.scrolled(self.scroll);
+            self.draw_content(state, &mut scrolled, true);
+            self.draw_overlays(state, &mut scrolled);
+        }
+        self.draw_scrollbars(state.tilegrid(), canvas);
+    }
 
     fn on_event(
         &mut self,
         event: &Event,
         state: &mut EditorState,
     ) -> Action<()> {
+        if let &Event::MouseMove(pt)
+        | &Event::MouseDown(pt, _)
+        | &Event::MouseDrag(pt, _) = event
+        {
+            self.mouse = Some(pt);
+        }
+        // The map may have shrunk since we last scrolled.
+        self.scroll_to(state.tilegrid(), self.scroll);
+        if let Some((col, row)) = self.key_cursor {
+            let (width, height) = state.tilegrid().size();
+            self.key_cursor = Some((col.min(width - 1), row.min(height - 1)));
+        }
+        match event {
+            &Event::MouseDown(_, _) => self.mouse_held = true,
+            &Event::MouseUp(_) => self.mouse_held = false,
+            _ => {}
+        }
+        let event = &match event {
+            &Event::MouseMove(pt) => Event::MouseMove(self.screen_to_map(pt)),
+            &Event::MouseDown(pt, kmod) => {
+                Event::MouseDown(self.screen_to_map(pt), kmod)
+            }
+            &Event::MouseDrag(pt, kmod) => {
+                Event::MouseDrag(self.screen_to_map(pt), kmod)
+            }
+            other => other.clone(),
+        };
+        // Mouse events carry the modifiers held at the time, which stay
+        // current even if keys were pressed or released while the canvas
+        // (or the window) didn't have focus.
+        if let &Event::KeyDown(_, kmod)
+        | &Event::KeyUp(_, kmod)
+        | &Event::MouseDown(_, kmod)
+        | &Event::MouseDrag(_, kmod)
+        | &Event::MouseUp(kmod) = event
+        {
+            self.keymod = kmod;
+        }
         match event {
             &Event::ClockTick => {
+                let mut action = Action::ignore();
+                if state.selection().is_some() && self.marquee_style.speed != 0
+                {
+                    self.selection_animation_counter = (self
+                        .selection_animation_counter
+                        + self.marquee_style.speed)
+                        .rem_euclid(2 * self.marquee_style.dash_length);
+                    action.also_redraw();
+                }
+                if self.step_camera_pan(state.tilegrid()) {
+                    action.also_redraw();
+                }
+                if let (true, Some((dx, dy)), Some(mouse)) =
+                    (self.mouse_held, self.edge_scroll_direction(), self.mouse)
+                {
+                    if self.scroll_by(state.tilegrid(), dx, dy, false) {
+                        // Continue the drag in progress at the mouse's new
+                        // position on the map.
+                        self.on_event(
+                            &Event::MouseDrag(mouse, self.keymod),
+                            state,
+                        );
+                        action.also_redraw();
+                    }
+                }
+                action
+            }
+            &Event::KeyDown(Keycode::J, kmod) if kmod == COMMAND | ALT => {
+                self.toggle_key_cursor(state);
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::Space, kmod)
+            | &Event::KeyDown(Keycode::Kp5, kmod)
+                if kmod == NONE && self.key_cursor.is_some() =>
+            {
+                let position = self.key_cursor.unwrap();
+                self.key_anchor = None;
+                state.unselect_if_necessary();
+                state.begin_stroke();
+                if self.paint_cell(position, state) {
+                    state.finish_stroke();
+                }
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(keycode, _)
+                if (keycode == Keycode::Delete
+                    || keycode == Keycode::Backspace)
+                    && self.key_cursor.is_some()
+                    && state.selection().is_none() =>
+            {
+                let position = self.key_cursor.unwrap();
+                let eraser = state.eraser_tile().clone();
+                if let Some(notice) =
+                    forbidden_notice(state, position, &eraser)
+                {
+                    state.set_notice(notice);
+                } else if !state.tilegrid().has_placed(position, &eraser) {
+                    state
+                        .mutation("erase")
+                        .tilegrid()
+                        .place_tile(position, eraser);
+                }
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::Backspace, _)
+            | &Event::KeyDown(Keycode::Delete, _) => {
                 if state.selection().is_some() {
-                    self.selection_animation_counter =
-                        (self.selection_animation_counter + 1)
-                            .rem_euclid(MARQUEE_ANIMATION_MODULUS);
-                    Action::redraw()
+                    state.mutation("delete selection").delete_selection();
+                    Action::redraw().and_stop()
                 } else {
                     Action::ignore()
                 }
             }
-            &Event::KeyDown(Keycode::Backspace, _) => {
-                if state.selection().is_some() {
-                    state.mutation().delete_selection();
+            &Event::KeyDown(Keycode::Escape, _) => {
+                if self.drag_from_to.is_some() {
+                    // Abort the drag in progress, without selecting or
+                    // moving anything.
+                    self.drag_from_to = None;
+                    if state.tool() == Tool::Select {
+                        state.cancel_persistent_mutation();
+                    }
+                    Action::redraw().and_stop()
+                } else if state.selection().is_some() {
+                    let description = if state.selection_is_cut() {
+                        "cancel selection"
+                    } else {
+                        "deselect"
+                    };
+                    state.mutation(description).cancel_selection();
                     Action::redraw().and_stop()
                 } else {
                     Action::ignore()
                 }
             }
-            &Event::KeyDown(Keycode::Escape, _) => {
-                if state.selection().is_some() {
-                    state.mutation().unselect();
+            &Event::KeyDown(Keycode::F, kmod)
+                if kmod == NONE || kmod == SHIFT =>
+            {
+                let tile = match *state.brush() {
+                    Some(ref tile) => tile.clone(),
+                    None => return Action::ignore(),
+                };
+                if state.brush_grid().size() != (1, 1) {
+                    state.set_notice(
+                        "Can't flip a multi-tile brush".to_string(),
+                    );
+                    return Action::redraw().and_stop();
+                }
+                let flip = if kmod == SHIFT {
+                    tile.flip().toggled(false, true)
+                } else {
+                    tile.flip().toggled(true, false)
+                };
+                state.set_brush(Some(tile.with_flip(flip)));
+                let description = match (flip.horz, flip.vert) {
+                    (false, false) => "unflipped",
+                    (true, false) => "flipped horizontally",
+                    (false, true) => "flipped vertically",
+                    (true, true) => "flipped both ways",
+                };
+                state.set_notice(format!("Brush {}", description));
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::Return, kmod)
+                if kmod == NONE && self.drag_from_to.is_none() =>
+            {
+                if state.anchor_selection() {
                     Action::redraw().and_stop()
                 } else {
                     Action::ignore()
                 }
             }
+            &Event::KeyDown(Keycode::D, kmod) if kmod == COMMAND | ALT => {
+                self.show_unsaved = !self.show_unsaved;
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::F, kmod) if kmod == COMMAND | ALT => {
+                self.show_front = !self.show_front;
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::A, kmod) if kmod == COMMAND | ALT => {
+                self.show_attributes = !self.show_attributes;
+                if self.show_attributes {
+                    if !state.tilegrid().tileset().has_palettes() {
+                        state.set_notice(
+                            "No palette attributes (.attr files) in tileset"
+                                .to_string(),
+                        );
+                    } else {
+                        let num_mixed =
+                            self.mixed_attribute_blocks(state).len();
+                        if num_mixed > 0 {
+                            state.set_notice(format!(
+                                "Warning: {} attribute block{} mix palettes",
+                                num_mixed,
+                                if num_mixed == 1 { "" } else { "s" }
+                            ));
+                        }
+                    }
+                }
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::Equals, kmod) if kmod == COMMAND => {
+                if self.change_zoom(state.tilegrid(), 1) {
+                    state.set_notice(format!("Zoom: {}x", self.zoom));
+                }
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::Minus, kmod) if kmod == COMMAND => {
+                if self.change_zoom(state.tilegrid(), -1) {
+                    state.set_notice(format!("Zoom: {}x", self.zoom));
+                }
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::P, kmod) if kmod == COMMAND | ALT => {
+                self.camera_view = match self.camera_view {
+                    CameraView::Off => CameraView::Frame,
+                    CameraView::Frame => CameraView::Letterbox,
+                    CameraView::Letterbox => CameraView::Off,
+                };
+                if self.camera_view == CameraView::Off {
+                    self.camera_pan = None;
+                }
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::P, kmod)
+                if kmod == COMMAND | SHIFT | ALT =>
+            {
+                if self.camera_pan.is_some() {
+                    self.camera_pan = None;
+                } else {
+                    self.start_camera_pan(state.tilegrid());
+                }
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(keycode, kmod)
+                if (kmod == NONE || kmod == SHIFT)
+                    && self.key_cursor.is_some()
+                    && arrow_direction(keycode).is_some() =>
+            {
+                let direction = arrow_direction(keycode).unwrap();
+                self.move_key_cursor(state, direction, kmod == SHIFT);
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(keycode, kmod)
+                if kmod == NONE
+                    && state.selection().is_some()
+                    && keypad_direction(keycode).is_some() =>
+            {
+                let (dx, dy) = keypad_direction(keycode).unwrap();
+                let position = state.selection().unwrap().1.offset(dx, dy);
+                state
+                    .mutation("move selection")
+                    .reposition_selection(position);
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(keycode, kmod)
+                if (kmod == NONE || kmod == SHIFT)
+                    && arrow_direction(keycode).is_some() =>
+            {
+                let (dx, dy) = arrow_direction(keycode).unwrap();
+                let tilegrid = state.tilegrid();
+                if self.camera_view != CameraView::Off {
+                    self.camera_pan = None;
+                    self.move_camera(tilegrid, dx, dy, kmod == SHIFT);
+                    Action::redraw().and_stop()
+                } else {
+                    let scrolled =
+                        self.scroll_by(tilegrid, dx, dy, kmod == SHIFT);
+                    Action::redraw_if(scrolled).and_stop()
+                }
+            }
+            &Event::KeyDown(Keycode::U, kmod) if kmod == COMMAND | ALT => {
+                self.onion = !self.onion;
+                Action::redraw().and_stop()
+            }
+            &Event::KeyDown(Keycode::N, kmod) if kmod == COMMAND | SHIFT => {
+                self.grid_labels = match self.grid_labels {
+                    GridLabels::Off => GridLabels::Tiles,
+                    GridLabels::Tiles => GridLabels::Pixels,
+                    GridLabels::Pixels => GridLabels::Off,
+                };
+                Action::redraw().and_stop()
+            }
             &Event::KeyDown(Keycode::R, kmod) if kmod == COMMAND | SHIFT => {
                 self.view_size = match self.view_size {
                     ViewSize::Small => ViewSize::Wide,
@@ -401,29 +2037,115 @@ impl GuiElement<EditorState, ()> for InnerCanvas {
                 };
                 Action::redraw().and_stop()
             }
-            &Event::MouseDown(pt) => match state.tool() {
+            &Event::MouseDown(pt, _)
+                if state.selection().is_none()
+                    && self.grid_edge_at(state.tilegrid(), pt).is_some() =>
+            {
+                let (horz, vert) =
+                    self.grid_edge_at(state.tilegrid(), pt).unwrap();
+                self.edge_drag = Some(EdgeDrag { horz, vert, to_pixel: pt });
+                Action::redraw().and_stop()
+            }
+            &Event::MouseDown(pt, kmod) => match state.tool() {
+                Tool::Border if state.border_tiles().is_none() => {
+                    state.set_notice(
+                        "No border tiles set (Cmd+Shift+O)".to_string(),
+                    );
+                    Action::redraw().and_stop()
+                }
+                Tool::Border
+                | Tool::DitherFill
+                | Tool::Line
+                | Tool::Rectangle => {
+                    self.drag_from_to = Some(CanvasDrag {
+                        from_selection: Point::new(0, 0),
+                        from_pixel: pt,
+                        to_pixel: pt,
+                        handle: None,
+                        tiling: None,
+                        adding: false,
+                    });
+                    Action::redraw().and_stop()
+                }
                 Tool::Eyedropper => {
                     let changed = self.try_eyedrop(pt, state);
                     Action::redraw_if(changed).and_stop()
                 }
                 Tool::PaintBucket => {
                     let changed = self.try_flood_fill(pt, state);
+                    if changed {
+                        state.finish_stroke();
+                    }
                     Action::redraw_if(changed).and_stop()
                 }
                 Tool::PaletteReplace => {
                     let changed = self.try_palette_replace(pt, state, false);
+                    if changed {
+                        state.finish_stroke();
+                    }
                     Action::redraw_if(changed).and_stop()
                 }
                 Tool::PaletteSwap => {
                     let changed = self.try_palette_replace(pt, state, true);
+                    if changed {
+                        state.finish_stroke();
+                    }
                     Action::redraw_if(changed).and_stop()
                 }
                 Tool::Pencil => {
-                    state.reset_persistent_mutation();
+                    state.begin_stroke();
                     let changed = self.try_paint(pt, state);
+                    self.stroke_changed = changed;
+                    Action::redraw_if(changed).and_stop()
+                }
+                Tool::Terrain => {
+                    if state.terrain().is_none() {
+                        state.set_notice(
+                            "No terrain loaded (Cmd+Shift+Alt+T)".to_string(),
+                        );
+                        return Action::redraw().and_stop();
+                    }
+                    state.begin_stroke();
+                    let changed = self.try_paint_terrain(pt, state);
+                    self.stroke_changed = changed;
                     Action::redraw_if(changed).and_stop()
                 }
                 Tool::Select => {
+                    // Any move or resize during this drag starts a new undo
+                    // step, so that Escape cancels only this drag's changes.
+                    state.reset_persistent_mutation();
+                    if kmod == SHIFT && state.selection().is_some() {
+                        self.drag_from_to = Some(CanvasDrag {
+                            from_selection: Point::new(0, 0),
+                            from_pixel: pt,
+                            to_pixel: pt,
+                            handle: None,
+                            tiling: None,
+                            adding: true,
+                        });
+                        return Action::redraw().and_stop();
+                    }
+                    if let Some(rect) = selection_rect(state) {
+                        let tile_size = state.tilegrid().tile_size();
+                        let marquee_rect =
+                            tile_rect_to_pixels(rect, tile_size);
+                        if let Some(handle) = handle_at(marquee_rect, pt) {
+                            let tiling = if kmod == ALT {
+                                state.selection().map(|(sub, _)| sub.clone())
+                            } else {
+                                None
+                            };
+                            self.drag_from_to = Some(CanvasDrag {
+                                from_selection: rect.top_left(),
+                                from_pixel: pt,
+                                to_pixel: pt,
+                                handle: Some((handle, rect)),
+                                tiling,
+                                adding: false,
+                            });
+                            return Action::redraw().and_stop();
+                        }
+                    }
                     let rect = if let Some((ref selected, topleft)) =
                         state.selection()
                     {
@@ -446,9 +2168,7 @@ impl GuiElement<EditorState, ()> for InnerCanvas {
                         )
                         .contains_point(pt)
                         {
-                            state.mutation().unselect();
-                        } else {
-                            state.reset_persistent_mutation();
+                            state.mutation("deselect").unselect();
                         }
                     }
                     self.drag_from_to = Some(CanvasDrag {
@@ -459,18 +2179,90 @@ impl GuiElement<EditorState, ()> for InnerCanvas {
                         },
                         from_pixel: pt,
                         to_pixel: pt,
+                        handle: None,
+                        tiling: None,
+                        adding: false,
                     });
                     Action::redraw().and_stop()
                 }
             },
-            &Event::MouseUp => {
+            &Event::MouseUp(_) if self.edge_drag.is_some() => {
+                let size = self.dragged_grid_size(state.tilegrid());
+                self.edge_drag = None;
+                if let Some((width, height)) = size {
+                    let tilegrid = state.tilegrid();
+                    if (width, height) != (tilegrid.width(), tilegrid.height())
+                    {
+                        state.unselect_if_necessary();
+                        let fill = self.edge_drag_fill();
+                        state
+                            .mutation("resize grid")
+                            .resize_grid(width, height, fill);
+                        self.scroll_to(state.tilegrid(), self.scroll);
+                    }
+                }
+                Action::redraw()
+            }
+            &Event::MouseUp(_) => {
                 match state.tool() {
+                    Tool::Border => {
+                        let changed = self.try_draw_border(state);
+                        if changed {
+                            state.finish_stroke();
+                        }
+                        self.drag_from_to = None;
+                        return Action::redraw_if(changed);
+                    }
+                    Tool::DitherFill => {
+                        let changed = self.try_dither_fill(state);
+                        if changed {
+                            state.finish_stroke();
+                        }
+                        self.drag_from_to = None;
+                        return Action::redraw_if(changed);
+                    }
+                    Tool::Line => {
+                        let changed = self.try_draw_line(state);
+                        if changed {
+                            state.finish_stroke();
+                        }
+                        self.drag_from_to = None;
+                        return Action::redraw_if(changed);
+                    }
+                    Tool::Rectangle => {
+                        let changed = self.try_draw_rectangle(state);
+                        if changed {
+                            state.finish_stroke();
+                        }
+                        self.drag_from_to = None;
+                        return Action::redraw_if(changed);
+                    }
+                    Tool::Pencil | Tool::Terrain => {
+                        if self.stroke_changed {
+                            self.stroke_changed = false;
+                            state.finish_stroke();
+                            self.drag_from_to = None;
+                            return Action::redraw();
+                        }
+                    }
                     Tool::Select => {
-                        if state.selection().is_none() {
+                        if let Some(CanvasDrag { adding: true, .. }) =
+                            self.drag_from_to
+                        {
+                            if let Some(rect) =
+                                self.dragged_rect(state.tilegrid())
+                            {
+                                state
+                                    .mutation("add to selection")
+                                    .add_to_selection(rect);
+                                self.drag_from_to = None;
+                                return Action::redraw();
+                            }
+                        } else if state.selection().is_none() {
                             if let Some(rect) =
                                 self.dragged_rect(state.tilegrid())
                             {
-                                state.mutation().select(rect);
+                                state.mutation("select").select(rect);
                                 self.drag_from_to = None;
                                 self.selection_animation_counter = 0;
                                 return Action::redraw();
@@ -482,20 +2274,45 @@ impl GuiElement<EditorState, ()> for InnerCanvas {
                 self.drag_from_to = None;
                 Action::ignore()
             }
-            &Event::MouseDrag(pt) => match state.tool() {
+            &Event::MouseDrag(pt, _) if self.edge_drag.is_some() => {
+                if let Some(ref mut drag) = self.edge_drag {
+                    drag.to_pixel = pt;
+                }
+                Action::redraw()
+            }
+            &Event::MouseDrag(pt, _) => match state.tool() {
+                Tool::Border
+                | Tool::DitherFill
+                | Tool::Line
+                | Tool::Rectangle => {
+                    if let Some(ref mut drag) = self.drag_from_to {
+                        drag.to_pixel = pt;
+                        Action::redraw()
+                    } else {
+                        Action::ignore()
+                    }
+                }
                 Tool::Pencil => {
                     let changed = self.try_paint(pt, state);
+                    self.stroke_changed |= changed;
+                    Action::redraw_if(changed)
+                }
+                Tool::Terrain => {
+                    let changed = self.try_paint_terrain(pt, state);
+                    self.stroke_changed |= changed;
                     Action::redraw_if(changed)
                 }
                 Tool::Select => {
                     if let Some(ref mut drag) = self.drag_from_to {
                         drag.to_pixel = pt;
-                        if state.selection().is_some() {
+                        if drag.handle.is_some() {
+                            drag.resize_selection(state);
+                        } else if !drag.adding && state.selection().is_some() {
                             let position = drag.from_selection
                                 + (pt - drag.from_pixel)
                                     / state.tilegrid().tile_size() as i32;
                             state
-                                .persistent_mutation()
+                                .persistent_mutation("move selection")
                                 .reposition_selection(position);
                         }
                         Action::redraw()
@@ -512,31 +2329,470 @@ impl GuiElement<EditorState, ()> for InnerCanvas {
 
 //===========================================================================//
 
-const MARQUEE_ANIMATION_MODULUS: i32 = 8;
+/// The resize handles drawn on the selection marquee, as directions from its
+/// center (corners first, so that they win when handles overlap).
+const HANDLES: [(i32, i32); 8] =
+    [(-1, -1), (1, -1), (-1, 1), (1, 1), (0, -1), (-1, 0), (1, 0), (0, 1)];
+
+const HANDLE_SIZE: u32 = 5;
+
+/// Returns the current selection's rect, in tiles.
+fn selection_rect(state: &EditorState) -> Option<Rect> {
+    state.selection().map(|(selected, topleft)| {
+        Rect::new(
+            topleft.x(),
+            topleft.y(),
+            selected.width(),
+            selected.height(),
+        )
+    })
+}
+
+/// Returns everything known about the given cell: its position, then one
+/// line per layer (marking the active one) with that layer's tile, its flip,
+/// and its tileset metadata, then any floating selection tile above it.
+fn describe_cell(state: &EditorState, col: u32, row: u32) -> Vec<String> {
+    let tilegrid = state.tilegrid();
+    let tile_size = tilegrid.tile_size();
+    let mut lines = vec![format!(
+        "Cell {},{} (px {},{})",
+        col,
+        row,
+        col * tile_size,
+        row * tile_size
+    )];
+    for index in 0..tilegrid.num_layers() {
+        let cell = &tilegrid.layer(index)[(col, row)];
+        let marker = if index == tilegrid.active_layer() { "*" } else { " " };
+        let mut text = format!(
+            "{}{}: {}",
+            marker,
+            tilegrid.layer_name(index),
+            tile_token(cell)
+        );
+        let flags = describe_tile(state, cell);
+        if !flags.is_empty() {
+            text.push_str(&format!(" [{}]", flags.join(", ")));
+        }
+        lines.push(text);
+    }
+    if let Some((subgrid, topleft)) = state.selection() {
+        let x = col as i32 - topleft.x();
+        let y = row as i32 - topleft.y();
+        if x >= 0
+            && y >= 0
+            && (x as u32) < subgrid.width()
+            && (y as u32) < subgrid.height()
+        {
+            let floating = &subgrid[(x as u32, y as u32)];
+            lines.push(format!(" floating: {}", tile_token(floating)));
+        }
+    }
+    lines
+}
+
+/// Returns the notable properties of a cell's tile: its flip, its tileset
+/// metadata, and whether it's the brush or eraser tile.
+fn describe_tile(state: &EditorState, cell: &Option<Tile>) -> Vec<String> {
+    let tile = match *cell {
+        Some(ref tile) => tile,
+        None => return Vec::new(),
+    };
+    let tileset = state.tilegrid().tileset();
+    let mut flags = Vec::new();
+    if let Some(description) = tile.flip().description() {
+        flags.push(format!("flip {}", description));
+    }
+    if tileset.is_animated(tile) {
+        flags.push("animated".to_string());
+    }
+    if tileset.is_front(tile) {
+        flags.push("front".to_string());
+    }
+    if let Some(palette) = tileset.tile_palette(tile) {
+        flags.push(format!("palette {}", palette));
+    }
+    if cell == state.brush() {
+        flags.push("brush".to_string());
+    }
+    if cell == state.eraser_tile() {
+        flags.push("eraser".to_string());
+    }
+    flags
+}
+
+/// Draws a placed tile with the given opacity, mirrored according to its
+/// flip attributes.
+fn draw_tile(canvas: &mut Canvas, tile: &Tile, topleft: Point, alpha: u8) {
+    let flip = tile.flip();
+    canvas.draw_sprite_flipped_alpha(
+        tile.sprite(),
+        topleft,
+        flip.horz,
+        flip.vert,
+        alpha,
+    );
+}
 
-fn draw_marquee(canvas: &mut Canvas, rect: Rect, anim: i32) {
-    canvas.draw_rect((255, 255, 255, 255), rect);
-    let color = (0, 0, 0, 255);
-    for x in 0..(rect.width() as i32) {
-        if (x - anim).rem_euclid(MARQUEE_ANIMATION_MODULUS) < 4 {
-            canvas.draw_pixel(color, Point::new(rect.left() + x, rect.top()));
+/// Returns the cells along a straight line between two cells (inclusive),
+/// using Bresenham's algorithm.
+fn line_cells(from: (u32, u32), to: (u32, u32)) -> Vec<(u32, u32)> {
+    let (mut col, mut row) = (from.0 as i32, from.1 as i32);
+    let (end_col, end_row) = (to.0 as i32, to.1 as i32);
+    let delta_col = (end_col - col).abs();
+    let delta_row = -(end_row - row).abs();
+    let step_col = if col < end_col { 1 } else { -1 };
+    let step_row = if row < end_row { 1 } else { -1 };
+    let mut error = delta_col + delta_row;
+    let mut cells = Vec::new();
+    loop {
+        cells.push((col as u32, row as u32));
+        if col == end_col && row == end_row {
+            return cells;
         }
-        if (x + anim).rem_euclid(MARQUEE_ANIMATION_MODULUS) < 4 {
-            canvas.draw_pixel(
-                color,
-                Point::new(rect.left() + x, rect.bottom() - 1),
-            );
+        let doubled = 2 * error;
+        if doubled >= delta_row {
+            error += delta_row;
+            col += step_col;
+        }
+        if doubled <= delta_col {
+            error += delta_col;
+            row += step_row;
+        }
+    }
+}
+
+/// Returns the direction that the given key points in, if it's an arrow
+/// key or a keypad direction key.
+fn arrow_direction(keycode: Keycode) -> Option<(i32, i32)> {
+    match keycode {
+        Keycode::Left => Some((-1, 0)),
+        Keycode::Right => Some((1, 0)),
+        Keycode::Up => Some((0, -1)),
+        Keycode::Down => Some((0, 1)),
+        _ => keypad_direction(keycode),
+    }
+}
+
+/// Returns the direction that the given keypad number key points in
+/// (including the diagonals), if it's one of the eight around 5.
+fn keypad_direction(keycode: Keycode) -> Option<(i32, i32)> {
+    match keycode {
+        Keycode::Kp7 => Some((-1, -1)),
+        Keycode::Kp8 => Some((0, -1)),
+        Keycode::Kp9 => Some((1, -1)),
+        Keycode::Kp4 => Some((-1, 0)),
+        Keycode::Kp6 => Some((1, 0)),
+        Keycode::Kp1 => Some((-1, 1)),
+        Keycode::Kp2 => Some((0, 1)),
+        Keycode::Kp3 => Some((1, 1)),
+        _ => None,
+    }
+}
+
+/// Returns the cells that a flood fill starting at the given cell would
+/// change: those connected to it that have the same tile, without leaving
+/// the paint clip (if any).
+fn flood_region(
+    start: (u32, u32),
+    state: &EditorState,
+) -> BTreeSet<(u32, u32)> {
+    let tilegrid = state.tilegrid();
+    let from_tile = &tilegrid[start];
+    let mut region = BTreeSet::new();
+    if !state.clip_allows(start) {
+        return region;
+    }
+    region.insert(start);
+    let mut stack: Vec<(u32, u32)> = vec![start];
+    while let Some(position) = stack.pop() {
+        for coords in tilegrid.neighbors(position) {
+            if tilegrid[coords] == *from_tile
+                && state.clip_allows(coords)
+                && region.insert(coords)
+            {
+                stack.push(coords);
+            }
+        }
+    }
+    region
+}
+
+/// If the region rules forbid placing the given tile at the given cell,
+/// returns a notice saying so.
+/// Returns the cells that painting the brush at the given cell would set:
+/// just that cell for a single-tile brush, or the non-empty cells of a block
+/// brush placed with its top-left corner there.
+fn brush_stamp(
+    state: &EditorState,
+    (col, row): (u32, u32),
+) -> Vec<((u32, u32), Option<Tile>)> {
+    let brush = state.brush_grid();
+    if brush.size() == (1, 1) {
+        return vec![((col, row), brush[(0, 0)].clone())];
+    }
+    let mut cells = Vec::new();
+    for brush_row in 0..brush.height() {
+        for brush_col in 0..brush.width() {
+            let tile = &brush[(brush_col, brush_row)];
+            if tile.is_some() {
+                cells.push(((col + brush_col, row + brush_row), tile.clone()));
+            }
+        }
+    }
+    cells
+}
+
+/// Paints the given tiles at the given cells as a single change (or as
+/// part of the current Pencil stroke, if `stroke` is true).  Cells off the
+/// map or outside the paint clip, cells that already have their tile, and
+/// cells where the region rules forbid their tile, are left alone.  Returns
+/// true if the grid changed.
+fn paint_cells(
+    state: &mut EditorState,
+    cells: Vec<((u32, u32), Option<Tile>)>,
+    description: &'static str,
+    stroke: bool,
+) -> bool {
+    let tilegrid = state.tilegrid();
+    let mut changes = Vec::new();
+    let mut forbidden = None;
+    for (position, tile) in cells {
+        if position.0 < tilegrid.width()
+            && position.1 < tilegrid.height()
+            && state.clip_allows(position)
+            && !tilegrid.has_placed(position, &tile)
+        {
+            match forbidden_notice(state, position, &tile) {
+                Some(notice) => forbidden = Some(notice),
+                None => changes.push((position, tile)),
+            }
+        }
+    }
+    let changed = !changes.is_empty();
+    if changed {
+        let mut mutation = if stroke {
+            state.stroke_mutation(description)
+        } else {
+            state.mutation(description)
+        };
+        for (position, tile) in changes {
+            mutation.tilegrid().place_tile(position, tile);
+        }
+    }
+    if let Some(notice) = forbidden {
+        state.set_notice(notice);
+    }
+    changed
+}
+
+fn forbidden_notice(
+    state: &EditorState,
+    position: (u32, u32),
+    tile: &Option<Tile>,
+) -> Option<String> {
+    state
+        .forbidding_region(position, tile)
+        .map(|name| format!("Can't paint that in {}", name))
+}
+
+fn tile_rect_to_pixels(rect: Rect, tile_size: u32) -> Rect {
+    Rect::new(
+        rect.x() * tile_size as i32,
+        rect.y() * tile_size as i32,
+        rect.width() * tile_size,
+        rect.height() * tile_size,
+    )
+}
+
+/// Returns the rect (in pixels) of the given handle of a marquee.
+fn handle_rect(marquee_rect: Rect, (dx, dy): (i32, i32)) -> Rect {
+    let x = match dx {
+        -1 => marquee_rect.left(),
+        0 => marquee_rect.center().x(),
+        _ => marquee_rect.right() - 1,
+    };
+    let y = match dy {
+        -1 => marquee_rect.top(),
+        0 => marquee_rect.center().y(),
+        _ => marquee_rect.bottom() - 1,
+    };
+    let half = HANDLE_SIZE as i32 / 2;
+    Rect::new(x - half, y - half, HANDLE_SIZE, HANDLE_SIZE)
+}
+
+/// Returns the handle of the marquee under the given point, if any.
+fn handle_at(marquee_rect: Rect, pt: Point) -> Option<(i32, i32)> {
+    HANDLES
+        .iter()
+        .copied()
+        .find(|&handle| handle_rect(marquee_rect, handle).contains_point(pt))
+}
+
+/// Moves the edges of `rect` on the side(s) of the given handle by `delta`,
+/// keeping it at least one tile in each dimension.
+fn resize_rect(rect: Rect, (dx, dy): (i32, i32), delta: Point) -> Rect {
+    let (mut left, mut right) = (rect.left(), rect.right());
+    let (mut top, mut bottom) = (rect.top(), rect.bottom());
+    match dx {
+        -1 => left = min(left + delta.x(), right - 1),
+        1 => right = max(right + delta.x(), left + 1),
+        _ => {}
+    }
+    match dy {
+        -1 => top = min(top + delta.y(), bottom - 1),
+        1 => bottom = max(bottom + delta.y(), top + 1),
+        _ => {}
+    }
+    Rect::new(left, top, (right - left) as u32, (bottom - top) as u32)
+}
+
+//===========================================================================//
+
+#[rustfmt::skip]
+const DITHER_MATRIX: [[u32; 4]; 4] = [
+    [ 0,  8,  2, 10],
+    [12,  4, 14,  6],
+    [ 3, 11,  1,  9],
+    [15,  7, 13,  5],
+];
+
+//===========================================================================//
+
+/// How the game camera preview behaves, as set in the config file: the
+/// camera's `size` in pixels, and how many pixels per clock tick it moves
+/// during the scroll preview.
+#[derive(Clone, Copy)]
+pub struct CameraSettings {
+    size: (u32, u32),
+    pan_speed: u32,
+}
+
+impl CameraSettings {
+    pub fn from_config(config: &Config) -> CameraSettings {
+        let width = config
+            .get_parsed("camera.width")
+            .unwrap_or(DEFAULT_CAMERA_SIZE.0)
+            .max(1);
+        let height = config
+            .get_parsed("camera.height")
+            .unwrap_or(DEFAULT_CAMERA_SIZE.1)
+            .max(1);
+        CameraSettings {
+            size: (width, height),
+            pan_speed: config
+                .get_parsed("camera.pan_speed")
+                .unwrap_or(DEFAULT_CAMERA_PAN_SPEED)
+                .max(1),
         }
     }
-    for y in 0..(rect.height() as i32) {
-        if (y + anim).rem_euclid(MARQUEE_ANIMATION_MODULUS) >= 4 {
-            canvas.draw_pixel(color, Point::new(rect.left(), rect.top() + y));
+}
+
+//===========================================================================//
+
+/// Returns the width and height, in tiles, of the blocks of tiles that share
+/// a palette, as set by `attributes.block_size` in the config file.
+pub fn attribute_block_size_from_config(config: &Config) -> u32 {
+    config
+        .get_parsed("attributes.block_size")
+        .unwrap_or(DEFAULT_ATTRIBUTE_BLOCK_SIZE)
+        .max(1)
+}
+
+//===========================================================================//
+
+/// How selection marquees are drawn, as set in the config file.  The
+/// marquee alternates between dashes of two colors, which march along by
+/// `speed` pixels per clock tick (or stay still, if `speed` is zero or the
+/// theme turns animations off).  Its thickness comes from the theme.
+#[derive(Clone, Copy)]
+pub struct MarqueeStyle {
+    light_color: (u8, u8, u8, u8),
+    dark_color: (u8, u8, u8, u8),
+    // Tints the cells that a new selection will capture while it's being
+    // dragged out:
+    tint_color: (u8, u8, u8, u8),
+    dash_length: i32,
+    speed: i32,
+    width: u32,
+}
+
+impl MarqueeStyle {
+    pub fn from_config(config: &Config, theme: &Theme) -> MarqueeStyle {
+        MarqueeStyle {
+            light_color: config
+                .get_color("marquee.light_color")
+                .unwrap_or((255, 255, 255, 255)),
+            dark_color: config
+                .get_color("marquee.dark_color")
+                .unwrap_or((0, 0, 0, 255)),
+            tint_color: config
+                .get_color("marquee.tint_color")
+                .unwrap_or((255, 255, 255, 64)),
+            dash_length: config
+                .get_parsed("marquee.dash_length")
+                .unwrap_or(4)
+                .max(1),
+            speed: if theme.animations {
+                config.get_parsed("marquee.speed").unwrap_or(1)
+            } else {
+                0
+            },
+            width: theme.outline_width,
         }
-        if (y - anim).rem_euclid(MARQUEE_ANIMATION_MODULUS) >= 4 {
-            canvas.draw_pixel(
-                color,
-                Point::new(rect.right() - 1, rect.top() + y),
+    }
+
+    fn draw_handle(&self, canvas: &mut Canvas, rect: Rect) {
+        canvas.fill_rect(self.light_color, rect);
+        canvas.draw_rect(self.dark_color, rect);
+    }
+
+    fn draw(&self, canvas: &mut Canvas, rect: Rect, anim: i32) {
+        for inset in 0..self.width {
+            if rect.width() <= 2 * inset || rect.height() <= 2 * inset {
+                break;
+            }
+            let ring = Rect::new(
+                rect.x() + inset as i32,
+                rect.y() + inset as i32,
+                rect.width() - 2 * inset,
+                rect.height() - 2 * inset,
             );
+            self.draw_ring(canvas, ring, anim);
+        }
+    }
+
+    fn draw_ring(&self, canvas: &mut Canvas, rect: Rect, anim: i32) {
+        let dash = self.dash_length;
+        canvas.draw_rect(self.light_color, rect);
+        let color = self.dark_color;
+        for x in 0..(rect.width() as i32) {
+            if (x - anim).rem_euclid(2 * dash) < dash {
+                canvas.draw_pixel(
+                    color,
+                    Point::new(rect.left() + x, rect.top()),
+                );
+            }
+            if (x + anim).rem_euclid(2 * dash) < dash {
+                canvas.draw_pixel(
+                    color,
+                    Point::new(rect.left() + x, rect.bottom() - 1),
+                );
+            }
+        }
+        for y in 0..(rect.height() as i32) {
+            if (y + anim).rem_euclid(2 * dash) >= dash {
+                canvas.draw_pixel(
+                    color,
+                    Point::new(rect.left(), rect.top() + y),
+                );
+            }
+            if (y - anim).rem_euclid(2 * dash) >= dash {
+                canvas.draw_pixel(
+                    color,
+                    Point::new(rect.right() - 1, rect.top() + y),
+                );
+            }
         }
     }
 }
@@ -17,12 +17,16 @@
 // | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
 // +--------------------------------------------------------------------------+
 
-use super::canvas::{Canvas, Sprite};
-use super::element::{Action, AggregateElement, GuiElement, SubrectElement};
-use super::event::{Event, Keycode, NONE};
+use super::canvas::{Canvas, Font, Sprite};
+use super::element::{
+    Action, AggregateElement, GuiElement, Movable, SubrectElement,
+};
+use super::event::{Event, KeyMod, Keycode, NONE, SHIFT};
 use super::state::{EditorState, Tool};
-use super::tilegrid::{Tile, Tileset};
+use super::theme::Theme;
+use super::tilegrid::{SubGrid, Tile, Tileset};
 use sdl2::rect::{Point, Rect};
+use std::cell::Cell;
 use std::cmp::max;
 use std::rc::Rc;
 
@@ -32,17 +36,99 @@ struct PaletteState {
     tileset: Rc<Tileset>,
     index: usize,
     brush: Option<Tile>,
+    alt_brush: Option<Tile>,
+    // The tiles of the brush, if it's a block of tiles:
+    brush_block: Vec<Tile>,
+    eraser_tile: Option<Tile>,
+    // Whether Shift is held, so that pressing a tile starts picking a block:
+    shift: bool,
+    // Set when a block of tiles is picked, to the new brush:
+    block: Option<SubGrid>,
+    // How many cells of the grid use tiles from the current file (only
+    // computed for drawing):
+    num_placed: Option<usize>,
+    // Whether to highlight the brush's entry (when flashing it):
+    flash: bool,
+    // Set when the page label is clicked, to open the file dropdown:
+    open_dropdown: bool,
+    // Set when a tile (or the eraser) is pressed, to the tile pressed:
+    picked: Option<Option<Tile>>,
+}
+
+impl PaletteState {
+    fn new(
+        state: &EditorState,
+        index: usize,
+        num_placed: Option<usize>,
+    ) -> PaletteState {
+        let tileset = state.tilegrid().tileset();
+        let brush_grid = state.brush_grid();
+        let mut brush_block = Vec::new();
+        if brush_grid.size() != (1, 1) {
+            for row in 0..brush_grid.height() {
+                for col in 0..brush_grid.width() {
+                    brush_block.extend(brush_grid[(col, row)].clone());
+                }
+            }
+        }
+        PaletteState {
+            tileset,
+            index,
+            brush: state.brush().clone(),
+            alt_brush: state.alt_brush().clone(),
+            brush_block,
+            eraser_tile: state.eraser_tile().clone(),
+            shift: false,
+            block: None,
+            num_placed,
+            flash: false,
+            open_dropdown: false,
+            picked: None,
+        }
+    }
 }
 
 //===========================================================================//
 
+const FLASH_FRAMES: u32 = 12;
+const FLASH_BLINK_FRAMES: u32 = 3;
+
 pub struct TilePalette {
     element: SubrectElement<AggregateElement<PaletteState, ()>>,
+    font: Rc<Font>,
     tileset_index: usize,
+    theme: Theme,
+    flash_frames: u32,
+    // Whether the dropdown list of tile files is open:
+    dropdown_open: bool,
+    // The index of the first tile file shown in the dropdown, when there
+    // are too many files to list at once:
+    dropdown_scroll: usize,
+    // The index of the tile file whose label (or dropdown entry) the mouse
+    // is hovering over, if any, along with the top of that label:
+    hover_file: Option<(usize, i32)>,
+    // The tile pressed in the palette, while the mouse button is still
+    // down, so that it can be dragged onto the canvas to paint with:
+    dragged_tile: Option<Option<Tile>>,
+    // The modifier keys currently held down (as of the last key or mouse
+    // event):
+    keymod: KeyMod,
+    // The grid generation and file index that the number of placed tiles
+    // was last counted for, and that number, so that drawing only rescans
+    // the grid after it changes:
+    num_placed: Cell<Option<(u64, usize, usize)>>,
 }
 
 impl TilePalette {
-    pub fn new(left: i32, top: i32, mut icons: Vec<Sprite>) -> TilePalette {
+    pub fn new(
+        left: i32,
+        top: i32,
+        width: u32,
+        height: u32,
+        mut icons: Vec<Sprite>,
+        font: Rc<Font>,
+        theme: Theme,
+    ) -> TilePalette {
         icons.truncate(2);
         assert_eq!(icons.len(), 2);
         let right_arrow = icons.pop().unwrap();
@@ -53,37 +139,410 @@ impl TilePalette {
                 Rect::new(2, 2, 42, 20),
             )),
             Box::new(SubrectElement::new(
-                ArrowButton::new(-1, Keycode::Left, left_arrow),
+                ArrowButton::new(
+                    -1,
+                    Keycode::PageUp,
+                    Keycode::Home,
+                    left_arrow,
+                ),
                 Rect::new(4, 26, 16, 16),
             )),
             Box::new(SubrectElement::new(
-                ArrowButton::new(1, Keycode::Right, right_arrow),
+                ArrowButton::new(
+                    1,
+                    Keycode::PageDown,
+                    Keycode::End,
+                    right_arrow,
+                ),
                 Rect::new(26, 26, 16, 16),
             )),
             Box::new(SubrectElement::new(
-                InnerPalette::new(),
-                Rect::new(0, 42, 46, 258),
+                PageLabel::new(font.clone()),
+                Rect::new(2, 42, width.saturating_sub(4), PAGE_LABEL_HEIGHT),
+            )),
+            Box::new(SubrectElement::new(
+                PaletteHeader::new(font.clone()),
+                Rect::new(
+                    2,
+                    42 + PAGE_LABEL_HEIGHT as i32,
+                    width.saturating_sub(4),
+                    HEADER_HEIGHT,
+                ),
+            )),
+            Box::new(SubrectElement::new(
+                InnerPalette::new(max(1, (width as i32 - 2) / 22)),
+                Rect::new(
+                    0,
+                    42 + (PAGE_LABEL_HEIGHT + HEADER_HEIGHT) as i32,
+                    width,
+                    height.saturating_sub(
+                        42 + PAGE_LABEL_HEIGHT + HEADER_HEIGHT,
+                    ),
+                ),
             )),
         ];
         TilePalette {
             element: SubrectElement::new(
                 AggregateElement::new(elements),
-                Rect::new(left, top, 46, 300),
+                Rect::new(left, top, width, height),
             ),
+            font,
             tileset_index: 0,
+            theme,
+            flash_frames: 0,
+            dropdown_open: false,
+            dropdown_scroll: 0,
+            hover_file: None,
+            dragged_tile: None,
+            keymod: NONE,
+            num_placed: Cell::new(None),
+        }
+    }
+
+    /// Returns how many cells of the grid use tiles from the current file,
+    /// counting them only if the grid or the file has changed since the
+    /// last call.
+    fn num_placed(&self, state: &EditorState) -> Option<usize> {
+        let generation = state.generation();
+        let index = self.tileset_index;
+        if let Some((gen, idx, count)) = self.num_placed.get() {
+            if (gen, idx) == (generation, index) {
+                return Some(count);
+            }
+        }
+        let filename = state.tilegrid().tileset().filenames().nth(index)?;
+        let count = state.tilegrid().count_tiles_from(&filename);
+        self.num_placed.set(Some((generation, index, count)));
+        Some(count)
+    }
+
+    /// Returns how many tile files the dropdown lists at once.
+    fn dropdown_rows(tileset: &Tileset) -> usize {
+        tileset.num_filenames().min(DROPDOWN_MAX_ROWS)
+    }
+
+    /// Returns the rect of the dropdown list of tile files, which hangs
+    /// below the page label (and may extend past the palette's right edge
+    /// to fit long filenames).
+    fn dropdown_rect(&self, tileset: &Tileset) -> Rect {
+        let rect = self.element.rect();
+        let mut width = rect.width().saturating_sub(4) as i32;
+        for filename in tileset.filenames() {
+            width = max(
+                width,
+                self.font.text_width(&filename) + 6 + DROPDOWN_ARROW_WIDTH,
+            );
+        }
+        Rect::new(
+            rect.x() + 2,
+            rect.y() + 42 + PAGE_LABEL_HEIGHT as i32,
+            width as u32,
+            TilePalette::dropdown_rows(tileset) as u32 * DROPDOWN_ROW_HEIGHT,
+        )
+    }
+
+    /// Opens the file dropdown, scrolled so that the current file is
+    /// listed.
+    fn open_dropdown(&mut self, tileset: &Tileset) {
+        self.dropdown_open = true;
+        let rows = TilePalette::dropdown_rows(tileset);
+        self.dropdown_scroll = 0;
+        self.scroll_dropdown(
+            tileset,
+            self.tileset_index as isize - (rows / 2) as isize,
+        );
+    }
+
+    /// Scrolls the file dropdown by the given number of rows, staying
+    /// within the list; returns true if it moved.
+    fn scroll_dropdown(&mut self, tileset: &Tileset, delta: isize) -> bool {
+        let max_scroll =
+            tileset.num_filenames() - TilePalette::dropdown_rows(tileset);
+        let scroll = (self.dropdown_scroll as isize + delta)
+            .max(0)
+            .min(max_scroll as isize) as usize;
+        let changed = scroll != self.dropdown_scroll;
+        self.dropdown_scroll = scroll;
+        changed
+    }
+
+    fn draw_dropdown(&self, tileset: &Tileset, canvas: &mut Canvas) {
+        let rect = self.dropdown_rect(tileset);
+        canvas.fill_rect(self.theme.popup, rect);
+        let rows = TilePalette::dropdown_rows(tileset);
+        let shown = tileset
+            .filenames()
+            .enumerate()
+            .skip(self.dropdown_scroll)
+            .take(rows);
+        for (row, (index, filename)) in shown.enumerate() {
+            let top = rect.y() + (row as u32 * DROPDOWN_ROW_HEIGHT) as i32;
+            if index == self.tileset_index {
+                canvas.fill_rect(
+                    self.theme.panel,
+                    Rect::new(
+                        rect.x(),
+                        top,
+                        rect.width(),
+                        DROPDOWN_ROW_HEIGHT,
+                    ),
+                );
+            }
+            let start = Point::new(rect.x() + 3, top + self.font.baseline());
+            canvas.draw_text(&self.font, start, &filename);
+        }
+        // Mark the ends of the list that are scrolled out of view; clicking
+        // a mark pages the list that way.
+        let arrow_left = rect.right() - DROPDOWN_ARROW_WIDTH;
+        if self.dropdown_scroll > 0 {
+            let start =
+                Point::new(arrow_left, rect.y() + self.font.baseline());
+            canvas.draw_text(&self.font, start, "^");
+        }
+        if self.dropdown_scroll + rows < tileset.num_filenames() {
+            let top = rect.bottom() - DROPDOWN_ROW_HEIGHT as i32;
+            let start = Point::new(arrow_left, top + self.font.baseline());
+            canvas.draw_text(&self.font, start, "v");
+        }
+        canvas.draw_rect(SELECTED_COLOR, rect);
+    }
+
+    /// Returns the tile file index (and label top) to preview for the given
+    /// mouse position: that of the dropdown entry under the mouse, or of
+    /// the current page when over the page label or header.
+    fn file_under_mouse(
+        &self,
+        tileset: &Tileset,
+        pt: Point,
+    ) -> Option<(usize, i32)> {
+        if self.dropdown_open {
+            let rect = self.dropdown_rect(tileset);
+            if rect.contains_point(pt) {
+                let row = (pt.y() - rect.y()) as u32 / DROPDOWN_ROW_HEIGHT;
+                let top = rect.y() + (row * DROPDOWN_ROW_HEIGHT) as i32;
+                return Some((self.dropdown_scroll + row as usize, top));
+            }
+        }
+        let rect = self.element.rect();
+        let label_rect = Rect::new(
+            rect.x() + 2,
+            rect.y() + 42,
+            rect.width().saturating_sub(4),
+            PAGE_LABEL_HEIGHT + HEADER_HEIGHT,
+        );
+        if label_rect.contains_point(pt) && tileset.num_filenames() > 0 {
+            Some((self.tileset_index, label_rect.y()))
+        } else {
+            None
+        }
+    }
+
+    /// Draws every tile in the hovered-over file in a grid beside the
+    /// palette (or the dropdown, if open), so the file can be inspected
+    /// without paging to it.
+    fn draw_preview(&self, tileset: &Tileset, canvas: &mut Canvas) {
+        let (index, top) = match self.hover_file {
+            Some(hover) => hover,
+            None => return,
+        };
+        let num_tiles = tileset.tiles(index).count() as u32;
+        if num_tiles == 0 {
+            return;
+        }
+        let left = if self.dropdown_open {
+            self.dropdown_rect(tileset).right() + 2
+        } else {
+            self.element.rect().right() + 2
+        };
+        let spacing = tileset.tile_size() + 2;
+        let columns = num_tiles.min(PREVIEW_COLUMNS);
+        let rows = num_tiles.div_ceil(columns);
+        let rect =
+            Rect::new(left, top, columns * spacing + 2, rows * spacing + 2);
+        canvas.fill_rect(self.theme.popup, rect);
+        canvas.draw_rect(SELECTED_COLOR, rect);
+        for (tile_index, tile) in tileset.tiles(index).enumerate() {
+            let col = tile_index as u32 % columns;
+            let row = tile_index as u32 / columns;
+            let topleft = Point::new(
+                left + 2 + (col * spacing) as i32,
+                top + 2 + (row * spacing) as i32,
+            );
+            canvas.draw_sprite(tile.sprite(), topleft);
+        }
+    }
+
+    /// Handles an event while the file dropdown is open; clicking a file
+    /// flips to its page, and any other click (except on a scroll mark) or
+    /// Escape closes the dropdown.  The arrow and page keys scroll the list.
+    fn on_dropdown_event(
+        &mut self,
+        event: &Event,
+        state: &EditorState,
+    ) -> Option<Action<()>> {
+        let tileset = state.tilegrid().tileset();
+        let page = TilePalette::dropdown_rows(&tileset) as isize;
+        match event {
+            &Event::MouseDown(pt, _) => {
+                let rect = self.dropdown_rect(&tileset);
+                if rect.contains_point(pt) {
+                    let row = (pt.y() - rect.y()) as u32 / DROPDOWN_ROW_HEIGHT;
+                    let on_arrow =
+                        pt.x() >= rect.right() - DROPDOWN_ARROW_WIDTH;
+                    if on_arrow && row == 0 && self.dropdown_scroll > 0 {
+                        self.scroll_dropdown(&tileset, -(page - 1));
+                        return Some(Action::redraw().and_stop());
+                    }
+                    if on_arrow
+                        && row as isize == page - 1
+                        && self.scroll_dropdown(&tileset, page - 1)
+                    {
+                        return Some(Action::redraw().and_stop());
+                    }
+                    self.tileset_index = self.dropdown_scroll + row as usize;
+                }
+                self.dropdown_open = false;
+                Some(Action::redraw().and_stop())
+            }
+            &Event::KeyDown(Keycode::Up, _) => Some(
+                Action::redraw_if(self.scroll_dropdown(&tileset, -1))
+                    .and_stop(),
+            ),
+            &Event::KeyDown(Keycode::Down, _) => Some(
+                Action::redraw_if(self.scroll_dropdown(&tileset, 1))
+                    .and_stop(),
+            ),
+            &Event::KeyDown(Keycode::PageUp, _) => Some(
+                Action::redraw_if(self.scroll_dropdown(&tileset, -page))
+                    .and_stop(),
+            ),
+            &Event::KeyDown(Keycode::PageDown, _) => Some(
+                Action::redraw_if(self.scroll_dropdown(&tileset, page))
+                    .and_stop(),
+            ),
+            &Event::KeyDown(Keycode::Escape, _) => {
+                self.dropdown_open = false;
+                Some(Action::redraw().and_stop())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the index of the tile file whose page is currently shown.
+    pub fn page(&self) -> usize {
+        self.tileset_index
+    }
+
+    /// Flips the palette to the page for the given tile file index (if the
+    /// tileset has that many files).
+    pub fn set_page(&mut self, state: &EditorState, index: usize) {
+        if index < state.tilegrid().tileset().num_filenames() {
+            self.tileset_index = index;
+        }
+    }
+
+    /// Flips the palette to the page for the tile file containing the
+    /// current brush.  Returns true if the page changed.
+    pub fn show_brush_file(&mut self, state: &EditorState) -> bool {
+        let filename = match *state.brush() {
+            Some(ref tile) => tile.filename(),
+            None => return false,
+        };
+        let tileset = state.tilegrid().tileset();
+        match tileset.filenames().position(|name| name == filename) {
+            Some(index) if index != self.tileset_index => {
+                self.tileset_index = index;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Moves the current page's tile file `delta` places earlier or later in
+    /// the tileset's file order, keeping the palette on its page.  Returns
+    /// true if the file moved.
+    fn move_page_file(&mut self, state: &mut EditorState, delta: i32) -> bool {
+        let num_filenames = state.tilegrid().tileset().num_filenames();
+        let new_index = self.tileset_index as i32 + delta;
+        if new_index < 0 || new_index >= num_filenames as i32 {
+            return false;
+        }
+        let new_index = new_index as usize;
+        state.unselect_if_necessary();
+        state
+            .mutation("reorder tile files")
+            .tilegrid()
+            .move_tile_file(self.tileset_index, new_index);
+        self.tileset_index = new_index;
+        if let Some(filename) =
+            state.tilegrid().tileset().filenames().nth(new_index)
+        {
+            state.set_notice(format!(
+                "{} is now file {} of {}",
+                filename,
+                new_index + 1,
+                num_filenames
+            ));
+        }
+        true
+    }
+
+    /// Returns the tile pressed in the palette, if the mouse button is still
+    /// down after pressing it.
+    pub fn dragged_tile(&self) -> Option<&Option<Tile>> {
+        self.dragged_tile.as_ref()
+    }
+
+    /// Starts blinking a highlight around the current brush's palette entry
+    /// (e.g. after picking it with the eyedropper), so that the user can see
+    /// where it came from.  Returns true if a redraw is needed.
+    pub fn flash_brush(&mut self, state: &EditorState) -> bool {
+        if state.brush().is_some() {
+            self.show_brush_file(state);
+            self.flash_frames = FLASH_FRAMES;
+            true
+        } else {
+            false
         }
     }
 }
 
+impl Movable for TilePalette {
+    fn rect(&self) -> Rect {
+        self.element.rect()
+    }
+
+    fn move_to(&mut self, topleft: Point) {
+        let rect = self.element.rect();
+        self.element.set_rect(Rect::new(
+            topleft.x(),
+            topleft.y(),
+            rect.width(),
+            rect.height(),
+        ));
+    }
+}
+
 impl GuiElement<EditorState, ()> for TilePalette {
     fn draw(&self, state: &EditorState, canvas: &mut Canvas) {
-        canvas.fill_rect((95, 95, 95, 255), self.element.rect());
-        let palette_state = PaletteState {
-            tileset: state.tilegrid().tileset(),
-            index: self.tileset_index,
-            brush: state.brush().clone(),
-        };
+        canvas.fill_rect(self.theme.panel, self.element.rect());
+        let num_placed = self.num_placed(state);
+        let mut palette_state =
+            PaletteState::new(state, self.tileset_index, num_placed);
+        // Without animations, the highlight stays on instead of blinking:
+        palette_state.flash = self.flash_frames > 0
+            && (!self.theme.animations
+                || (self.flash_frames - 1) % (2 * FLASH_BLINK_FRAMES)
+                    >= FLASH_BLINK_FRAMES);
         self.element.draw(&palette_state, canvas);
+        if state.brush_locked() {
+            let rect = self.element.rect();
+            draw_lock_icon(canvas, Point::new(rect.x() + 20, rect.y() + 30));
+        }
+        if self.dropdown_open {
+            self.draw_dropdown(&palette_state.tileset, canvas);
+        }
+        self.draw_preview(&palette_state.tileset, canvas);
     }
 
     fn on_event(
@@ -91,14 +550,77 @@ impl GuiElement<EditorState, ()> for TilePalette {
         event: &Event,
         state: &mut EditorState,
     ) -> Action<()> {
-        let mut palette_state = PaletteState {
-            tileset: state.tilegrid().tileset(),
-            index: self.tileset_index,
-            brush: state.brush().clone(),
-        };
+        if let &Event::MouseUp(_) = event {
+            self.dragged_tile = None;
+        }
+        if let &Event::KeyDown(_, kmod)
+        | &Event::KeyUp(_, kmod)
+        | &Event::MouseDown(_, kmod)
+        | &Event::MouseDrag(_, kmod)
+        | &Event::MouseUp(kmod) = event
+        {
+            self.keymod = kmod;
+        }
+        if let &Event::MouseMove(pt) = event {
+            let tileset = state.tilegrid().tileset();
+            let hover = self.file_under_mouse(&tileset, pt);
+            let changed = hover != self.hover_file;
+            self.hover_file = hover;
+            return Action::redraw_if(changed);
+        }
+        if self.dropdown_open {
+            if let Some(action) = self.on_dropdown_event(event, state) {
+                self.hover_file = None;
+                return action;
+            }
+        }
+        match event {
+            &Event::ClockTick if self.flash_frames > 0 => {
+                self.flash_frames -= 1;
+                return Action::redraw();
+            }
+            &Event::KeyDown(Keycode::W, kmod) if kmod == NONE => {
+                state.swap_brushes();
+                return Action::redraw().and_stop();
+            }
+            &Event::KeyDown(Keycode::L, kmod) if kmod == NONE => {
+                state.toggle_brush_locked();
+                return Action::redraw().and_stop();
+            }
+            &Event::KeyDown(Keycode::J, kmod) if kmod == NONE => {
+                return Action::redraw_if(self.show_brush_file(state))
+                    .and_stop();
+            }
+            &Event::KeyDown(Keycode::PageUp, kmod) if kmod == SHIFT => {
+                return Action::redraw_if(self.move_page_file(state, -1))
+                    .and_stop();
+            }
+            &Event::KeyDown(Keycode::PageDown, kmod) if kmod == SHIFT => {
+                return Action::redraw_if(self.move_page_file(state, 1))
+                    .and_stop();
+            }
+            _ => {}
+        }
+        let mut palette_state =
+            PaletteState::new(state, self.tileset_index, None);
+        palette_state.shift = self.keymod == SHIFT;
         let action = self.element.on_event(event, &mut palette_state);
         self.tileset_index = palette_state.index;
-        if palette_state.brush != *state.brush() {
+        if palette_state.open_dropdown {
+            self.open_dropdown(&state.tilegrid().tileset());
+        }
+        if palette_state.picked.is_some() {
+            self.dragged_tile = palette_state.picked;
+        }
+        if state.brush_locked() {
+            return action;
+        }
+        if let Some(block) = palette_state.block {
+            state.set_brush_grid(block);
+            if state.tool() == Tool::Select {
+                state.set_tool(Tool::Pencil);
+            }
+        } else if palette_state.brush != *state.brush() {
             state.set_brush(palette_state.brush);
             if state.tool() == Tool::Select {
                 state.set_tool(Tool::Pencil);
@@ -110,23 +632,208 @@ impl GuiElement<EditorState, ()> for TilePalette {
 
 //===========================================================================//
 
+const PAGE_LABEL_HEIGHT: u32 = 12;
+const DROPDOWN_ROW_HEIGHT: u32 = 11;
+const DROPDOWN_MAX_ROWS: usize = 20;
+const DROPDOWN_ARROW_WIDTH: i32 = 9;
+const PREVIEW_COLUMNS: u32 = 8;
+
+/// Shows which tile file's page is current (e.g. "file 2 of 5"); clicking
+/// it opens a dropdown list of all the files.
+struct PageLabel {
+    font: Rc<Font>,
+}
+
+impl PageLabel {
+    fn new(font: Rc<Font>) -> PageLabel {
+        PageLabel { font }
+    }
+}
+
+impl GuiElement<PaletteState, ()> for PageLabel {
+    fn draw(&self, state: &PaletteState, canvas: &mut Canvas) {
+        let num = state.tileset.num_filenames();
+        if num == 0 {
+            return;
+        }
+        let width = canvas.rect().width() as i32;
+        let current = state.index + 1;
+        let candidates = [
+            format!("file {} of {}", current, num),
+            format!("{} of {}", current, num),
+            format!("{}/{}", current, num),
+        ];
+        let text = candidates
+            .iter()
+            .find(|text| self.font.text_width(text) <= width)
+            .unwrap_or(&candidates[2]);
+        let left = max(0, (width - self.font.text_width(text)) / 2);
+        canvas.draw_text(
+            &self.font,
+            Point::new(left, self.font.baseline()),
+            text,
+        );
+    }
+
+    fn on_event(
+        &mut self,
+        event: &Event,
+        state: &mut PaletteState,
+    ) -> Action<()> {
+        match event {
+            &Event::MouseDown(_, _) if state.tileset.num_filenames() > 0 => {
+                state.open_dropdown = true;
+                Action::redraw().and_stop()
+            }
+            _ => Action::ignore(),
+        }
+    }
+}
+
+//===========================================================================//
+
+const HEADER_HEIGHT: u32 = 20;
+
+/// Shows the name of the current tile file, and how many tiles it has and
+/// how many cells of the grid use them.
+struct PaletteHeader {
+    font: Rc<Font>,
+}
+
+impl PaletteHeader {
+    fn new(font: Rc<Font>) -> PaletteHeader {
+        PaletteHeader { font }
+    }
+
+    /// Truncates the text (if necessary) to fit within the given width.
+    fn fit_text(&self, text: &str, width: i32) -> String {
+        let mut text = text.to_string();
+        while !text.is_empty() && self.font.text_width(&text) > width {
+            text.pop();
+        }
+        text
+    }
+}
+
+impl GuiElement<PaletteState, ()> for PaletteHeader {
+    fn draw(&self, state: &PaletteState, canvas: &mut Canvas) {
+        let filename = match state.tileset.filenames().nth(state.index) {
+            Some(filename) => filename,
+            None => return,
+        };
+        let width = canvas.rect().width() as i32;
+        let baseline = self.font.baseline();
+        let name = self.fit_text(&filename, width);
+        canvas.draw_text(&self.font, Point::new(0, baseline), &name);
+        let num_tiles = state.tileset.tiles(state.index).count();
+        let num_placed = state.num_placed.unwrap_or(0);
+        let mut counts = format!("{} tiles, {} placed", num_tiles, num_placed);
+        if self.font.text_width(&counts) > width {
+            counts = format!("{}/{}", num_tiles, num_placed);
+        }
+        let counts = self.fit_text(&counts, width);
+        let start = Point::new(0, baseline + HEADER_HEIGHT as i32 / 2);
+        canvas.draw_text(&self.font, start, &counts);
+    }
+
+    fn on_event(&mut self, _: &Event, _: &mut PaletteState) -> Action<()> {
+        Action::ignore()
+    }
+}
+
+//===========================================================================//
+
 const SELECTED_COLOR: (u8, u8, u8, u8) = (255, 255, 255, 255);
+const ALT_SELECTED_COLOR: (u8, u8, u8, u8) = (0, 0, 0, 255);
+const FLASH_COLOR: (u8, u8, u8, u8) = (255, 255, 0, 255);
+const LOCK_COLOR: (u8, u8, u8, u8) = (255, 255, 0, 255);
 
-struct InnerPalette {}
+struct InnerPalette {
+    columns: i32,
+    // While Shift-dragging out a block of tiles, the index of the tile
+    // where the drag started:
+    block_anchor: Option<usize>,
+}
 
 impl InnerPalette {
-    fn new() -> InnerPalette {
-        InnerPalette {}
+    fn new(columns: i32) -> InnerPalette {
+        InnerPalette { columns, block_anchor: None }
+    }
+
+    fn tile_topleft(&self, index: usize) -> Point {
+        let left = 4 + 22 * (index as i32 % self.columns);
+        let top = 4 + 22 * (index as i32 / self.columns);
+        Point::new(left, top)
+    }
+
+    /// Returns the index of the tile (on the current page) under the given
+    /// point, if any.
+    fn tile_at(&self, state: &PaletteState, pt: Point) -> Option<usize> {
+        (0..state.tileset.tiles(state.index).count()).find(|&index| {
+            let topleft = self.tile_topleft(index);
+            Rect::new(topleft.x(), topleft.y(), 16, 16).contains_point(pt)
+        })
+    }
+
+    /// Returns the block of tiles spanning the rectangle (as laid out in
+    /// the palette) with the given tiles at opposite corners.  Places in
+    /// the rectangle past the last tile are left empty.
+    fn block_between(
+        &self,
+        state: &PaletteState,
+        from: usize,
+        to: usize,
+    ) -> SubGrid {
+        let columns = self.columns as usize;
+        let (left, right) = (
+            (from % columns).min(to % columns),
+            (from % columns).max(to % columns),
+        );
+        let (top, bottom) = (
+            (from / columns).min(to / columns),
+            (from / columns).max(to / columns),
+        );
+        let tiles: Vec<Tile> = state.tileset.tiles(state.index).collect();
+        let mut block =
+            SubGrid::new((right - left + 1) as u32, (bottom - top + 1) as u32);
+        for row in top..=bottom {
+            for col in left..=right {
+                if let Some(tile) = tiles.get(row * columns + col) {
+                    block.set(
+                        ((col - left) as u32, (row - top) as u32),
+                        Some(tile.clone()),
+                    );
+                }
+            }
+        }
+        block
     }
 }
 
 impl GuiElement<PaletteState, ()> for InnerPalette {
     fn draw(&self, state: &PaletteState, canvas: &mut Canvas) {
+        // Highlight the brushes' entries even if they're mirrored.
+        let brush = state.brush.as_ref().map(Tile::unflipped);
+        let alt_brush = state.alt_brush.as_ref().map(Tile::unflipped);
         for (index, tile) in state.tileset.tiles(state.index).enumerate() {
-            let left = 4 + 22 * (index % 2) as i32;
-            let top = 4 + 22 * (index / 2) as i32;
-            canvas.draw_sprite(tile.sprite(), Point::new(left, top));
-            if Some(tile) == state.brush {
+            let topleft = self.tile_topleft(index);
+            let (left, top) = (topleft.x(), topleft.y());
+            if state.flash && Some(&tile) == brush.as_ref() {
+                canvas.fill_rect(
+                    FLASH_COLOR,
+                    Rect::new(left - 3, top - 3, 22, 22),
+                );
+            }
+            canvas.draw_sprite(tile.sprite(), topleft);
+            if Some(&tile) == alt_brush.as_ref() {
+                canvas.draw_rect(
+                    ALT_SELECTED_COLOR,
+                    Rect::new(left - 1, top - 1, 18, 18),
+                );
+            }
+            if Some(&tile) == brush.as_ref()
+                || state.brush_block.contains(&tile)
+            {
                 canvas.draw_rect(
                     SELECTED_COLOR,
                     Rect::new(left - 2, top - 2, 20, 20),
@@ -141,20 +848,46 @@ impl GuiElement<PaletteState, ()> for InnerPalette {
         state: &mut PaletteState,
     ) -> Action<()> {
         match event {
-            &Event::MouseDown(pt) => {
+            &Event::MouseDown(pt, _) if state.shift => {
+                match self.tile_at(state, pt) {
+                    Some(index) => {
+                        self.block_anchor = Some(index);
+                        state.block =
+                            Some(self.block_between(state, index, index));
+                        Action::redraw().and_stop()
+                    }
+                    None => Action::ignore(),
+                }
+            }
+            &Event::MouseDrag(pt, _) if self.block_anchor.is_some() => {
+                let anchor = self.block_anchor.unwrap();
+                match self.tile_at(state, pt) {
+                    Some(index) => {
+                        state.block =
+                            Some(self.block_between(state, anchor, index));
+                        Action::redraw().and_stop()
+                    }
+                    None => Action::ignore().and_stop(),
+                }
+            }
+            &Event::MouseUp(_) => {
+                self.block_anchor = None;
+                Action::ignore()
+            }
+            &Event::MouseDown(pt, _) => {
                 let mut found = None;
                 for (index, tile) in
                     state.tileset.tiles(state.index).enumerate()
                 {
-                    let left = 4 + 22 * (index % 2) as i32;
-                    let top = 4 + 22 * (index / 2) as i32;
-                    let rect = Rect::new(left, top, 16, 16);
+                    let topleft = self.tile_topleft(index);
+                    let rect = Rect::new(topleft.x(), topleft.y(), 16, 16);
                     if rect.contains_point(pt) {
                         found = Some(Some(tile));
                         break;
                     }
                 }
                 if let Some(brush) = found {
+                    state.picked = Some(brush.clone());
                     state.brush = brush;
                     Action::redraw().and_stop()
                 } else {
@@ -182,7 +915,19 @@ impl GuiElement<PaletteState, ()> for EraserPicker {
         canvas.draw_rect((0, 0, 0, 255), shrink(rect, 2));
         canvas.draw_rect((0, 0, 0, 255), shrink(rect, 4));
         canvas.draw_rect((0, 0, 0, 255), shrink(rect, 6));
-        if state.brush.is_none() {
+        if let Some(ref tile) = state.eraser_tile {
+            // Show which floor tile the eraser is bound to.
+            let sprite = tile.sprite();
+            let topleft = Point::new(
+                (rect.width() as i32 - sprite.width() as i32) / 2,
+                (rect.height() as i32 - sprite.height() as i32) / 2,
+            );
+            canvas.draw_sprite(sprite, topleft);
+        }
+        if state.alt_brush == state.eraser_tile {
+            canvas.draw_rect(ALT_SELECTED_COLOR, shrink(rect, 1));
+        }
+        if state.brush == state.eraser_tile {
             canvas.draw_rect(SELECTED_COLOR, rect);
         }
     }
@@ -193,8 +938,9 @@ impl GuiElement<PaletteState, ()> for EraserPicker {
         state: &mut PaletteState,
     ) -> Action<()> {
         match event {
-            &Event::MouseDown(_) => {
-                state.brush = None;
+            &Event::MouseDown(_, _) => {
+                state.picked = Some(state.eraser_tile.clone());
+                state.brush = state.eraser_tile.clone();
                 Action::redraw().and_stop()
             }
             _ => Action::ignore(),
@@ -204,15 +950,34 @@ impl GuiElement<PaletteState, ()> for EraserPicker {
 
 //===========================================================================//
 
+/// Turns the palette page by `delta` when clicked or when `key` is pressed,
+/// and jumps to the first or last page (depending on the sign of `delta`)
+/// when `jump_key` is pressed.
 struct ArrowButton {
     icon: Sprite,
     key: Keycode,
+    jump_key: Keycode,
     delta: i32,
 }
 
 impl ArrowButton {
-    fn new(delta: i32, key: Keycode, icon: Sprite) -> ArrowButton {
-        ArrowButton { icon, key, delta }
+    fn new(
+        delta: i32,
+        key: Keycode,
+        jump_key: Keycode,
+        icon: Sprite,
+    ) -> ArrowButton {
+        ArrowButton { icon, key, jump_key, delta }
+    }
+
+    fn jump(&self, state: &mut PaletteState) -> Action<()> {
+        let num_filenames = state.tileset.num_filenames();
+        if num_filenames > 0 {
+            state.index = if self.delta < 0 { 0 } else { num_filenames - 1 };
+            Action::redraw().and_stop()
+        } else {
+            Action::ignore()
+        }
     }
 
     fn increment(&self, state: &mut PaletteState) -> Action<()> {
@@ -239,10 +1004,15 @@ impl GuiElement<PaletteState, ()> for ArrowButton {
         state: &mut PaletteState,
     ) -> Action<()> {
         match event {
-            &Event::MouseDown(_) => self.increment(state),
+            &Event::MouseDown(_, _) => self.increment(state),
             &Event::KeyDown(key, kmod) if key == self.key && kmod == NONE => {
                 self.increment(state)
             }
+            &Event::KeyDown(key, kmod)
+                if key == self.jump_key && kmod == NONE =>
+            {
+                self.jump(state)
+            }
             _ => Action::ignore(),
         }
     }
@@ -250,6 +1020,14 @@ impl GuiElement<PaletteState, ()> for ArrowButton {
 
 //===========================================================================//
 
+/// Draws a small (6x8) padlock, shown between the arrow buttons while the
+/// brush is locked.
+fn draw_lock_icon(canvas: &mut Canvas, topleft: Point) {
+    let (x, y) = (topleft.x(), topleft.y());
+    canvas.draw_rect(LOCK_COLOR, Rect::new(x + 1, y, 4, 4));
+    canvas.fill_rect(LOCK_COLOR, Rect::new(x, y + 3, 6, 5));
+}
+
 fn shrink(rect: Rect, by: i32) -> Rect {
     Rect::new(
         rect.x() + by,
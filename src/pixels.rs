@@ -0,0 +1,251 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::canvas::Canvas;
+use super::element::{Action, GuiElement, Movable};
+use super::event::Event;
+use super::state::EditorState;
+use super::tilegrid::{Tile, Tileset};
+use super::util;
+use ahi::{Collection, Image, Palette};
+use sdl2::rect::{Point, Rect};
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+//===========================================================================//
+
+// The maximum width/height of the zoomed-in pixel area, in screen pixels:
+const PIXEL_AREA_SIZE: u32 = 128;
+const NUM_COLORS: u32 = 16;
+const SWATCH_SIZE: u32 = PIXEL_AREA_SIZE / NUM_COLORS;
+const MARGIN: u32 = 2;
+
+const BACKGROUND_COLOR: (u8, u8, u8, u8) = (95, 95, 95, 255);
+const TRANSPARENT_COLOR: (u8, u8, u8, u8) = (63, 63, 63, 255);
+const SELECTED_COLOR: (u8, u8, u8, u8) = (255, 255, 255, 255);
+
+//===========================================================================//
+
+/// A small panel for editing the pixels of a single tile.  Each completed
+/// stroke is written back to the tile's .ahi file, after which the editor
+/// view reloads the file so that the tile's sprites are refreshed.
+pub struct PixelEditor {
+    topleft: Point,
+    filename: String,
+    path: PathBuf,
+    tile_index: usize,
+    collection: Collection,
+    color: u8,
+    painting: bool,
+    changed: bool,
+}
+
+impl PixelEditor {
+    pub fn open(
+        left: i32,
+        top: i32,
+        tileset: &Tileset,
+        tile: &Tile,
+    ) -> io::Result<PixelEditor> {
+        let path = tileset.file_path(tile.filename());
+        let collection = util::load_ahi_from_file(&path)?;
+        if tile.index() >= collection.images.len() {
+            let msg = format!("no such tile: {}", tile.name());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        Ok(PixelEditor {
+            topleft: Point::new(left, top),
+            filename: tile.filename().to_string(),
+            path,
+            tile_index: tile.index(),
+            collection,
+            color: 1,
+            painting: false,
+            changed: false,
+        })
+    }
+
+    fn image(&self) -> &Image {
+        &self.collection.images[self.tile_index]
+    }
+
+    fn palette(&self) -> &Palette {
+        self.collection.palettes.first().unwrap_or(Palette::default())
+    }
+
+    /// Returns the number of screen pixels per tile pixel.
+    fn scale(&self) -> u32 {
+        let image = self.image();
+        (PIXEL_AREA_SIZE / image.width().max(image.height()).max(1)).max(1)
+    }
+
+    fn pixel_rect(&self) -> Rect {
+        let scale = self.scale();
+        Rect::new(
+            self.topleft.x() + MARGIN as i32,
+            self.topleft.y() + MARGIN as i32,
+            self.image().width() * scale,
+            self.image().height() * scale,
+        )
+    }
+
+    fn swatch_rect(&self, index: u32) -> Rect {
+        Rect::new(
+            self.topleft.x() + (MARGIN + index * SWATCH_SIZE) as i32,
+            self.pixel_rect().bottom() + MARGIN as i32,
+            SWATCH_SIZE,
+            SWATCH_SIZE,
+        )
+    }
+
+    fn pixel_at(&self, pt: Point) -> Option<(u32, u32)> {
+        let rect = self.pixel_rect();
+        if !rect.contains_point(pt) {
+            return None;
+        }
+        let scale = self.scale() as i32;
+        Some((
+            ((pt.x() - rect.x()) / scale) as u32,
+            ((pt.y() - rect.y()) / scale) as u32,
+        ))
+    }
+
+    fn swatch_at(&self, pt: Point) -> Option<u8> {
+        (0..NUM_COLORS)
+            .find(|&index| self.swatch_rect(index).contains_point(pt))
+            .map(|index| index as u8)
+    }
+
+    fn paint(&mut self, (x, y): (u32, u32)) -> bool {
+        let color = self.color;
+        let image = &mut self.collection.images[self.tile_index];
+        if image[(x, y)] == color {
+            return false;
+        }
+        image[(x, y)] = color;
+        self.changed = true;
+        true
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut file = File::create(&self.path)?;
+        self.collection.write(&mut file)
+    }
+}
+
+/// Returns the RGBA color of each pixel of the image, in reading order.
+fn pixel_colors(image: &Image, palette: &Palette) -> Vec<(u8, u8, u8, u8)> {
+    image
+        .rgba_data(palette)
+        .chunks(4)
+        .map(|rgba| (rgba[0], rgba[1], rgba[2], rgba[3]))
+        .collect()
+}
+
+impl Movable for PixelEditor {
+    fn rect(&self) -> Rect {
+        Rect::new(
+            self.topleft.x(),
+            self.topleft.y(),
+            PIXEL_AREA_SIZE + 2 * MARGIN,
+            self.pixel_rect().height() + SWATCH_SIZE + 3 * MARGIN,
+        )
+    }
+
+    fn move_to(&mut self, topleft: Point) {
+        self.topleft = topleft;
+    }
+}
+
+impl GuiElement<EditorState, String> for PixelEditor {
+    fn draw(&self, _: &EditorState, canvas: &mut Canvas) {
+        canvas.fill_rect(BACKGROUND_COLOR, self.rect());
+        let image = self.image();
+        let scale = self.scale();
+        let area = self.pixel_rect();
+        let colors = pixel_colors(image, self.palette());
+        for (index, &color) in colors.iter().enumerate() {
+            let x = index as u32 % image.width();
+            let y = index as u32 / image.width();
+            let color = if color.3 == 0 { TRANSPARENT_COLOR } else { color };
+            let rect = Rect::new(
+                area.x() + (x * scale) as i32,
+                area.y() + (y * scale) as i32,
+                scale,
+                scale,
+            );
+            canvas.fill_rect(color, rect);
+        }
+        let mut swatches = Image::new(NUM_COLORS, 1);
+        for index in 0..NUM_COLORS {
+            swatches[(index, 0)] = index as u8;
+        }
+        let colors = pixel_colors(&swatches, self.palette());
+        for (index, &color) in colors.iter().enumerate() {
+            let rect = self.swatch_rect(index as u32);
+            let color = if color.3 == 0 { TRANSPARENT_COLOR } else { color };
+            canvas.fill_rect(color, rect);
+            if index as u8 == self.color {
+                canvas.draw_rect(SELECTED_COLOR, rect);
+            }
+        }
+    }
+
+    fn on_event(
+        &mut self,
+        event: &Event,
+        _: &mut EditorState,
+    ) -> Action<String> {
+        match event {
+            &Event::MouseDown(pt, _) => {
+                if let Some(color) = self.swatch_at(pt) {
+                    self.color = color;
+                    Action::redraw().and_stop()
+                } else if let Some(position) = self.pixel_at(pt) {
+                    self.painting = true;
+                    Action::redraw_if(self.paint(position)).and_stop()
+                } else if self.rect().contains_point(pt) {
+                    Action::ignore().and_stop()
+                } else {
+                    Action::ignore()
+                }
+            }
+            &Event::MouseDrag(pt, _) if self.painting => {
+                match self.pixel_at(pt) {
+                    Some(position) => Action::redraw_if(self.paint(position)),
+                    None => Action::ignore(),
+                }
+                .and_stop()
+            }
+            &Event::MouseUp(_) if self.painting => {
+                self.painting = false;
+                if self.changed && self.save().is_ok() {
+                    self.changed = false;
+                    Action::redraw().and_return(self.filename.clone())
+                } else {
+                    Action::ignore()
+                }
+            }
+            _ => Action::ignore(),
+        }
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,247 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::canvas::{Font, Sprite};
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use sdl2::rect::{Point, Rect};
+use std::io::{self, Write};
+
+//===========================================================================//
+
+/// An in-memory RGBA image that can be composited from sprites and then
+/// written out as a PNG file.
+pub struct RgbaImage {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+impl RgbaImage {
+    pub fn new(width: u32, height: u32) -> RgbaImage {
+        RgbaImage {
+            width,
+            height,
+            data: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    /// Wraps row-major RGBA bytes (e.g. read back from the window) as an
+    /// image.  Returns `None` if the data is the wrong size.
+    pub fn from_rgba_data(
+        width: u32,
+        height: u32,
+        data: Vec<u8>,
+    ) -> Option<RgbaImage> {
+        if data.len() == (width * height * 4) as usize {
+            Some(RgbaImage { width, height, data })
+        } else {
+            None
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn fill_rect(&mut self, color: (u8, u8, u8, u8), rect: Rect) {
+        let (red, green, blue, alpha) = color;
+        for row in rect.top()..rect.bottom() {
+            for col in rect.left()..rect.right() {
+                self.blend_pixel(col, row, [red, green, blue, alpha]);
+            }
+        }
+    }
+
+    pub fn draw_sprite(&mut self, sprite: &Sprite, left: i32, top: i32) {
+        self.draw_sprite_flipped(sprite, left, top, false, false);
+    }
+
+    /// Draws the sprite mirrored horizontally and/or vertically.
+    pub fn draw_sprite_flipped(
+        &mut self,
+        sprite: &Sprite,
+        left: i32,
+        top: i32,
+        horz: bool,
+        vert: bool,
+    ) {
+        let data = sprite.rgba_data();
+        let (width, height) = (sprite.width(), sprite.height());
+        for row in 0..height {
+            for col in 0..width {
+                let start = ((row * width + col) * 4) as usize;
+                let mut pixel = [0u8; 4];
+                pixel.copy_from_slice(&data[start..(start + 4)]);
+                let x = if horz { width - 1 - col } else { col };
+                let y = if vert { height - 1 - row } else { row };
+                self.blend_pixel(left + x as i32, top + y as i32, pixel);
+            }
+        }
+    }
+
+    pub fn draw_text(&mut self, font: &Font, start: Point, text: &str) {
+        for (sprite, topleft) in font.layout_text(start, text) {
+            self.draw_sprite(sprite, topleft.x(), topleft.y());
+        }
+    }
+
+    fn blend_pixel(&mut self, col: i32, row: i32, pixel: [u8; 4]) {
+        if col < 0
+            || row < 0
+            || col as u32 >= self.width
+            || row as u32 >= self.height
+        {
+            return;
+        }
+        let start = ((row as u32 * self.width + col as u32) * 4) as usize;
+        let alpha = pixel[3] as u32;
+        let dest = &mut self.data[start..(start + 4)];
+        for index in 0..3 {
+            dest[index] = ((pixel[index] as u32 * alpha
+                + dest[index] as u32 * (255 - alpha))
+                / 255) as u8;
+        }
+        dest[3] = (alpha + dest[3] as u32 * (255 - alpha) / 255) as u8;
+    }
+
+    /// Writes the image as a PNG file.
+    pub fn write_png<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
+        self.write_header(&mut writer)?;
+        write_chunk(&mut writer, b"IDAT", &self.compressed_data()?)?;
+        write_chunk(&mut writer, b"IEND", &[])
+    }
+
+    /// Writes the given frames, which must all be the same size, as an
+    /// animated PNG that loops forever, showing each frame for
+    /// `frame_millis` milliseconds.
+    pub fn write_apng<W: io::Write>(
+        frames: &[RgbaImage],
+        frame_millis: u16,
+        mut writer: W,
+    ) -> io::Result<()> {
+        let first = match frames.first() {
+            Some(first) => first,
+            None => {
+                let msg = "no frames to write";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        first.write_header(&mut writer)?;
+        let mut control = Vec::new();
+        control.extend_from_slice(&(frames.len() as u32).to_be_bytes());
+        control.extend_from_slice(&0u32.to_be_bytes());
+        write_chunk(&mut writer, b"acTL", &control)?;
+        let mut sequence: u32 = 0;
+        for (index, frame) in frames.iter().enumerate() {
+            let mut control = Vec::new();
+            control.extend_from_slice(&sequence.to_be_bytes());
+            control.extend_from_slice(&first.width.to_be_bytes());
+            control.extend_from_slice(&first.height.to_be_bytes());
+            control.extend_from_slice(&[0; 8]);
+            control.extend_from_slice(&frame_millis.to_be_bytes());
+            control.extend_from_slice(&1000u16.to_be_bytes());
+            control.extend_from_slice(&[0, 0]);
+            write_chunk(&mut writer, b"fcTL", &control)?;
+            sequence += 1;
+            if index == 0 {
+                write_chunk(&mut writer, b"IDAT", &frame.compressed_data()?)?;
+            } else {
+                let mut data = sequence.to_be_bytes().to_vec();
+                data.extend_from_slice(&frame.compressed_data()?);
+                write_chunk(&mut writer, b"fdAT", &data)?;
+                sequence += 1;
+            }
+        }
+        write_chunk(&mut writer, b"IEND", &[])
+    }
+
+    fn write_header<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+        let mut header = Vec::new();
+        header.extend_from_slice(&self.width.to_be_bytes());
+        header.extend_from_slice(&self.height.to_be_bytes());
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+        write_chunk(writer, b"IHDR", &header)
+    }
+
+    /// Returns the image data, with each row unfiltered, compressed for an
+    /// IDAT (or fdAT) chunk.
+    fn compressed_data(&self) -> io::Result<Vec<u8>> {
+        let stride = (self.width * 4) as usize;
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        for row in self.data.chunks(stride.max(1)) {
+            encoder.write_all(&[0])?;
+            encoder.write_all(row)?;
+        }
+        encoder.finish()
+    }
+}
+
+//===========================================================================//
+
+fn write_chunk<W: io::Write>(
+    writer: &mut W,
+    kind: &[u8; 4],
+    data: &[u8],
+) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    writer.write_all(&crc.sum().to_be_bytes())
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::RgbaImage;
+    use flate2::read::ZlibDecoder;
+    use sdl2::rect::Rect;
+    use std::io::Read;
+
+    #[test]
+    fn png_chunks() {
+        let mut image = RgbaImage::new(2, 1);
+        image.fill_rect((1, 2, 3, 255), Rect::new(1, 0, 1, 1));
+        let mut png = Vec::new();
+        image.write_png(&mut png).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // The IEND chunk is always the same, CRC included.
+        assert_eq!(&png[(png.len() - 12)..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+        // The IDAT chunk follows the 25-byte IHDR chunk.
+        let idat = &png[33..(png.len() - 12)];
+        let len = u32::from_be_bytes([idat[0], idat[1], idat[2], idat[3]]);
+        assert_eq!(&idat[4..8], b"IDAT");
+        let mut raw = Vec::new();
+        ZlibDecoder::new(&idat[8..(8 + len as usize)])
+            .read_to_end(&mut raw)
+            .unwrap();
+        assert_eq!(raw, vec![0, 0, 0, 0, 0, 1, 2, 3, 255]);
+    }
+}
+
+//===========================================================================//
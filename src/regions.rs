@@ -0,0 +1,244 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::tilegrid::{Tile, TileGrid};
+use sdl2::rect::Rect;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+//===========================================================================//
+
+/// A named rectangle of the map (in tiles) where only tiles from certain
+/// tile files may be placed.
+#[derive(Debug, Eq, PartialEq)]
+struct Region {
+    name: String,
+    rect: Rect,
+    // The tile files whose tiles may be placed in the region (if empty, no
+    // tiles may be placed there at all):
+    allowed: Vec<String>,
+}
+
+impl Region {
+    fn contains(&self, (col, row): (u32, u32)) -> bool {
+        self.rect.contains_point((col as i32, row as i32))
+    }
+
+    fn allows(&self, tile: &Option<Tile>) -> bool {
+        match *tile {
+            Some(ref tile) => self.allows_file(tile.filename()),
+            None => true,
+        }
+    }
+
+    fn allows_file(&self, filename: &str) -> bool {
+        self.allowed.iter().any(|allowed| allowed == filename)
+    }
+}
+
+//===========================================================================//
+
+/// A set of placement rules for a map, read from a `.regions` file.  Each
+/// line of the file gives a region's top-left cell, its size in tiles, the
+/// tile files allowed in it, and its name, e.g.
+///
+/// ```text
+/// # "-" means that no tiles are allowed at all.
+/// 0,0   40x3  -             HUD area
+/// 10,12 8x4   water,shore   Lake
+/// ```
+///
+/// Empty cells are always allowed.  Where regions overlap, a tile must be
+/// allowed by all of them.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Regions {
+    regions: Vec<Region>,
+}
+
+impl Regions {
+    pub fn parse(text: &str) -> io::Result<Regions> {
+        let mut regions = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let pieces: Vec<&str> = line.split_whitespace().collect();
+            if pieces.len() < 4 {
+                return Err(invalid_line(line));
+            }
+            let (col, row) = parse_pair(pieces[0], ',')
+                .ok_or_else(|| invalid_line(line))?;
+            let (width, height) = parse_pair(pieces[1], 'x')
+                .ok_or_else(|| invalid_line(line))?;
+            if width == 0 || height == 0 {
+                return Err(invalid_line(line));
+            }
+            let allowed = if pieces[2] == "-" {
+                Vec::new()
+            } else {
+                pieces[2].split(',').map(str::to_string).collect()
+            };
+            regions.push(Region {
+                name: pieces[3..].join(" "),
+                rect: Rect::new(col as i32, row as i32, width, height),
+                allowed,
+            });
+        }
+        Ok(Regions { regions })
+    }
+
+    pub fn load_from_path(path: &Path) -> io::Result<Regions> {
+        Regions::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Returns the name and rect (in tiles) of each region.
+    pub fn rects(&self) -> impl Iterator<Item = (&str, Rect)> + '_ {
+        self.regions.iter().map(|region| (region.name.as_str(), region.rect))
+    }
+
+    /// If placing the given tile at the given cell breaks the rules, returns
+    /// the name of a region that forbids it.
+    pub fn forbidding(
+        &self,
+        position: (u32, u32),
+        tile: &Option<Tile>,
+    ) -> Option<&str> {
+        self.regions
+            .iter()
+            .find(|region| region.contains(position) && !region.allows(tile))
+            .map(|region| region.name.as_str())
+    }
+
+    /// Returns the changes from `old` to `new` that break the rules where
+    /// they didn't already (i.e. where no layer of `old` broke them at that
+    /// cell), as the layer and cell of each one, along with the name of a
+    /// region that forbids it.  Changes to the size or layers of the grid,
+    /// or to its tile files, aren't painting and so aren't checked.
+    pub fn new_violations<'a>(
+        &'a self,
+        old: &TileGrid,
+        new: &TileGrid,
+    ) -> Vec<(usize, (u32, u32), &'a str)> {
+        let mut violations = Vec::new();
+        if old.size() != new.size()
+            || old.num_layers() != new.num_layers()
+            || !old.tileset().filenames().eq(new.tileset().filenames())
+        {
+            return violations;
+        }
+        for region in self.regions.iter() {
+            let right = (region.rect.right() as u32).min(new.width());
+            let bottom = (region.rect.bottom() as u32).min(new.height());
+            for row in (region.rect.top() as u32)..bottom {
+                for col in (region.rect.left() as u32)..right {
+                    let already_broken = (0..old.num_layers()).any(|index| {
+                        !region.allows(&old.layer(index)[(col, row)])
+                    });
+                    if already_broken {
+                        continue;
+                    }
+                    for index in 0..new.num_layers() {
+                        if !region.allows(&new.layer(index)[(col, row)]) {
+                            violations.push((
+                                index,
+                                (col, row),
+                                region.name.as_str(),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        violations
+    }
+
+    /// Returns the cells of the grid that, on any layer, contain a tile that
+    /// breaks the rules.
+    pub fn violations(&self, tilegrid: &TileGrid) -> BTreeSet<(u32, u32)> {
+        let mut cells = BTreeSet::new();
+        for region in self.regions.iter() {
+            let right = (region.rect.right() as u32).min(tilegrid.width());
+            let bottom = (region.rect.bottom() as u32).min(tilegrid.height());
+            for row in (region.rect.top() as u32)..bottom {
+                for col in (region.rect.left() as u32)..right {
+                    let broken = (0..tilegrid.num_layers()).any(|index| {
+                        !region.allows(&tilegrid.layer(index)[(col, row)])
+                    });
+                    if broken {
+                        cells.insert((col, row));
+                    }
+                }
+            }
+        }
+        cells
+    }
+}
+
+fn invalid_line(line: &str) -> io::Error {
+    let msg = format!("invalid region line: {:?}", line);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parses a pair of numbers separated by the given character, like "3,4".
+fn parse_pair(token: &str, separator: char) -> Option<(u32, u32)> {
+    let (first, second) = token.split_once(separator)?;
+    Some((first.parse().ok()?, second.parse().ok()?))
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::Regions;
+    use sdl2::rect::Rect;
+
+    #[test]
+    fn parse_regions() {
+        let text = "# Comment\n\
+                    0,0 40x3 - HUD area\n\
+                    \n\
+                    10,12 8x4 water,shore Lake  # pond\n";
+        let regions = Regions::parse(text).unwrap();
+        let rects: Vec<(&str, Rect)> = regions.rects().collect();
+        assert_eq!(
+            rects,
+            vec![
+                ("HUD area", Rect::new(0, 0, 40, 3)),
+                ("Lake", Rect::new(10, 12, 8, 4)),
+            ]
+        );
+        assert!(!regions.regions[0].allows_file("water"));
+        assert!(regions.regions[1].allows_file("shore"));
+        assert!(!regions.regions[1].allows_file("grass"));
+        assert_eq!(regions.forbidding((5, 1), &None), None);
+    }
+
+    #[test]
+    fn parse_invalid_regions() {
+        assert!(Regions::parse("0,0 4x3 -\n").is_err());
+        assert!(Regions::parse("0,0 0x3 - Nothing\n").is_err());
+        assert!(Regions::parse("0;0 4x3 - Bad\n").is_err());
+        assert!(Regions::parse("0,0 4 - Bad\n").is_err());
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,103 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::tilegrid::BgFile;
+use super::util;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+//===========================================================================//
+
+/// Prints, across all the given .bg files, how many times each tile is used
+/// in each file, followed by the tiles and tile files in the tiles directory
+/// that none of the files use.
+pub fn print_usage_report(
+    tiles_dir: &Path,
+    paths: &[String],
+) -> io::Result<()> {
+    // Maps each tile name to the number of uses in each .bg file.
+    let mut usage = BTreeMap::<(String, usize), BTreeMap<&str, usize>>::new();
+    for path in paths.iter() {
+        let bg_file =
+            BgFile::read(util::open_maybe_gzipped(path)?).map_err(|err| {
+                let msg = format!("{}: {}", path, err);
+                io::Error::new(err.kind(), msg)
+            })?;
+        let layers = bg_file.layers.iter().map(|(_, cells)| cells);
+        let cells = bg_file.cells.iter().chain(layers.flatten());
+        for &(_, (file_index, tile_index)) in cells {
+            let filename =
+                bg_file.filenames.get(file_index).ok_or_else(|| {
+                    let msg = format!("{}: invalid file index", path);
+                    io::Error::new(io::ErrorKind::InvalidData, msg)
+                })?;
+            let key = (filename.clone(), tile_index);
+            *usage.entry(key).or_default().entry(path).or_insert(0) += 1;
+        }
+    }
+
+    println!("Tile usage:");
+    for (&(ref filename, index), counts) in usage.iter() {
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(path, count)| format!("{} in {}", count, path))
+            .collect();
+        println!("  {}:{}  {}", filename, index, counts.join(", "));
+    }
+
+    let used_files: BTreeSet<&str> =
+        usage.keys().map(|(filename, _)| filename.as_str()).collect();
+    let mut unused_tiles = Vec::new();
+    let mut unused_files = Vec::new();
+    let mut entries: Vec<_> = fs::read_dir(tiles_dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ahi"))
+        .collect();
+    entries.sort();
+    for path in entries {
+        let filename = match path.file_stem() {
+            Some(stem) => stem.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        if !used_files.contains(filename.as_str()) {
+            unused_files.push(filename);
+            continue;
+        }
+        let num_tiles = util::load_ahi_from_file(&path)?.images.len();
+        for index in 0..num_tiles {
+            if !usage.contains_key(&(filename.clone(), index)) {
+                unused_tiles.push(format!("{}:{}", filename, index));
+            }
+        }
+    }
+    println!("Unused tiles:");
+    for name in unused_tiles {
+        println!("  {}", name);
+    }
+    println!("Unreferenced tile files:");
+    for filename in unused_files {
+        println!("  {}", filename);
+    }
+    Ok(())
+}
+
+//===========================================================================//
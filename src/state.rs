@@ -16,25 +16,147 @@
 // | You should have received a copy of the GNU General Public License along  |
 // | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
 // +--------------------------------------------------------------------------+
+use super::anim::TileAnimation;
 
-use super::canvas::Window;
-use super::tilegrid::{SubGrid, Tile, TileGrid};
+use super::automap::Automap;
+use super::backup;
+use super::canvas::{Sprite, Window};
+use super::export::ExportFormat;
+use super::generate::WeightedTiles;
+use super::lockfile::FileLock;
+use super::random::Rng;
+use super::regions::Regions;
+use super::terrain::Terrain;
+use super::tilegrid::{Backdrop, SubGrid, Tile, TileGrid};
+use super::util;
+use super::writer::{Contents, FileWriter};
 use sdl2::rect::{Point, Rect};
-use std::fs::File;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::io;
 use std::mem;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
 
 //===========================================================================//
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub enum Tool {
+    Border,
+    DitherFill,
     Eyedropper,
+    Line,
     PaintBucket,
     PaletteReplace,
     PaletteSwap,
     Pencil,
+    Rectangle,
     Select,
+    Terrain,
+}
+
+impl Tool {
+    const ALL: &'static [Tool] = &[
+        Tool::Border,
+        Tool::DitherFill,
+        Tool::Eyedropper,
+        Tool::Line,
+        Tool::PaintBucket,
+        Tool::PaletteReplace,
+        Tool::PaletteSwap,
+        Tool::Pencil,
+        Tool::Rectangle,
+        Tool::Select,
+        Tool::Terrain,
+    ];
+
+    /// Returns a name for this tool, as accepted by `Tool::from_name`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Border => "border",
+            Tool::DitherFill => "dither",
+            Tool::Eyedropper => "eyedropper",
+            Tool::Line => "line",
+            Tool::PaintBucket => "bucket",
+            Tool::PaletteReplace => "replace",
+            Tool::PaletteSwap => "swap",
+            Tool::Pencil => "pencil",
+            Tool::Rectangle => "rectangle",
+            Tool::Select => "select",
+            Tool::Terrain => "terrain",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Tool> {
+        Tool::ALL.iter().copied().find(|tool| tool.name() == name)
+    }
+}
+
+//===========================================================================//
+
+/// How Pencil strokes are grouped into undo steps.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PencilUndo {
+    /// Each stroke (from mouse-down to mouse-up) is one undo step.
+    Stroke,
+    /// Each painted cell is its own undo step.
+    Cell,
+    /// Consecutive strokes begun within the given time of the end of the
+    /// previous stroke are merged into one undo step.
+    Merge(Duration),
+}
+
+impl PencilUndo {
+    /// Parses "stroke", "cell", or a number of seconds to merge within.
+    pub fn parse(string: &str) -> Option<PencilUndo> {
+        match string {
+            "stroke" => Some(PencilUndo::Stroke),
+            "cell" => Some(PencilUndo::Cell),
+            _ => {
+                let seconds = string.parse::<f64>().ok()?;
+                if seconds.is_finite() && seconds >= 0.0 {
+                    Some(PencilUndo::Merge(Duration::from_secs_f64(seconds)))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+//===========================================================================//
+
+/// How to fill the cells added when the grid is enlarged.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResizeFill {
+    /// The new cells are left empty.
+    Empty,
+    /// The new cells of the active layer are painted with the brush,
+    /// repeated from the top-left corner of the map.
+    Brush,
+    /// The new cells of every layer repeat that layer's last column and row.
+    Extend,
+}
+
+impl ResizeFill {
+    /// Parses "brush" or "extend" (or "" for none).
+    pub fn parse(string: &str) -> Option<ResizeFill> {
+        match string {
+            "" => Some(ResizeFill::Empty),
+            "brush" => Some(ResizeFill::Brush),
+            "extend" => Some(ResizeFill::Extend),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ResizeFill::Empty => "",
+            ResizeFill::Brush => "brush",
+            ResizeFill::Extend => "extend",
+        }
+    }
 }
 
 //===========================================================================//
@@ -42,43 +164,202 @@ pub enum Tool {
 // This limit is currently arbitrary:
 const MAX_UNDOS: usize = 100;
 
+const DEFAULT_OVERLAY_OPACITY: u8 = 128;
+
 #[derive(Clone)]
 struct Snapshot {
     tilegrid: Rc<TileGrid>,
     selection: Option<(Rc<SubGrid>, Point)>,
-    unsaved: bool,
+    // If the selection was built from several rectangles, those rectangles
+    // (relative to the selection's top-left), for drawing its marquee:
+    selection_mask: Option<Vec<Rect>>,
+    // The cells that the selection was cut from, as they were before the
+    // cut, and where they were (for cancelling the selection):
+    selection_source: Option<(Rc<SubGrid>, Point)>,
+    // A number identifying this state of the grid, unique within a session:
+    generation: u64,
+    // A short description of the change that produced this snapshot:
+    description: &'static str,
+}
+
+impl Snapshot {
+    fn set_tiles_dirpath(&mut self, dirpath: &Path) {
+        if self.tilegrid.tileset().dirpath() != dirpath {
+            Rc::make_mut(&mut self.tilegrid).set_tiles_dirpath(dirpath);
+        }
+    }
+
+    fn set_tile_animations(
+        &mut self,
+        filename: &str,
+        animations: &[TileAnimation],
+    ) {
+        Rc::make_mut(&mut self.tilegrid)
+            .set_tile_animations(filename, animations.to_vec());
+    }
+
+    fn replace_sprites(
+        &mut self,
+        filename: &str,
+        sprites: &[Rc<Sprite>],
+        checksum: u64,
+    ) {
+        Rc::make_mut(&mut self.tilegrid)
+            .replace_sprites(filename, sprites, checksum);
+        if let Some((ref mut selected, _)) = self.selection {
+            Rc::make_mut(selected).replace_sprites(filename, sprites);
+        }
+        if let Some((ref mut source, _)) = self.selection_source {
+            Rc::make_mut(source).replace_sprites(filename, sprites);
+        }
+    }
 }
 
 //===========================================================================//
 
+// A save waiting to be written to disk:
+#[derive(Default)]
+struct PendingSave {
+    filepath: String,
+    // The path of the backup being written along with the map, if any:
+    backup: Option<PathBuf>,
+    // The generation and grid saved, or None if the map has since been
+    // replaced by loading another:
+    saved: Option<(u64, Rc<TileGrid>)>,
+}
+
+// The generation that the cells breaking the region rules were found for,
+// and those cells:
+type RegionViolations = (u64, Rc<BTreeSet<(u32, u32)>>);
+
 pub struct EditorState {
     filepath: String,
     current: Snapshot,
     undo_stack: Vec<Snapshot>,
     redo_stack: Vec<Snapshot>,
+    checkpoints: Vec<(String, Snapshot)>,
+    next_generation: u64,
+    saved_generation: u64,
+    // The grid as it was last saved to (or loaded from) disk:
+    saved_tilegrid: Rc<TileGrid>,
+    num_saves: u64,
+    // The saves that are waiting to be written to disk, oldest first:
+    pending_saves: VecDeque<PendingSave>,
+    // The path and checksum of the map file most recently written to disk:
+    last_save: Option<(String, u64)>,
+    save_error: Option<String>,
+    load_warning: Option<String>,
+    // The advisory lock on the map file, if it has been opened or saved:
+    lock: Option<FileLock>,
+    // A message to show in the status bar until the next change:
+    notice: Option<String>,
+    // The cell the mouse was last over on the canvas, if any:
+    hovered_cell: Option<(u32, u32)>,
+    writer: FileWriter,
+    watch_export: Option<ExportFormat>,
+    trim_on_save: bool,
+    // How many backups of the map to keep (or zero to not make any):
+    max_backups: usize,
     clipboard: Option<(Rc<SubGrid>, Point)>,
     tool: Tool,
     prev_tool: Tool,
-    brush: Option<Tile>,
+    // The brushes are usually a single tile (or empty cell), but can be a
+    // rectangular block of tiles picked from the palette:
+    brush: SubGrid,
+    alt_brush: SubGrid,
+    // The tile that the palette's eraser paints with (or None to erase to
+    // empty cells):
+    eraser_tile: Option<Tile>,
+    // If true, clicks in the palette don't change the brush:
+    brush_locked: bool,
     persistent_mutation_active: bool,
+    pencil_undo: PencilUndo,
+    last_stroke_end: Option<Instant>,
+    automap: Option<(String, Automap)>,
+    automap_after_stroke: bool,
+    terrain: Option<(String, Terrain)>,
+    // Named regions of the map where painting is restricted:
+    regions: Option<(String, Regions)>,
+    // The cells last found breaking the region rules, cached so that drawing
+    // only rescans the grid after it changes:
+    region_violations: RefCell<Option<RegionViolations>>,
+    // If set, the Pencil and Paint Bucket only change cells within these
+    // rects (in tiles), as taken from a selection:
+    paint_clip: Option<Vec<Rect>>,
+    border_tiles: Option<[Option<Tile>; 8]>,
+    // If set, the shades (two or three) that the dither fill blends across,
+    // instead of from the brush to the alternate brush:
+    dither_tiles: Option<Vec<Option<Tile>>>,
+    // If set, the paint bucket fills with tiles chosen at random from these
+    // instead of with the brush:
+    random_fill: Option<WeightedTiles>,
+    overlay: Option<(String, Sprite)>,
+    overlay_opacity: u8,
+    // The names of the layers that aren't drawn while editing:
+    hidden_layers: BTreeSet<String>,
+    // The tiles directory to swap to (see `swap_tiles_dir`), if any:
+    alt_tiles_dir: Option<PathBuf>,
+    // The (old, new) names of renamed tile files, to apply to maps as they
+    // are loaded:
+    tile_renames: Vec<(String, String)>,
 }
 
 impl EditorState {
     pub fn new(filepath: String, tilegrid: TileGrid) -> EditorState {
+        let load_warning = load_warning(&tilegrid);
+        let tilegrid = Rc::new(tilegrid);
         EditorState {
             filepath,
             current: Snapshot {
-                tilegrid: Rc::new(tilegrid),
+                tilegrid: tilegrid.clone(),
                 selection: None,
-                unsaved: false,
+                selection_mask: None,
+                selection_source: None,
+                generation: 0,
+                description: "",
             },
             undo_stack: Vec::new(),
             redo_stack: Vec::new(),
+            checkpoints: Vec::new(),
+            next_generation: 1,
+            saved_generation: 0,
+            saved_tilegrid: tilegrid,
+            num_saves: 0,
+            pending_saves: VecDeque::new(),
+            last_save: None,
+            save_error: None,
+            load_warning,
+            lock: None,
+            notice: None,
+            hovered_cell: None,
+            writer: FileWriter::new(),
+            watch_export: None,
+            trim_on_save: false,
+            max_backups: 0,
             clipboard: None,
             tool: Tool::Pencil,
             prev_tool: Tool::Pencil,
-            brush: None,
+            brush: single_tile_brush(None),
+            alt_brush: single_tile_brush(None),
+            eraser_tile: None,
+            brush_locked: false,
             persistent_mutation_active: false,
+            pencil_undo: PencilUndo::Stroke,
+            last_stroke_end: None,
+            automap: None,
+            terrain: None,
+            regions: None,
+            region_violations: RefCell::new(None),
+            paint_clip: None,
+            automap_after_stroke: false,
+            border_tiles: None,
+            dither_tiles: None,
+            random_fill: None,
+            overlay: None,
+            overlay_opacity: DEFAULT_OVERLAY_OPACITY,
+            hidden_layers: BTreeSet::new(),
+            alt_tiles_dir: None,
+            tile_renames: Vec::new(),
         }
     }
 
@@ -94,8 +375,63 @@ impl EditorState {
         &self.current.tilegrid
     }
 
+    /// Returns a number identifying the current state of the grid, which
+    /// changes with every change (including undoing and redoing).
+    pub fn generation(&self) -> u64 {
+        self.current.generation
+    }
+
+    /// Returns true if the current state differs from what was last saved
+    /// to (or loaded from) disk.
     pub fn is_unsaved(&self) -> bool {
-        self.current.unsaved
+        self.current.generation != self.saved_generation
+    }
+
+    /// Returns the grid as it was last saved to (or loaded from) disk.
+    pub fn saved_tilegrid(&self) -> &TileGrid {
+        &self.saved_tilegrid
+    }
+
+    /// Sets a format to also export the map in, next to the .bg file,
+    /// whenever the map is saved.
+    pub fn set_watch_export(&mut self, format: Option<ExportFormat>) {
+        self.watch_export = format;
+    }
+
+    /// Sets whether to trim trailing empty rows and columns from the grid
+    /// when saving it (without changing the grid being edited).
+    pub fn set_trim_on_save(&mut self, trim: bool) {
+        self.trim_on_save = trim;
+    }
+
+    /// Sets how many timestamped backups of the map to keep, one written on
+    /// each save (or zero to not make backups).
+    pub fn set_max_backups(&mut self, max_backups: usize) {
+        self.max_backups = max_backups;
+    }
+
+    /// Returns the timestamps of the map's backups, oldest first.
+    pub fn backup_names(&self) -> Vec<String> {
+        backup::list_backups(Path::new(&self.filepath))
+    }
+
+    /// Returns the number of times the map has been saved this session.
+    pub fn num_saves(&self) -> u64 {
+        self.num_saves
+    }
+
+    /// Returns the path of the most recently saved map file, and the
+    /// checksum of the data written to it, if any saves have finished.
+    pub fn last_save(&self) -> Option<(&str, u64)> {
+        self.last_save
+            .as_ref()
+            .map(|(path, checksum)| (path.as_str(), *checksum))
+    }
+
+    fn new_generation(&mut self) -> u64 {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        generation
     }
 
     pub fn tool(&self) -> Tool {
@@ -110,16 +446,234 @@ impl EditorState {
         }
     }
 
+    /// Returns the brush's tile (or, for a block brush, its top-left tile).
     pub fn brush(&self) -> &Option<Tile> {
-        &self.brush
+        &self.brush[(0, 0)]
     }
 
     pub fn set_brush(&mut self, tile: Option<Tile>) {
-        self.brush = tile;
+        self.brush = single_tile_brush(tile);
+    }
+
+    /// Returns the whole brush, which is 1x1 unless a block of tiles was
+    /// picked from the palette.
+    pub fn brush_grid(&self) -> &SubGrid {
+        &self.brush
+    }
+
+    pub fn set_brush_grid(&mut self, brush: SubGrid) {
+        debug_assert!(brush.width() > 0 && brush.height() > 0);
+        self.brush = brush;
+    }
+
+    /// Makes the selected tiles into a stamp brush, for painting copies of
+    /// them with the pencil.  Returns false if nothing is selected.
+    pub fn selection_to_brush(&mut self) -> bool {
+        match self.selection() {
+            Some((selected, _)) => {
+                self.brush = selected.clone();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn alt_brush(&self) -> &Option<Tile> {
+        &self.alt_brush[(0, 0)]
+    }
+
+    pub fn eraser_tile(&self) -> &Option<Tile> {
+        &self.eraser_tile
+    }
+
+    /// Binds the eraser to the given "floor" tile, for maps in which that
+    /// tile (rather than an empty cell) is the base.
+    pub fn set_eraser_tile(&mut self, tile: Option<Tile>) {
+        self.eraser_tile = tile;
+    }
+
+    pub fn brush_locked(&self) -> bool {
+        self.brush_locked
+    }
+
+    pub fn toggle_brush_locked(&mut self) {
+        self.brush_locked = !self.brush_locked;
+    }
+
+    pub fn swap_brushes(&mut self) {
+        mem::swap(&mut self.brush, &mut self.alt_brush);
+    }
+
+    /// Returns the eight tiles used by the border tool, in the order expected
+    /// by `SubGrid::draw_border`, or `None` if they haven't been configured
+    /// yet.
+    pub fn border_tiles(&self) -> Option<&[Option<Tile>; 8]> {
+        self.border_tiles.as_ref()
+    }
+
+    pub fn set_border_tiles(&mut self, tiles: [Option<Tile>; 8]) {
+        self.border_tiles = Some(tiles);
+    }
+
+    /// Returns the tiles that the dither fill blends across, in order.  If
+    /// none have been configured, this blends from the brush to the
+    /// alternate brush.
+    pub fn dither_tiles(&self) -> Vec<Option<Tile>> {
+        match self.dither_tiles {
+            Some(ref tiles) => tiles.clone(),
+            None => vec![self.brush().clone(), self.alt_brush().clone()],
+        }
+    }
+
+    /// Sets the tiles that the dither fill blends across, or goes back to
+    /// the brushes if `None`.  Returns false (changing nothing) unless there
+    /// are two or three tiles.
+    pub fn set_dither_tiles(
+        &mut self,
+        tiles: Option<Vec<Option<Tile>>>,
+    ) -> bool {
+        if let Some(ref tiles) = tiles {
+            if tiles.len() < 2 || tiles.len() > 3 {
+                return false;
+            }
+        }
+        self.dither_tiles = tiles;
+        true
+    }
+
+    pub fn random_fill(&self) -> Option<&WeightedTiles> {
+        self.random_fill.as_ref()
+    }
+
+    pub fn set_random_fill(&mut self, tiles: Option<WeightedTiles>) {
+        self.random_fill = tiles;
+    }
+
+    /// Commits the floating selection to the grid and switches back to the
+    /// tool that was in use before selecting.  Returns false if nothing is
+    /// selected.
+    pub fn anchor_selection(&mut self) -> bool {
+        if self.selection().is_none() {
+            return false;
+        }
+        self.mutation("anchor selection").unselect();
+        let tool = if self.prev_tool == Tool::Select {
+            Tool::Pencil
+        } else {
+            self.prev_tool
+        };
+        self.set_tool(tool);
+        true
+    }
+
+    /// Changes the tileset to the given tile files, clearing any cells that
+    /// use tiles from files no longer in it, as a single undo step.  Nothing
+    /// changes (and no undo step is made) if a file fails to load or the
+    /// list of files is unchanged.
+    pub fn change_tile_filenames(
+        &mut self,
+        window: &Window,
+        filenames: Vec<&str>,
+    ) -> io::Result<()> {
+        if self.tilegrid().tileset().filenames().eq(filenames.iter().copied())
+        {
+            return Ok(());
+        }
+        let mut tilegrid = (*self.current.tilegrid).clone();
+        tilegrid.set_tile_filenames(window, filenames)?;
+        *self.mutation("change tilesets").tilegrid() = tilegrid;
+        Ok(())
+    }
+
+    /// Replaces the `from` tile file with the `to` tile file (see
+    /// `TileGrid::rename_tile_file`) as a single undo step, updating the
+    /// brushes and eraser tile to match.
+    pub fn rename_tile_file(
+        &mut self,
+        window: &Window,
+        from: &str,
+        to: &str,
+    ) -> io::Result<()> {
+        let mut tilegrid = (*self.current.tilegrid).clone();
+        tilegrid.rename_tile_file(window, from, to)?;
+        *self.mutation("rename tile file").tilegrid() = tilegrid;
+        let tileset = self.tilegrid().tileset();
+        let rename = |cell: &mut Option<Tile>| {
+            let renamed = match *cell {
+                Some(ref tile) if tile.filename() == from => Some(
+                    tileset
+                        .get_named(to, tile.index())
+                        .map(|renamed| renamed.with_flip(tile.flip())),
+                ),
+                _ => None,
+            };
+            if let Some(renamed) = renamed {
+                *cell = renamed;
+            }
+        };
+        for brush in [&mut self.brush, &mut self.alt_brush] {
+            for row in 0..brush.height() {
+                for col in 0..brush.width() {
+                    let mut cell = brush[(col, row)].clone();
+                    if cell.is_some() {
+                        rename(&mut cell);
+                        brush.set((col, row), cell);
+                    }
+                }
+            }
+        }
+        rename(&mut self.eraser_tile);
+        Ok(())
+    }
+
+    /// Merges the `from` tile file into the `into` tile file (see
+    /// `TileGrid::merge_tile_files`), updating the brushes and eraser tile
+    /// to match.  Since that rewrites the `into` file on disk, which undoing
+    /// couldn't put back, the merge clears the undo history (and says so).
+    pub fn merge_tile_files(
+        &mut self,
+        window: &Window,
+        into: &str,
+        from: &str,
+    ) -> io::Result<()> {
+        let mut tilegrid = (*self.current.tilegrid).clone();
+        let offset = tilegrid.merge_tile_files(window, into, from)?;
+        *self.mutation("merge tile files").tilegrid() = tilegrid;
+        self.undo_stack.clear();
+        self.set_notice(format!(
+            "Merged {} into {} (can't be undone)",
+            from, into
+        ));
+        let tileset = self.tilegrid().tileset();
+        let merge = |cell: &mut Option<Tile>| {
+            let merged = match *cell {
+                Some(ref tile) if tile.filename() == from => {
+                    Some(tileset.get_named(into, offset + tile.index()))
+                }
+                _ => None,
+            };
+            if let Some(merged) = merged {
+                *cell = merged;
+            }
+        };
+        for brush in [&mut self.brush, &mut self.alt_brush] {
+            for row in 0..brush.height() {
+                for col in 0..brush.width() {
+                    let mut cell = brush[(col, row)].clone();
+                    if cell.is_some() {
+                        merge(&mut cell);
+                        brush.set((col, row), cell);
+                    }
+                }
+            }
+        }
+        merge(&mut self.eraser_tile);
+        Ok(())
     }
 
     pub fn eyedrop(&mut self, position: (u32, u32)) {
-        self.brush = self.current.tilegrid[position].clone();
+        self.brush =
+            single_tile_brush(self.current.tilegrid[position].clone());
         if self.tool == Tool::Eyedropper {
             self.tool = if self.prev_tool == Tool::Select {
                 Tool::Pencil
@@ -129,6 +683,393 @@ impl EditorState {
         }
     }
 
+    pub fn automap_path(&self) -> Option<&str> {
+        self.automap.as_ref().map(|(path, _)| path.as_str())
+    }
+
+    pub fn set_automap(&mut self, path: String, automap: Automap) {
+        self.automap = Some((path, automap));
+    }
+
+    /// Applies the automap rules (if any) as a new undoable change, unless
+    /// they wouldn't change anything.  Returns true if the grid changed.
+    pub fn apply_automap(&mut self) -> bool {
+        let mut tilegrid = self.tilegrid().clone();
+        let changed = match self.automap {
+            Some((_, ref automap)) => automap.apply(&mut tilegrid),
+            None => false,
+        };
+        if changed {
+            *self.mutation("automap").tilegrid() = tilegrid;
+        }
+        changed
+    }
+
+    pub fn terrain_path(&self) -> Option<&str> {
+        self.terrain.as_ref().map(|(path, _)| path.as_str())
+    }
+
+    pub fn terrain(&self) -> Option<&Terrain> {
+        self.terrain.as_ref().map(|(_, terrain)| terrain)
+    }
+
+    pub fn set_terrain(&mut self, path: String, terrain: Terrain) {
+        self.terrain = Some((path, terrain));
+    }
+
+    pub fn regions_path(&self) -> Option<&str> {
+        self.regions.as_ref().map(|(path, _)| path.as_str())
+    }
+
+    pub fn regions(&self) -> Option<&Regions> {
+        self.regions.as_ref().map(|(_, regions)| regions)
+    }
+
+    pub fn set_regions(&mut self, regions: Option<(String, Regions)>) {
+        self.regions = regions;
+        self.region_violations.replace(None);
+    }
+
+    /// Returns the cells of the grid that break the region rules (see
+    /// `Regions::violations`), finding them again only if the grid has
+    /// changed since the last call.
+    pub fn region_violations(&self) -> Rc<BTreeSet<(u32, u32)>> {
+        let generation = self.current.generation;
+        if let Some((gen, ref cells)) = *self.region_violations.borrow() {
+            if gen == generation {
+                return cells.clone();
+            }
+        }
+        let cells = Rc::new(match self.regions() {
+            Some(regions) => regions.violations(self.tilegrid()),
+            None => BTreeSet::new(),
+        });
+        self.region_violations.replace(Some((generation, cells.clone())));
+        cells
+    }
+
+    /// If the change from `previous` to the current state breaks the region
+    /// rules (see `Regions::new_violations`), rejects the whole change by
+    /// putting `previous` back (and, if `pushed` is true, removing the undo
+    /// step that the change pushed), and returns the name of a region that
+    /// forbids it.  This is how the rules apply to every kind of edit.
+    fn reject_region_violations(
+        &mut self,
+        previous: Snapshot,
+        pushed: bool,
+    ) -> Option<String> {
+        let regions = match self.regions {
+            Some((_, ref regions)) => regions,
+            None => return None,
+        };
+        if Rc::ptr_eq(&previous.tilegrid, &self.current.tilegrid) {
+            return None;
+        }
+        let violations =
+            regions.new_violations(&previous.tilegrid, &self.current.tilegrid);
+        let region = violations.first()?.2.to_string();
+        if pushed {
+            self.undo_stack.pop();
+            self.persistent_mutation_active = false;
+        }
+        self.current = previous;
+        Some(region)
+    }
+
+    /// If the region rules forbid placing the given tile at the given cell,
+    /// returns the name of a region that forbids it.
+    pub fn forbidding_region(
+        &self,
+        position: (u32, u32),
+        tile: &Option<Tile>,
+    ) -> Option<&str> {
+        self.regions()?.forbidding(position, tile)
+    }
+
+    pub fn paint_clip(&self) -> Option<&[Rect]> {
+        self.paint_clip.as_deref()
+    }
+
+    /// Clips painting to the cells of the floating selection, or, if
+    /// painting is already clipped, stops clipping it.  Returns false if
+    /// there's no selection to clip to.
+    pub fn toggle_paint_clip(&mut self) -> bool {
+        if self.paint_clip.take().is_some() {
+            return true;
+        }
+        let (subgrid, topleft) = match self.selection() {
+            Some(selection) => selection,
+            None => return false,
+        };
+        let rects = match self.selection_mask() {
+            Some(mask) => mask
+                .iter()
+                .map(|&rect| {
+                    let mut rect = rect;
+                    rect.offset(topleft.x(), topleft.y());
+                    rect
+                })
+                .collect(),
+            None => vec![Rect::new(
+                topleft.x(),
+                topleft.y(),
+                subgrid.width(),
+                subgrid.height(),
+            )],
+        };
+        self.paint_clip = Some(rects);
+        true
+    }
+
+    /// Returns true unless painting is clipped and the given cell is
+    /// outside of the clip.
+    pub fn clip_allows(&self, (col, row): (u32, u32)) -> bool {
+        match self.paint_clip {
+            Some(ref rects) => rects
+                .iter()
+                .any(|rect| rect.contains_point((col as i32, row as i32))),
+            None => true,
+        }
+    }
+
+    pub fn toggle_automap_after_stroke(&mut self) -> bool {
+        if self.automap.is_some() {
+            self.automap_after_stroke = !self.automap_after_stroke;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn overlay_path(&self) -> Option<&str> {
+        self.overlay.as_ref().map(|(path, _)| path.as_str())
+    }
+
+    /// Returns the comparison image to draw over the tiles, if any, along
+    /// with its opacity.
+    pub fn overlay(&self) -> Option<(&Sprite, u8)> {
+        self.overlay.as_ref().map(|(_, sprite)| (sprite, self.overlay_opacity))
+    }
+
+    /// Reloads the named tile file from disk, and updates every copy of its
+    /// tiles (including those in the undo history, the clipboard, and the
+    /// brushes) to use the new images.  This doesn't count as a change to
+    /// the map.
+    pub fn reload_tile_file(
+        &mut self,
+        window: &Window,
+        filename: &str,
+    ) -> io::Result<()> {
+        let (sprites, checksum) =
+            self.tilegrid().tileset().load_sprites(window, filename)?;
+        self.replace_sprites(filename, &sprites, checksum);
+        Ok(())
+    }
+
+    pub fn set_alt_tiles_dir(&mut self, dirpath: Option<PathBuf>) {
+        self.alt_tiles_dir = dirpath;
+    }
+
+    /// Returns the (old, new) names of tile files to rename in maps as they
+    /// are loaded (see `BgFile::rename_tile_files`).
+    pub fn tile_renames(&self) -> &[(String, String)] {
+        &self.tile_renames
+    }
+
+    pub fn set_tile_renames(&mut self, renames: Vec<(String, String)>) {
+        self.tile_renames = renames;
+    }
+
+    /// Reloads every tile file from the alternate tiles directory (e.g. one
+    /// with higher-resolution art), which then becomes the current tiles
+    /// directory; the old one becomes the alternate, so that calling this
+    /// again swaps back.  Like `reload_tile_file`, this doesn't count as a
+    /// change to the map.  Returns false if there's no alternate directory.
+    pub fn swap_tiles_dir(&mut self, window: &Window) -> io::Result<bool> {
+        let alt_dirpath = match self.alt_tiles_dir {
+            Some(ref dirpath) => dirpath.clone(),
+            None => return Ok(false),
+        };
+        let old_tileset = self.tilegrid().tileset();
+        let mut tileset = (*old_tileset).clone();
+        tileset.set_dirpath(alt_dirpath.clone());
+        let mut all_sprites = Vec::new();
+        for (index, filename) in old_tileset.filenames().enumerate() {
+            let (sprites, checksum) =
+                tileset.load_sprites(window, &filename)?;
+            // Don't lose any placed tiles to a shorter alternate file.
+            if sprites.len() < old_tileset.tiles(index).count() {
+                let msg = format!(
+                    "{:?} has fewer tiles in {:?}",
+                    filename, alt_dirpath
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+            all_sprites.push((filename, sprites, checksum));
+        }
+        let snapshots = self
+            .undo_stack
+            .iter_mut()
+            .chain(self.redo_stack.iter_mut())
+            .chain(self.checkpoints.iter_mut().map(|&mut (_, ref mut s)| s))
+            .chain(Some(&mut self.current));
+        for snapshot in snapshots {
+            snapshot.set_tiles_dirpath(&alt_dirpath);
+        }
+        for (filename, sprites, checksum) in all_sprites {
+            self.replace_sprites(&filename, &sprites, checksum);
+        }
+        self.alt_tiles_dir = Some(old_tileset.dirpath().to_path_buf());
+        Ok(true)
+    }
+
+    /// Saves the given animations as the named tile file's `.anim` sidecar,
+    /// and uses them from now on (e.g. when exporting an animated map).
+    /// Like `reload_tile_file`, this doesn't count as a change to the map.
+    pub fn set_tile_animations(
+        &mut self,
+        filename: &str,
+        animations: Vec<TileAnimation>,
+    ) -> io::Result<()> {
+        let dirpath = self.tilegrid().tileset().dirpath().to_path_buf();
+        TileAnimation::save_sidecar(&dirpath, filename, &animations)?;
+        let snapshots = self
+            .undo_stack
+            .iter_mut()
+            .chain(self.redo_stack.iter_mut())
+            .chain(self.checkpoints.iter_mut().map(|&mut (_, ref mut s)| s))
+            .chain(Some(&mut self.current));
+        for snapshot in snapshots {
+            snapshot.set_tile_animations(filename, &animations);
+        }
+        Ok(())
+    }
+
+    /// Updates every copy of the named tile file's tiles (including those in
+    /// the undo history, the clipboard, and the brushes) to use the given
+    /// images, and records the file's new checksum.
+    fn replace_sprites(
+        &mut self,
+        filename: &str,
+        sprites: &[Rc<Sprite>],
+        checksum: u64,
+    ) {
+        let snapshots = self
+            .undo_stack
+            .iter_mut()
+            .chain(self.redo_stack.iter_mut())
+            .chain(self.checkpoints.iter_mut().map(|&mut (_, ref mut s)| s));
+        for snapshot in snapshots {
+            snapshot.replace_sprites(filename, sprites, checksum);
+        }
+        self.current.replace_sprites(filename, sprites, checksum);
+        if let Some((ref mut clipboard, _)) = self.clipboard {
+            Rc::make_mut(clipboard).replace_sprites(filename, sprites);
+        }
+        self.brush.replace_sprites(filename, sprites);
+        self.alt_brush.replace_sprites(filename, sprites);
+        let mut tiles: Vec<&mut Option<Tile>> = vec![&mut self.eraser_tile];
+        if let Some(ref mut border_tiles) = self.border_tiles {
+            tiles.extend(border_tiles.iter_mut());
+        }
+        if let Some(ref mut dither_tiles) = self.dither_tiles {
+            tiles.extend(dither_tiles.iter_mut());
+        }
+        if let Some(ref mut random_fill) = self.random_fill {
+            random_fill.replace_sprites(filename, sprites);
+        }
+        for cell in tiles {
+            let replacement = match *cell {
+                Some(ref tile) => tile.with_sprites(filename, sprites),
+                None => None,
+            };
+            if let Some(replacement) = replacement {
+                *cell = replacement;
+            }
+        }
+    }
+
+    pub fn set_overlay(&mut self, overlay: Option<(String, Sprite)>) {
+        self.overlay = overlay;
+    }
+
+    pub fn adjust_overlay_opacity(&mut self, delta: i32) -> bool {
+        if self.overlay.is_some() {
+            self.overlay_opacity =
+                (self.overlay_opacity as i32 + delta).clamp(0, 255) as u8;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_pencil_undo(&mut self, pencil_undo: PencilUndo) {
+        self.pencil_undo = pencil_undo;
+    }
+
+    /// Should be called at the start of each Pencil stroke.  Unless this
+    /// stroke should be merged with the previous one, this ends the
+    /// previous stroke's undo step.
+    pub fn begin_stroke(&mut self) {
+        let merge = match (self.pencil_undo, self.last_stroke_end) {
+            (PencilUndo::Merge(window), Some(end)) => end.elapsed() <= window,
+            _ => false,
+        };
+        if !merge {
+            self.reset_persistent_mutation();
+        }
+    }
+
+    /// Returns a `Mutation` for painting part of a Pencil stroke, which will
+    /// be grouped into undo steps according to the `PencilUndo` setting.
+    pub fn stroke_mutation(
+        &mut self,
+        description: &'static str,
+    ) -> Mutation<'_> {
+        if self.pencil_undo == PencilUndo::Cell {
+            self.mutation(description)
+        } else {
+            self.persistent_mutation(description)
+        }
+    }
+
+    /// Should be called after each completed paint stroke that changed the
+    /// grid.  If automapping after every stroke is enabled, this applies the
+    /// automap rules, folding the result into the stroke's undo step.
+    pub fn finish_stroke(&mut self) {
+        self.last_stroke_end = Some(Instant::now());
+        if self.automap_after_stroke {
+            if let Some((_, ref automap)) = self.automap {
+                let previous = self.current.clone();
+                if !automap.apply(Rc::make_mut(&mut self.current.tilegrid)) {
+                    self.current = previous;
+                    return;
+                }
+                self.current.generation = self.new_generation();
+                if let Some(region) =
+                    self.reject_region_violations(previous, false)
+                {
+                    self.notice =
+                        Some(format!("Can't automap that in {}", region));
+                }
+            }
+        }
+    }
+
+    /// Returns the clipboard's contents as text for the system clipboard
+    /// (see `SubGrid::to_text`), if it has any.
+    pub fn clipboard_text(&self) -> Option<String> {
+        self.clipboard
+            .as_ref()
+            .map(|(subgrid, position)| subgrid.to_text(*position))
+    }
+
+    /// Replaces the clipboard's contents (e.g. with tiles copied in another
+    /// editor and read from the system clipboard).
+    pub fn set_clipboard(&mut self, subgrid: SubGrid, position: Point) {
+        self.clipboard = Some((Rc::new(subgrid), position));
+    }
+
     pub fn selection(&self) -> Option<(&SubGrid, Point)> {
         match self.current.selection {
             Some((ref subgrid, position)) => Some((&subgrid, position)),
@@ -136,33 +1077,211 @@ impl EditorState {
         }
     }
 
+    /// Returns true if the floating selection was cut from the grid (rather
+    /// than pasted), so that cancelling it puts the cut cells back.
+    pub fn selection_is_cut(&self) -> bool {
+        self.current.selection_source.is_some()
+    }
+
+    /// Returns a copy of the map cropped to the selection's bounding rect
+    /// (with the selection itself pasted in), or `None` if nothing within
+    /// the map is selected.
+    pub fn selected_region(&self) -> Option<TileGrid> {
+        let (subgrid, topleft) = self.selection()?;
+        let tilegrid = self.tilegrid();
+        let rect = Rect::new(
+            topleft.x(),
+            topleft.y(),
+            subgrid.width(),
+            subgrid.height(),
+        )
+        .intersection(Rect::new(
+            0,
+            0,
+            tilegrid.width(),
+            tilegrid.height(),
+        ))?;
+        let mut region = tilegrid.clone();
+        region.paste_subgrid(subgrid, topleft);
+        region.crop(rect);
+        Some(region)
+    }
+
+    /// Returns the rectangles making up the selection (relative to its
+    /// top-left), if it was built from more than one.
+    pub fn selection_mask(&self) -> Option<&[Rect]> {
+        self.current.selection_mask.as_deref()
+    }
+
+    /// Expands the selection by `delta` cells on every side (or contracts
+    /// it, if `delta` is negative), staying within the grid, and re-cuts it
+    /// from the grid.  Returns true if the selection changed.
+    pub fn grow_selection(&mut self, delta: i32) -> bool {
+        let rect = match self.selection() {
+            Some((subgrid, topleft)) => Rect::new(
+                topleft.x(),
+                topleft.y(),
+                subgrid.width(),
+                subgrid.height(),
+            ),
+            None => return false,
+        };
+        let width = rect.width() as i32 + 2 * delta;
+        let height = rect.height() as i32 + 2 * delta;
+        if width <= 0 || height <= 0 {
+            return false;
+        }
+        let (grid_width, grid_height) = self.tilegrid().size();
+        let grown = Rect::new(
+            rect.x() - delta,
+            rect.y() - delta,
+            width as u32,
+            height as u32,
+        );
+        let bounds = Rect::new(0, 0, grid_width, grid_height);
+        match grown.intersection(bounds) {
+            Some(new_rect) if new_rect != rect => {
+                let description = if delta > 0 {
+                    "grow selection"
+                } else {
+                    "shrink selection"
+                };
+                self.mutation(description).select(new_rect);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Crops the grid to the bounding box of its non-empty cells.  Returns
+    /// false if there are no empty border rows or columns to remove.
+    pub fn trim_to_content(&mut self) -> bool {
+        self.unselect_if_necessary();
+        let (width, height) = self.tilegrid().size();
+        match self.tilegrid().content_bounds() {
+            Some(bounds) if bounds != Rect::new(0, 0, width, height) => {
+                self.mutation("trim to content").crop_grid(bounds);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if the named layer is drawn while editing.  Hidden
+    /// layers are still saved and exported.
+    pub fn is_layer_visible(&self, name: &str) -> bool {
+        !self.hidden_layers.contains(name)
+    }
+
+    pub fn toggle_layer_visible(&mut self, name: &str) {
+        if !self.hidden_layers.remove(name) {
+            self.hidden_layers.insert(name.to_string());
+        }
+    }
+
+    /// Makes the given layer the one that editing acts on, first dropping
+    /// any floating selection into the old layer.  Switching layers isn't
+    /// an undoable change, and stays in effect across undo and redo.
+    pub fn set_active_layer(&mut self, index: usize) -> bool {
+        if index >= self.tilegrid().num_layers()
+            || index == self.tilegrid().active_layer()
+        {
+            return false;
+        }
+        self.unselect_if_necessary();
+        Rc::make_mut(&mut self.current.tilegrid).set_active_layer(index);
+        true
+    }
+
+    /// After undoing or redoing, switches back to the given layer, unless
+    /// the restored state has a floating selection (which belongs to the
+    /// layer that was active when it was made).
+    fn keep_active_layer(&mut self, index: usize) {
+        let tilegrid = &self.current.tilegrid;
+        if self.current.selection.is_none()
+            && index < tilegrid.num_layers()
+            && index != tilegrid.active_layer()
+        {
+            Rc::make_mut(&mut self.current.tilegrid).set_active_layer(index);
+        }
+    }
+
     pub fn unselect_if_necessary(&mut self) {
         self.reset_persistent_mutation();
         if self.selection().is_some() {
-            self.mutation().unselect();
+            self.mutation("deselect").unselect();
         }
     }
 
-    pub fn mutation(&mut self) -> Mutation {
+    /// Returns a `Mutation` for making a new undoable change, which will be
+    /// labeled with the given description (e.g. "flood fill").
+    pub fn mutation(&mut self, description: &'static str) -> Mutation {
+        let previous = self.current.clone();
         self.push_change();
-        self.current.unsaved = true;
-        Mutation { state: self }
+        self.current.generation = self.new_generation();
+        self.current.description = description;
+        Mutation { state: self, previous: Some((previous, true)) }
     }
 
-    pub fn persistent_mutation(&mut self) -> Mutation {
-        if !self.persistent_mutation_active {
+    pub fn persistent_mutation(
+        &mut self,
+        description: &'static str,
+    ) -> Mutation {
+        let previous = self.current.clone();
+        let pushed = !self.persistent_mutation_active;
+        if pushed {
             self.push_change();
             self.persistent_mutation_active = true;
+            self.current.description = description;
+        }
+        self.current.generation = self.new_generation();
+        Mutation { state: self, previous: Some((previous, pushed)) }
+    }
+
+    /// Returns the grid as it was before the change that `undo` would
+    /// revert, if any.
+    pub fn previous_tilegrid(&self) -> Option<&TileGrid> {
+        self.undo_stack.last().map(|snapshot| &*snapshot.tilegrid)
+    }
+
+    /// Returns the description of the change that `undo` would revert.
+    pub fn undo_description(&self) -> Option<&'static str> {
+        if self.undo_stack.is_empty() {
+            None
+        } else {
+            Some(self.current.description)
         }
-        self.current.unsaved = true;
-        Mutation { state: self }
+    }
+
+    /// Returns the description of the change that `redo` would reapply.
+    pub fn redo_description(&self) -> Option<&'static str> {
+        self.redo_stack.last().map(|snapshot| snapshot.description)
     }
 
     pub fn reset_persistent_mutation(&mut self) {
         self.persistent_mutation_active = false;
     }
 
+    /// Reverts the changes made by the active persistent mutation (if any),
+    /// without leaving anything to redo.  Returns true if anything was
+    /// reverted.
+    pub fn cancel_persistent_mutation(&mut self) -> bool {
+        if !self.persistent_mutation_active {
+            return false;
+        }
+        self.persistent_mutation_active = false;
+        match self.undo_stack.pop() {
+            Some(snapshot) => {
+                self.current = snapshot;
+                true
+            }
+            None => false,
+        }
+    }
+
     fn push_change(&mut self) {
+        self.load_warning = None;
+        self.notice = None;
         self.reset_persistent_mutation();
         self.redo_stack.clear();
         self.undo_stack.push(self.current.clone());
@@ -171,10 +1290,20 @@ impl EditorState {
         }
     }
 
+    /// Ends any persistent mutation (and any pending Pencil stroke merge),
+    /// so that the next change after an undo or redo is its own step.
+    fn end_merging(&mut self) {
+        self.reset_persistent_mutation();
+        self.last_stroke_end = None;
+    }
+
     pub fn undo(&mut self) -> bool {
         if let Some(mut snapshot) = self.undo_stack.pop() {
+            self.end_merging();
+            let layer = self.tilegrid().active_layer();
             mem::swap(&mut snapshot, &mut self.current);
             self.redo_stack.push(snapshot);
+            self.keep_active_layer(layer);
             if self.current.selection.is_some() {
                 self.tool = Tool::Select;
             }
@@ -186,8 +1315,11 @@ impl EditorState {
 
     pub fn redo(&mut self) -> bool {
         if let Some(mut snapshot) = self.redo_stack.pop() {
+            self.end_merging();
+            let layer = self.tilegrid().active_layer();
             mem::swap(&mut snapshot, &mut self.current);
             self.undo_stack.push(snapshot);
+            self.keep_active_layer(layer);
             if self.current.selection.is_some() {
                 self.tool = Tool::Select;
             }
@@ -197,28 +1329,273 @@ impl EditorState {
         }
     }
 
+    pub fn checkpoint_names(&self) -> Vec<String> {
+        self.checkpoints.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Records the current state under the given name (replacing any
+    /// existing checkpoint with that name), so that it can be restored later
+    /// in the session.
+    pub fn save_checkpoint(&mut self, name: String) {
+        self.unselect_if_necessary();
+        self.checkpoints.retain(|(other, _)| *other != name);
+        self.checkpoints.push((name, self.current.clone()));
+    }
+
+    /// Restores the named checkpoint as a single undoable change, unless
+    /// that would break the region rules.  Returns false if there is no such
+    /// checkpoint.
+    pub fn restore_checkpoint(&mut self, name: &str) -> bool {
+        let snapshot =
+            match self.checkpoints.iter().find(|(other, _)| other == name) {
+                Some((_, snapshot)) => snapshot.clone(),
+                None => return false,
+            };
+        self.unselect_if_necessary();
+        let previous = self.current.clone();
+        self.push_change();
+        self.current = snapshot;
+        self.current.generation = self.new_generation();
+        self.current.description = "restore checkpoint";
+        if let Some(region) = self.reject_region_violations(previous, true) {
+            self.notice =
+                Some(format!("Can't restore that checkpoint in {}", region));
+        }
+        true
+    }
+
+    /// Replaces the grid with one loaded from a backup, as an undoable
+    /// change, unless that would break the region rules.
+    pub fn restore_backup(&mut self, tilegrid: TileGrid) {
+        self.unselect_if_necessary();
+        let previous = self.current.clone();
+        self.push_change();
+        self.current.tilegrid = Rc::new(tilegrid);
+        self.current.generation = self.new_generation();
+        self.current.description = "restore backup";
+        if let Some(region) = self.reject_region_violations(previous, true) {
+            self.notice =
+                Some(format!("Can't restore that backup in {}", region));
+        }
+    }
+
+    /// Queues the map to be saved to its file (and exported, if a watch
+    /// export format is set).  The files are written in the background; use
+    /// `poll_saves` to find out when they're done.
     pub fn save_to_file(&mut self) -> io::Result<()> {
         self.unselect_if_necessary();
-        let mut file = File::create(&self.filepath)?;
-        self.tilegrid().save(&mut file)?;
-        self.current.unsaved = false;
-        for snapshot in self.undo_stack.iter_mut() {
-            snapshot.unsaved = true;
+        // The map has to be serialized here, since the tile grid can't be
+        // sent to the writer's thread, but compressing the data (and any
+        // exported images) happens there.
+        let mut data = Vec::new();
+        if self.trim_on_save {
+            self.tilegrid().trimmed().save(&mut data)?;
+        } else {
+            self.tilegrid().save(&mut data)?;
+        }
+        let mut path = PathBuf::from(&self.filepath);
+        let contents: Contents = if util::is_gzip_path(&path) {
+            path.set_extension("");
+            Box::new(move || util::gzip(&data))
+        } else {
+            Box::new(move || Ok(data))
+        };
+        let mut paths = vec![PathBuf::from(&self.filepath)];
+        // Failing to make a backup shouldn't stop the map from being saved.
+        let mut backup = None;
+        let mut backup_error = None;
+        if self.max_backups > 0 {
+            let pending: Vec<PathBuf> = self
+                .pending_saves
+                .iter()
+                .filter_map(|pending| pending.backup.clone())
+                .collect();
+            let map_path = Path::new(&self.filepath);
+            match backup::new_backup_path(
+                map_path,
+                SystemTime::now(),
+                &pending,
+            ) {
+                Ok(path) => {
+                    paths.push(path.clone());
+                    backup = Some(path);
+                }
+                Err(error) => backup_error = Some(error),
+            }
+        }
+        let mut files = vec![(paths, contents)];
+        if let Some(format) = self.watch_export {
+            let path = path.with_extension(format.extension());
+            let contents = format.prepare(self.tilegrid())?;
+            for (path, contents) in format.image_files(self.tilegrid(), &path)
+            {
+                files.push((vec![path], contents));
+            }
+            files.push((vec![path], contents));
+        }
+        self.writer.write(files)?;
+        self.pending_saves.push_back(PendingSave {
+            filepath: self.filepath.clone(),
+            backup,
+            saved: Some((
+                self.current.generation,
+                self.current.tilegrid.clone(),
+            )),
+        });
+        if let Some(error) = backup_error {
+            self.notice =
+                Some(format!("Saved, but couldn't back up: {}", error));
         }
-        for snapshot in self.redo_stack.iter_mut() {
-            snapshot.unsaved = true;
+        if let Some(ref mut lock) = self.lock {
+            lock.retry();
+            if let Some(holder) = lock.other_holder() {
+                self.notice =
+                    Some(format!("Saved, but also open by {}", holder));
+            }
         }
         Ok(())
     }
 
+    /// Handles any background saves that have finished since the last call.
+    /// Returns true if any did.
+    pub fn poll_saves(&mut self) -> bool {
+        let mut any_finished = false;
+        while let Some(result) = self.writer.poll() {
+            any_finished = true;
+            let pending = self.pending_saves.pop_front().unwrap_or_default();
+            match result {
+                Ok(checksums) => {
+                    if let Some((generation, tilegrid)) = pending.saved {
+                        self.saved_generation = generation;
+                        self.saved_tilegrid = tilegrid;
+                    }
+                    if pending.backup.is_some() {
+                        let map_path = Path::new(&pending.filepath);
+                        if let Err(error) =
+                            backup::prune_backups(map_path, self.max_backups)
+                        {
+                            self.notice = Some(format!(
+                                "Couldn't delete old backups: {}",
+                                error
+                            ));
+                        }
+                    }
+                    // The map file is always the first file in its batch.
+                    if let Some(&checksum) = checksums.first() {
+                        self.last_save = Some((pending.filepath, checksum));
+                    }
+                    self.num_saves += 1;
+                    self.save_error = None;
+                }
+                Err(error) => {
+                    self.save_error = Some(error.to_string());
+                }
+            }
+        }
+        any_finished
+    }
+
+    /// Returns true if there are saves still being written to disk.
+    pub fn is_saving(&self) -> bool {
+        !self.pending_saves.is_empty()
+    }
+
+    /// Returns a warning about the most recently loaded map (e.g. that its
+    /// tile files have changed since it was saved), until the first change
+    /// is made to it.
+    pub fn load_warning(&self) -> Option<&str> {
+        self.load_warning.as_deref()
+    }
+
+    /// Returns the most recent notice (e.g. the details of an inspected
+    /// cell), until the next change is made.
+    pub fn notice(&self) -> Option<&str> {
+        self.notice.as_deref()
+    }
+
+    pub fn set_notice(&mut self, notice: String) {
+        self.notice = Some(notice);
+    }
+
+    /// Returns the cell that the mouse was last over on the canvas, for the
+    /// coords indicators to show when there is no selection.
+    pub fn hovered_cell(&self) -> Option<(u32, u32)> {
+        self.hovered_cell
+    }
+
+    pub fn set_hovered_cell(&mut self, cell: (u32, u32)) {
+        self.hovered_cell = Some(cell);
+    }
+
+    /// Returns the error from the most recent save, if it failed.
+    pub fn save_error(&self) -> Option<&str> {
+        self.save_error.as_deref()
+    }
+
     pub fn load_tilegrid(&mut self, path: String, tilegrid: TileGrid) {
+        self.load_warning = load_warning(&tilegrid);
+        self.notice = None;
+        self.hovered_cell = None;
         self.filepath = path;
         self.current.tilegrid = Rc::new(tilegrid);
-        self.current.unsaved = false;
+        self.current.generation = self.new_generation();
+        self.saved_generation = self.current.generation;
+        self.saved_tilegrid = self.current.tilegrid.clone();
+        for pending in self.pending_saves.iter_mut() {
+            pending.saved = None;
+        }
         self.undo_stack.clear();
         self.redo_stack.clear();
-        self.brush = None;
+        self.checkpoints.clear();
+        self.brush = single_tile_brush(None);
+        self.alt_brush = single_tile_brush(None);
+        self.eraser_tile = None;
+        self.random_fill = None;
+        self.paint_clip = None;
         self.persistent_mutation_active = false;
+        self.lock_map_file();
+    }
+
+    /// Takes the advisory lock on the map file (releasing the lock on any
+    /// previous file), with a warning if another instance already has the
+    /// file open.
+    pub fn lock_map_file(&mut self) {
+        self.lock = None;
+        let lock = FileLock::acquire(Path::new(&self.filepath));
+        if let Some(holder) = lock.other_holder() {
+            let warning = format!("Also open by {}", holder);
+            self.load_warning = Some(match self.load_warning.take() {
+                Some(other) => format!("{}; {}", warning, other),
+                None => warning,
+            });
+        }
+        self.lock = Some(lock);
+    }
+}
+
+/// Returns a 1x1 brush of the given tile (or empty cell).
+fn single_tile_brush(tile: Option<Tile>) -> SubGrid {
+    let mut brush = SubGrid::new(1, 1);
+    brush.set((0, 0), tile);
+    brush
+}
+
+fn load_warning(tilegrid: &TileGrid) -> Option<String> {
+    let changed = tilegrid.changed_tile_files();
+    let tileset = tilegrid.tileset();
+    let oversized = tileset.oversized_files();
+    if !changed.is_empty() {
+        Some(format!("Tiles changed since save: {}", changed.join(", ")))
+    } else if let Some(error) = tilegrid.backdrop_error() {
+        Some(format!("Backdrop not loaded: {}", error))
+    } else if !oversized.is_empty() {
+        Some(format!(
+            "Tiles larger than {}px: {}",
+            tilegrid.tile_size(),
+            oversized.join(", ")
+        ))
+    } else {
+        None
     }
 }
 
@@ -226,6 +1603,22 @@ impl EditorState {
 
 pub struct Mutation<'a> {
     state: &'a mut EditorState,
+    // The state from before this mutation, and whether the mutation pushed
+    // a new undo step, for rejecting changes that break the region rules:
+    previous: Option<(Snapshot, bool)>,
+}
+
+impl<'a> Drop for Mutation<'a> {
+    fn drop(&mut self) {
+        if let Some((previous, pushed)) = self.previous.take() {
+            if let Some(region) =
+                self.state.reject_region_violations(previous, pushed)
+            {
+                self.state.notice =
+                    Some(format!("Can't paint that in {}", region));
+            }
+        }
+    }
 }
 
 impl<'a> Mutation<'a> {
@@ -233,24 +1626,68 @@ impl<'a> Mutation<'a> {
         Rc::make_mut(&mut self.state.current.tilegrid)
     }
 
-    pub fn resize_grid(&mut self, width: u32, height: u32) {
-        self.tilegrid().resize(width, height);
+    pub fn resize_grid(&mut self, width: u32, height: u32, fill: ResizeFill) {
+        match fill {
+            ResizeFill::Empty => self.tilegrid().resize(width, height),
+            ResizeFill::Brush => {
+                let (old_width, old_height) = self.state.tilegrid().size();
+                let pattern = self.state.brush_grid().tiled(
+                    width,
+                    height,
+                    Point::new(0, 0),
+                );
+                let tilegrid = self.tilegrid();
+                tilegrid.resize(width, height);
+                for row in 0..height {
+                    for col in 0..width {
+                        if col >= old_width || row >= old_height {
+                            tilegrid
+                                .set((col, row), pattern[(col, row)].clone());
+                        }
+                    }
+                }
+            }
+            ResizeFill::Extend => {
+                self.tilegrid().resize_extending(width, height)
+            }
+        }
+    }
+
+    pub fn crop_grid(&mut self, rect: Rect) {
+        self.tilegrid().crop(rect);
     }
 
     pub fn set_background_color(&mut self, red: u8, green: u8, blue: u8) {
         self.tilegrid().set_background_color(red, green, blue);
     }
 
-    pub fn set_tile_filenames(
+    pub fn set_background_gradient(&mut self, bottom: Option<(u8, u8, u8)>) {
+        self.tilegrid().set_background_gradient(bottom);
+    }
+
+    pub fn set_backdrop(&mut self, backdrop: Option<Backdrop>) {
+        self.tilegrid().set_backdrop(backdrop);
+    }
+
+    pub fn set_metadata(&mut self, metadata: Vec<(String, String)>) {
+        self.tilegrid().set_metadata(metadata);
+    }
+
+    pub fn replace_adjacent(
         &mut self,
-        window: &Window,
-        filenames: Vec<&str>,
-    ) -> io::Result<()> {
-        self.tilegrid().set_tile_filenames(window, filenames)
+        from: &Option<Tile>,
+        to: &Option<Tile>,
+        near: &Option<Tile>,
+    ) {
+        self.tilegrid().replace_adjacent(from, to, near);
     }
 
     pub fn select(&mut self, rect: Rect) {
         self.unselect();
+        let rect = self.tilegrid().expand_to_tiles(rect);
+        let source = self.tilegrid().copy_subgrid(rect);
+        self.state.current.selection_source =
+            Some((Rc::new(source), rect.top_left()));
         let subgrid = self.tilegrid().cut_subgrid(rect);
         self.state.current.selection =
             Some((Rc::new(subgrid), rect.top_left()));
@@ -263,15 +1700,108 @@ impl<'a> Mutation<'a> {
         self.select(Rect::new(0, 0, width, height));
     }
 
+    /// Selects every cell of the grid containing the given tile (or every
+    /// empty cell, for `None`), as a masked selection if those cells don't
+    /// form a single rectangle.  Returns the number of cells selected.
+    pub fn select_matching(&mut self, tile: &Option<Tile>) -> u32 {
+        self.unselect();
+        let rects = self.tilegrid().rects_containing(tile);
+        let count =
+            rects.iter().map(|rect| rect.width() * rect.height()).sum();
+        if !rects.is_empty() {
+            self.float_parts(rects);
+            self.state.prev_tool = self.state.tool;
+            self.state.tool = Tool::Select;
+        }
+        count
+    }
+
+    /// Adds another rectangle of the grid to the selection.  The selection
+    /// still floats as a single subgrid, spanning all of its rectangles,
+    /// whose cells between the rectangles are left empty.  If the existing
+    /// selection has been moved, the move is committed first.
+    pub fn add_to_selection(&mut self, rect: Rect) {
+        let (old, position) = match self.state.current.selection {
+            Some((ref old, position)) => (old.clone(), position),
+            None => {
+                self.select(rect);
+                return;
+            }
+        };
+        let mask = self.state.current.selection_mask.clone();
+        let (width, height) = self.tilegrid().size();
+        let grid_rect = Rect::new(0, 0, width, height);
+        let mut rects: Vec<Rect> = mask
+            .unwrap_or_else(|| {
+                vec![Rect::new(0, 0, old.width(), old.height())]
+            })
+            .into_iter()
+            .filter_map(|mut part| {
+                part.offset(position.x(), position.y());
+                part.intersection(grid_rect)
+            })
+            .collect();
+        rects.push(rect);
+        self.unselect();
+        self.float_parts(rects);
+    }
+
+    /// Cuts the given (non-empty list of) rectangles out of the grid as a
+    /// single floating selection spanning all of them, masked to just those
+    /// rectangles.  There must not already be a selection.
+    fn float_parts(&mut self, mut rects: Vec<Rect>) {
+        let bounds = rects[1..]
+            .iter()
+            .fold(rects[0], |bounds, &part| bounds.union(part));
+        let source = self.tilegrid().copy_subgrid(bounds);
+        let mut combined = SubGrid::new(bounds.width(), bounds.height());
+        for part in rects.iter_mut() {
+            let piece = self.tilegrid().cut_subgrid(*part);
+            part.offset(-bounds.x(), -bounds.y());
+            combined.paste(&piece, (part.x() as u32, part.y() as u32));
+        }
+        self.state.current.selection =
+            Some((Rc::new(combined), bounds.top_left()));
+        self.state.current.selection_mask = Some(rects);
+        self.state.current.selection_source =
+            Some((Rc::new(source), bounds.top_left()));
+    }
+
     pub fn unselect(&mut self) {
+        self.state.current.selection_mask = None;
+        self.state.current.selection_source = None;
         if let Some((grid, position)) = self.state.current.selection.take() {
             self.tilegrid().paste_subgrid(&grid, position);
         }
     }
 
+    /// Discards the floating selection (and any changes made to it), putting
+    /// back the cells it was cut from where they originally were.  A
+    /// selection that wasn't cut from the grid (such as a paste) is dropped
+    /// in place instead, as by `unselect`.
+    pub fn cancel_selection(&mut self) {
+        if self.state.current.selection_source.is_none() {
+            self.unselect();
+            return;
+        }
+        self.state.current.selection = None;
+        self.state.current.selection_mask = None;
+        if let Some((source, position)) =
+            self.state.current.selection_source.take()
+        {
+            self.tilegrid().paste_subgrid(&source, position);
+        }
+    }
+
     pub fn flip_selection_horz(&mut self) {
         if let Some((ref mut subgrid, _)) = self.state.current.selection {
             Rc::make_mut(subgrid).flip_horz();
+            if let Some(ref mut mask) = self.state.current.selection_mask {
+                for part in mask.iter_mut() {
+                    let right = subgrid.width() as i32 - part.right();
+                    part.set_x(right);
+                }
+            }
         } else {
             let (width, height) = self.tilegrid().size();
             let rect = Rect::new(0, 0, width, height);
@@ -284,6 +1814,12 @@ impl<'a> Mutation<'a> {
     pub fn flip_selection_vert(&mut self) {
         if let Some((ref mut subgrid, _)) = self.state.current.selection {
             Rc::make_mut(subgrid).flip_vert();
+            if let Some(ref mut mask) = self.state.current.selection_mask {
+                for part in mask.iter_mut() {
+                    let bottom = subgrid.height() as i32 - part.bottom();
+                    part.set_y(bottom);
+                }
+            }
         } else {
             let (width, height) = self.tilegrid().size();
             let rect = Rect::new(0, 0, width, height);
@@ -293,13 +1829,68 @@ impl<'a> Mutation<'a> {
         }
     }
 
+    /// Replaces each tile in the grid that is a key in the given map with its
+    /// value (e.g. to canonicalize references to duplicate tiles).
+    pub fn substitute_tiles(&mut self, substitutions: &BTreeMap<Tile, Tile>) {
+        self.tilegrid().substitute_tiles(substitutions);
+    }
+
+    /// Re-rolls a random variant for each cell (within the selection, if
+    /// any, or else the whole grid) that contains one of the given variants.
+    pub fn rerandomize_variants(&mut self, variants: &[Tile]) {
+        let mut rng = Rng::from_time();
+        if let Some((ref mut subgrid, _)) = self.state.current.selection {
+            Rc::make_mut(subgrid).rerandomize(variants, &mut rng);
+        } else {
+            self.tilegrid().rerandomize(variants, &mut rng);
+        }
+    }
+
+    /// Replaces the contents of the selection (or of the whole grid, if
+    /// nothing is selected) with the subgrid returned by `generate`, which
+    /// is passed the size and grid position of the area to fill.
+    pub fn generate<F>(&mut self, generate: F)
+    where
+        F: FnOnce(u32, u32, Point) -> SubGrid,
+    {
+        if let Some((ref mut subgrid, position)) = self.state.current.selection
+        {
+            let (width, height) = subgrid.size();
+            *subgrid = Rc::new(generate(width, height, position));
+            self.state.current.selection_mask = None;
+        } else {
+            let (width, height) = self.tilegrid().size();
+            let subgrid = generate(width, height, Point::new(0, 0));
+            if let Err(error) = self.tilegrid().replace_subgrid(subgrid) {
+                self.state.notice =
+                    Some(format!("Couldn't generate: {}", error));
+            }
+        }
+    }
+
+    pub fn border_selection(&mut self) {
+        let tiles = match self.state.border_tiles {
+            Some(ref tiles) => tiles,
+            None => return,
+        };
+        if let Some((ref mut subgrid, _)) = self.state.current.selection {
+            let (width, height) = subgrid.size();
+            Rc::make_mut(subgrid)
+                .draw_border(Rect::new(0, 0, width, height), tiles);
+        }
+    }
+
     pub fn delete_selection(&mut self) {
         self.state.current.selection = None;
+        self.state.current.selection_mask = None;
+        self.state.current.selection_source = None;
     }
 
     pub fn cut_selection(&mut self) {
         if self.state.current.selection.is_some() {
             self.state.clipboard = self.state.current.selection.take();
+            self.state.current.selection_mask = None;
+            self.state.current.selection_source = None;
         } else {
             let (width, height) = self.tilegrid().size();
             let rect = Rect::new(0, 0, width, height);
@@ -327,6 +1918,26 @@ impl<'a> Mutation<'a> {
         }
     }
 
+    /// Drops a copy of the floating selection where it is, and leaves the
+    /// selection floating one tile down and to the right of the copy.
+    pub fn duplicate_selection(&mut self) {
+        if let Some((subgrid, position)) = self.state.current.selection.clone()
+        {
+            let mask = self.state.current.selection_mask.clone();
+            self.unselect();
+            self.state.current.selection =
+                Some((subgrid, position.offset(1, 1)));
+            self.state.current.selection_mask = mask;
+        }
+    }
+
+    /// Replaces the selection's contents and position, without changing the
+    /// grid underneath.
+    pub fn replace_selection(&mut self, subgrid: SubGrid, position: Point) {
+        self.state.current.selection = Some((Rc::new(subgrid), position));
+        self.state.current.selection_mask = None;
+    }
+
     pub fn reposition_selection(&mut self, new_position: Point) {
         if let Some((_, ref mut position)) = self.state.current.selection {
             *position = new_position;
@@ -335,3 +1946,82 @@ impl<'a> Mutation<'a> {
 }
 
 //===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{EditorState, PencilUndo, ResizeFill};
+    use crate::regions::Regions;
+    use crate::tilegrid::{Tile, TileGrid, Tileset};
+    use sdl2::rect::{Point, Rect};
+    use std::time::Duration;
+
+    // Returns a state for a 4x4 grid with a rock at (0, 0), and a region
+    // that allows no tiles over its bottom-right quarter.
+    fn state_with_region() -> EditorState {
+        let mut tilegrid = TileGrid::new(Tileset::empty());
+        tilegrid.resize(4, 4);
+        tilegrid.set((0, 0), Some(Tile::blank("rock", 0)));
+        let mut state = EditorState::new("test.bg".to_string(), tilegrid);
+        let regions = Regions::parse("2,2 2x2 - Keep out\n").unwrap();
+        state.set_regions(Some(("test.regions".to_string(), regions)));
+        state
+    }
+
+    #[test]
+    fn dropping_selection_in_region_is_rejected() {
+        let mut state = state_with_region();
+        state.mutation("select").select(Rect::new(0, 0, 1, 1));
+        state.mutation("move").reposition_selection(Point::new(2, 2));
+        state.mutation("deselect").unselect();
+        assert_eq!(state.notice(), Some("Can't paint that in Keep out"));
+        let (subgrid, position) = state.selection().unwrap();
+        assert_eq!(position, Point::new(2, 2));
+        assert!(subgrid[(0, 0)] == Some(Tile::blank("rock", 0)));
+        assert!(state.tilegrid()[(2, 2)].is_none());
+        assert_eq!(state.undo_description(), Some("move"));
+    }
+
+    #[test]
+    fn restoring_into_region_is_rejected() {
+        let mut state = state_with_region();
+        let mut tilegrid = state.tilegrid().clone();
+        tilegrid.set((3, 3), Some(Tile::blank("rock", 0)));
+        state.restore_backup(tilegrid.clone());
+        assert_eq!(
+            state.notice(),
+            Some("Can't restore that backup in Keep out")
+        );
+        assert!(state.tilegrid()[(3, 3)].is_none());
+        assert!(!state.undo());
+        state.set_regions(None);
+        state.restore_backup(tilegrid);
+        state.save_checkpoint("rock".to_string());
+        assert!(state.undo());
+        let regions = Regions::parse("2,2 2x2 - Keep out\n").unwrap();
+        state.set_regions(Some(("test.regions".to_string(), regions)));
+        assert!(state.restore_checkpoint("rock"));
+        assert!(state.tilegrid()[(3, 3)].is_none());
+        assert_eq!(state.redo_description(), None);
+        assert!(!state.undo());
+    }
+
+    #[test]
+    fn merged_stroke_after_undo_is_new_step() {
+        let tilegrid = TileGrid::new(Tileset::empty());
+        let size = tilegrid.size();
+        let mut state = EditorState::new("test.bg".to_string(), tilegrid);
+        state.set_pencil_undo(PencilUndo::Merge(Duration::from_secs(3600)));
+        state.begin_stroke();
+        state.stroke_mutation("pencil").resize_grid(2, 2, ResizeFill::Empty);
+        state.finish_stroke();
+        assert!(state.undo());
+        assert_eq!(state.tilegrid().size(), size);
+        state.begin_stroke();
+        state.stroke_mutation("pencil").resize_grid(3, 3, ResizeFill::Empty);
+        state.finish_stroke();
+        assert_eq!(state.tilegrid().size(), (3, 3));
+        assert!(state.undo());
+        assert_eq!(state.tilegrid().size(), size);
+        assert!(!state.undo());
+    }
+}
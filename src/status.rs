@@ -0,0 +1,92 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::canvas::{Canvas, Font};
+use super::element::{Action, GuiElement};
+use super::event::Event;
+use super::state::EditorState;
+use sdl2::rect::Point;
+use std::rc::Rc;
+
+// ========================================================================= //
+
+/// Displays a short right-aligned status message, such as a description of
+/// the change that would be undone (and the overlay's opacity, if there is
+/// an overlay).
+pub struct StatusBar {
+    topright: Point,
+    font: Rc<Font>,
+}
+
+impl StatusBar {
+    pub fn new(right: i32, top: i32, font: Rc<Font>) -> StatusBar {
+        StatusBar { topright: Point::new(right, top), font }
+    }
+
+    /// Returns the message to show: the overlay's opacity (while an overlay
+    /// is shown), followed by the most pressing status message, if any.
+    fn message(&self, state: &EditorState) -> Option<String> {
+        let message = self.status_message(state);
+        let (_, opacity) = match state.overlay() {
+            Some(overlay) => overlay,
+            None => return message,
+        };
+        let overlay =
+            format!("Overlay: {}%", (opacity as u32 * 100 + 127) / 255);
+        Some(match message {
+            Some(message) => format!("{}  {}", overlay, message),
+            None => overlay,
+        })
+    }
+
+    fn status_message(&self, state: &EditorState) -> Option<String> {
+        if state.is_saving() {
+            Some("Saving...".to_string())
+        } else if let Some(error) = state.save_error() {
+            Some(format!("Save failed: {}", error))
+        } else if let Some(notice) = state.notice() {
+            Some(notice.to_string())
+        } else if let Some(warning) = state.load_warning() {
+            Some(warning.to_string())
+        } else if let Some(description) = state.undo_description() {
+            Some(format!("Undo: {}", description))
+        } else {
+            state
+                .redo_description()
+                .map(|description| format!("Redo: {}", description))
+        }
+    }
+}
+
+impl GuiElement<EditorState, ()> for StatusBar {
+    fn draw(&self, state: &EditorState, canvas: &mut Canvas) {
+        if let Some(message) = self.message(state) {
+            let width = self.font.text_width(&message);
+            let start =
+                self.topright + Point::new(-width, self.font.baseline());
+            canvas.draw_text(&self.font, start, &message);
+        }
+    }
+
+    fn on_event(&mut self, _: &Event, _: &mut EditorState) -> Action<()> {
+        Action::ignore()
+    }
+}
+
+// ========================================================================= //
@@ -0,0 +1,230 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::tilegrid::{parse_tile_name, Tile, TileGrid, Tileset};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+//===========================================================================//
+
+// The bits of a neighbor mask, for each direction whose neighbor is also part
+// of the terrain:
+const NORTH: usize = 1;
+const EAST: usize = 2;
+const SOUTH: usize = 4;
+const WEST: usize = 8;
+const ALL_SIDES: usize = NORTH | EAST | SOUTH | WEST;
+
+/// A terrain for the terrain brush, read from a `.terrain` rules file.  Each
+/// line of the file gives the tile to use for a terrain cell whose
+/// orthogonal neighbors in the terrain are the listed directions, e.g.
+///
+/// ```text
+/// # Directions are N, E, S, and W; "-" means no neighbors.
+/// -    grass:0
+/// ES   grass:1
+/// NESW grass:4
+/// ```
+///
+/// Every tile listed is part of the terrain.  Combinations not listed use
+/// the `NESW` tile (or, failing that, the first tile listed).  Cells past the
+/// edge of the map count as part of the terrain, so that terrain running off
+/// the map doesn't get an edge.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Terrain {
+    // The tile for each neighbor mask:
+    tiles: Vec<Option<(String, usize)>>,
+    // The mask of the first tile listed:
+    first: usize,
+}
+
+impl Terrain {
+    pub fn parse(text: &str) -> io::Result<Terrain> {
+        let mut tiles = vec![None; ALL_SIDES + 1];
+        let mut first = None;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let pieces: Vec<&str> = line.split_whitespace().collect();
+            if pieces.len() != 2 {
+                let msg = format!("invalid terrain line: {:?}", line);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+            let mask = parse_mask(pieces[0])?;
+            let (filename, index) =
+                parse_tile_name(pieces[1]).ok_or_else(|| {
+                    let msg = format!("invalid terrain tile: {:?}", pieces[1]);
+                    io::Error::new(io::ErrorKind::InvalidData, msg)
+                })?;
+            if tiles[mask].is_some() {
+                let msg = format!("duplicate terrain neighbors: {:?}", line);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+            tiles[mask] = Some((filename.to_string(), index));
+            first.get_or_insert(mask);
+        }
+        let first = first.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "terrain has no tiles")
+        })?;
+        Ok(Terrain { tiles, first })
+    }
+
+    pub fn load_from_path(path: &Path) -> io::Result<Terrain> {
+        Terrain::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Returns an error if the terrain refers to a tile that doesn't exist in
+    /// the given tileset.
+    pub fn validate(&self, tileset: &Tileset) -> io::Result<()> {
+        for &(ref filename, index) in self.tiles.iter().flatten() {
+            if tileset.get_named(filename, index).is_none() {
+                let msg = format!("no such tile: {}:{}", filename, index);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+        }
+        Ok(())
+    }
+
+    fn contains(&self, cell: &Option<Tile>) -> bool {
+        match *cell {
+            Some(ref tile) => {
+                self.tiles.iter().flatten().any(|&(ref filename, index)| {
+                    tile.filename() == filename && tile.index() == index
+                })
+            }
+            None => false,
+        }
+    }
+
+    fn tile_name_for(&self, mask: usize) -> &(String, usize) {
+        self.tiles[mask]
+            .as_ref()
+            .or(self.tiles[ALL_SIDES].as_ref())
+            .or(self.tiles[self.first].as_ref())
+            .unwrap()
+    }
+
+    /// Returns the cells that change (and the tiles they change to) when
+    /// the terrain is painted at the given position: that cell joins the
+    /// terrain, and it and its neighbors in the terrain switch to the tiles
+    /// matching their own neighbors.
+    pub fn paint_changes(
+        &self,
+        tilegrid: &TileGrid,
+        position: (u32, u32),
+    ) -> Vec<((u32, u32), Tile)> {
+        let tileset = tilegrid.tileset();
+        let (width, height) =
+            (tilegrid.width() as i32, tilegrid.height() as i32);
+        let in_terrain = |col: i32, row: i32| {
+            if col < 0 || row < 0 || col >= width || row >= height {
+                return true;
+            }
+            let cell = (col as u32, row as u32);
+            cell == position || self.contains(&tilegrid[cell])
+        };
+        let (col, row) = (position.0 as i32, position.1 as i32);
+        let mut changes = Vec::new();
+        for (dx, dy) in [(0, 0), (0, -1), (1, 0), (0, 1), (-1, 0)] {
+            let (col, row) = (col + dx, row + dy);
+            if col < 0 || row < 0 || col >= width || row >= height {
+                continue;
+            }
+            if !in_terrain(col, row) {
+                continue;
+            }
+            let mut mask = 0;
+            for (bit, dx, dy) in
+                [(NORTH, 0, -1), (EAST, 1, 0), (SOUTH, 0, 1), (WEST, -1, 0)]
+            {
+                if in_terrain(col + dx, row + dy) {
+                    mask |= bit;
+                }
+            }
+            let &(ref filename, index) = self.tile_name_for(mask);
+            let cell = (col as u32, row as u32);
+            if let Some(tile) = tileset.get_named(filename, index) {
+                if tilegrid[cell].as_ref() != Some(&tile) {
+                    changes.push((cell, tile));
+                }
+            }
+        }
+        changes
+    }
+}
+
+/// Parses a set of directions like "NES" (or "-" for none) into a mask.
+fn parse_mask(token: &str) -> io::Result<usize> {
+    if token == "-" {
+        return Ok(0);
+    }
+    let mut mask = 0;
+    for chr in token.chars() {
+        let bit = match chr {
+            'N' => NORTH,
+            'E' => EAST,
+            'S' => SOUTH,
+            'W' => WEST,
+            _ => 0,
+        };
+        if bit == 0 || mask & bit != 0 {
+            let msg = format!("invalid terrain neighbors: {:?}", token);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        mask |= bit;
+    }
+    Ok(mask)
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_mask, Terrain, ALL_SIDES, EAST, NORTH, SOUTH, WEST};
+
+    #[test]
+    fn parse_terrain() {
+        let text = "# Comment\n\
+                    - a:0\n\
+                    \n\
+                    ES a:1  # corner\n\
+                    NESW a:4\n";
+        let terrain = Terrain::parse(text).unwrap();
+        assert_eq!(terrain.first, 0);
+        assert_eq!(terrain.tile_name_for(EAST | SOUTH), &("a".to_string(), 1));
+        assert_eq!(terrain.tile_name_for(NORTH), &("a".to_string(), 4));
+        assert_eq!(terrain.tile_name_for(ALL_SIDES), &("a".to_string(), 4));
+        assert_eq!(parse_mask("WN").unwrap(), NORTH | WEST);
+    }
+
+    #[test]
+    fn parse_invalid_terrain() {
+        assert!(Terrain::parse("").is_err());
+        assert!(Terrain::parse("NX a:0\n").is_err());
+        assert!(Terrain::parse("NN a:0\n").is_err());
+        assert!(Terrain::parse("N a:0\nN a:1\n").is_err());
+        assert!(Terrain::parse("N bogus\n").is_err());
+        assert!(Terrain::parse("N\n").is_err());
+    }
+}
+
+//===========================================================================//
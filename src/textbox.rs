@@ -21,6 +21,7 @@ use super::canvas::{Canvas, Font};
 use super::element::{Action, GuiElement, SubrectElement};
 use super::event::{Event, Keycode};
 use super::state::EditorState;
+use super::theme::Theme;
 use sdl2::rect::{Point, Rect};
 use std::cmp;
 use std::ffi::OsStr;
@@ -45,13 +46,55 @@ pub enum Mode {
     Resize,
     ChangeColor,
     ChangeTiles,
+    // Re-prompts for a tileset change that would clear cells, listing them:
+    ConfirmChangeTiles,
+    // Shows the .bg format help until dismissed:
+    FormatHelp,
+    // Shows the details of the hovered cell until dismissed:
+    Inspect,
+    Automap,
+    Terrain,
+    Regions,
+    ReplaceAdjacent,
+    Rerandomize,
+    BorderTiles,
+    DitherTiles,
+    NoiseFill,
+    // Edits the animation that the brush's tile is part of:
+    Animation,
+    RandomFill,
+    Generate,
+    SaveCheckpoint,
+    RestoreCheckpoint,
+    RestoreBackup,
+    AddLayer,
+    ContactSheet,
+    Export,
+    ExportSelection,
+    Overlay,
+    Backdrop,
+    MergeTiles,
+    RenameTiles,
+    Dedupe,
+    Metadata,
+    Eraser,
+    Screenshot,
 }
 
 impl Mode {
     fn is_file_picker(self) -> bool {
+        self.file_extension().is_some()
+    }
+
+    fn file_extension(self) -> Option<&'static str> {
         match self {
-            Mode::LoadFile | Mode::SaveAs => true,
-            _ => false,
+            Mode::LoadFile | Mode::SaveAs => Some("bg"),
+            Mode::Automap => Some("rules"),
+            Mode::Terrain => Some("terrain"),
+            Mode::Regions => Some("regions"),
+            Mode::Overlay => Some("ahi"),
+            Mode::ContactSheet | Mode::Screenshot => Some("png"),
+            _ => None,
         }
     }
 }
@@ -63,11 +106,18 @@ struct TextBox {
     byte_index: usize,
     cursor_blink: u32,
     text: String,
+    theme: Theme,
 }
 
 impl TextBox {
-    pub fn new(font: Rc<Font>) -> TextBox {
-        TextBox { font, byte_index: 0, cursor_blink: 0, text: String::new() }
+    pub fn new(font: Rc<Font>, theme: Theme) -> TextBox {
+        TextBox {
+            font,
+            byte_index: 0,
+            cursor_blink: 0,
+            text: String::new(),
+            theme,
+        }
     }
 
     pub fn text(&self) -> &str {
@@ -87,21 +137,21 @@ impl GuiElement<(), ()> for TextBox {
         let rect_width = rect.width() as i32;
         let text_width = self.font.text_width(&self.text);
         let text_left = cmp::min(4, rect_width - 4 - text_width);
-        canvas.fill_rect((128, 128, 128, 255), rect);
+        canvas.fill_rect(self.theme.field, rect);
         render_string(canvas, &self.font, text_left, 4, &self.text);
-        canvas.draw_rect((255, 255, 255, 255), rect);
+        canvas.draw_rect(self.theme.field_border, rect);
         if self.cursor_blink < CURSOR_ON_FRAMES {
             let cursor_x = text_left
                 + self.font.text_width(&self.text[..self.byte_index]);
             let cursor_rect =
                 Rect::new(cursor_x, rect.y() + 3, 1, rect.height() - 6);
-            canvas.fill_rect((255, 255, 0, 255), cursor_rect);
+            canvas.fill_rect(self.theme.text_cursor, cursor_rect);
         }
     }
 
     fn on_event(&mut self, event: &Event, _: &mut ()) -> Action<()> {
         match event {
-            &Event::ClockTick => {
+            &Event::ClockTick if self.theme.animations => {
                 let was_on = self.cursor_blink < CURSOR_ON_FRAMES;
                 self.cursor_blink = (self.cursor_blink + 1)
                     % (CURSOR_ON_FRAMES + CURSOR_OFF_FRAMES);
@@ -183,11 +233,12 @@ struct MatchesPanel {
     top: i32,
     font: Rc<Font>,
     matches: Vec<String>,
+    theme: Theme,
 }
 
 impl MatchesPanel {
-    fn new(left: i32, top: i32, font: Rc<Font>) -> MatchesPanel {
-        MatchesPanel { left, top, font, matches: Vec::new() }
+    fn new(left: i32, top: i32, font: Rc<Font>, theme: Theme) -> MatchesPanel {
+        MatchesPanel { left, top, font, matches: Vec::new(), theme }
     }
 
     fn set_matches(&mut self, matches: Vec<String>) {
@@ -202,14 +253,20 @@ impl MatchesPanel {
 impl GuiElement<(), ()> for MatchesPanel {
     fn draw(&self, _: &(), canvas: &mut Canvas) {
         if !self.matches.is_empty() {
+            let widest = self
+                .matches
+                .iter()
+                .map(|string| self.font.text_width(string))
+                .max()
+                .unwrap_or(0);
             let rect = Rect::new(
                 self.left,
                 self.top,
-                360,
+                cmp::max(360, widest + 8) as u32,
                 4 + 14 * (self.matches.len() as u32),
             );
-            canvas.fill_rect((128, 128, 128, 255), rect);
-            canvas.draw_rect((255, 255, 255, 255), rect);
+            canvas.fill_rect(self.theme.field, rect);
+            canvas.draw_rect(self.theme.field_border, rect);
             for (row, string) in self.matches.iter().enumerate() {
                 render_string(
                     canvas,
@@ -239,14 +296,19 @@ pub struct ModalTextBox {
 }
 
 impl ModalTextBox {
-    pub fn new(left: i32, top: i32, font: Rc<Font>) -> ModalTextBox {
+    pub fn new(
+        left: i32,
+        top: i32,
+        font: Rc<Font>,
+        theme: Theme,
+    ) -> ModalTextBox {
         ModalTextBox {
             left,
             top,
             font: font.clone(),
             mode: Mode::Edit,
             textbox: SubrectElement::new(
-                TextBox::new(font.clone()),
+                TextBox::new(font.clone(), theme),
                 Rect::new(
                     left + LABEL_WIDTH,
                     top,
@@ -258,6 +320,7 @@ impl ModalTextBox {
                 left + LABEL_WIDTH,
                 top + 20,
                 font.clone(),
+                theme,
             ),
         }
     }
@@ -272,6 +335,12 @@ impl ModalTextBox {
         self.matches_panel.clear_matches();
     }
 
+    /// Lists the given strings below the text box (e.g. the valid choices
+    /// for the current mode).
+    pub fn show_matches(&mut self, matches: Vec<String>) {
+        self.matches_panel.set_matches(matches);
+    }
+
     pub fn clear_mode(&mut self) {
         self.mode = Mode::Edit;
         self.textbox.inner_mut().set_text(String::new());
@@ -279,7 +348,8 @@ impl ModalTextBox {
     }
 
     fn tab_complete(&mut self) -> bool {
-        match tab_complete_path(self.textbox.inner().text()) {
+        let extension = self.mode.file_extension().unwrap_or("");
+        match tab_complete_path(self.textbox.inner().text(), extension) {
             Ok((path, matches)) => {
                 self.textbox.inner_mut().set_text(path);
                 if matches.len() > 1 {
@@ -306,9 +376,7 @@ impl GuiElement<EditorState, (Mode, String)> for ModalTextBox {
             );
         } else {
             self.textbox.draw(&(), canvas);
-            if self.mode.is_file_picker() {
-                self.matches_panel.draw(&(), canvas);
-            }
+            self.matches_panel.draw(&(), canvas);
         }
         let label = match self.mode {
             Mode::Edit => "Path:",
@@ -317,6 +385,35 @@ impl GuiElement<EditorState, (Mode, String)> for ModalTextBox {
             Mode::Resize => "Size:",
             Mode::ChangeColor => "Color:",
             Mode::ChangeTiles => "Tiles:",
+            Mode::ConfirmChangeTiles => "Clear?",
+            Mode::FormatHelp => "Format:",
+            Mode::Inspect => "Inspect:",
+            Mode::Automap => "Rules:",
+            Mode::Terrain => "Terrain:",
+            Mode::Regions => "Regions:",
+            Mode::ReplaceAdjacent => "Edge:",
+            Mode::Rerandomize => "Vary:",
+            Mode::BorderTiles => "Border:",
+            Mode::DitherTiles => "Dither:",
+            Mode::NoiseFill => "Noise:",
+            Mode::Animation => "Anim:",
+            Mode::RandomFill => "Random:",
+            Mode::Generate => "Level:",
+            Mode::SaveCheckpoint => "Mark:",
+            Mode::RestoreCheckpoint => "Back:",
+            Mode::RestoreBackup => "Backup:",
+            Mode::AddLayer => "Layer:",
+            Mode::ContactSheet => "Sheet:",
+            Mode::Export => "Export:",
+            Mode::ExportSelection => "Export sel:",
+            Mode::Overlay => "Overlay:",
+            Mode::Backdrop => "Backdrop:",
+            Mode::MergeTiles => "Merge:",
+            Mode::RenameTiles => "Rename:",
+            Mode::Dedupe => "Dedupe:",
+            Mode::Metadata => "Props:",
+            Mode::Eraser => "Eraser:",
+            Mode::Screenshot => "Shot:",
         };
         let text_width = self.font.text_width(label);
         render_string(
@@ -374,7 +471,10 @@ fn render_string(
     canvas.draw_text(font, Point::new(left, top + font.baseline()), string);
 }
 
-fn tab_complete_path(path_string: &str) -> io::Result<(String, Vec<String>)> {
+fn tab_complete_path(
+    path_string: &str,
+    extension: &str,
+) -> io::Result<(String, Vec<String>)> {
     let path = Path::new(path_string);
     let (dir, prefix): (&Path, &str) = if path_string.ends_with('/') {
         (path, "")
@@ -391,7 +491,11 @@ fn tab_complete_path(path_string: &str) -> io::Result<(String, Vec<String>)> {
         let entry = entry_result?;
         let file_name = entry.file_name().to_str().unwrap_or("").to_string();
         if file_name.starts_with(prefix) {
-            if entry.file_type()?.is_dir() || file_name.ends_with(".bg") {
+            if entry.file_type()?.is_dir()
+                || Path::new(&file_name).extension()
+                    == Some(extension.as_ref())
+                || (extension == "bg" && file_name.ends_with(".bg.gz"))
+            {
                 file_names.push(file_name);
             }
         }
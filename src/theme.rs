@@ -0,0 +1,148 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::config::Config;
+
+//===========================================================================//
+
+pub type Color = (u8, u8, u8, u8);
+
+/// The colors of the editor's own UI (as opposed to the map being edited),
+/// and whether it may animate.  The `theme` config key picks a base theme
+/// (`standard` or `high_contrast`), and `theme.*` keys override parts of
+/// it, e.g.
+///
+/// ```text
+/// theme = high_contrast
+/// theme.text_cursor = 255,0,0
+/// theme.animations = false
+/// ```
+///
+/// With animations off, marquees stop marching, the text cursor stops
+/// blinking, and palette entries are highlighted steadily instead of
+/// flashing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Theme {
+    pub background: Color,
+    pub panel: Color,
+    // Dropdowns and previews that pop up over the panels:
+    pub popup: Color,
+    pub field: Color,
+    pub field_border: Color,
+    pub text_cursor: Color,
+    pub selected_tool: Color,
+    // The thickness, in pixels, of selection marquees:
+    pub outline_width: u32,
+    pub animations: bool,
+}
+
+impl Theme {
+    pub fn standard() -> Theme {
+        Theme {
+            background: (64, 64, 64, 255),
+            panel: (95, 95, 95, 255),
+            popup: (63, 63, 63, 255),
+            field: (128, 128, 128, 255),
+            field_border: (255, 255, 255, 255),
+            text_cursor: (255, 255, 0, 255),
+            selected_tool: (255, 255, 255, 255),
+            outline_width: 1,
+            animations: true,
+        }
+    }
+
+    /// A theme that keeps the (black) UI text on white, outlines fields in
+    /// black, and draws thicker marquees.
+    pub fn high_contrast() -> Theme {
+        Theme {
+            background: (255, 255, 255, 255),
+            panel: (255, 255, 255, 255),
+            popup: (255, 255, 255, 255),
+            field: (255, 255, 255, 255),
+            field_border: (0, 0, 0, 255),
+            text_cursor: (0, 0, 255, 255),
+            selected_tool: (255, 200, 0, 255),
+            outline_width: 2,
+            animations: true,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Theme> {
+        match name {
+            "standard" => Some(Theme::standard()),
+            "high_contrast" => Some(Theme::high_contrast()),
+            _ => None,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Theme {
+        let mut theme = config
+            .get("theme")
+            .and_then(Theme::from_name)
+            .unwrap_or(Theme::standard());
+        let colors = [
+            ("theme.background", &mut theme.background),
+            ("theme.panel", &mut theme.panel),
+            ("theme.popup", &mut theme.popup),
+            ("theme.field", &mut theme.field),
+            ("theme.field_border", &mut theme.field_border),
+            ("theme.text_cursor", &mut theme.text_cursor),
+            ("theme.selected_tool", &mut theme.selected_tool),
+        ];
+        for (key, color) in colors {
+            if let Some(value) = config.get_color(key) {
+                *color = value;
+            }
+        }
+        if let Some(width) = config.get_parsed::<u32>("theme.outline_width") {
+            theme.outline_width = width.max(1);
+        }
+        if let Some(animations) = config.get_parsed("theme.animations") {
+            theme.animations = animations;
+        }
+        theme
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::Theme;
+    use crate::config::Config;
+
+    #[test]
+    fn theme_from_config() {
+        let mut config = Config::empty();
+        assert_eq!(Theme::from_config(&config), Theme::standard());
+        config.set("theme", "high_contrast".to_string());
+        config.set("theme.panel", "0,0,0".to_string());
+        config.set("theme.outline_width", "0".to_string());
+        config.set("theme.animations", "false".to_string());
+        let theme = Theme::from_config(&config);
+        assert_eq!(theme.background, Theme::high_contrast().background);
+        assert_eq!(theme.panel, (0, 0, 0, 255));
+        assert_eq!(theme.outline_width, 1);
+        assert!(!theme.animations);
+        config.set("theme", "bogus".to_string());
+        assert_eq!(Theme::from_config(&config).panel, (0, 0, 0, 255));
+    }
+}
+
+//===========================================================================//
@@ -17,15 +17,19 @@
 // | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
 // +--------------------------------------------------------------------------+
 
+use super::anim::TileAnimation;
 use super::canvas::{Sprite, Window};
+use super::random::Rng;
 use super::util;
 use ahi::Palette;
 use sdl2::rect::{Point, Rect};
 use std::cmp::{max, min, Ordering};
 use std::collections::{BTreeMap, BTreeSet};
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
-use std::ops::{Deref, Index, IndexMut};
+use std::iter;
+use std::mem;
+use std::ops::{Deref, Index};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
@@ -33,11 +37,168 @@ use std::rc::Rc;
 
 const DEFAULT_TILE_SIZE: u32 = 8;
 
+/// How to fit tiles that are larger than a locked tile size.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TileFit {
+    /// Leave the tiles as they are, overlapping their neighbors.
+    Overflow,
+    /// Keep only the top-left corner of each tile.
+    Crop,
+    /// Shrink each tile by a whole factor until it fits, centered.
+    Letterbox,
+}
+
+impl TileFit {
+    pub fn from_name(name: &str) -> Option<TileFit> {
+        match name {
+            "overflow" => Some(TileFit::Overflow),
+            "crop" => Some(TileFit::Crop),
+            "letterbox" => Some(TileFit::Letterbox),
+            _ => None,
+        }
+    }
+}
+
+/// A fixed tile size for a project's tilesets, and how to fit tiles that
+/// are larger than it, so that one oversized tile doesn't change the
+/// geometry of the whole grid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TileSizeLock {
+    pub size: u32,
+    pub fit: TileFit,
+}
+
+impl TileSizeLock {
+    /// Returns true if the image is larger than the locked size, other than
+    /// by being a whole multiple of it (see `Tileset::tile_span`).
+    fn is_exceeded_by(&self, image: &ahi::Image) -> bool {
+        (image.width() > self.size || image.height() > self.size)
+            && !(image.width().is_multiple_of(self.size)
+                && image.height().is_multiple_of(self.size))
+    }
+
+    fn fit_image(&self, image: ahi::Image) -> ahi::Image {
+        if !self.is_exceeded_by(&image) {
+            return image;
+        }
+        match self.fit {
+            TileFit::Overflow => image,
+            TileFit::Crop => {
+                let width = image.width().min(self.size);
+                let height = image.height().min(self.size);
+                let mut cropped = ahi::Image::new(width, height);
+                for y in 0..height {
+                    for x in 0..width {
+                        cropped[(x, y)] = image[(x, y)];
+                    }
+                }
+                cropped
+            }
+            TileFit::Letterbox => {
+                let longest = image.width().max(image.height());
+                let factor = longest.div_ceil(self.size);
+                let width = image.width() / factor;
+                let height = image.height() / factor;
+                let left = (self.size - width) / 2;
+                let top = (self.size - height) / 2;
+                let mut boxed = ahi::Image::new(self.size, self.size);
+                for y in 0..height {
+                    for x in 0..width {
+                        boxed[(left + x, top + y)] =
+                            image[(x * factor, y * factor)];
+                    }
+                }
+                boxed
+            }
+        }
+    }
+}
+
+/// Loads the indices of the named tile file's tiles that are drawn in front
+/// of sprites (like the priority bit of many consoles' background tiles).
+/// They're listed in an optional `.front` sidecar next to the tile file's
+/// `.ahi` file, separated by whitespace, with `#` starting a comment.
+fn load_front_sidecar(
+    dirpath: &Path,
+    filename: &str,
+) -> io::Result<BTreeSet<usize>> {
+    let path = dirpath.join(filename).with_extension("front");
+    match fs::read_to_string(path) {
+        Ok(text) => parse_front_sidecar(&text),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            Ok(BTreeSet::new())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Loads the palette number of each of the named tile file's tiles, for
+/// hardware that assigns palettes to blocks of tiles.  They're listed in
+/// tile order in an optional `.attr` sidecar next to the tile file's `.ahi`
+/// file, separated by whitespace, with `#` starting a comment.  Tiles past
+/// the end of the list have no palette.
+fn load_palette_sidecar(
+    dirpath: &Path,
+    filename: &str,
+) -> io::Result<Vec<u32>> {
+    let path = dirpath.join(filename).with_extension("attr");
+    match fs::read_to_string(path) {
+        Ok(text) => parse_palette_sidecar(&text),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            Ok(Vec::new())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn parse_palette_sidecar(text: &str) -> io::Result<Vec<u32>> {
+    let mut palettes = Vec::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        for token in line.split_whitespace() {
+            let palette = token.parse::<u32>().map_err(|_| {
+                let msg = format!("invalid tile palette: {:?}", token);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?;
+            palettes.push(palette);
+        }
+    }
+    Ok(palettes)
+}
+
+fn parse_front_sidecar(text: &str) -> io::Result<BTreeSet<usize>> {
+    let mut indices = BTreeSet::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        for token in line.split_whitespace() {
+            let index = token.parse::<usize>().map_err(|_| {
+                let msg = format!("invalid front tile index: {:?}", token);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?;
+            indices.insert(index);
+        }
+    }
+    Ok(indices)
+}
+
 #[derive(Clone)]
 pub struct Tileset {
     dirpath: PathBuf,
     tiles: Vec<(String, Vec<Rc<Sprite>>)>,
+    animations: BTreeMap<String, Vec<TileAnimation>>,
+    // For each tile file, the indices of its tiles that are drawn in front
+    // of sprites:
+    front_tiles: BTreeMap<String, BTreeSet<usize>>,
+    // For each tile file, the palette number of each of its tiles (from its
+    // `.attr` sidecar, if any):
+    palettes: BTreeMap<String, Vec<u32>>,
+    // For each tile file, a checksum of its contents on disk as of when it
+    // was last loaded:
+    checksums: BTreeMap<String, u64>,
     tile_size: u32,
+    size_lock: Option<TileSizeLock>,
+    // The tile files with tiles larger than the locked tile size:
+    oversized_files: Vec<String>,
 }
 
 impl Tileset {
@@ -45,23 +206,77 @@ impl Tileset {
         window: &Window,
         dirpath: &Path,
         filenames: &[String],
+        size_lock: Option<TileSizeLock>,
     ) -> io::Result<Tileset> {
         let mut tiles = vec![];
+        let mut animations = BTreeMap::new();
+        let mut front_tiles = BTreeMap::new();
+        let mut palettes = BTreeMap::new();
+        let mut checksums = BTreeMap::new();
+        let mut oversized_files = Vec::new();
         for filename in filenames {
-            let path = dirpath.join(filename).with_extension("ahi");
-            let collection =
-                util::load_ahi_from_file(&path.to_str().unwrap().to_string())?;
-            let palette =
-                collection.palettes.first().unwrap_or(Palette::default());
-            let mut sprites = vec![];
-            for image in collection.images {
-                let sprite = window.new_sprite(&image, palette);
-                sprites.push(Rc::new(sprite));
+            let (sprites, oversized, checksum) = Tileset::load_fitted_sprites(
+                window, dirpath, filename, size_lock,
+            )?;
+            if oversized {
+                oversized_files.push(filename.to_string());
             }
             tiles.push((filename.to_string(), sprites));
+            checksums.insert(filename.to_string(), checksum);
+            animations.insert(
+                filename.to_string(),
+                TileAnimation::load_sidecar(dirpath, filename)?,
+            );
+            front_tiles.insert(
+                filename.to_string(),
+                load_front_sidecar(dirpath, filename)?,
+            );
+            palettes.insert(
+                filename.to_string(),
+                load_palette_sidecar(dirpath, filename)?,
+            );
+        }
+        let tile_size = Tileset::locked_tile_size(&tiles, size_lock);
+        Ok(Tileset {
+            dirpath: dirpath.to_path_buf(),
+            tiles,
+            animations,
+            front_tiles,
+            palettes,
+            checksums,
+            tile_size,
+            size_lock,
+            oversized_files,
+        })
+    }
+
+    /// Returns a tileset with no tile files, for tests that don't need any
+    /// sprites.
+    #[cfg(test)]
+    pub fn empty() -> Tileset {
+        Tileset {
+            dirpath: PathBuf::new(),
+            tiles: Vec::new(),
+            animations: BTreeMap::new(),
+            front_tiles: BTreeMap::new(),
+            palettes: BTreeMap::new(),
+            checksums: BTreeMap::new(),
+            tile_size: 16,
+            size_lock: None,
+            oversized_files: Vec::new(),
+        }
+    }
+
+    /// Moves the tile file at `index` to `new_index` in the file order (which
+    /// determines the file indices written to the .bg file).  Returns false
+    /// if either index is out of range.
+    pub fn move_file(&mut self, index: usize, new_index: usize) -> bool {
+        if index >= self.tiles.len() || new_index >= self.tiles.len() {
+            return false;
         }
-        let tile_size = Tileset::max_tile_size(&tiles);
-        Ok(Tileset { dirpath: dirpath.to_path_buf(), tiles, tile_size })
+        let entry = self.tiles.remove(index);
+        self.tiles.insert(new_index, entry);
+        true
     }
 
     pub fn reload(
@@ -74,32 +289,143 @@ impl Tileset {
             old_tiles.insert(filename.clone(), sprites.clone());
         }
         let mut new_tiles: Vec<(String, Vec<Rc<Sprite>>)> = Vec::new();
+        let mut new_animations = BTreeMap::new();
+        let mut new_front_tiles = BTreeMap::new();
+        let mut new_palettes = BTreeMap::new();
+        let mut new_checksums = BTreeMap::new();
         for filename in filenames {
+            let animations = match self.animations.get(*filename) {
+                Some(animations) => animations.clone(),
+                None => TileAnimation::load_sidecar(&self.dirpath, filename)?,
+            };
+            new_animations.insert(filename.to_string(), animations);
+            let front = match self.front_tiles.get(*filename) {
+                Some(front) => front.clone(),
+                None => load_front_sidecar(&self.dirpath, filename)?,
+            };
+            new_front_tiles.insert(filename.to_string(), front);
+            let palettes = match self.palettes.get(*filename) {
+                Some(palettes) => palettes.clone(),
+                None => load_palette_sidecar(&self.dirpath, filename)?,
+            };
+            new_palettes.insert(filename.to_string(), palettes);
             if let Some(sprites) = old_tiles.get(&filename.to_string()) {
                 new_tiles.push((filename.to_string(), sprites.clone()));
+                if let Some(&checksum) = self.checksums.get(*filename) {
+                    new_checksums.insert(filename.to_string(), checksum);
+                }
                 continue;
             }
-            let path = self.dirpath.join(filename).with_extension("ahi");
-            let collection =
-                util::load_ahi_from_file(&path.to_str().unwrap().to_string())?;
-            let palette =
-                collection.palettes.first().unwrap_or(Palette::default());
-            let mut sprites = vec![];
-            for image in collection.images {
-                let sprite = window.new_sprite(&image, &palette);
-                sprites.push(Rc::new(sprite));
+            let (sprites, oversized, checksum) = Tileset::load_fitted_sprites(
+                window,
+                &self.dirpath,
+                filename,
+                self.size_lock,
+            )?;
+            if oversized {
+                self.oversized_files.push(filename.to_string());
             }
             new_tiles.push((filename.to_string(), sprites));
+            new_checksums.insert(filename.to_string(), checksum);
         }
+        self.oversized_files.retain(|name| filenames.contains(&name.as_str()));
         self.tiles = new_tiles;
-        self.tile_size = Tileset::max_tile_size(&self.tiles);
+        self.animations = new_animations;
+        self.front_tiles = new_front_tiles;
+        self.palettes = new_palettes;
+        self.checksums = new_checksums;
+        self.tile_size =
+            Tileset::locked_tile_size(&self.tiles, self.size_lock);
         Ok(())
     }
 
+    /// Loads the sprites for the named tile file in this tileset's
+    /// directory, fitting them to the locked tile size (if any), along with
+    /// a checksum of the file's contents.
+    pub fn load_sprites(
+        &self,
+        window: &Window,
+        filename: &str,
+    ) -> io::Result<(Vec<Rc<Sprite>>, u64)> {
+        let (sprites, _, checksum) = Tileset::load_fitted_sprites(
+            window,
+            &self.dirpath,
+            filename,
+            self.size_lock,
+        )?;
+        Ok((sprites, checksum))
+    }
+
+    /// Loads the sprites for the named tile file in the given directory,
+    /// fitting them to the given tile size lock (if any).  Also returns
+    /// true if any of the file's tiles were larger than the locked size, and
+    /// a checksum of the file's contents.
+    fn load_fitted_sprites(
+        window: &Window,
+        dirpath: &Path,
+        filename: &str,
+        size_lock: Option<TileSizeLock>,
+    ) -> io::Result<(Vec<Rc<Sprite>>, bool, u64)> {
+        let path = dirpath.join(filename).with_extension("ahi");
+        let data = fs::read(&path)?;
+        let checksum = util::checksum(&data);
+        let collection = ahi::Collection::read(&mut &data[..])?;
+        let palette =
+            collection.palettes.first().unwrap_or(Palette::default());
+        let mut sprites = vec![];
+        let mut oversized = false;
+        for mut image in collection.images {
+            if let Some(lock) = size_lock {
+                oversized |= lock.is_exceeded_by(&image);
+                image = lock.fit_image(image);
+            }
+            let sprite = window.new_sprite(&image, palette);
+            sprites.push(Rc::new(sprite));
+        }
+        Ok((sprites, oversized, checksum))
+    }
+
+    /// Returns the path of the .ahi file for the named tile file.
+    pub fn file_path(&self, filename: &str) -> PathBuf {
+        self.dirpath.join(filename).with_extension("ahi")
+    }
+
+    /// Returns a checksum of the named tile file's contents on disk, as of
+    /// when it was last loaded.
+    pub fn file_checksum(&self, filename: &str) -> Option<u64> {
+        self.checksums.get(filename).copied()
+    }
+
+    fn has_filename(&self, filename: &str) -> bool {
+        self.tiles.iter().any(|(name, _)| name == filename)
+    }
+
+    fn replace_sprites(
+        &mut self,
+        filename: &str,
+        sprites: &[Rc<Sprite>],
+        checksum: u64,
+    ) {
+        for &mut (ref name, ref mut old_sprites) in self.tiles.iter_mut() {
+            if name == filename {
+                *old_sprites = sprites.to_vec();
+            }
+        }
+        self.checksums.insert(filename.to_string(), checksum);
+        self.tile_size =
+            Tileset::locked_tile_size(&self.tiles, self.size_lock);
+    }
+
     pub fn dirpath(&self) -> &Path {
         &self.dirpath
     }
 
+    /// Changes the directory that tile files are loaded from.  This doesn't
+    /// reload any of the tileset's files.
+    pub fn set_dirpath(&mut self, dirpath: PathBuf) {
+        self.dirpath = dirpath;
+    }
+
     pub fn num_filenames(&self) -> usize {
         self.tiles.len()
     }
@@ -112,6 +438,40 @@ impl Tileset {
         self.tile_size
     }
 
+    /// Returns the number of columns and rows of cells that the given tile
+    /// occupies.  Tiles whose sides are whole multiples of the tile size
+    /// (e.g. 16x16 props in an 8x8 tileset with a locked tile size) occupy
+    /// several cells, anchored at the top-left one; all other tiles occupy
+    /// one cell.
+    pub fn tile_span(&self, tile: &Tile) -> (u32, u32) {
+        let (width, height) = (tile.sprite.width(), tile.sprite.height());
+        if width.is_multiple_of(self.tile_size)
+            && height.is_multiple_of(self.tile_size)
+        {
+            let cols = (width / self.tile_size).max(1);
+            let rows = (height / self.tile_size).max(1);
+            (cols, rows)
+        } else {
+            (1, 1)
+        }
+    }
+
+    /// Returns the most columns or rows of cells that any tile in this
+    /// tileset occupies.
+    fn max_span(&self) -> u32 {
+        (Tileset::max_tile_size(&self.tiles) / self.tile_size).max(1)
+    }
+
+    pub fn size_lock(&self) -> Option<TileSizeLock> {
+        self.size_lock
+    }
+
+    /// Returns the tile files that had tiles larger than the locked tile
+    /// size when they were loaded.
+    pub fn oversized_files(&self) -> &[String] {
+        &self.oversized_files
+    }
+
     pub fn tiles(&self, file_index: usize) -> Tiles {
         Tiles { tileset: self, file_index, tile_index: 0 }
     }
@@ -128,9 +488,139 @@ impl Tileset {
             filename: filename.clone(),
             index: tile_index,
             sprite: sprites[tile_index].clone(),
+            flip: Flip::default(),
         })
     }
 
+    pub fn get_named(
+        &self,
+        filename: &str,
+        tile_index: usize,
+    ) -> Option<Tile> {
+        let file_index =
+            self.tiles.iter().position(|(name, _)| name == filename)?;
+        self.get(file_index, tile_index)
+    }
+
+    /// Returns all the tile animations defined for this tileset's files.
+    pub fn animations(&self) -> Vec<&TileAnimation> {
+        self.animations.values().flatten().collect()
+    }
+
+    /// Returns the animations defined for the named tile file.
+    pub fn file_animations(&self, filename: &str) -> &[TileAnimation] {
+        self.animations.get(filename).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    fn set_file_animations(
+        &mut self,
+        filename: &str,
+        animations: Vec<TileAnimation>,
+    ) {
+        self.animations.insert(filename.to_string(), animations);
+    }
+
+    /// Returns true if the given tile is a frame of one of its file's
+    /// animations.
+    pub fn is_animated(&self, tile: &Tile) -> bool {
+        match self.animations.get(&tile.filename) {
+            Some(animations) => animations
+                .iter()
+                .any(|anim| anim.frame_at(tile.index, 0).is_some()),
+            None => false,
+        }
+    }
+
+    /// Returns true if the given tile is flagged (in its file's `.front`
+    /// sidecar) to be drawn in front of sprites.
+    pub fn is_front(&self, tile: &Tile) -> bool {
+        match self.front_tiles.get(&tile.filename) {
+            Some(front) => front.contains(&tile.index),
+            None => false,
+        }
+    }
+
+    /// Returns true if any of this tileset's files has palette attributes.
+    pub fn has_palettes(&self) -> bool {
+        self.palettes.values().any(|palettes| !palettes.is_empty())
+    }
+
+    /// Returns the palette number of the given tile, if its file's `.attr`
+    /// sidecar gives it one.
+    pub fn tile_palette(&self, tile: &Tile) -> Option<u32> {
+        self.palettes.get(&tile.filename)?.get(tile.index).copied()
+    }
+
+    /// Returns the tile to show in place of the given tile after `millis`
+    /// milliseconds of animation.
+    pub fn animation_frame(&self, tile: &Tile, millis: u64) -> Tile {
+        if let Some(animations) = self.animations.get(&tile.filename) {
+            for animation in animations.iter() {
+                if let Some(index) = animation.frame_at(tile.index, millis) {
+                    if let Some(frame) = self.get_named(&tile.filename, index)
+                    {
+                        return frame.with_flip(tile.flip);
+                    }
+                }
+            }
+        }
+        tile.clone()
+    }
+
+    /// Returns each group of two or more tiles (across all files) whose
+    /// pixels are identical, in tileset order.
+    pub fn duplicate_tiles(&self) -> Vec<Vec<Tile>> {
+        // Tiles are bucketed by size and checksum, and then split within
+        // each bucket by their actual pixels, in case of a hash collision.
+        let mut buckets = BTreeMap::<(u32, u32, u64), Vec<Vec<Tile>>>::new();
+        for file_index in 0..self.tiles.len() {
+            for tile in self.tiles(file_index) {
+                let sprite = tile.sprite();
+                let key = (
+                    sprite.width(),
+                    sprite.height(),
+                    util::checksum(sprite.rgba_data()),
+                );
+                let bucket = buckets.entry(key).or_default();
+                match bucket.iter_mut().find(|group| {
+                    group[0].sprite().rgba_data() == sprite.rgba_data()
+                }) {
+                    Some(group) => group.push(tile),
+                    None => bucket.push(vec![tile]),
+                }
+            }
+        }
+        let mut groups: Vec<Vec<Tile>> = buckets
+            .into_values()
+            .flatten()
+            .filter(|group| group.len() > 1)
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    /// Parses a tile token as typed by the user: either a tile name (see
+    /// `parse_tile_name`) or `-` for an empty cell.
+    pub fn parse_tile(&self, token: &str) -> Option<Option<Tile>> {
+        if token == "-" {
+            return Some(None);
+        }
+        let (filename, index) = parse_tile_name(token)?;
+        self.get_named(filename, index).map(Some)
+    }
+
+    /// Returns the locked tile size if there is one, or else the largest
+    /// tile size among the given tiles.
+    fn locked_tile_size(
+        tiles: &Vec<(String, Vec<Rc<Sprite>>)>,
+        size_lock: Option<TileSizeLock>,
+    ) -> u32 {
+        match size_lock {
+            Some(lock) => lock.size,
+            None => Tileset::max_tile_size(tiles),
+        }
+    }
+
     pub fn max_tile_size(tiles: &Vec<(String, Vec<Rc<Sprite>>)>) -> u32 {
         let mut max = 0;
         for &(_, ref sprites) in tiles.iter() {
@@ -186,6 +676,7 @@ impl<'a> Iterator for Tiles<'a> {
             filename: filename.clone(),
             index: self.tile_index,
             sprite: tiles[self.tile_index].clone(),
+            flip: Flip::default(),
         };
         self.tile_index += 1;
         return Some(tile);
@@ -194,22 +685,145 @@ impl<'a> Iterator for Tiles<'a> {
 
 //===========================================================================//
 
+/// Which ways a placed tile is mirrored, as with the per-tile flip bits of
+/// many hardware tilemaps.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Flip {
+    pub horz: bool,
+    pub vert: bool,
+}
+
+impl Flip {
+    /// Returns this flip with the horizontal and/or vertical mirroring
+    /// toggled.
+    pub fn toggled(self, horz: bool, vert: bool) -> Flip {
+        Flip { horz: self.horz != horz, vert: self.vert != vert }
+    }
+
+    /// Returns the character written before a flipped cell in a .bg file,
+    /// or `None` if the cell isn't flipped.
+    fn marker(self) -> Option<char> {
+        FLIP_MARKERS
+            .iter()
+            .find(|marker| (marker.1, marker.2) == (self.horz, self.vert))
+            .map(|marker| marker.0)
+    }
+
+    /// Returns a description of this flip (e.g. "horizontal"), or `None`
+    /// if the cell isn't flipped.
+    pub fn description(self) -> Option<&'static str> {
+        FLIP_MARKERS
+            .iter()
+            .find(|marker| (marker.1, marker.2) == (self.horz, self.vert))
+            .map(|marker| marker.3)
+    }
+
+    fn from_marker(byte: u8) -> Option<Flip> {
+        FLIP_MARKERS
+            .iter()
+            .find(|marker| marker.0 as u32 == byte as u32)
+            .map(|marker| Flip { horz: marker.1, vert: marker.2 })
+    }
+}
+
 #[derive(Clone)]
 pub struct Tile {
     filename: String,
     index: usize,
     sprite: Rc<Sprite>,
+    flip: Flip,
 }
 
 impl Tile {
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// If this tile is from the given file, returns a copy of it using the
+    /// corresponding sprite from the given list (or an empty cell, if the
+    /// file no longer has that many tiles).  Returns `None` for tiles from
+    /// other files.
+    pub fn with_sprites(
+        &self,
+        filename: &str,
+        sprites: &[Rc<Sprite>],
+    ) -> Option<Option<Tile>> {
+        if self.filename != filename {
+            return None;
+        }
+        Some(sprites.get(self.index).map(|sprite| Tile {
+            filename: self.filename.clone(),
+            index: self.index,
+            sprite: sprite.clone(),
+            flip: self.flip,
+        }))
+    }
+
+    /// Returns an unflipped tile with a blank sprite, for tests.
+    #[cfg(test)]
+    pub fn blank(filename: &str, index: usize) -> Tile {
+        Tile {
+            filename: filename.to_string(),
+            index,
+            sprite: Rc::new(Sprite::blank()),
+            flip: Flip::default(),
+        }
+    }
+
     pub fn sprite(&self) -> &Sprite {
         self.sprite.as_ref()
     }
+
+    pub fn flip(&self) -> Flip {
+        self.flip
+    }
+
+    /// Returns a copy of this tile, mirrored as given.
+    pub fn with_flip(&self, flip: Flip) -> Tile {
+        Tile { flip, ..self.clone() }
+    }
+
+    /// Returns a copy of this tile without any mirroring.
+    pub fn unflipped(&self) -> Tile {
+        self.with_flip(Flip::default())
+    }
+
+    /// Returns the name of this tile in the form "filename:index", as
+    /// accepted by `parse_tile_name`.
+    pub fn name(&self) -> String {
+        format!("{}:{}", self.filename, self.index)
+    }
+}
+
+/// Returns the name of a possibly-empty cell, as accepted by
+/// `Tileset::parse_tile`.
+pub fn tile_token(tile: &Option<Tile>) -> String {
+    match *tile {
+        Some(ref tile) => tile.name(),
+        None => "-".to_string(),
+    }
+}
+
+/// Parses a tile name of the form "filename:index" into its two parts.
+pub fn parse_tile_name(name: &str) -> Option<(&str, usize)> {
+    let colon = name.rfind(':')?;
+    let filename = &name[..colon];
+    if filename.is_empty() {
+        return None;
+    }
+    let index = name[(colon + 1)..].parse::<usize>().ok()?;
+    Some((filename, index))
 }
 
 impl PartialEq for Tile {
     fn eq(&self, other: &Tile) -> bool {
-        self.filename == other.filename && self.index == other.index
+        self.filename == other.filename
+            && self.index == other.index
+            && self.flip == other.flip
     }
 }
 
@@ -223,22 +837,43 @@ impl PartialOrd for Tile {
 
 impl Ord for Tile {
     fn cmp(&self, other: &Tile) -> Ordering {
-        (&self.filename, self.index).cmp(&(&other.filename, other.index))
+        (&self.filename, self.index, self.flip).cmp(&(
+            &other.filename,
+            other.index,
+            other.flip,
+        ))
     }
 }
 
 //===========================================================================//
 
+// Grid cells are stored in square chunks of this many cells on a side.
+// Chunks are reference-counted, so cloning a grid (e.g. for an undo snapshot)
+// is cheap, and only the chunks that are subsequently modified get copied.
+// Chunks that are entirely empty aren't allocated at all, so large,
+// mostly-empty grids stay cheap to store, copy, and undo.
+const CHUNK_SIZE: u32 = 16;
+
+type Chunk<T> = Rc<Vec<Option<T>>>;
+
+fn is_empty_chunk<T>(chunk: &Chunk<T>) -> bool {
+    chunk.iter().all(Option::is_none)
+}
+
+/// A rectangular grid of cells, each of which is either empty or holds a
+/// `T` (normally a `Tile`).
 #[derive(Clone)]
-pub struct SubGrid {
+pub struct SubGrid<T = Tile> {
     width: u32,
     height: u32,
-    grid: Vec<Option<Tile>>,
+    chunks: Vec<Option<Chunk<T>>>,
 }
 
-impl SubGrid {
-    pub fn new(width: u32, height: u32) -> SubGrid {
-        SubGrid { width, height, grid: vec![None; (width * height) as usize] }
+impl<T: Clone> SubGrid<T> {
+    pub fn new(width: u32, height: u32) -> SubGrid<T> {
+        let num_chunks =
+            width.div_ceil(CHUNK_SIZE) * height.div_ceil(CHUNK_SIZE);
+        SubGrid { width, height, chunks: vec![None; num_chunks as usize] }
     }
 
     pub fn width(&self) -> u32 {
@@ -253,71 +888,637 @@ impl SubGrid {
         (self.width, self.height)
     }
 
+    fn chunk_position(&self, (col, row): (u32, u32)) -> (usize, usize) {
+        if col >= self.width || row >= self.height {
+            panic!("index out of range");
+        }
+        let chunk_cols = self.width.div_ceil(CHUNK_SIZE);
+        let chunk = (row / CHUNK_SIZE) * chunk_cols + (col / CHUNK_SIZE);
+        let offset = (row % CHUNK_SIZE) * CHUNK_SIZE + (col % CHUNK_SIZE);
+        (chunk as usize, offset as usize)
+    }
+
+    /// Sets the given cell.  Erasing a cell in an unallocated chunk doesn't
+    /// allocate it, and erasing the last tile in a chunk frees the chunk.
+    pub fn set(&mut self, position: (u32, u32), cell: Option<T>) {
+        let (index, offset) = self.chunk_position(position);
+        let slot = &mut self.chunks[index];
+        if cell.is_none() {
+            if let Some(ref mut chunk) = *slot {
+                if chunk[offset].is_some() {
+                    Rc::make_mut(chunk)[offset] = None;
+                    if is_empty_chunk(chunk) {
+                        *slot = None;
+                    }
+                }
+            }
+            return;
+        }
+        let chunk = slot.get_or_insert_with(|| {
+            Rc::new(vec![None; (CHUNK_SIZE * CHUNK_SIZE) as usize])
+        });
+        Rc::make_mut(chunk)[offset] = cell;
+    }
+
+    /// Frees any allocated chunks whose cells are all empty.
+    fn release_empty_chunks(&mut self) {
+        for chunk in self.chunks.iter_mut() {
+            if chunk.as_ref().is_some_and(is_empty_chunk) {
+                *chunk = None;
+            }
+        }
+    }
+
+    /// Returns the smallest rect containing every non-empty cell, or `None`
+    /// if the subgrid is entirely empty.
+    pub fn content_bounds(&self) -> Option<Rect> {
+        let mut bounds: Option<Rect> = None;
+        for row in 0..self.height {
+            for col in 0..self.width {
+                if self[(col, row)].is_some() {
+                    let cell = Rect::new(col as i32, row as i32, 1, 1);
+                    bounds = Some(match bounds {
+                        Some(bounds) => bounds.union(cell),
+                        None => cell,
+                    });
+                }
+            }
+        }
+        bounds
+    }
+
+    /// Returns the number of empty cells.
+    pub fn count_empty_cells(&self) -> usize {
+        let mut count = 0;
+        for row in 0..self.height {
+            for col in 0..self.width {
+                if self[(col, row)].is_none() {
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    /// Copies the non-empty cells of `other` into this subgrid, with its
+    /// top-left cell at the given position (which must keep it in bounds).
+    pub fn paste(&mut self, other: &SubGrid<T>, (left, top): (u32, u32)) {
+        for row in 0..other.height {
+            for col in 0..other.width {
+                let cell = &other[(col, row)];
+                if cell.is_some() {
+                    self.set((left + col, top + row), cell.clone());
+                }
+            }
+        }
+    }
+
+    /// Returns a copy of the cells within the given rect (clamped to this
+    /// subgrid's bounds).
+    pub fn copy_rect(&self, rect: Rect) -> SubGrid<T> {
+        let (width, height) = (self.width as i32, self.height as i32);
+        let start_col = rect.left().clamp(0, width);
+        let end_col = rect.right().clamp(start_col, width) as u32;
+        let start_col = start_col as u32;
+        let start_row = rect.top().clamp(0, height);
+        let end_row = rect.bottom().clamp(start_row, height) as u32;
+        let start_row = start_row as u32;
+        let mut subgrid =
+            SubGrid::new(end_col - start_col, end_row - start_row);
+        for row in start_row..end_row {
+            for col in start_col..end_col {
+                subgrid.set(
+                    (col - start_col, row - start_row),
+                    self[(col, row)].clone(),
+                );
+            }
+        }
+        subgrid
+    }
+
+    /// Returns a copy of this subgrid grown or shrunk to the given size,
+    /// keeping its top-left cells in place.
+    fn resized(&self, new_width: u32, new_height: u32) -> SubGrid<T> {
+        let mut new_subgrid = SubGrid::new(new_width, new_height);
+        for row in 0..self.height.min(new_height) {
+            for col in 0..self.width.min(new_width) {
+                new_subgrid.set((col, row), self[(col, row)].clone());
+            }
+        }
+        new_subgrid
+    }
+
+    /// Like `resized`, but fills any added cells by repeating this subgrid's
+    /// last column and row.
+    fn extended(&self, new_width: u32, new_height: u32) -> SubGrid<T> {
+        if self.width == 0 || self.height == 0 {
+            return self.resized(new_width, new_height);
+        }
+        let mut new_subgrid = SubGrid::new(new_width, new_height);
+        for row in 0..new_height {
+            for col in 0..new_width {
+                let from = (col.min(self.width - 1), row.min(self.height - 1));
+                new_subgrid.set((col, row), self[from].clone());
+            }
+        }
+        new_subgrid
+    }
+
+    /// Returns a new subgrid of the given size filled by repeating this
+    /// one, where `origin` is the position in the new subgrid at which a
+    /// copy of this one's top-left cell starts.
+    pub fn tiled(&self, width: u32, height: u32, origin: Point) -> SubGrid<T> {
+        let mut new_subgrid = SubGrid::new(width, height);
+        if self.width == 0 || self.height == 0 {
+            return new_subgrid;
+        }
+        for row in 0..height {
+            for col in 0..width {
+                let src_col =
+                    (col as i32 - origin.x()).rem_euclid(self.width as i32);
+                let src_row =
+                    (row as i32 - origin.y()).rem_euclid(self.height as i32);
+                new_subgrid.set(
+                    (col, row),
+                    self[(src_col as u32, src_row as u32)].clone(),
+                );
+            }
+        }
+        new_subgrid
+    }
+}
+
+impl SubGrid {
+    /// Calls the given function on each non-empty cell; if the function
+    /// returns `Some(new_cell)`, the cell is replaced with `new_cell`.  Only
+    /// chunks that actually change are copied.
+    fn replace_tiles<F>(&mut self, mut func: F)
+    where
+        F: FnMut(&Tile) -> Option<Option<Tile>>,
+    {
+        for chunk in self.chunks.iter_mut().flatten() {
+            for index in 0..chunk.len() {
+                let replacement = match chunk[index] {
+                    Some(ref tile) => func(tile),
+                    None => None,
+                };
+                if let Some(cell) = replacement {
+                    Rc::make_mut(chunk)[index] = cell;
+                }
+            }
+        }
+        self.release_empty_chunks();
+    }
+
+    /// Updates every tile from the given file to use the corresponding sprite
+    /// from the given list (e.g. after the file has been reloaded).
+    pub fn replace_sprites(&mut self, filename: &str, sprites: &[Rc<Sprite>]) {
+        self.replace_tiles(|tile| tile.with_sprites(filename, sprites));
+    }
+
+    /// Returns the number of cells containing tiles from the given file.
+    pub fn count_tiles_from(&self, filename: &str) -> usize {
+        self.chunks
+            .iter()
+            .flatten()
+            .flat_map(|chunk| chunk.iter().flatten())
+            .filter(|tile| tile.filename == filename)
+            .count()
+    }
+
+    /// Replaces each tile that is a key in the given map with its value,
+    /// keeping any mirroring of the cell.
+    pub fn substitute_tiles(&mut self, substitutions: &BTreeMap<Tile, Tile>) {
+        self.replace_tiles(|tile| {
+            let new_tile = substitutions.get(&tile.unflipped())?;
+            Some(Some(new_tile.with_flip(tile.flip)))
+        });
+    }
+
+    /// Replaces each cell containing one of the given variant tiles with a
+    /// randomly-chosen variant.
+    pub fn rerandomize(&mut self, variants: &[Tile], rng: &mut Rng) {
+        if variants.is_empty() {
+            return;
+        }
+        self.replace_tiles(|tile| {
+            if variants.contains(tile) {
+                let index = rng.below(variants.len() as u32) as usize;
+                Some(Some(variants[index].clone()))
+            } else {
+                None
+            }
+        });
+    }
+
+    /// Draws a border around the perimeter of the given rectangle (in cell
+    /// coordinates, clipped to this subgrid).  The eight border tiles are
+    /// given in reading order: top-left, top, top-right, left, right,
+    /// bottom-left, bottom, bottom-right.
+    pub fn draw_border(&mut self, rect: Rect, tiles: &[Option<Tile>; 8]) {
+        for row in rect.top()..rect.bottom() {
+            for col in rect.left()..rect.right() {
+                if col < 0
+                    || row < 0
+                    || col >= self.width as i32
+                    || row >= self.height as i32
+                {
+                    continue;
+                }
+                let left = col == rect.left();
+                let right = col == rect.right() - 1;
+                let top = row == rect.top();
+                let bottom = row == rect.bottom() - 1;
+                let index = match (top, bottom, left, right) {
+                    (true, _, true, _) => 0,
+                    (true, _, _, true) => 2,
+                    (true, _, _, _) => 1,
+                    (_, true, true, _) => 5,
+                    (_, true, _, true) => 7,
+                    (_, true, _, _) => 6,
+                    (_, _, true, _) => 3,
+                    (_, _, _, true) => 4,
+                    _ => continue,
+                };
+                self.set((col as u32, row as u32), tiles[index].clone());
+            }
+        }
+    }
+
     pub fn flip_horz(&mut self) {
-        let mut new_grid: Vec<Option<Tile>> = vec![None; self.grid.len()];
+        let mut new_subgrid = SubGrid::new(self.width, self.height);
         for row in 0..self.height {
             for col in 0..self.width {
-                new_grid
-                    [(row * self.width + (self.width - col - 1)) as usize] =
-                    self[(col, row)].clone();
+                new_subgrid.set(
+                    (self.width - col - 1, row),
+                    self[(col, row)].clone(),
+                );
             }
         }
-        self.grid = new_grid;
+        *self = new_subgrid;
     }
 
     pub fn flip_vert(&mut self) {
-        let mut new_grid: Vec<Option<Tile>> = vec![None; self.grid.len()];
+        let mut new_subgrid = SubGrid::new(self.width, self.height);
         for row in 0..self.height {
             for col in 0..self.width {
-                new_grid
-                    [((self.height - row - 1) * self.width + col) as usize] =
-                    self[(col, row)].clone();
+                new_subgrid.set(
+                    (col, self.height - row - 1),
+                    self[(col, row)].clone(),
+                );
             }
         }
-        self.grid = new_grid;
+        *self = new_subgrid;
     }
-}
 
-impl Index<(u32, u32)> for SubGrid {
-    type Output = Option<Tile>;
-    fn index(&self, (col, row): (u32, u32)) -> &Option<Tile> {
-        if col >= self.width || row >= self.height {
-            panic!("index out of range");
+    /// Returns a set of non-overlapping rects that together cover exactly
+    /// the cells containing the given tile (or the empty cells, for `None`).
+    pub fn rects_containing(&self, tile: &Option<Tile>) -> Vec<Rect> {
+        let mut rects: Vec<Rect> = Vec::new();
+        // The indices in `rects` of the runs of matching cells found in the
+        // previous row, which a run in this row with the same columns can
+        // extend downwards:
+        let mut prev_runs: Vec<usize> = Vec::new();
+        for row in 0..self.height {
+            let mut runs = Vec::new();
+            let mut col = 0;
+            while col < self.width {
+                if self[(col, row)] != *tile {
+                    col += 1;
+                    continue;
+                }
+                let start = col;
+                while col < self.width && self[(col, row)] == *tile {
+                    col += 1;
+                }
+                let (left, width) = (start as i32, col - start);
+                let above = prev_runs.iter().copied().find(|&index| {
+                    rects[index].left() == left
+                        && rects[index].width() == width
+                });
+                if let Some(index) = above {
+                    let height = rects[index].height();
+                    rects[index].set_height(height + 1);
+                    runs.push(index);
+                } else {
+                    runs.push(rects.len());
+                    rects.push(Rect::new(left, row as i32, width, 1));
+                }
+            }
+            prev_runs = runs;
         }
-        &self.grid[(row * self.width + col) as usize]
+        rects
+    }
+
+    /// Writes this subgrid, floating at the given position, as text for the
+    /// system clipboard: a header line giving its size and position, then
+    /// one line per row of cell tokens (see `tile_token`), each prefixed by
+    /// a flip marker if the tile is flipped, e.g.
+    ///
+    /// ```text
+    /// @SUBGRID 3x2 4,5
+    /// grass:0 !grass:0 -
+    /// - water:3 water:3
+    /// ```
+    pub fn to_text(&self, position: Point) -> String {
+        let mut text = format!(
+            "{} {}x{} {},{}\n",
+            SUBGRID_TEXT_HEADER,
+            self.width,
+            self.height,
+            position.x(),
+            position.y()
+        );
+        for row in 0..self.height {
+            let tokens: Vec<String> = (0..self.width)
+                .map(|col| {
+                    let cell = &self[(col, row)];
+                    let marker = cell
+                        .as_ref()
+                        .and_then(|tile| tile.flip().marker())
+                        .map(String::from)
+                        .unwrap_or_default();
+                    marker + &tile_token(cell)
+                })
+                .collect();
+            text.push_str(&tokens.join(" "));
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Returns true if the given text looks like it was written by
+    /// `to_text` (though it may still fail to parse).
+    pub fn is_subgrid_text(text: &str) -> bool {
+        text.split_whitespace().next() == Some(SUBGRID_TEXT_HEADER)
+    }
+
+    /// Parses text written by `to_text`, returning the subgrid and its
+    /// position.  Fails if any tile isn't in the given tileset.
+    pub fn from_text(
+        text: &str,
+        tileset: &Tileset,
+    ) -> io::Result<(SubGrid, Point)> {
+        SubGrid::parse_text(text, |token| tileset.parse_tile(token))
+    }
+
+    fn parse_text<F>(
+        text: &str,
+        mut parse_tile: F,
+    ) -> io::Result<(SubGrid, Point)>
+    where
+        F: FnMut(&str) -> Option<Option<Tile>>,
+    {
+        let invalid =
+            |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut lines = text.lines();
+        let header = lines.next().unwrap_or("");
+        let pieces: Vec<&str> = header.split_whitespace().collect();
+        let parsed = match pieces.as_slice() {
+            &[SUBGRID_TEXT_HEADER, size, position] => {
+                size.split_once('x').zip(position.split_once(','))
+            }
+            _ => None,
+        };
+        let (width, height, left, top) = parsed
+            .and_then(|((width, height), (left, top))| {
+                Some((
+                    width.parse::<u32>().ok()?,
+                    height.parse::<u32>().ok()?,
+                    left.parse::<i32>().ok()?,
+                    top.parse::<i32>().ok()?,
+                ))
+            })
+            .ok_or_else(|| {
+                invalid(format!("invalid subgrid header: {:?}", header))
+            })?;
+        let mut subgrid = SubGrid::new(width, height);
+        for row in 0..height {
+            let line = lines.next().unwrap_or("");
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.len() != width as usize {
+                let msg = format!("wrong number of cells in row {}", row);
+                return Err(invalid(msg));
+            }
+            for (col, &token) in tokens.iter().enumerate() {
+                let mut name = token;
+                let mut flip = Flip::default();
+                if let Some(marked) =
+                    name.bytes().next().and_then(Flip::from_marker)
+                {
+                    flip = marked;
+                    name = &name[1..];
+                }
+                let cell = parse_tile(name).ok_or_else(|| {
+                    invalid(format!("no such tile: {}", token))
+                })?;
+                let cell = match cell {
+                    Some(tile) => Some(tile.with_flip(flip)),
+                    None if flip == Flip::default() => None,
+                    None => {
+                        return Err(invalid(format!(
+                            "flipped empty cell: {}",
+                            token
+                        )))
+                    }
+                };
+                subgrid.set((col as u32, row), cell);
+            }
+        }
+        if lines.any(|line| !line.trim().is_empty()) {
+            return Err(invalid("too many rows".to_string()));
+        }
+        Ok((subgrid, Point::new(left, top)))
     }
 }
 
-impl IndexMut<(u32, u32)> for SubGrid {
-    fn index_mut(&mut self, (col, row): (u32, u32)) -> &mut Option<Tile> {
-        if col >= self.width || row >= self.height {
-            panic!("index out of range");
+impl<T: Clone> Index<(u32, u32)> for SubGrid<T> {
+    type Output = Option<T>;
+    fn index(&self, position: (u32, u32)) -> &Option<T> {
+        let (chunk, offset) = self.chunk_position(position);
+        match self.chunks[chunk] {
+            Some(ref chunk) => &chunk[offset],
+            None => &None,
         }
-        &mut self.grid[(row * self.width + col) as usize]
+    }
+}
+
+/// Returns the smallest rect containing every non-empty cell of any of the
+/// given layers, or `None` if they're all entirely empty.
+fn layers_content_bounds<'a, T: Clone + 'a>(
+    layers: impl IntoIterator<Item = &'a SubGrid<T>>,
+) -> Option<Rect> {
+    layers
+        .into_iter()
+        .filter_map(SubGrid::content_bounds)
+        .reduce(|bounds, other| bounds.union(other))
+}
+
+/// Flattens the given layers (bottom first, all the same size) into one,
+/// with each cell taking its value from the topmost layer that isn't empty
+/// there.
+fn flatten_layers<'a, T: Clone + 'a>(
+    layers: impl IntoIterator<Item = &'a SubGrid<T>>,
+) -> SubGrid<T> {
+    let mut layers = layers.into_iter();
+    let mut flattened = layers.next().expect("no layers").clone();
+    for layer in layers {
+        flattened.paste(layer, (0, 0));
+    }
+    flattened
+}
+
+//===========================================================================//
+
+/// A non-tile image (e.g. parallax background art) drawn behind the tiles,
+/// offset by the given number of pixels from the top-left of the grid.  The
+/// image is loaded from an .ahi file in the tiles directory.
+#[derive(Clone)]
+pub struct Backdrop {
+    filename: String,
+    offset: Point,
+    // The image, unless it couldn't be loaded when the map was (in which
+    // case the backdrop is kept, so that saving doesn't drop it):
+    sprite: Option<Rc<Sprite>>,
+}
+
+impl Backdrop {
+    pub fn load(
+        window: &Window,
+        dirpath: &Path,
+        filename: &str,
+        offset: Point,
+    ) -> io::Result<Backdrop> {
+        let path = dirpath.join(filename).with_extension("ahi");
+        let collection = util::load_ahi_from_file(&path)?;
+        let image = collection.images.first().ok_or_else(|| {
+            let msg = format!("no images in backdrop: {}", filename);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })?;
+        let palette =
+            collection.palettes.first().unwrap_or(Palette::default());
+        Ok(Backdrop {
+            filename: filename.to_string(),
+            offset,
+            sprite: Some(Rc::new(window.new_sprite(image, palette))),
+        })
+    }
+
+    /// Returns a backdrop whose image couldn't be loaded, which draws
+    /// nothing but keeps its place in the .bg file.
+    fn unloaded(filename: &str, offset: Point) -> Backdrop {
+        Backdrop { filename: filename.to_string(), offset, sprite: None }
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn offset(&self) -> Point {
+        self.offset
+    }
+
+    pub fn sprite(&self) -> Option<&Sprite> {
+        self.sprite.as_deref()
     }
 }
 
 //===========================================================================//
 
+// The metadata key under which a trimmed map records its original size:
+const ORIGINAL_SIZE_KEY: &str = "original_size";
+
+// The name of a map's first layer, unless it's given another:
+const DEFAULT_LAYER_NAME: &str = "base";
+
+// The first word of the text that `SubGrid::to_text` writes:
+const SUBGRID_TEXT_HEADER: &str = "@SUBGRID";
+
+// The character written before a flipped cell in a .bg file, whether it
+// mirrors the tile horizontally and vertically, and a description:
+const FLIP_MARKERS: [(char, bool, bool, &str); 3] = [
+    ('!', true, false, "horizontal"),
+    ('^', false, true, "vertical"),
+    ('*', true, true, "both"),
+];
+
+/// One kind of extended `@KEYWORD ...` header line in a .bg file, as
+/// described by the built-in format help.
+struct HeaderLine {
+    keyword: &'static str,
+    arguments: &'static str,
+    meaning: &'static str,
+}
+
+const HEADER_LINES: [HeaderLine; 5] = [
+    HeaderLine {
+        keyword: "HASH",
+        arguments: "checksum filename",
+        meaning: "a tile file's checksum when saved",
+    },
+    HeaderLine {
+        keyword: "GRADIENT",
+        arguments: "r g b",
+        meaning: "bottom color of a background gradient",
+    },
+    HeaderLine {
+        keyword: "BACKDROP",
+        arguments: "x y filename",
+        meaning: "backdrop image and its pixel offset",
+    },
+    HeaderLine {
+        keyword: "META",
+        arguments: "key value",
+        meaning: "metadata (the value is the rest of the line)",
+    },
+    HeaderLine {
+        keyword: "LAYER",
+        arguments: "name",
+        meaning: "names the first layer; after the rows, starts another",
+    },
+];
+
 pub const GRID_DEFAULT_NUM_COLS: u32 = 36;
 pub const GRID_DEFAULT_NUM_ROWS: u32 = 24;
 
 #[derive(Clone)]
 pub struct TileGrid {
     background_color: (u8, u8, u8),
+    background_gradient: Option<(u8, u8, u8)>,
+    backdrop: Option<Backdrop>,
+    metadata: Vec<(String, String)>,
+    // The tile files whose contents have changed since the map was saved:
+    changed_tile_files: Vec<String>,
+    // Why the backdrop image couldn't be loaded along with the map, if it
+    // couldn't:
+    backdrop_error: Option<String>,
     tileset: Rc<Tileset>,
+    // The cells of the active layer:
     subgrid: SubGrid,
+    // The names and cells of the map's layers, bottom first.  The active
+    // layer's cells are kept in `subgrid` instead (so that everything that
+    // edits the grid edits the active layer), leaving an empty placeholder
+    // in its slot here.
+    layers: Vec<(String, SubGrid)>,
+    active_layer: usize,
 }
 
 impl TileGrid {
     pub fn new(tileset: Tileset) -> TileGrid {
         TileGrid {
             background_color: (15, 15, 15),
+            background_gradient: None,
+            backdrop: None,
+            metadata: Vec::new(),
+            changed_tile_files: Vec::new(),
+            backdrop_error: None,
             tileset: Rc::new(tileset),
             subgrid: SubGrid::new(
                 GRID_DEFAULT_NUM_COLS,
                 GRID_DEFAULT_NUM_ROWS,
             ),
+            layers: vec![(DEFAULT_LAYER_NAME.to_string(), SubGrid::new(0, 0))],
+            active_layer: 0,
         }
     }
 
@@ -333,14 +1534,134 @@ impl TileGrid {
         self.subgrid.size()
     }
 
+    /// Sets the given cell of the active layer.
+    pub fn set(&mut self, position: (u32, u32), cell: Option<Tile>) {
+        self.subgrid.set(position, cell);
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn layer_name(&self, index: usize) -> &str {
+        &self.layers[index].0
+    }
+
+    /// Returns the cells of the given layer.
+    pub fn layer(&self, index: usize) -> &SubGrid {
+        if index == self.active_layer {
+            &self.subgrid
+        } else {
+            &self.layers[index].1
+        }
+    }
+
+    /// Returns the index of the layer that indexing into the grid (and so
+    /// every editing operation) acts on.
+    pub fn active_layer(&self) -> usize {
+        self.active_layer
+    }
+
+    pub fn set_active_layer(&mut self, index: usize) {
+        assert!(index < self.layers.len());
+        let placeholder = SubGrid::new(0, 0);
+        let old = mem::replace(&mut self.subgrid, placeholder);
+        self.layers[self.active_layer].1 = old;
+        let new = mem::replace(&mut self.layers[index].1, SubGrid::new(0, 0));
+        self.subgrid = new;
+        self.active_layer = index;
+    }
+
+    /// Adds a new empty layer just above the active one, and makes it the
+    /// active layer.
+    pub fn add_layer(&mut self, name: String) {
+        let index = self.active_layer + 1;
+        let (width, height) = self.size();
+        self.layers.insert(index, (name, SubGrid::new(width, height)));
+        self.set_active_layer(index);
+    }
+
+    /// Removes the active layer (unless it's the only one), making the layer
+    /// below it (if any) active.  Returns false if there was only one layer.
+    pub fn remove_active_layer(&mut self) -> bool {
+        if self.layers.len() < 2 {
+            return false;
+        }
+        let index = self.active_layer;
+        let below = index.saturating_sub(1);
+        let above = index + 1;
+        self.set_active_layer(if index > 0 { below } else { above });
+        self.layers.remove(index);
+        if self.active_layer > index {
+            self.active_layer -= 1;
+        }
+        true
+    }
+
+    /// Returns the cells of each layer other than the active one's
+    /// placeholder, including the active layer's actual cells.
+    fn all_layers(&self) -> impl Iterator<Item = &SubGrid> + '_ {
+        (0..self.num_layers()).map(move |index| self.layer(index))
+    }
+
+    fn all_layers_mut(&mut self) -> impl Iterator<Item = &mut SubGrid> + '_ {
+        let active = self.active_layer;
+        self.layers
+            .iter_mut()
+            .enumerate()
+            .filter(move |&(index, _)| index != active)
+            .map(|(_, (_, subgrid))| subgrid)
+            .chain(iter::once(&mut self.subgrid))
+    }
+
     pub fn resize(&mut self, new_width: u32, new_height: u32) {
-        let mut new_subgrid = SubGrid::new(new_width, new_height);
-        for row in 0..self.height().min(new_height) {
-            for col in 0..self.width().min(new_width) {
-                new_subgrid[(col, row)] = self.subgrid[(col, row)].take();
-            }
+        for subgrid in self.all_layers_mut() {
+            *subgrid = subgrid.resized(new_width, new_height);
         }
-        self.subgrid = new_subgrid;
+    }
+
+    /// Like `resize`, but fills the cells added to each layer by repeating
+    /// that layer's last column and row.
+    pub fn resize_extending(&mut self, new_width: u32, new_height: u32) {
+        for subgrid in self.all_layers_mut() {
+            *subgrid = subgrid.extended(new_width, new_height);
+        }
+    }
+
+    /// Crops the grid to the given rect (which must lie within the grid),
+    /// moving the backdrop so that it stays in place behind the tiles.
+    pub fn crop(&mut self, rect: Rect) {
+        for subgrid in self.all_layers_mut() {
+            *subgrid = subgrid.copy_rect(rect);
+        }
+        let tile_size = self.tile_size() as i32;
+        if let Some(ref mut backdrop) = self.backdrop {
+            backdrop.offset -= rect.top_left() * tile_size;
+        }
+    }
+
+    /// Returns the smallest rect containing every non-empty cell (in any
+    /// layer), or `None` if the grid is entirely empty.
+    pub fn content_bounds(&self) -> Option<Rect> {
+        layers_content_bounds(self.all_layers())
+    }
+
+    /// Returns the layers flattened into one, with each cell taking its
+    /// tile from the topmost layer that isn't empty there.
+    pub fn flattened(&self) -> SubGrid {
+        flatten_layers(self.all_layers())
+    }
+
+    /// Returns the number of cells that are empty in every layer.
+    pub fn count_empty_cells(&self) -> usize {
+        self.flattened().count_empty_cells()
+    }
+
+    /// Returns rects covering the cells of the active layer containing the
+    /// given tile (see `SubGrid::rects_containing`), since that's the layer
+    /// that selections act on.
+    pub fn rects_containing(&self, tile: &Option<Tile>) -> Vec<Rect> {
+        self.subgrid.rects_containing(tile)
     }
 
     pub fn tile_size(&self) -> u32 {
@@ -356,65 +1677,417 @@ impl TileGrid {
         self.background_color = (red, green, blue);
     }
 
-    pub fn tileset(&self) -> Rc<Tileset> {
-        self.tileset.clone()
+    /// Returns the bottom color of the background gradient, if any.  When
+    /// there is a gradient, the background color is used as its top color.
+    pub fn background_gradient(&self) -> Option<(u8, u8, u8, u8)> {
+        self.background_gradient.map(|(r, g, b)| (r, g, b, 255))
+    }
+
+    pub fn set_background_gradient(&mut self, bottom: Option<(u8, u8, u8)>) {
+        self.background_gradient = bottom;
+    }
+
+    pub fn backdrop(&self) -> Option<&Backdrop> {
+        self.backdrop.as_ref()
+    }
+
+    pub fn set_backdrop(&mut self, backdrop: Option<Backdrop>) {
+        self.backdrop = backdrop;
+    }
+
+    /// Returns the map's metadata entries (e.g. author or music track), in
+    /// the order they appear in the file.
+    pub fn metadata(&self) -> &[(String, String)] {
+        &self.metadata
+    }
+
+    pub fn set_metadata(&mut self, metadata: Vec<(String, String)>) {
+        self.metadata = metadata;
+    }
+
+    /// Returns the tile files whose contents on disk no longer match the
+    /// checksums recorded when the map was last saved (e.g. because the
+    /// tiles were reordered), as detected when the map was loaded.
+    pub fn changed_tile_files(&self) -> &[String] {
+        &self.changed_tile_files
+    }
+
+    /// Returns why the backdrop's image couldn't be loaded when the map was,
+    /// if it couldn't.
+    pub fn backdrop_error(&self) -> Option<&str> {
+        self.backdrop_error.as_deref()
+    }
+
+    /// Returns the background color for the given row of pixels, taking any
+    /// background gradient into account.
+    pub fn background_color_at(&self, pixel_row: u32) -> (u8, u8, u8, u8) {
+        let (top_r, top_g, top_b) = self.background_color;
+        let (bot_r, bot_g, bot_b) = match self.background_gradient {
+            Some(bottom) => bottom,
+            None => return (top_r, top_g, top_b, 255),
+        };
+        let span = (self.height() * self.tile_size()).max(2) - 1;
+        let pixel_row = pixel_row.min(span);
+        let mix = |top: u8, bottom: u8| -> u8 {
+            ((top as u32 * (span - pixel_row) + bottom as u32 * pixel_row)
+                / span) as u8
+        };
+        (mix(top_r, bot_r), mix(top_g, bot_g), mix(top_b, bot_b), 255)
+    }
+
+    pub fn tileset(&self) -> Rc<Tileset> {
+        self.tileset.clone()
+    }
+
+    pub fn set_tile_filenames(
+        &mut self,
+        window: &Window,
+        filenames: Vec<&str>,
+    ) -> io::Result<()> {
+        Rc::make_mut(&mut self.tileset).reload(window, &filenames)?;
+        let filenames_set: BTreeSet<String> =
+            filenames.iter().cloned().map(str::to_string).collect();
+        for subgrid in self.all_layers_mut() {
+            subgrid.replace_tiles(|tile| {
+                if filenames_set.contains(&tile.filename) {
+                    None
+                } else {
+                    Some(None)
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Changes the directory that the tileset's files are loaded from (see
+    /// `Tileset::set_dirpath`).
+    pub fn set_tiles_dirpath(&mut self, dirpath: &Path) {
+        Rc::make_mut(&mut self.tileset).set_dirpath(dirpath.to_path_buf());
+    }
+
+    /// Replaces the animations of the named tile file (e.g. after editing
+    /// its sidecar).
+    pub fn set_tile_animations(
+        &mut self,
+        filename: &str,
+        animations: Vec<TileAnimation>,
+    ) {
+        Rc::make_mut(&mut self.tileset)
+            .set_file_animations(filename, animations);
+    }
+
+    /// Moves the tile file at `index` to `new_index` in the tileset's file
+    /// order.  Placed cells refer to their tiles by filename, so they keep
+    /// their tiles; only the indices written to the .bg file change.
+    pub fn move_tile_file(&mut self, index: usize, new_index: usize) -> bool {
+        Rc::make_mut(&mut self.tileset).move_file(index, new_index)
+    }
+
+    /// Replaces the `from` tile file in the tileset with the `to` tile file
+    /// (loaded from disk, in the same place in the file order), for when a
+    /// tile file has been renamed or moved.  Cells using `from` tiles switch
+    /// to the `to` tiles with the same indices; any past the end of the `to`
+    /// file are cleared.
+    pub fn rename_tile_file(
+        &mut self,
+        window: &Window,
+        from: &str,
+        to: &str,
+    ) -> io::Result<()> {
+        if !self.tileset.has_filename(from) || self.tileset.has_filename(to) {
+            let msg = format!("can't rename {:?} to {:?}", from, to);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let filenames: Vec<String> = self
+            .tileset
+            .filenames()
+            .map(|name| if name == from { to.to_string() } else { name })
+            .collect();
+        let filenames: Vec<&str> =
+            filenames.iter().map(String::as_str).collect();
+        Rc::make_mut(&mut self.tileset).reload(window, &filenames)?;
+        let tileset = self.tileset.clone();
+        for subgrid in self.all_layers_mut() {
+            subgrid.replace_tiles(|tile| {
+                if tile.filename == from {
+                    Some(
+                        tileset
+                            .get_named(to, tile.index)
+                            .map(|renamed| renamed.with_flip(tile.flip)),
+                    )
+                } else {
+                    None
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Appends the tiles of the `from` tile file onto the end of the `into`
+    /// tile file (rewriting the `into` .ahi file on disk), changes every cell
+    /// that used a `from` tile to use the corresponding `into` tile, and
+    /// then removes `from` from the tileset.  The merged tiles keep their
+    /// pixel values, but will be drawn with the `into` file's palette.
+    /// Returns the index within `into` of the first merged tile.
+    pub fn merge_tile_files(
+        &mut self,
+        window: &Window,
+        into: &str,
+        from: &str,
+    ) -> io::Result<usize> {
+        if into == from
+            || !self.tileset.has_filename(into)
+            || !self.tileset.has_filename(from)
+        {
+            let msg = format!("can't merge {:?} into {:?}", from, into);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let into_path = self.tileset.file_path(into);
+        let from_path = self.tileset.file_path(from);
+        let mut collection = util::load_ahi_from_file(&into_path)?;
+        let from_collection = util::load_ahi_from_file(&from_path)?;
+        let offset = collection.images.len();
+        collection.images.extend(from_collection.images);
+        collection.write(&mut File::create(&into_path)?)?;
+        let (sprites, checksum) = self.tileset.load_sprites(window, into)?;
+        self.replace_sprites(into, &sprites, checksum);
+        let tileset = self.tileset.clone();
+        for subgrid in self.all_layers_mut() {
+            subgrid.replace_tiles(|tile| {
+                if tile.filename == from {
+                    Some(tileset.get_named(into, offset + tile.index))
+                } else {
+                    None
+                }
+            });
+        }
+        let filenames: Vec<String> =
+            self.tileset.filenames().filter(|name| name != from).collect();
+        self.set_tile_filenames(
+            window,
+            filenames.iter().map(String::as_str).collect(),
+        )?;
+        Ok(offset)
+    }
+
+    /// Returns the positions of the (up to four) cells orthogonally adjacent
+    /// to the given position.
+    pub fn neighbors(&self, (col, row): (u32, u32)) -> Vec<(u32, u32)> {
+        let mut neighbors = Vec::with_capacity(4);
+        if col > 0 {
+            neighbors.push((col - 1, row));
+        }
+        if col + 1 < self.width() {
+            neighbors.push((col + 1, row));
+        }
+        if row > 0 {
+            neighbors.push((col, row - 1));
+        }
+        if row + 1 < self.height() {
+            neighbors.push((col, row + 1));
+        }
+        neighbors
+    }
+
+    /// Replaces every `from` cell that is orthogonally adjacent to a `near`
+    /// cell with `to`.  Adjacency is judged against the grid as it was
+    /// before any replacements were made.
+    pub fn replace_adjacent(
+        &mut self,
+        from: &Option<Tile>,
+        to: &Option<Tile>,
+        near: &Option<Tile>,
+    ) {
+        let original = self.clone();
+        for row in 0..self.height() {
+            for col in 0..self.width() {
+                if original[(col, row)] == *from
+                    && original
+                        .neighbors((col, row))
+                        .into_iter()
+                        .any(|position| original[position] == *near)
+                {
+                    self.set((col, row), to.clone());
+                }
+            }
+        }
+    }
+
+    /// Updates the tileset and every tile from the given file to use the
+    /// given sprites (e.g. after the file has been reloaded), and records
+    /// the file's new checksum.
+    pub fn replace_sprites(
+        &mut self,
+        filename: &str,
+        sprites: &[Rc<Sprite>],
+        checksum: u64,
+    ) {
+        if self.tileset.has_filename(filename) {
+            Rc::make_mut(&mut self.tileset)
+                .replace_sprites(filename, sprites, checksum);
+            for subgrid in self.all_layers_mut() {
+                subgrid.replace_sprites(filename, sprites);
+            }
+        }
+    }
+
+    pub fn substitute_tiles(&mut self, substitutions: &BTreeMap<Tile, Tile>) {
+        for subgrid in self.all_layers_mut() {
+            subgrid.substitute_tiles(substitutions);
+        }
+    }
+
+    pub fn count_tiles_from(&self, filename: &str) -> usize {
+        (0..self.num_layers())
+            .map(|index| self.layer(index).count_tiles_from(filename))
+            .sum()
+    }
+
+    pub fn rerandomize(&mut self, variants: &[Tile], rng: &mut Rng) {
+        self.subgrid.rerandomize(variants, rng);
+    }
+
+    pub fn draw_border(&mut self, rect: Rect, tiles: &[Option<Tile>; 8]) {
+        self.subgrid.draw_border(rect, tiles);
+    }
+
+    /// Replaces every cell in the grid with the contents of the given
+    /// subgrid, which must be the same size as the grid.
+    pub fn replace_subgrid(&mut self, subgrid: SubGrid) -> io::Result<()> {
+        if subgrid.size() != self.size() {
+            let msg = format!(
+                "Subgrid is {}x{}, but grid is {}x{}",
+                subgrid.width(),
+                subgrid.height(),
+                self.width(),
+                self.height()
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.subgrid = subgrid;
+        Ok(())
+    }
+
+    /// Returns the position of the tile occupying the given cell: the cell
+    /// itself if it has a tile, or else the anchor of a multi-cell tile that
+    /// covers it (see `Tileset::tile_span`), if any.
+    pub fn tile_anchor(&self, (col, row): (u32, u32)) -> Option<(u32, u32)> {
+        if self[(col, row)].is_some() {
+            return Some((col, row));
+        }
+        let max_span = self.tileset.max_span();
+        for anchor_row in row.saturating_sub(max_span - 1)..=row {
+            for anchor_col in col.saturating_sub(max_span - 1)..=col {
+                if let Some(ref tile) = self[(anchor_col, anchor_row)] {
+                    let (cols, rows) = self.tileset.tile_span(tile);
+                    if col < anchor_col + cols && row < anchor_row + rows {
+                        return Some((anchor_col, anchor_row));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns true if putting the given tile in the given cell with
+    /// `place_tile` would change nothing.
+    pub fn has_placed(
+        &self,
+        position: (u32, u32),
+        tile: &Option<Tile>,
+    ) -> bool {
+        match self.tile_anchor(position) {
+            Some(anchor) if anchor != position => false,
+            _ => self[position] == *tile,
+        }
     }
 
-    pub fn set_tile_filenames(
-        &mut self,
-        window: &Window,
-        filenames: Vec<&str>,
-    ) -> io::Result<()> {
-        Rc::make_mut(&mut self.tileset).reload(window, &filenames)?;
-        let filenames_set: BTreeSet<String> =
-            filenames.iter().cloned().map(str::to_string).collect();
-        for tile in self.subgrid.grid.iter_mut() {
-            let bad = match *tile {
-                Some(ref tile) => !filenames_set.contains(&tile.filename),
-                None => false,
-            };
-            if bad {
-                *tile = None;
+    /// Puts the given tile (or an empty cell) in the given cell, first
+    /// removing any tiles occupying the cells that the new tile will cover,
+    /// so that multi-cell tiles are painted and erased as a unit.
+    pub fn place_tile(&mut self, (col, row): (u32, u32), tile: Option<Tile>) {
+        let (cols, rows) = match tile {
+            Some(ref tile) => self.tileset.tile_span(tile),
+            None => (1, 1),
+        };
+        for covered_row in row..(row + rows).min(self.height()) {
+            for covered_col in col..(col + cols).min(self.width()) {
+                if let Some(anchor) =
+                    self.tile_anchor((covered_col, covered_row))
+                {
+                    self.subgrid.set(anchor, None);
+                }
             }
         }
-        Ok(())
+        self.subgrid.set((col, row), tile);
     }
 
-    pub fn copy_subgrid(&self, rect: Rect) -> SubGrid {
-        let mut grid = Vec::new();
-        let start_col = max(0, rect.left()) as u32;
-        let end_col = min(self.width() as i32, rect.right()) as u32;
-        let start_row = max(0, rect.top()) as u32;
-        let end_row = min(self.height() as i32, rect.bottom()) as u32;
-        for row in start_row..end_row {
-            for col in start_col..end_col {
-                grid.push(self[(col, row)].clone());
+    /// Grows the given rect of cells (clamped to the grid) until it includes
+    /// all of every multi-cell tile that it overlaps.
+    pub fn expand_to_tiles(&self, rect: Rect) -> Rect {
+        let mut bounds = (
+            max(0, rect.left()) as u32,
+            max(0, rect.top()) as u32,
+            min(self.width() as i32, rect.right()).max(0) as u32,
+            min(self.height() as i32, rect.bottom()).max(0) as u32,
+        );
+        loop {
+            let (left, top, right, bottom) = bounds;
+            let mut grown = bounds;
+            for row in top..bottom {
+                for col in left..right {
+                    let anchor = match self.tile_anchor((col, row)) {
+                        Some(anchor) => anchor,
+                        None => continue,
+                    };
+                    let (cols, rows) = match self[anchor] {
+                        Some(ref tile) => self.tileset.tile_span(tile),
+                        None => continue,
+                    };
+                    let (anchor_col, anchor_row) = anchor;
+                    grown.0 = grown.0.min(anchor_col);
+                    grown.1 = grown.1.min(anchor_row);
+                    grown.2 =
+                        grown.2.max((anchor_col + cols).min(self.width()));
+                    grown.3 =
+                        grown.3.max((anchor_row + rows).min(self.height()));
+                }
             }
+            if grown == bounds {
+                break;
+            }
+            bounds = grown;
         }
-        SubGrid {
-            width: end_col - start_col,
-            height: end_row - start_row,
-            grid,
+        let (left, top, right, bottom) = bounds;
+        if left < right && top < bottom {
+            Rect::new(left as i32, top as i32, right - left, bottom - top)
+        } else {
+            rect
         }
     }
 
+    pub fn copy_subgrid(&self, rect: Rect) -> SubGrid {
+        self.subgrid.copy_rect(rect)
+    }
+
     pub fn cut_subgrid(&mut self, rect: Rect) -> SubGrid {
-        let mut grid = Vec::new();
         let start_col = max(0, rect.left()) as u32;
         let end_col = min(self.width() as i32, rect.right()) as u32;
         let start_row = max(0, rect.top()) as u32;
         let end_row = min(self.height() as i32, rect.bottom()) as u32;
+        let mut subgrid =
+            SubGrid::new(end_col - start_col, end_row - start_row);
         for row in start_row..end_row {
             for col in start_col..end_col {
-                grid.push(self[(col, row)].clone());
-                self[(col, row)] = None;
+                subgrid.set(
+                    (col - start_col, row - start_row),
+                    self[(col, row)].clone(),
+                );
+                self.subgrid.set((col, row), None);
             }
         }
-        SubGrid {
-            width: end_col - start_col,
-            height: end_row - start_row,
-            grid,
-        }
+        self.subgrid.release_empty_chunks();
+        subgrid
     }
 
     pub fn paste_subgrid(&mut self, subgrid: &SubGrid, topleft: Point) {
@@ -433,13 +2106,36 @@ impl TileGrid {
                 let tile =
                     &subgrid[(src_start_col + col, src_start_row + row)];
                 if tile.is_some() {
-                    self[(dest_start_col + col, dest_start_row + row)] =
-                        tile.clone();
+                    self.set(
+                        (dest_start_col + col, dest_start_row + row),
+                        tile.clone(),
+                    );
                 }
             }
         }
     }
 
+    /// Returns a copy of the grid with any empty columns along its right
+    /// edge and empty rows along its bottom edge removed, recording the
+    /// original size in the metadata so that loading the map restores it.
+    pub fn trimmed(&self) -> TileGrid {
+        let (width, height) = self.size();
+        let (new_width, new_height) = match self.content_bounds() {
+            Some(bounds) => (bounds.right() as u32, bounds.bottom() as u32),
+            None => (1, 1),
+        };
+        let mut trimmed = self.clone();
+        if (new_width, new_height) != (width, height) {
+            trimmed.resize(new_width, new_height);
+            trimmed.metadata.retain(|(key, _)| key != ORIGINAL_SIZE_KEY);
+            trimmed.metadata.push((
+                ORIGINAL_SIZE_KEY.to_string(),
+                format!("{}x{}", width, height),
+            ));
+        }
+        trimmed
+    }
+
     pub fn save<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
         let (red, green, blue) = self.background_color;
         write!(writer, "@BG {} {} {}", red, green, blue)?;
@@ -453,21 +2149,147 @@ impl TileGrid {
         for filename in self.tileset.filenames() {
             write!(writer, ">{}\n", filename)?;
         }
+        for filename in self.tileset.filenames() {
+            if let Some(checksum) = self.tileset.file_checksum(&filename) {
+                writeln!(writer, "@HASH {:016x} {}", checksum, filename)?;
+            }
+        }
+        if let Some((red, green, blue)) = self.background_gradient {
+            writeln!(writer, "@GRADIENT {} {} {}", red, green, blue)?;
+        }
+        if let Some(ref backdrop) = self.backdrop {
+            let offset = backdrop.offset;
+            writeln!(
+                writer,
+                "@BACKDROP {} {} {}",
+                offset.x(),
+                offset.y(),
+                backdrop.filename
+            )?;
+        }
+        for (key, value) in self.metadata.iter() {
+            writeln!(writer, "@META {} {}", key, value)?;
+        }
+        if self.layer_name(0) != DEFAULT_LAYER_NAME {
+            writeln!(writer, "@LAYER {}", self.layer_name(0))?;
+        }
         let mut map = BTreeMap::<String, usize>::new();
         for (index, filename) in self.tileset.filenames().enumerate() {
             map.insert(filename.clone(), index);
         }
+        let lines = self.layer_lines(0, &map);
+        if !lines.is_empty() || self.num_layers() > 1 {
+            write!(writer, "\n")?;
+            for line in lines {
+                writeln!(writer, "{}", line)?;
+            }
+        }
+        for index in 1..self.num_layers() {
+            writeln!(writer, "@LAYER {}", self.layer_name(index))?;
+            for line in self.layer_lines(index, &map) {
+                writeln!(writer, "{}", line)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes the .bg file format, for the built-in format help, noting
+    /// which of its optional parts this map uses.
+    pub fn format_help(&self) -> Vec<String> {
+        let flipped = (0..self.num_layers()).any(|index| {
+            let layer = self.layer(index);
+            (0..self.height()).any(|row| {
+                (0..self.width()).any(|col| match layer[(col, row)] {
+                    Some(ref tile) => tile.flip.marker().is_some(),
+                    None => false,
+                })
+            })
+        });
+        let used = |keyword: &str| match keyword {
+            "HASH" => self.tileset.filenames().next().is_some(),
+            "GRADIENT" => self.background_gradient.is_some(),
+            "BACKDROP" => self.backdrop.is_some(),
+            "META" => !self.metadata.is_empty(),
+            "LAYER" => {
+                self.num_layers() > 1
+                    || self.layer_name(0) != DEFAULT_LAYER_NAME
+            }
+            _ => false,
+        };
+        let mut lines = vec![
+            format!(
+                "@BG r g b WxH - background color and size ({}x{} if omitted)",
+                GRID_DEFAULT_NUM_COLS, GRID_DEFAULT_NUM_ROWS
+            ),
+            format!(
+                ">filename - a tile file, in file index order ({} here)",
+                self.tileset.filenames().count()
+            ),
+        ];
+        for header in HEADER_LINES.iter() {
+            let mut line = format!(
+                "@{} {} - {}",
+                header.keyword, header.arguments, header.meaning
+            );
+            if used(header.keyword) {
+                line.push_str(" [used]");
+            }
+            lines.push(line);
+        }
+        lines.push(
+            "A blank line ends the header; each line after is a row"
+                .to_string(),
+        );
+        lines.push(format!(
+            "Cell: file index, tile index; digits {}-{} {}-{} {}-{} {} {}",
+            index_to_base64(0),
+            index_to_base64(25),
+            index_to_base64(26),
+            index_to_base64(51),
+            index_to_base64(52),
+            index_to_base64(61),
+            index_to_base64(62),
+            index_to_base64(63)
+        ));
+        lines.push(
+            "Empty cells are two spaces; trailing ones are left off"
+                .to_string(),
+        );
+        let markers: Vec<String> = FLIP_MARKERS
+            .iter()
+            .map(|marker| format!("{} {}", marker.0, marker.3))
+            .collect();
+        let mut line =
+            format!("Flipped cells are prefixed: {}", markers.join(", "));
+        if flipped {
+            line.push_str(" [used]");
+        }
+        lines.push(line);
+        lines
+    }
+
+    /// Encodes the cells of the given layer as lines of a .bg file, without
+    /// any trailing empty lines.
+    fn layer_lines(
+        &self,
+        index: usize,
+        map: &BTreeMap<String, usize>,
+    ) -> Vec<String> {
+        let layer = self.layer(index);
         let mut lines = Vec::<String>::new();
         for row in 0..self.height() {
             let mut line = String::new();
             let mut spaces = 0;
             for col in 0..self.width() {
-                match self[(col, row)] {
+                match layer[(col, row)] {
                     Some(ref tile) => {
                         for _ in 0..spaces {
                             line.push_str("  ");
                         }
                         spaces = 0;
+                        if let Some(marker) = tile.flip.marker() {
+                            line.push(marker);
+                        }
                         let file_index = *map.get(&tile.filename).unwrap();
                         let char1 = index_to_base64(file_index);
                         let char2 = index_to_base64(tile.index);
@@ -483,20 +2305,282 @@ impl TileGrid {
         while matches!(lines.last().map(String::deref), Some("")) {
             lines.pop();
         }
-        if !lines.is_empty() {
-            write!(writer, "\n")?;
-            for line in lines {
-                writeln!(writer, "{}", line)?;
+        lines
+    }
+
+    /// Loads a map in .bg format, first pointing its references to renamed
+    /// tile files at their new names (see `BgFile::rename_tile_files`).
+    pub fn load<R: io::Read>(
+        window: &Window,
+        dirpath: &Path,
+        size_lock: Option<TileSizeLock>,
+        renames: &[(String, String)],
+        reader: R,
+    ) -> io::Result<TileGrid> {
+        let mut bg_file = BgFile::read(reader)?;
+        bg_file.restore_original_size();
+        bg_file.rename_tile_files(renames)?;
+        let tileset = Rc::new(Tileset::load(
+            window,
+            dirpath,
+            &bg_file.filenames,
+            size_lock,
+        )?);
+        let (width, height) = (bg_file.width, bg_file.height);
+        let mut subgrid =
+            make_subgrid(&tileset, width, height, &bg_file.cells)?;
+        let mut layers = vec![(bg_file.base_layer_name, SubGrid::new(0, 0))];
+        for (name, cells) in bg_file.layers.iter() {
+            let layer = make_subgrid(&tileset, width, height, cells)?;
+            layers.push((name.clone(), layer));
+        }
+        for &(layer, position, flip) in bg_file.flips.iter() {
+            let cells = match layer {
+                0 => &mut subgrid,
+                _ => &mut layers[layer].1,
+            };
+            if let Some(ref tile) = cells[position] {
+                let flipped = tile.with_flip(flip);
+                cells.set(position, Some(flipped));
             }
         }
-        Ok(())
+        let mut changed_tile_files = Vec::new();
+        for &(ref filename, checksum) in bg_file.tile_checksums.iter() {
+            if tileset.file_checksum(filename) != Some(checksum) {
+                changed_tile_files.push(filename.clone());
+            }
+        }
+        let mut backdrop_error = None;
+        let backdrop = match bg_file.backdrop {
+            Some((ref filename, offset)) => {
+                match Backdrop::load(window, dirpath, filename, offset) {
+                    Ok(backdrop) => Some(backdrop),
+                    Err(error) => {
+                        backdrop_error =
+                            Some(format!("{}: {}", filename, error));
+                        Some(Backdrop::unloaded(filename, offset))
+                    }
+                }
+            }
+            None => None,
+        };
+        Ok(TileGrid {
+            background_color: bg_file.background_color,
+            background_gradient: bg_file.background_gradient,
+            backdrop,
+            metadata: bg_file.metadata,
+            changed_tile_files,
+            backdrop_error,
+            tileset,
+            subgrid,
+            layers,
+            active_layer: 0,
+        })
     }
 
-    pub fn load<R: io::Read>(
+    pub fn load_from_path(
         window: &Window,
         dirpath: &Path,
-        mut reader: R,
+        size_lock: Option<TileSizeLock>,
+        renames: &[(String, String)],
+        path: &String,
     ) -> io::Result<TileGrid> {
+        let reader = util::open_maybe_gzipped(path)?;
+        TileGrid::load(window, dirpath, size_lock, renames, reader)
+    }
+}
+
+/// Formats metadata entries for editing as "key=value; key=value; ...".
+/// Backslashes escape any `;`, `=`, or `\` within keys and values, as well
+/// as whitespace at the ends of values, so that `parse_metadata_text` gives back
+/// the same entries.
+pub fn metadata_text(metadata: &[(String, String)]) -> String {
+    let escape = |text: &str| {
+        let mut escaped = String::new();
+        let len = text.chars().count();
+        for (index, ch) in text.chars().enumerate() {
+            let at_end = index == 0 || index + 1 == len;
+            if matches!(ch, ';' | '=' | '\\') || (at_end && ch.is_whitespace())
+            {
+                escaped.push('\\');
+            }
+            escaped.push(ch);
+        }
+        escaped
+    };
+    let entries: Vec<String> = metadata
+        .iter()
+        .map(|(key, value)| format!("{}={}", escape(key), escape(value)))
+        .collect();
+    entries.join("; ")
+}
+
+/// Parses metadata entries as formatted by `metadata_text`.  Returns `None`
+/// if an entry has no `=`, or its key is empty or contains whitespace, or
+/// its value contains a line break (which a .bg file can't hold).
+pub fn parse_metadata_text(text: &str) -> Option<Vec<(String, String)>> {
+    // Split into entries and keys/values on unescaped delimiters, keeping
+    // track of which characters were escaped so that they aren't trimmed.
+    let mut entries = vec![(Vec::new(), None)];
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        let (key, value) = entries.last_mut().unwrap();
+        if ch == ';' {
+            entries.push((Vec::new(), None));
+        } else if ch == '=' && value.is_none() {
+            *value = Some(Vec::new());
+        } else {
+            let escaped = ch == '\\';
+            let ch = if escaped { chars.next()? } else { ch };
+            value.as_mut().unwrap_or(key).push((ch, escaped));
+        }
+    }
+    let unescape = |part: &[(char, bool)]| -> String {
+        let is_trimmable =
+            |&(ch, escaped): &(char, bool)| !escaped && ch.is_whitespace();
+        let start = part.iter().position(|c| !is_trimmable(c));
+        let end = part.iter().rposition(|c| !is_trimmable(c));
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                part[start..=end].iter().map(|&(ch, _)| ch).collect()
+            }
+            _ => String::new(),
+        }
+    };
+    let mut metadata = Vec::new();
+    for (key, value) in entries {
+        let key = unescape(&key);
+        let value = match value {
+            Some(value) => unescape(&value),
+            None if key.is_empty() => continue,
+            None => return None,
+        };
+        if key.is_empty()
+            || key.contains(char::is_whitespace)
+            || value.contains(['\n', '\r'])
+        {
+            return None;
+        }
+        metadata.push((key, value));
+    }
+    Some(metadata)
+}
+
+/// A non-empty cell of a .bg file: its (col, row) position and the (file
+/// index, tile index) of its tile.
+pub type BgCell = ((u32, u32), (usize, usize));
+
+/// Builds a subgrid of the given size from the cells of a .bg file.
+fn make_subgrid(
+    tileset: &Tileset,
+    width: u32,
+    height: u32,
+    cells: &[BgCell],
+) -> io::Result<SubGrid> {
+    let mut subgrid = SubGrid::new(width, height);
+    for &((col, row), (file_index, tile_index)) in cells.iter() {
+        let opt_tile = tileset.get(file_index, tile_index);
+        let tile = opt_tile.ok_or_else(|| {
+            let msg = format!("invalid tile: {} {}", file_index, tile_index);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })?;
+        subgrid.set((col, row), Some(tile));
+    }
+    Ok(subgrid)
+}
+
+/// The contents of a .bg file, read without loading any tile images.  Each
+/// non-empty cell is given as its (col, row) position and the (file index,
+/// tile index) of its tile.
+///
+/// Besides the `>filename` lines, the header may contain extended `@KEY ...`
+/// lines: `@GRADIENT r g b` gives the bottom color of a vertical background
+/// gradient, `@BACKDROP x y filename` gives a backdrop image and its pixel
+/// offset, each `@META key value` line gives a metadata entry (the value
+/// being the rest of the line, verbatim), and each `@HASH checksum filename`
+/// line gives the checksum of a tile file as of when the map was saved.
+///
+/// A map saved with its trailing empty rows and columns trimmed off records
+/// its untrimmed size as an `original_size WxH` metadata entry.
+///
+/// A cell may be preceded by a `!`, `^`, or `*` to mark its tile as
+/// mirrored horizontally, vertically, or both; these are collected in
+/// `flips`, apart from the cells themselves.
+///
+/// The rows of cells after the header are the map's first layer.  A header
+/// line of `@LAYER name` names that layer, and each `@LAYER name` line after
+/// the first layer's rows begins the rows of another layer, bottom first.
+pub struct BgFile {
+    pub background_color: (u8, u8, u8),
+    pub background_gradient: Option<(u8, u8, u8)>,
+    pub backdrop: Option<(String, Point)>,
+    pub metadata: Vec<(String, String)>,
+    pub tile_checksums: Vec<(String, u64)>,
+    pub width: u32,
+    pub height: u32,
+    pub filenames: Vec<String>,
+    pub base_layer_name: String,
+    pub cells: Vec<BgCell>,
+    // The names and cells of the layers above the first one, bottom first:
+    pub layers: Vec<(String, Vec<BgCell>)>,
+    // The (layer index, position, flip) of each mirrored cell, where layer 0
+    // is the first layer:
+    pub flips: Vec<(usize, (u32, u32), Flip)>,
+}
+
+impl BgFile {
+    /// For each `(old, new)` pair, points the map's references to the `old`
+    /// tile file (including its checksum) at the `new` one instead, for
+    /// loading maps whose tile files have since been renamed or moved.
+    /// Pairs whose `old` file the map doesn't use are ignored.
+    pub fn rename_tile_files(
+        &mut self,
+        renames: &[(String, String)],
+    ) -> io::Result<()> {
+        for (old, new) in renames.iter() {
+            if !self.filenames.contains(old) {
+                continue;
+            }
+            if self.filenames.contains(new) {
+                let msg = format!(
+                    "can't rename {:?} to {:?}, which the map already uses",
+                    old, new
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+            let checksum_names =
+                self.tile_checksums.iter_mut().map(|(name, _)| name);
+            for filename in self.filenames.iter_mut().chain(checksum_names) {
+                if filename == old {
+                    *filename = new.clone();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// If the map was saved trimmed, removes the `original_size` metadata
+    /// entry and grows the grid back to that size.
+    pub fn restore_original_size(&mut self) {
+        let index = match self
+            .metadata
+            .iter()
+            .position(|(key, _)| key == ORIGINAL_SIZE_KEY)
+        {
+            Some(index) => index,
+            None => return,
+        };
+        let (_, value) = self.metadata.remove(index);
+        let mut pieces = value.split('x').map(|piece| piece.parse::<u32>());
+        if let (Some(Ok(width)), Some(Ok(height)), None) =
+            (pieces.next(), pieces.next(), pieces.next())
+        {
+            self.width = self.width.max(width);
+            self.height = self.height.max(height);
+        }
+    }
+
+    pub fn read<R: io::Read>(mut reader: R) -> io::Result<BgFile> {
         read_exactly(reader.by_ref(), b"@BG ")?;
         let red = read_int_with(reader.by_ref(), b' ')?;
         let green = read_int_with(reader.by_ref(), b' ')?;
@@ -514,13 +2598,30 @@ impl TileGrid {
             );
             return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
         };
-        let background_color = (red as u8, green as u8, blue as u8);
-        let mut subgrid = SubGrid::new(width, height);
-        let mut filenames = Vec::new();
+        let mut bg_file = BgFile {
+            background_color: (red as u8, green as u8, blue as u8),
+            background_gradient: None,
+            backdrop: None,
+            metadata: Vec::new(),
+            tile_checksums: Vec::new(),
+            width,
+            height,
+            filenames: Vec::new(),
+            base_layer_name: DEFAULT_LAYER_NAME.to_string(),
+            cells: Vec::new(),
+            layers: Vec::new(),
+            flips: Vec::new(),
+        };
         loop {
             match read_byte_or_eof(reader.by_ref())? {
                 Some(b'>') => {
-                    filenames.push(read_string(reader.by_ref(), b'\n')?);
+                    bg_file
+                        .filenames
+                        .push(read_string(reader.by_ref(), b'\n')?);
+                }
+                Some(b'@') => {
+                    let line = read_string(reader.by_ref(), b'\n')?;
+                    bg_file.read_header_line(&line)?;
                 }
                 Some(b'\n') => break,
                 Some(byte) => {
@@ -530,61 +2631,144 @@ impl TileGrid {
                         msg,
                     ));
                 }
-                None => {
-                    let tileset =
-                        Rc::new(Tileset::load(window, dirpath, &filenames)?);
-                    return Ok(TileGrid {
-                        background_color,
-                        tileset,
-                        subgrid,
-                    });
+                None => return Ok(bg_file),
+            }
+        }
+        let (mut col, mut row) = (0, 0);
+        loop {
+            let mut byte1 = match read_byte_or_eof(reader.by_ref())? {
+                None => return Ok(bg_file),
+                Some(b'\n') => {
+                    col = 0;
+                    row += 1;
+                    continue;
+                }
+                Some(b'@') if col == 0 => {
+                    let line = read_string(reader.by_ref(), b'\n')?;
+                    let name = BgFile::read_layer_line(&line)?;
+                    bg_file.layers.push((name, Vec::new()));
+                    row = 0;
+                    continue;
+                }
+                Some(byte) => byte,
+            };
+            if row >= height {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "too many rows",
+                ));
+            }
+            if col >= width {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "too many columns",
+                ));
+            }
+            let flip = Flip::from_marker(byte1);
+            if flip.is_some() {
+                byte1 = read_byte(reader.by_ref())?;
+            }
+            let byte2 = read_byte(reader.by_ref())?;
+            if let Some(flip) = flip {
+                let layer = bg_file.layers.len();
+                bg_file.flips.push((layer, (col, row), flip));
+            }
+            if byte1 != b' ' || byte2 != b' ' {
+                let file_index = base64_to_index(byte1)?;
+                let tile_index = base64_to_index(byte2)?;
+                let cell = ((col, row), (file_index, tile_index));
+                match bg_file.layers.last_mut() {
+                    Some((_, cells)) => cells.push(cell),
+                    None => bg_file.cells.push(cell),
                 }
             }
+            col += 1;
         }
-        let tileset = Rc::new(Tileset::load(window, dirpath, &filenames)?);
-        for row in 0..height {
-            let mut col = 0;
-            loop {
-                let byte1 = match read_byte_or_eof(reader.by_ref())? {
-                    None => {
-                        return Ok(TileGrid {
-                            background_color,
-                            tileset,
-                            subgrid,
-                        });
-                    }
-                    Some(b'\n') => break,
-                    Some(byte) => byte,
-                };
-                if col >= width {
+    }
+
+    /// Parses the name from a `LAYER name` line (without its leading `@`).
+    fn read_layer_line(line: &str) -> io::Result<String> {
+        match line.strip_prefix("LAYER ") {
+            Some(name) if !name.is_empty() => Ok(name.to_string()),
+            _ => {
+                let msg = format!("invalid layer: @{}", line);
+                Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+            }
+        }
+    }
+
+    fn read_header_line(&mut self, line: &str) -> io::Result<()> {
+        if line.starts_with("LAYER") {
+            self.base_layer_name = BgFile::read_layer_line(line)?;
+            return Ok(());
+        }
+        if let Some(entry) = line.strip_prefix("META ") {
+            let (key, value) = entry.split_once(' ').unwrap_or((entry, ""));
+            if key.is_empty() {
+                let msg = format!("invalid metadata: {}", line);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+            self.metadata.push((key.to_string(), value.to_string()));
+            return Ok(());
+        }
+        if let Some(entry) = line.strip_prefix("HASH ") {
+            let checksum =
+                entry.split_once(' ').and_then(|(hex, filename)| {
+                    Some((filename, u64::from_str_radix(hex, 16).ok()?))
+                });
+            let (filename, checksum) = checksum.ok_or_else(|| {
+                let msg = format!("invalid tile file hash: {}", line);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?;
+            self.tile_checksums.push((filename.to_string(), checksum));
+            return Ok(());
+        }
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("GRADIENT") => {
+                let values: Vec<u8> = words
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()
+                    .unwrap_or_default();
+                if values.len() != 3 {
+                    let msg = format!("invalid gradient: {}", line);
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidData,
-                        "too many columns",
+                        msg,
                     ));
                 }
-                let byte2 = read_byte(reader.by_ref())?;
-                if byte1 != b' ' || byte2 != b' ' {
-                    let file_index = base64_to_index(byte1)?;
-                    let tile_index = base64_to_index(byte2)?;
-                    let opt_tile = tileset.get(file_index, tile_index);
-                    let tile = opt_tile.ok_or_else(|| {
-                        let msg = format!("invalid tile: {} {}", byte1, byte2);
-                        io::Error::new(io::ErrorKind::InvalidData, msg)
-                    })?;
-                    subgrid[(col, row)] = Some(tile);
+                self.background_gradient =
+                    Some((values[0], values[1], values[2]));
+            }
+            Some("BACKDROP") => {
+                let x = words.next().and_then(|word| word.parse().ok());
+                let y = words.next().and_then(|word| word.parse().ok());
+                let filename = words.collect::<Vec<&str>>().join(" ");
+                match (x, y) {
+                    (Some(x), Some(y)) if !filename.is_empty() => {
+                        self.backdrop = Some((filename, Point::new(x, y)));
+                    }
+                    _ => {
+                        let msg = format!("invalid backdrop: {}", line);
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            msg,
+                        ));
+                    }
                 }
-                col += 1;
+            }
+            _ => {
+                let keywords: Vec<&str> =
+                    HEADER_LINES.iter().map(|header| header.keyword).collect();
+                let msg = format!(
+                    "unknown header line: @{} (expected @BG or one of {})",
+                    line,
+                    keywords.join(", ")
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
             }
         }
-        return Ok(TileGrid { background_color, tileset, subgrid });
-    }
-
-    pub fn load_from_path(
-        window: &Window,
-        dirpath: &Path,
-        path: &String,
-    ) -> io::Result<TileGrid> {
-        TileGrid::load(window, dirpath, File::open(path)?)
+        Ok(())
     }
 }
 
@@ -618,12 +2802,6 @@ impl Index<(u32, u32)> for TileGrid {
     }
 }
 
-impl IndexMut<(u32, u32)> for TileGrid {
-    fn index_mut(&mut self, (col, row): (u32, u32)) -> &mut Option<Tile> {
-        &mut self.subgrid[(col, row)]
-    }
-}
-
 //===========================================================================//
 
 fn read_byte_or_eof<R: io::Read>(reader: R) -> io::Result<Option<u8>> {
@@ -710,7 +2888,16 @@ fn read_string<R: io::Read>(reader: R, terminator: u8) -> io::Result<String> {
 
 #[cfg(test)]
 mod tests {
-    use super::{base64_to_index, index_to_base64};
+    use super::{
+        base64_to_index, flatten_layers, index_to_base64,
+        layers_content_bounds, metadata_text, parse_front_sidecar,
+        parse_metadata_text, parse_palette_sidecar, parse_tile_name, BgFile,
+        Flip, SubGrid, TileFit, TileSizeLock, HEADER_LINES,
+    };
+    use crate::util;
+    use flate2::read::GzDecoder;
+    use sdl2::rect::Point;
+    use sdl2::rect::Rect;
 
     #[test]
     fn base64_round_trip() {
@@ -721,6 +2908,330 @@ mod tests {
             assert_eq!(Some(index), base64_to_index(i as u8).ok());
         }
     }
+
+    #[test]
+    fn parse_subgrid_text() {
+        let subgrid = SubGrid::new(3, 2);
+        let text = subgrid.to_text(Point::new(-1, 4));
+        assert_eq!(text, "@SUBGRID 3x2 -1,4\n- - -\n- - -\n");
+        assert!(SubGrid::is_subgrid_text(&text));
+        let parse = |text: &str| {
+            SubGrid::parse_text(text, |token| {
+                if token == "-" {
+                    Some(None)
+                } else {
+                    None
+                }
+            })
+        };
+        let (parsed, position) = parse(&text).unwrap();
+        assert_eq!(parsed.size(), (3, 2));
+        assert_eq!(parsed.count_empty_cells(), 6);
+        assert_eq!(position, Point::new(-1, 4));
+        assert!(!SubGrid::is_subgrid_text("@BG 0 0 0\n"));
+        assert!(parse("@SUBGRID 3 0,0\n").is_err());
+        assert!(parse("@SUBGRID 2x1 0,0\n-\n").is_err());
+        assert!(parse("@SUBGRID 1x1 0,0\n!-\n").is_err());
+        assert!(parse("@SUBGRID 1x1 0,0\na:0\n").is_err());
+        assert!(parse("@SUBGRID 1x1 0,0\n-\n-\n").is_err());
+    }
+
+    #[test]
+    fn parse_tile_names() {
+        assert_eq!(parse_tile_name("red_brick:3"), Some(("red_brick", 3)));
+        assert_eq!(parse_tile_name("a:b:12"), Some(("a:b", 12)));
+        assert_eq!(parse_tile_name("red_brick"), None);
+        assert_eq!(parse_tile_name(":3"), None);
+        assert_eq!(parse_tile_name("red_brick:x"), None);
+    }
+
+    #[test]
+    fn read_gzipped() {
+        let data = util::gzip(b"@BG 1 2 3 2x1\n>foo\n\n  AB\n").unwrap();
+        assert_eq!(&data[..2], &[0x1f, 0x8b]);
+        let bg_file = BgFile::read(GzDecoder::new(&data[..])).unwrap();
+        assert_eq!(bg_file.background_color, (1, 2, 3));
+        assert_eq!(bg_file.cells, vec![((1, 0), (0, 1))]);
+    }
+
+    #[test]
+    fn read_gradient_header() {
+        let data = b"@BG 1 2 3 2x1\n>foo\n@GRADIENT 4 5 6\n\n  AB\n";
+        let bg_file = BgFile::read(&data[..]).unwrap();
+        assert_eq!(bg_file.background_color, (1, 2, 3));
+        assert_eq!(bg_file.background_gradient, Some((4, 5, 6)));
+        assert_eq!(bg_file.filenames, vec!["foo".to_string()]);
+        assert_eq!(bg_file.cells, vec![((1, 0), (0, 1))]);
+        let data = b"@BG 1 2 3\n@GRADIENT 4 5\n";
+        assert!(BgFile::read(&data[..]).is_err());
+    }
+
+    #[test]
+    fn read_backdrop_header() {
+        let data = b"@BG 1 2 3\n@BACKDROP -8 16 sky art\n";
+        let bg_file = BgFile::read(&data[..]).unwrap();
+        let (filename, offset) = bg_file.backdrop.unwrap();
+        assert_eq!(filename, "sky art");
+        assert_eq!((offset.x(), offset.y()), (-8, 16));
+        let data = b"@BG 1 2 3\n@BACKDROP 8 sky\n";
+        assert!(BgFile::read(&data[..]).is_err());
+    }
+
+    #[test]
+    fn read_hash_header() {
+        let data = b"@BG 1 2 3\n>a b\n@HASH 00000000000000ff a b\n";
+        let bg_file = BgFile::read(&data[..]).unwrap();
+        assert_eq!(bg_file.tile_checksums, vec![("a b".to_string(), 255)]);
+        let data = b"@BG 1 2 3\n@HASH xyz a\n";
+        assert!(BgFile::read(&data[..]).is_err());
+    }
+
+    #[test]
+    fn read_metadata_header() {
+        let data = b"@BG 1 2 3\n@META author Jo  Q. \n@META music 7\n\n";
+        let bg_file = BgFile::read(&data[..]).unwrap();
+        assert_eq!(
+            bg_file.metadata,
+            vec![
+                ("author".to_string(), "Jo  Q. ".to_string()),
+                ("music".to_string(), "7".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn metadata_text_round_trip() {
+        let metadata = vec![
+            ("author".to_string(), " Jo; Q.=x\\ ".to_string()),
+            ("a=b".to_string(), "".to_string()),
+            ("music".to_string(), "7".to_string()),
+        ];
+        let text = metadata_text(&metadata);
+        assert_eq!(text, "author=\\ Jo\\; Q.\\=x\\\\\\ ; a\\=b=; music=7");
+        assert_eq!(parse_metadata_text(&text), Some(metadata));
+        assert_eq!(
+            parse_metadata_text(" a = b c ;; d=e"),
+            Some(vec![
+                ("a".to_string(), "b c".to_string()),
+                ("d".to_string(), "e".to_string()),
+            ])
+        );
+        assert_eq!(parse_metadata_text("a b=c"), None);
+        assert_eq!(parse_metadata_text("a"), None);
+        assert_eq!(parse_metadata_text("a=b\\"), None);
+        assert_eq!(parse_metadata_text(""), Some(Vec::new()));
+    }
+
+    #[test]
+    fn restore_trimmed_size() {
+        let data = b"@BG 1 2 3 2x1\n@META original_size 40x30\n@META a b\n\n";
+        let mut bg_file = BgFile::read(&data[..]).unwrap();
+        bg_file.restore_original_size();
+        assert_eq!((bg_file.width, bg_file.height), (40, 30));
+        assert_eq!(bg_file.metadata, vec![("a".to_string(), "b".to_string())]);
+    }
+
+    #[test]
+    fn rename_tile_files() {
+        let data = b"@BG 1 2 3\n>foo\n>bar\n@HASH 00000000000000ff foo\n";
+        let mut bg_file = BgFile::read(&data[..]).unwrap();
+        let renames = vec![
+            ("foo".to_string(), "new/foo".to_string()),
+            ("baz".to_string(), "quux".to_string()),
+        ];
+        bg_file.rename_tile_files(&renames).unwrap();
+        assert_eq!(bg_file.filenames, vec!["new/foo", "bar"]);
+        assert_eq!(
+            bg_file.tile_checksums,
+            vec![("new/foo".to_string(), 0xff)]
+        );
+        let renames = vec![("bar".to_string(), "new/foo".to_string())];
+        assert!(bg_file.rename_tile_files(&renames).is_err());
+    }
+
+    #[test]
+    fn header_line_examples() {
+        // Every kind of header line in the format help should parse.
+        for header in HEADER_LINES.iter() {
+            let example = match header.keyword {
+                "HASH" => "00000000deadbeef tiles",
+                "GRADIENT" => "0 0 64",
+                "BACKDROP" => "-8 0 sky.png",
+                "META" => "music cave theme",
+                "LAYER" => "walls",
+                other => panic!("no example for @{}", other),
+            };
+            let text = format!("@BG 0 0 0\n@{} {}\n", header.keyword, example);
+            assert!(BgFile::read(text.as_bytes()).is_ok(), "{}", text);
+        }
+        assert!(BgFile::read(&b"@BG 0 0 0\n@BOGUS 1\n"[..]).is_err());
+    }
+
+    #[test]
+    fn subgrid_stats() {
+        let subgrid = SubGrid::new(20, 3);
+        assert_eq!(subgrid.count_empty_cells(), 60);
+        assert_eq!(subgrid.content_bounds(), None);
+        assert_eq!(
+            subgrid.rects_containing(&None),
+            vec![Rect::new(0, 0, 20, 3)]
+        );
+
+        let mut back: SubGrid<u32> = SubGrid::new(20, 3);
+        back.set((2, 1), Some(1));
+        back.set((4, 1), Some(1));
+        assert_eq!(back.count_empty_cells(), 58);
+        assert_eq!(back.content_bounds(), Some(Rect::new(2, 1, 3, 1)));
+        let mut front: SubGrid<u32> = SubGrid::new(20, 3);
+        front.set((19, 0), Some(2));
+        assert_eq!(front.content_bounds(), Some(Rect::new(19, 0, 1, 1)));
+        let empty: SubGrid<u32> = SubGrid::new(20, 3);
+        assert_eq!(layers_content_bounds([&empty, &empty]), None);
+        assert_eq!(
+            layers_content_bounds([&back, &empty, &front]),
+            Some(Rect::new(2, 0, 18, 2))
+        );
+    }
+
+    #[test]
+    fn flatten_several_layers() {
+        let mut back: SubGrid<u32> = SubGrid::new(3, 2);
+        back.set((0, 0), Some(1));
+        back.set((1, 0), Some(1));
+        let mut front: SubGrid<u32> = SubGrid::new(3, 2);
+        front.set((1, 0), Some(2));
+        front.set((2, 1), Some(2));
+        let empty: SubGrid<u32> = SubGrid::new(3, 2);
+        let flattened = flatten_layers([&back, &front, &empty]);
+        assert_eq!(flattened[(0, 0)], Some(1));
+        assert_eq!(flattened[(1, 0)], Some(2));
+        assert_eq!(flattened[(2, 1)], Some(2));
+        assert_eq!(flattened.count_empty_cells(), 3);
+        assert_eq!(flatten_layers([&front, &back])[(1, 0)], Some(1));
+    }
+
+    #[test]
+    fn subgrid_chunks() {
+        // 40x20 cells make 3x2 chunks, the last column and row partial.
+        let mut subgrid: SubGrid<u32> = SubGrid::new(40, 20);
+        let allocated = |subgrid: &SubGrid<u32>| {
+            subgrid.chunks.iter().filter(|chunk| chunk.is_some()).count()
+        };
+        subgrid.set((0, 0), None);
+        assert_eq!(allocated(&subgrid), 0);
+        subgrid.set((39, 19), Some(1));
+        subgrid.set((15, 15), Some(2));
+        subgrid.set((16, 16), Some(3));
+        assert_eq!(allocated(&subgrid), 3);
+        assert_eq!(subgrid[(39, 19)], Some(1));
+        assert_eq!(subgrid[(0, 19)], None);
+        assert_eq!(subgrid.count_empty_cells(), 797);
+        assert_eq!(subgrid.content_bounds(), Some(Rect::new(15, 15, 25, 5)));
+
+        let across = subgrid.copy_rect(Rect::new(14, 14, 4, 4));
+        assert_eq!(across.size(), (4, 4));
+        assert_eq!(across[(1, 1)], Some(2));
+        assert_eq!(across[(2, 2)], Some(3));
+        let corner = subgrid.copy_rect(Rect::new(38, 18, 5, 5));
+        assert_eq!(corner.size(), (2, 2));
+        assert_eq!(corner[(1, 1)], Some(1));
+        assert_eq!(subgrid.copy_rect(Rect::new(-2, -3, 4, 4)).size(), (2, 1));
+        assert_eq!(subgrid.copy_rect(Rect::new(-9, 2, 3, 3)).size(), (0, 3));
+        assert_eq!(subgrid.copy_rect(Rect::new(45, 2, 3, 3)).size(), (0, 3));
+
+        let mut pasted: SubGrid<u32> = SubGrid::new(20, 20);
+        pasted.paste(&across, (14, 14));
+        assert_eq!(pasted[(15, 15)], Some(2));
+        assert_eq!(pasted[(16, 16)], Some(3));
+        assert_eq!(allocated(&pasted), 2);
+
+        subgrid.set((39, 19), None);
+        subgrid.set((16, 16), None);
+        assert_eq!(allocated(&subgrid), 1);
+        subgrid.set((15, 15), None);
+        assert_eq!(allocated(&subgrid), 0);
+        assert_eq!(subgrid.content_bounds(), None);
+    }
+
+    #[test]
+    fn tiled_subgrid_with_negative_origin() {
+        let mut pattern: SubGrid<u32> = SubGrid::new(2, 1);
+        pattern.set((0, 0), Some(1));
+        pattern.set((1, 0), Some(2));
+        let tiled = pattern.tiled(3, 2, Point::new(-1, -1));
+        let row: Vec<_> = (0..3).map(|col| tiled[(col, 0)]).collect();
+        assert_eq!(row, vec![Some(2), Some(1), Some(2)]);
+        assert_eq!(tiled[(0, 1)], Some(2));
+    }
+
+    #[test]
+    #[should_panic]
+    fn subgrid_index_out_of_range() {
+        let subgrid: SubGrid<u32> = SubGrid::new(16, 16);
+        let _ = subgrid[(16, 0)];
+    }
+
+    #[test]
+    fn front_sidecar() {
+        let front = parse_front_sidecar("3 4 # walls\n\n10\n").unwrap();
+        assert_eq!(front.into_iter().collect::<Vec<_>>(), vec![3, 4, 10]);
+        assert!(parse_front_sidecar("3 x").is_err());
+    }
+
+    #[test]
+    fn palette_sidecar() {
+        let text = "0 0 1 # sky\n\n3\n";
+        assert_eq!(parse_palette_sidecar(text).unwrap(), vec![0, 0, 1, 3]);
+        assert!(parse_palette_sidecar("0 -1").is_err());
+    }
+
+    #[test]
+    fn read_layers() {
+        let data =
+            b"@BG 1 2 3 2x2\n>foo\n@LAYER back\n\n  AB\n@LAYER front\n\nAC\n";
+        let bg_file = BgFile::read(&data[..]).unwrap();
+        assert_eq!(bg_file.base_layer_name, "back");
+        assert_eq!(bg_file.cells, vec![((1, 0), (0, 1))]);
+        assert_eq!(
+            bg_file.layers,
+            vec![("front".to_string(), vec![((0, 1), (0, 2))])]
+        );
+        let data = b"@BG 1 2 3 2x2\n>foo\n\n@LAYER front\nAB\nAB\nAB\n";
+        assert!(BgFile::read(&data[..]).is_err());
+    }
+
+    #[test]
+    fn read_flips() {
+        let data = b"@BG 1 2 3 2x2\n>foo\n\n!AB  \nAC*AD\n@LAYER top\n^AE\n";
+        let bg_file = BgFile::read(&data[..]).unwrap();
+        assert_eq!(
+            bg_file.cells,
+            vec![((0, 0), (0, 1)), ((0, 1), (0, 2)), ((1, 1), (0, 3))]
+        );
+        let horz = Flip { horz: true, vert: false };
+        let vert = Flip { horz: false, vert: true };
+        let both = Flip { horz: true, vert: true };
+        assert_eq!(
+            bg_file.flips,
+            vec![(0, (0, 0), horz), (0, (1, 1), both), (1, (0, 0), vert)]
+        );
+        let data = b"@BG 1 2 3 2x2\n>foo\n\n!";
+        assert!(BgFile::read(&data[..]).is_err());
+    }
+
+    #[test]
+    fn fit_oversized_image() {
+        let mut image = ahi::Image::new(16, 12);
+        image[(2, 2)] = 5;
+        let crop = TileSizeLock { size: 8, fit: TileFit::Crop };
+        let cropped = crop.fit_image(image.clone());
+        assert_eq!((cropped.width(), cropped.height()), (8, 8));
+        assert_eq!(cropped[(2, 2)], 5);
+        let letterbox = TileSizeLock { size: 8, fit: TileFit::Letterbox };
+        let boxed = letterbox.fit_image(image);
+        assert_eq!((boxed.width(), boxed.height()), (8, 8));
+        assert_eq!(boxed[(1, 2)], 5);
+    }
 }
 
 //===========================================================================//
@@ -0,0 +1,96 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::time::SystemTime;
+
+//===========================================================================//
+
+/// The command used to edit tile files when the `tile_editor.command` config
+/// key isn't set.
+#[cfg(target_os = "macos")]
+pub const DEFAULT_TILE_EDITOR_COMMAND: &str = "open";
+#[cfg(not(target_os = "macos"))]
+pub const DEFAULT_TILE_EDITOR_COMMAND: &str = "xdg-open";
+
+//===========================================================================//
+
+/// A tile file that is being edited in an external program, and that is
+/// watched for changes so that it can be reloaded.
+pub struct TileSource {
+    filename: String,
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    child: Option<Child>,
+}
+
+impl TileSource {
+    /// Launches the given command (split on whitespace, with the file's path
+    /// appended as the last argument) to edit the named tile file, and
+    /// starts watching the file for changes.
+    pub fn launch(
+        command: &str,
+        filename: &str,
+        path: PathBuf,
+    ) -> io::Result<TileSource> {
+        let mut words = command.split_whitespace();
+        let program = words.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "empty command")
+        })?;
+        let child = Command::new(program).args(words).arg(&path).spawn()?;
+        Ok(TileSource {
+            filename: filename.to_string(),
+            modified: modified_time(&path),
+            path,
+            child: Some(child),
+        })
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Returns true if the file has been modified since the last time this
+    /// was called (or since the editor was launched).
+    pub fn poll(&mut self) -> bool {
+        // Reap the editor process once it exits, but keep watching the file,
+        // since some commands (e.g. "open") exit right away.
+        if let Some(ref mut child) = self.child {
+            if let Ok(Some(_)) = child.try_wait() {
+                self.child = None;
+            }
+        }
+        let modified = modified_time(&self.path);
+        if modified != self.modified {
+            self.modified = modified;
+            modified.is_some()
+        } else {
+            false
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+//===========================================================================//
@@ -18,21 +18,44 @@
 // +--------------------------------------------------------------------------+
 
 use super::canvas::{Canvas, Sprite};
-use super::element::{Action, AggregateElement, GuiElement, SubrectElement};
+use super::element::{
+    Action, AggregateElement, GuiElement, Movable, SubrectElement,
+};
 use super::event::{Event, Keycode, NONE};
 use super::state::{EditorState, Tool};
+use super::theme::Theme;
 use sdl2::rect::{Point, Rect};
+use std::time::{Duration, Instant};
 
 //===========================================================================//
 
+/// If a tool's key is held down for at least this long, the tool is only
+/// active temporarily, and the previous tool is restored on key release.
+const HOLD_DURATION: Duration = Duration::from_millis(300);
+
 pub struct Toolbox {
     element: SubrectElement<AggregateElement<Tool, ()>>,
+    // The key that most recently switched tools (while it's still held
+    // down), along with the tool to restore on release, and when it was
+    // pressed:
+    held: Option<(Keycode, Tool, Instant)>,
+    theme: Theme,
 }
 
 impl Toolbox {
-    pub fn new(left: i32, top: i32, mut icons: Vec<Sprite>) -> Toolbox {
-        icons.truncate(6);
-        assert_eq!(icons.len(), 6);
+    pub fn new(
+        left: i32,
+        top: i32,
+        mut icons: Vec<Sprite>,
+        theme: Theme,
+    ) -> Toolbox {
+        icons.truncate(11);
+        assert_eq!(icons.len(), 11);
+        let terrain_icon = icons.pop().unwrap();
+        let line_icon = icons.pop().unwrap();
+        let rect_icon = icons.pop().unwrap();
+        let border_icon = icons.pop().unwrap();
+        let dither_icon = icons.pop().unwrap();
         let swap_icon = icons.pop().unwrap();
         let replace_icon = icons.pop().unwrap();
         let select_icon = icons.pop().unwrap();
@@ -40,24 +63,95 @@ impl Toolbox {
         let bucket_icon = icons.pop().unwrap();
         let pencil_icon = icons.pop().unwrap();
         let elements: Vec<Box<dyn GuiElement<Tool, ()>>> = vec![
-            Toolbox::picker(2, 2, Tool::Pencil, Keycode::P, pencil_icon),
-            Toolbox::picker(24, 2, Tool::PaintBucket, Keycode::K, bucket_icon),
-            Toolbox::picker(2, 24, Tool::Eyedropper, Keycode::Y, eyedrop_icon),
-            Toolbox::picker(24, 24, Tool::Select, Keycode::S, select_icon),
+            Toolbox::picker(
+                2,
+                2,
+                Tool::Pencil,
+                Keycode::P,
+                pencil_icon,
+                theme,
+            ),
+            Toolbox::picker(
+                24,
+                2,
+                Tool::PaintBucket,
+                Keycode::K,
+                bucket_icon,
+                theme,
+            ),
+            Toolbox::picker(
+                2,
+                24,
+                Tool::Eyedropper,
+                Keycode::Y,
+                eyedrop_icon,
+                theme,
+            ),
+            Toolbox::picker(
+                24,
+                24,
+                Tool::Select,
+                Keycode::S,
+                select_icon,
+                theme,
+            ),
             Toolbox::picker(
                 2,
                 46,
                 Tool::PaletteReplace,
                 Keycode::V,
                 replace_icon,
+                theme,
+            ),
+            Toolbox::picker(
+                24,
+                46,
+                Tool::PaletteSwap,
+                Keycode::X,
+                swap_icon,
+                theme,
+            ),
+            Toolbox::picker(
+                2,
+                68,
+                Tool::DitherFill,
+                Keycode::D,
+                dither_icon,
+                theme,
+            ),
+            Toolbox::picker(
+                24,
+                68,
+                Tool::Border,
+                Keycode::O,
+                border_icon,
+                theme,
+            ),
+            Toolbox::picker(
+                2,
+                90,
+                Tool::Rectangle,
+                Keycode::R,
+                rect_icon,
+                theme,
+            ),
+            Toolbox::picker(24, 90, Tool::Line, Keycode::I, line_icon, theme),
+            Toolbox::picker(
+                2,
+                112,
+                Tool::Terrain,
+                Keycode::T,
+                terrain_icon,
+                theme,
             ),
-            Toolbox::picker(24, 46, Tool::PaletteSwap, Keycode::X, swap_icon),
         ];
         Toolbox {
             element: SubrectElement::new(
                 AggregateElement::new(elements),
-                Rect::new(left, top, 46, 68),
+                Rect::new(left, top, 46, 134),
             ),
+            held: None,
+            theme,
         }
     }
 
@@ -67,17 +161,34 @@ impl Toolbox {
         tool: Tool,
         key: Keycode,
         icon: Sprite,
+        theme: Theme,
     ) -> Box<dyn GuiElement<Tool, ()>> {
         Box::new(SubrectElement::new(
-            ToolPicker::new(tool, key, icon),
+            ToolPicker::new(tool, key, icon, theme),
             Rect::new(x, y, 20, 20),
         ))
     }
 }
 
+impl Movable for Toolbox {
+    fn rect(&self) -> Rect {
+        self.element.rect()
+    }
+
+    fn move_to(&mut self, topleft: Point) {
+        let rect = self.element.rect();
+        self.element.set_rect(Rect::new(
+            topleft.x(),
+            topleft.y(),
+            rect.width(),
+            rect.height(),
+        ));
+    }
+}
+
 impl GuiElement<EditorState, ()> for Toolbox {
     fn draw(&self, state: &EditorState, canvas: &mut Canvas) {
-        canvas.fill_rect((95, 95, 95, 255), self.element.rect());
+        canvas.fill_rect(self.theme.panel, self.element.rect());
         self.element.draw(&state.tool(), canvas);
     }
 
@@ -86,10 +197,28 @@ impl GuiElement<EditorState, ()> for Toolbox {
         event: &Event,
         state: &mut EditorState,
     ) -> Action<()> {
-        let mut new_tool = state.tool();
+        if let &Event::KeyUp(key, _) = event {
+            if let Some((held_key, old_tool, pressed)) = self.held {
+                if key == held_key {
+                    self.held = None;
+                    if pressed.elapsed() >= HOLD_DURATION
+                        && state.tool() != old_tool
+                    {
+                        state.set_tool(old_tool);
+                        return Action::redraw().and_stop();
+                    }
+                }
+            }
+            return Action::ignore();
+        }
+        let old_tool = state.tool();
+        let mut new_tool = old_tool;
         let action = self.element.on_event(event, &mut new_tool);
-        if new_tool != state.tool() {
+        if new_tool != old_tool {
             state.set_tool(new_tool);
+            if let &Event::KeyDown(key, _) = event {
+                self.held = Some((key, old_tool, Instant::now()));
+            }
         }
         action
     }
@@ -101,27 +230,33 @@ struct ToolPicker {
     tool: Tool,
     key: Keycode,
     icon: Sprite,
+    theme: Theme,
 }
 
 impl ToolPicker {
-    fn new(tool: Tool, key: Keycode, icon: Sprite) -> ToolPicker {
-        ToolPicker { tool, key, icon }
+    fn new(
+        tool: Tool,
+        key: Keycode,
+        icon: Sprite,
+        theme: Theme,
+    ) -> ToolPicker {
+        ToolPicker { tool, key, icon, theme }
     }
 }
 
 impl GuiElement<Tool, ()> for ToolPicker {
     fn draw(&self, tool: &Tool, canvas: &mut Canvas) {
         if *tool == self.tool {
-            canvas.clear((255, 255, 255, 255));
+            canvas.clear(self.theme.selected_tool);
         } else {
-            canvas.clear((95, 95, 95, 255));
+            canvas.clear(self.theme.panel);
         }
         canvas.draw_sprite(&self.icon, Point::new(2, 2));
     }
 
     fn on_event(&mut self, event: &Event, tool: &mut Tool) -> Action<()> {
         match event {
-            &Event::MouseDown(_) => {
+            &Event::MouseDown(_, _) => {
                 *tool = self.tool;
                 return Action::redraw().and_stop();
             }
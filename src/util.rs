@@ -18,8 +18,16 @@
 // +--------------------------------------------------------------------------+
 
 use ahi;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::fs::File;
-use std::io;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+//===========================================================================//
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
 
 //===========================================================================//
 
@@ -28,9 +36,47 @@ pub fn load_ahf_from_file(path: &String) -> io::Result<ahi::Font> {
     ahi::Font::read(&mut file)
 }
 
-pub fn load_ahi_from_file(path: &String) -> io::Result<ahi::Collection> {
+pub fn load_ahi_from_file<P: AsRef<Path>>(
+    path: P,
+) -> io::Result<ahi::Collection> {
     let mut file = File::open(path)?;
     ahi::Collection::read(&mut file)
 }
 
+/// Opens the file at the given path for reading, transparently decompressing
+/// it if it starts with the gzip magic bytes.
+pub fn open_maybe_gzipped<P: AsRef<Path>>(
+    path: P,
+) -> io::Result<Box<dyn io::Read>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(GzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Returns true if files saved to the given path should be gzip-compressed
+/// (that is, if the path ends in ".gz").
+pub fn is_gzip_path(path: &Path) -> bool {
+    path.extension() == Some("gz".as_ref())
+}
+
+pub fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Computes a 64-bit FNV-1a hash of the given data.  This is just for
+/// detecting changes, not for security.
+pub fn checksum(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
 //===========================================================================//
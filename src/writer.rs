@@ -0,0 +1,167 @@
+// +--------------------------------------------------------------------------+
+// | Copyright 2016 Matthew D. Steele <mdsteele@alum.mit.edu>                 |
+// |                                                                          |
+// | This file is part of Linoleum.                                           |
+// |                                                                          |
+// | Linoleum is free software: you can redistribute it and/or modify it      |
+// | under the terms of the GNU General Public License as published by the    |
+// | Free Software Foundation, either version 3 of the License, or (at your   |
+// | option) any later version.                                               |
+// |                                                                          |
+// | Linoleum is distributed in the hope that it will be useful, but WITHOUT  |
+// | ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or    |
+// | FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License    |
+// | for details.                                                             |
+// |                                                                          |
+// | You should have received a copy of the GNU General Public License along  |
+// | with Linoleum.  If not, see <http://www.gnu.org/licenses/>.              |
+// +--------------------------------------------------------------------------+
+
+use super::util;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+//===========================================================================//
+
+/// Produces the contents of a file to be written.  This is run on the
+/// writer's thread, so it can do the slow parts of saving (e.g. compressing
+/// the data) without stalling the UI.
+pub type Contents = Box<dyn FnOnce() -> io::Result<Vec<u8>> + Send>;
+
+// A batch of files to write, each as the paths to write it to and a function
+// producing its contents:
+type FileBatch = Vec<(Vec<PathBuf>, Contents)>;
+
+/// Writes files on a background thread, so that saving large maps doesn't
+/// stall the UI.  Batches of files are written in the order they were
+/// queued, and dropping the writer waits for any queued writes to finish.
+/// The result of writing a batch is the checksum of each of its files'
+/// contents, in order.
+pub struct FileWriter {
+    jobs: Option<Sender<FileBatch>>,
+    results: Receiver<io::Result<Vec<u64>>>,
+    thread: Option<JoinHandle<()>>,
+    disconnected: bool,
+}
+
+impl FileWriter {
+    pub fn new() -> FileWriter {
+        let (jobs_sender, jobs_receiver) = mpsc::channel::<FileBatch>();
+        let (results_sender, results_receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for files in jobs_receiver {
+                let result = files
+                    .into_iter()
+                    .map(|(paths, contents)| {
+                        let data = contents()?;
+                        for path in paths {
+                            fs::write(path, &data)?;
+                        }
+                        Ok(util::checksum(&data))
+                    })
+                    .collect();
+                if results_sender.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+        FileWriter {
+            jobs: Some(jobs_sender),
+            results: results_receiver,
+            thread: Some(thread),
+            disconnected: false,
+        }
+    }
+
+    /// Queues a batch of files to be written.  The result of writing the
+    /// batch will later be returned by `poll`.
+    pub fn write(&self, files: FileBatch) -> io::Result<()> {
+        let sent = match self.jobs {
+            Some(ref jobs) => jobs.send(files).is_ok(),
+            None => false,
+        };
+        if sent {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "file writer thread has stopped",
+            ))
+        }
+    }
+
+    /// Returns the result of the next finished batch of writes, if any.  If
+    /// the thread has stopped, returns an error once, and then `None`.
+    pub fn poll(&mut self) -> Option<io::Result<Vec<u64>>> {
+        match self.results.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) if self.disconnected => None,
+            Err(TryRecvError::Disconnected) => {
+                self.disconnected = true;
+                Some(Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "file writer thread has stopped",
+                )))
+            }
+        }
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        // Closing the job channel makes the thread exit once it has finished
+        // all queued writes.
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::super::util;
+    use super::{Contents, FileWriter};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+
+    fn file(path: &Path, data: &'static [u8]) -> (Vec<PathBuf>, Contents) {
+        (vec![path.to_path_buf()], Box::new(move || Ok(data.to_vec())))
+    }
+
+    #[test]
+    fn writes_in_order() {
+        let dir = std::env::temp_dir()
+            .join(format!("linoleum-writer-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.txt");
+        let mut writer = FileWriter::new();
+        writer.write(vec![file(&path, b"first")]).unwrap();
+        writer.write(vec![file(&path, b"second")]).unwrap();
+        let missing = dir.join("missing").join("out.txt");
+        writer.write(vec![file(&missing, b"third")]).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut results = Vec::new();
+        while results.len() < 3 {
+            assert!(Instant::now() < deadline, "timed out waiting for writes");
+            match writer.poll() {
+                Some(result) => results.push(result.ok()),
+                None => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        let first = vec![util::checksum(b"first")];
+        let second = vec![util::checksum(b"second")];
+        assert_eq!(results, vec![Some(first), Some(second), None]);
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+//===========================================================================//